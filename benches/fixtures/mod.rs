@@ -0,0 +1,87 @@
+// Representative data for the benchmarks. Stations and listings are generated, so the benchmarks
+// don't depend on locast being reachable.
+use locast2tuner::service::station::{Listing, Station};
+
+pub static MASTER_PLAYLIST: &str = include_str!("master.m3u8");
+pub static MEDIA_PLAYLIST: &str = include_str!("media.m3u8");
+pub static PLAYLIST_URL: &str = "https://cdn.example.com/live/station/playlist.m3u8";
+
+static CALL_SIGNS: [&str; 8] = [
+    "KABC", "KCBS", "KNBC", "KTTV", "KCOP", "KCET", "KTLA", "KMEX",
+];
+static START_TIME: i64 = 1_614_556_800_000;
+static LISTING_DURATION: i64 = 1_800;
+
+/// Generate `count` stations for `dma`, each with `days` of half hour listings
+pub fn stations(dma: i64, count: usize, days: i64) -> Vec<Station> {
+    (0..count)
+        .map(|i| {
+            let id = dma * 10_000 + i as i64;
+            let call_sign = CALL_SIGNS[i % CALL_SIGNS.len()];
+            Station {
+                active: true,
+                callSign: call_sign.to_string(),
+                channel: Some(format!("{}.{}", i / 4 + 2, i % 4 + 1)),
+                city: Some(format!("City {}", dma)),
+                dma,
+                id,
+                listings: listings(id, days),
+                logo226Url: None,
+                logoUrl: Some(format!("https://static.example.com/logos/{}.png", id)),
+                name: format!("{} {}", call_sign, i),
+                sequence: None,
+                stationId: id.to_string(),
+                timezone: Some("America/Los_Angeles".to_string()),
+                tivoId: None,
+                transcodeId: id,
+                channel_remapped: None,
+                callSign_remapped: None,
+                remapped: None,
+                channel_locast: None,
+                channel_fcc: None,
+            }
+        })
+        .collect()
+}
+
+fn listings(station_id: i64, days: i64) -> Vec<Listing> {
+    (0..days * 24 * 3600 / LISTING_DURATION)
+        .map(|i| {
+            Listing {
+            airdate: Some(START_TIME - 86_400_000 * 365),
+            audioProperties: Some("stereo".to_string()),
+            description: Some(
+                "A long enough description of the programme, because real guide data has these too."
+                    .to_string(),
+            ),
+            directors: None,
+            duration: LISTING_DURATION,
+            entityType: "Episode".to_string(),
+            episodeNumber: Some((i % 22) as i16 + 1),
+            episodeTitle: Some(format!("Episode {}", i)),
+            genres: Some("Comedy, Drama".to_string()),
+            hasImageArtwork: false,
+            hasSeriesArtwork: false,
+            isNew: Some(i % 3 == 0),
+            isLive: None,
+            isPremiere: None,
+            preferredImage: Some("https://static.example.com/images/programme.jpg".to_string()),
+            preferredImageHeight: Some(1080),
+            preferredImageWidth: Some(1440),
+            programId: format!("EP{:08}{:04}", station_id, i),
+            rating: Some("TV-PG".to_string()),
+            releaseDate: None,
+            releaseYear: Some(2020),
+            seasonNumber: Some(3),
+            seriesId: Some(format!("SH{:08}", station_id)),
+            shortDescription: Some("A short description.".to_string()),
+            showType: "Series".to_string(),
+            startTime: START_TIME + i * LISTING_DURATION * 1000,
+            stationId: station_id,
+            title: format!("Programme {}", i % 48),
+            topCast: Some("Actor One, Actor Two".to_string()),
+            videoProperties: Some("HD".to_string()),
+        }
+        })
+        .collect()
+}
@@ -0,0 +1,101 @@
+// Benchmarks for the paths that are hit on every guide refresh or stream request. Run with
+// `cargo bench`; see the "Benchmarks" section in the README.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use hls_m3u8::MasterPlaylist;
+use locast2tuner::{
+    http::{
+        playlist::media_playlist,
+        templates::{self, ChannelName, M3uFormat, M3uGroup, M3uSort},
+    },
+    service::{highest_quality_url, multiplexer::remap_station, station::Station},
+};
+use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+
+mod fixtures;
+
+static DMAS: [i64; 3] = [803, 504, 602];
+static STATIONS_PER_DMA: usize = 100;
+static DAYS: i64 = 7;
+
+fn all_stations() -> Vec<Vec<Station>> {
+    DMAS.iter()
+        .map(|dma| fixtures::stations(*dma, STATIONS_PER_DMA, DAYS))
+        .collect()
+}
+
+fn epg_xml(c: &mut Criterion) {
+    let stations: Arc<Vec<Station>> = Arc::new(all_stations().into_iter().flatten().collect());
+    let overrides = Arc::new(HashMap::new());
+    let mut group = c.benchmark_group("epg");
+    group.sample_size(10);
+    group.bench_function("epg_xml", |b| {
+        b.iter(|| {
+            templates::epg_xml(
+                stations.clone(),
+                "http://127.0.0.1:6077".to_string(),
+                overrides.clone(),
+            )
+            .collect::<String>()
+        })
+    });
+    group.finish();
+}
+
+fn tuner_m3u(c: &mut Criterion) {
+    let stations: Vec<Station> = all_stations().into_iter().flatten().collect();
+    c.bench_function("tuner_m3u", |b| {
+        b.iter(|| {
+            templates::tuner_m3u(
+                &stations,
+                "http://127.0.0.1:6077",
+                &ChannelName::CallSignAndCity,
+                M3uFormat::Plex,
+                M3uSort::Lineup,
+                &M3uGroup::City,
+                &[],
+            )
+        })
+    });
+}
+
+fn multiplexer(c: &mut Criterion) {
+    let stations = all_stations();
+    let channel_remap = HashMap::new();
+    let mut group = c.benchmark_group("multiplexer");
+    for remap in [false, true] {
+        group.bench_function(format!("aggregate (remap: {})", remap), |b| {
+            b.iter_batched(
+                || stations.clone(),
+                |stations| {
+                    let mut all_stations = Vec::new();
+                    for (i, service_stations) in stations.into_iter().enumerate() {
+                        for mut station in service_stations {
+                            remap_station(&mut station, 100 * i as u32, remap, &channel_remap);
+                            all_stations.push(station);
+                        }
+                    }
+                    all_stations
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn playlists(c: &mut Criterion) {
+    let mut group = c.benchmark_group("playlist");
+    group.bench_function("master", |b| {
+        b.iter(|| {
+            let mp = MasterPlaylist::try_from(fixtures::MASTER_PLAYLIST).unwrap();
+            highest_quality_url(mp.variant_streams, fixtures::PLAYLIST_URL)
+        })
+    });
+    group.bench_function("media", |b| {
+        b.iter(|| media_playlist(fixtures::MEDIA_PLAYLIST, fixtures::PLAYLIST_URL).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, epg_xml, tuner_m3u, multiplexer, playlists);
+criterion_main!(benches);
@@ -0,0 +1,168 @@
+use crate::{
+    config::Config,
+    credentials::check_account,
+    fcc_facilities::FCCFacilities,
+    service::{check_location, station::ChannelRemapEntry, Location},
+};
+use simple_error::SimpleError;
+use std::{collections::HashMap, fs::File, path::PathBuf, sync::Arc};
+
+/// Outcome of a single check
+struct CheckResult {
+    check: String,
+    ok: bool,
+    detail: String,
+}
+
+/// Check the configuration without starting any servers: the config and remap files, the locast
+/// accounts and the zip codes. A summary is printed to stdout. Returns an error if any check failed,
+/// so it can be used in scripts.
+pub async fn run(config: Arc<Config>) -> Result<(), SimpleError> {
+    let mut results = vec![config_file(&config), remap_file(&config)];
+
+    if config.needs_setup() {
+        results.push(CheckResult {
+            check: "credentials".to_owned(),
+            ok: false,
+            detail: "No locast username and password configured".to_owned(),
+        });
+    } else {
+        let accounts = std::iter::once((config.username.as_str(), config.password.as_str())).chain(
+            config
+                .extra_accounts
+                .iter()
+                .map(|a| (a.username.as_str(), a.password.as_str()))
+                .chain(
+                    config
+                        .location_accounts
+                        .iter()
+                        .map(|a| (a.username.as_str(), a.password.as_str())),
+                ),
+        );
+        for (username, password) in accounts {
+            let result = check_account(username, password).await;
+            results.push(CheckResult {
+                check: format!("account {}", username),
+                ok: result.is_ok(),
+                detail: result.map_or_else(|e| e.to_string(), |_| "Logged in".to_owned()),
+            });
+        }
+    }
+
+    let fcc_facilities = FCCFacilities::new(config.clone()).await;
+    let health = fcc_facilities.health().await;
+    results.push(CheckResult {
+        check: "fcc facilities".to_owned(),
+        ok: health.facilities > 0,
+        detail: match &health.reason {
+            Some(reason) => format!("{} facilities ({})", health.facilities, reason),
+            None => format!("{} facilities", health.facilities),
+        },
+    });
+
+    for location in Location::configured(&config) {
+        let check = match &location {
+            Location::Ip => "location (by IP address)".to_owned(),
+            l => format!("location {}", l),
+        };
+        let result = match check_location(&location).await {
+            Ok(geo) => {
+                let facilities = match geo.DMA.parse::<i64>() {
+                    Ok(dma) => fcc_facilities.dma_facilities(dma).await,
+                    Err(_) => 0,
+                };
+                CheckResult {
+                    check,
+                    ok: facilities > 0,
+                    detail: if facilities > 0 {
+                        format!(
+                            "{} (DMA {}), {} FCC facilities",
+                            geo.name, geo.DMA, facilities
+                        )
+                    } else {
+                        format!("{} (DMA {}) has no FCC facilities", geo.name, geo.DMA)
+                    },
+                }
+            }
+            Err(e) => CheckResult {
+                check,
+                ok: false,
+                detail: e.to_string(),
+            },
+        };
+        results.push(result);
+    }
+
+    print_summary(&results);
+    let failed = results.iter().filter(|r| !r.ok).count();
+    if failed > 0 {
+        Err(SimpleError::new(format!("{} check(s) failed", failed)))
+    } else {
+        Ok(())
+    }
+}
+
+/// The config file is optional, since everything can be passed on the command line
+fn config_file(config: &Config) -> CheckResult {
+    CheckResult {
+        check: "config file".to_owned(),
+        ok: true,
+        detail: if config.config_file.exists() {
+            format!("{}", config.config_file.display())
+        } else {
+            "None, using command line options".to_owned()
+        },
+    }
+}
+
+/// The remap file is only used when multiplexing, but has to be valid JSON if it exists
+fn remap_file(config: &Config) -> CheckResult {
+    let check = "remap file".to_owned();
+    let path = match &config.remap_file {
+        Some(f) => PathBuf::from(f),
+        None => {
+            return CheckResult {
+                check,
+                ok: true,
+                detail: "Not configured".to_owned(),
+            }
+        }
+    };
+    if !path.exists() {
+        return CheckResult {
+            check,
+            ok: true,
+            detail: format!("{} doesn't exist yet", path.display()),
+        };
+    }
+    let parsed = File::open(&path).map_err(|e| e.to_string()).and_then(|f| {
+        serde_json::from_reader::<_, HashMap<String, ChannelRemapEntry>>(f)
+            .map_err(|e| e.to_string())
+    });
+    match parsed {
+        Ok(entries) => CheckResult {
+            check,
+            ok: true,
+            detail: format!("{} remappings in {}", entries.len(), path.display()),
+        },
+        Err(e) => CheckResult {
+            check,
+            ok: false,
+            detail: format!("{}: {}", path.display(), e),
+        },
+    }
+}
+
+fn print_summary(results: &[CheckResult]) {
+    let width = results.iter().map(|r| r.check.len()).max().unwrap_or(0);
+    println!("{:width$}  RESULT  DETAIL", "CHECK", width = width);
+    for r in results {
+        println!(
+            "{:width$}  {:6}  {}",
+            r.check,
+            if r.ok { "OK" } else { "FAIL" },
+            r.detail,
+            width = width
+        );
+    }
+}
@@ -1,78 +1,459 @@
-use crate::config::Config;
+use crate::{
+    config::Config,
+    notify::{self, Event},
+    telemetry::Span,
+};
 use chrono::{DateTime, Utc};
 use futures::lock::Mutex;
-use log::{error, info};
-use serde::Deserialize;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use simple_error::SimpleError;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::time::{sleep, Duration};
 
 static LOGIN_URL: &str = "https://api.locastnet.org/api/user/login";
 static USER_URL: &str = "https://api.locastnet.org/api/user/me";
 static TOKEN_LIFETIME: i64 = 3600;
+static MIN_RELOGIN_INTERVAL: i64 = 30;
+// Retries of a login after locast rejected the token. A failure ends the stream, rather than the
+// whole process.
+static RELOGIN_RETRIES: usize = 10;
+/// Seconds between checks of the donation status of all accounts
+static DONATION_CHECK_INTERVAL: u64 = 3600;
+/// Seconds between warnings about a donation that's about to expire
+static DONATION_WARNING_INTERVAL: i64 = 86400;
 
-// Struct that holds the locast token and is able to login to the locast service
+lazy_static! {
+    // Held while an account is picked and its stream count is incremented, so concurrent streams
+    // don't pick the same account. It's shared by all credentials, since locations share accounts.
+    static ref LEASE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+// Struct that holds the locast tokens for all configured accounts and is able to login to the
+// locast service. The first account is the primary account, which is used for everything but
+// streams. Streams are assigned to the least loaded account using `lease`.
 #[derive(Debug)]
 pub struct LocastCredentials {
-    config: Arc<Config>,
-    token: Arc<Mutex<String>>,
-    last_login: Arc<Mutex<DateTime<Utc>>>,
+    accounts: Vec<Arc<Account>>,
+    // Accounts that are only used for a single location, with that location (zip code, DMA or
+    // coordinates)
+    location_accounts: Vec<(String, Arc<Account>)>,
 }
 
 impl LocastCredentials {
     // Construct a new object
     pub async fn new(config: Arc<Config>) -> LocastCredentials {
-        let token = login(&(config.username), &(config.password)).await;
-        validate_user(&token).await;
+        let mut accounts = vec![Arc::new(
+            Account::new(&config.username, &config.password).await,
+        )];
+        for a in &config.extra_accounts {
+            accounts.push(Arc::new(Account::new(&a.username, &a.password).await));
+        }
+        // An account that's bound to multiple locations is logged in once, so all of them share its
+        // token
+        let mut location_accounts: Vec<(String, Arc<Account>)> = Vec::new();
+        for a in &config.location_accounts {
+            let account = match location_accounts
+                .iter()
+                .find(|(_, account)| account.username == a.username)
+            {
+                Some((_, account)) => account.clone(),
+                None => Arc::new(Account::new(&a.username, &a.password).await),
+            };
+            location_accounts.push((a.location.clone(), account));
+        }
         LocastCredentials {
-            config,
-            token: Arc::new(Mutex::new(token)),
-            last_login: Arc::new(Mutex::new(Utc::now())),
+            accounts,
+            location_accounts,
+        }
+    }
+
+    // The credentials a location uses: the accounts bound to the location (for which `matches`
+    // returns true) if there are any, otherwise the primary and extra accounts. This way, the
+    // tokens of a location's accounts are never used for other locations.
+    pub fn for_location<F: Fn(&str) -> bool>(self: &Arc<Self>, matches: F) -> Arc<Self> {
+        let location_accounts: Vec<(String, Arc<Account>)> = self
+            .location_accounts
+            .iter()
+            .filter(|(location, _)| matches(location))
+            .cloned()
+            .collect();
+        let accounts: Vec<Arc<Account>> = location_accounts
+            .iter()
+            .map(|(_, account)| account.clone())
+            .collect();
+        if accounts.is_empty() {
+            return self.clone();
+        }
+        info!(
+            "Using account(s) {} for this location",
+            accounts
+                .iter()
+                .map(|a| a.username.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        // The location accounts are kept, so the status shows which locations they're bound to
+        Arc::new(LocastCredentials {
+            accounts,
+            location_accounts,
+        })
+    }
+
+    // All accounts, including the ones bound to a location. Accounts bound to multiple locations
+    // are only included once.
+    fn all_accounts(&self) -> Vec<(&Arc<Account>, Vec<&str>)> {
+        let mut all: Vec<(&Arc<Account>, Vec<&str>)> =
+            self.accounts.iter().map(|a| (a, Vec::new())).collect();
+        for (location, account) in &self.location_accounts {
+            match all.iter_mut().find(|(a, _)| Arc::ptr_eq(a, account)) {
+                Some((_, locations)) => locations.push(location),
+                None => all.push((account, vec![location.as_str()])),
+            }
         }
+        all
     }
 
-    // Retrieve the locast token (used for subsequent authenticated  requests).
+    // Retrieve the locast token of the primary account (used for subsequent authenticated requests).
     // This will first validate the token.
     pub async fn token(&self) -> String {
+        self.accounts[0].token().await
+    }
+
+    // Status of all accounts, used for diagnostics
+    pub async fn status(&self) -> Vec<AccountStatus> {
+        let mut status = Vec::new();
+        for (a, locations) in self.all_accounts() {
+            let last_login = *a.last_login.lock().await;
+            let donation = *a.donation.lock().await;
+            let login_error = a.login_error.lock().await.clone();
+            status.push(AccountStatus {
+                username: a.username.clone(),
+                logged_in: login_error.is_none()
+                    && (Utc::now() - last_login).num_seconds() < TOKEN_LIFETIME,
+                last_login: last_login.timestamp(),
+                login_error,
+                donated: donation.donated,
+                donation_expire: donation.expire,
+                donation_days_left: donation.days_left(),
+                active_streams: a.streams.load(Ordering::SeqCst),
+                locations: locations.iter().map(|l| l.to_string()).collect(),
+            });
+        }
+        status
+    }
+
+    // Check the donation status of all accounts every `DONATION_CHECK_INTERVAL` seconds and warn
+    // (and notify webhooks) once a day when a donation expires within
+    // `donation_warning_days` days.
+    pub async fn watch_donations(self: Arc<Self>, config: Arc<Config>) {
+        if config.donation_warning_days == 0 {
+            return;
+        }
+        loop {
+            for (a, _) in self.all_accounts() {
+                a.warn_donation_expiry(&config).await;
+            }
+            sleep(Duration::from_secs(DONATION_CHECK_INTERVAL)).await;
+            for (a, _) in self.all_accounts() {
+                if let Err(e) = a.refresh_donation().await {
+                    warn!("Unable to check the donation of {}: {}", a.username, e);
+                }
+            }
+        }
+    }
+
+    // Lease the account with the least active streams. The lease should be held for as long as the
+    // stream is active.
+    pub fn lease(&self) -> AccountLease {
+        let _guard = LEASE_LOCK.lock().unwrap();
+        let account = self
+            .accounts
+            .iter()
+            .min_by_key(|a| a.streams.load(Ordering::SeqCst))
+            .unwrap()
+            .clone();
+        account.streams.fetch_add(1, Ordering::SeqCst);
+        AccountLease { account }
+    }
+}
+
+// A lease on a locast account for a single stream. The stream count of the account is decremented
+// when the lease is dropped.
+#[derive(Debug)]
+pub struct AccountLease {
+    account: Arc<Account>,
+}
+
+impl AccountLease {
+    // Retrieve the locast token of the leased account
+    pub async fn token(&self) -> String {
+        self.account.token().await
+    }
+
+    // Force a new login of the leased account
+    pub async fn relogin(&self) -> Result<(), SimpleError> {
+        self.account.relogin().await
+    }
+
+    // The username of the leased account
+    pub fn username(&self) -> &str {
+        &self.account.username
+    }
+}
+
+impl Drop for AccountLease {
+    fn drop(&mut self) {
+        self.account.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Login state of an account. Timestamps are unix timestamps in seconds.
+#[derive(Debug, Serialize)]
+pub struct AccountStatus {
+    pub username: String,
+    pub logged_in: bool,
+    pub last_login: i64,
+    // Why the last login or use of the login token failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_error: Option<String>,
+    pub donated: bool,
+    pub donation_expire: Option<i64>,
+    // Whole days until the donation expires, negative once it expired
+    pub donation_days_left: Option<i64>,
+    pub active_streams: usize,
+    // Locations the account is bound to, empty if it's used for all locations
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<String>,
+}
+
+// Donation status of an account, as reported by locast
+#[derive(Debug, Clone, Copy)]
+struct Donation {
+    donated: bool,
+    // Unix timestamp of the donation expiry
+    expire: Option<i64>,
+}
+
+impl Donation {
+    fn days_left(&self) -> Option<i64> {
+        self.expire
+            .map(|e| (e - Utc::now().timestamp()).div_euclid(86400))
+    }
+}
+
+impl From<&UserInfo> for Donation {
+    fn from(user_info: &UserInfo) -> Donation {
+        Donation {
+            donated: user_info.didDonate,
+            expire: user_info.donationExpire.map(|e| e / 1000),
+        }
+    }
+}
+
+// A single locast account and its login state
+#[derive(Debug)]
+struct Account {
+    username: String,
+    password: String,
+    token: Mutex<String>,
+    last_login: Mutex<DateTime<Utc>>,
+    // Why the last login or use of the token failed, cleared when it works again
+    login_error: Mutex<Option<String>>,
+    donation: Mutex<Donation>,
+    // Unix timestamp of the last warning about the donation expiring
+    last_warning: Mutex<i64>,
+    streams: AtomicUsize,
+}
+
+impl Account {
+    async fn new(username: &str, password: &str) -> Account {
+        let token = login(username, password).await;
+        let user_info = validate_user(&token).await;
+        let donation = Donation::from(&user_info);
+        if let (Some(expire), Some(days_left)) = (donation.expire, donation.days_left()) {
+            info!(
+                "Donation of {} expires on {} ({} days left)",
+                username,
+                crate::utils::format_date_iso(expire * 1000),
+                days_left
+            );
+        }
+        Account {
+            username: username.to_owned(),
+            password: password.to_owned(),
+            token: Mutex::new(token),
+            last_login: Mutex::new(Utc::now()),
+            login_error: Mutex::new(None),
+            donation: Mutex::new(donation),
+            last_warning: Mutex::new(0),
+            streams: AtomicUsize::new(0),
+        }
+    }
+
+    // Fetch the donation status from locast. This also checks that locast still accepts the token.
+    async fn refresh_donation(&self) -> Result<(), SimpleError> {
+        let result = fetch_user(&self.token().await).await;
+        *self.login_error.lock().await = result.as_ref().err().map(|e| e.to_string());
+        *self.donation.lock().await = Donation::from(&result?);
+        Ok(())
+    }
+
+    // Warn if the donation expires within `donation_warning_days`, at most once every
+    // `DONATION_WARNING_INTERVAL` seconds
+    async fn warn_donation_expiry(&self, config: &Config) {
+        let donation = *self.donation.lock().await;
+        let days_left = match donation.days_left() {
+            Some(d) if d < config.donation_warning_days as i64 => d,
+            _ => return,
+        };
+        let mut last_warning = self.last_warning.lock().await;
+        let now = Utc::now().timestamp();
+        if now - *last_warning < DONATION_WARNING_INTERVAL {
+            return;
+        }
+        *last_warning = now;
+
+        if days_left < 0 {
+            warn!(
+                "Donation of {} expired! Streams will fail until it's renewed at locast.org",
+                self.username
+            );
+        } else {
+            warn!(
+                "Donation of {} expires in {} day(s)! Renew it at locast.org",
+                self.username, days_left
+            );
+        }
+        notify::send(Event::DonationExpiring {
+            username: self.username.clone(),
+            donation_expire: donation.expire,
+            days_left,
+        })
+        .await;
+    }
+
+    // Retrieve the locast token. This will first validate the token.
+    async fn token(&self) -> String {
         self.validate_token().await;
         self.token.lock().await.to_owned()
     }
 
     // Validate the login token by comparing it to `TOKEN_LIFETIME`. If it has expired,
     // a new login attempt will be made.
-    pub async fn validate_token(&self) {
+    async fn validate_token(&self) {
         let mut last_login = self.last_login.lock().await;
         if (Utc::now() - *last_login).num_seconds() < TOKEN_LIFETIME {
             return;
         }
-        info!("Login token expired: {:?}", self.last_login);
+        info!("Login token expired: {:?}", *last_login);
 
         // Lock the token and try to login. Then set the new token and reset last_login.
         let mut token = self.token.lock().await;
-        *token = login(&(self.config.username), &(self.config.password)).await;
+        *token = match self.login(10000).await {
+            Ok(t) => t,
+            Err(e) => panic!("{}", e),
+        };
+        *last_login = Utc::now();
+    }
+
+    // Force a new login, e.g. when locast rejected the token before `TOKEN_LIFETIME` expired.
+    // If a login happened less than `MIN_RELOGIN_INTERVAL` seconds ago, the token is considered
+    // fresh and no new login attempt is made. This prevents a login storm when multiple
+    // streams fail at the same time.
+    async fn relogin(&self) -> Result<(), SimpleError> {
+        let mut last_login = self.last_login.lock().await;
+        if (Utc::now() - *last_login).num_seconds() < MIN_RELOGIN_INTERVAL {
+            return Ok(());
+        }
+        info!("Login token rejected, logging in again..");
+
+        let mut token = self.token.lock().await;
+        *token = self.login(RELOGIN_RETRIES).await?;
         *last_login = Utc::now();
+        Ok(())
+    }
+
+    // Log in to locast.org, remembering whether it worked
+    async fn login(&self, max_retries: usize) -> Result<String, SimpleError> {
+        match try_login(&self.username, &self.password, max_retries).await {
+            Ok(token) => {
+                *self.login_error.lock().await = None;
+                Ok(token)
+            }
+            Err(e) => {
+                *self.login_error.lock().await = Some(e.to_string());
+                notify_login_failed(&self.username, &e).await;
+                Err(e)
+            }
+        }
     }
 }
 
+// Check if an account can log in to locast.org and has an active donation, without panicking.
+// Used to validate credentials entered in the setup wizard.
+pub async fn check_account(username: &str, password: &str) -> Result<(), SimpleError> {
+    let token = try_login(username, password, 3).await?;
+    check_user(&token).await?;
+    Ok(())
+}
+
 // Log in to locast.org
-async fn login<'a>(username: &str, password: &str) -> String {
+async fn login(username: &str, password: &str) -> String {
+    match try_login(username, password, 10000).await {
+        Ok(token) => token,
+        Err(e) => login_failed(username, e).await,
+    }
+}
+
+// Notify about a failed login and panic
+async fn login_failed(username: &str, e: SimpleError) -> ! {
+    notify_login_failed(username, &e).await;
+    panic!("{}", e)
+}
+
+async fn notify_login_failed(username: &str, e: &SimpleError) {
+    notify::send(Event::LoginFailed {
+        username: username.to_owned(),
+        error: e.to_string(),
+    })
+    .await;
+}
+
+async fn try_login(
+    username: &str,
+    password: &str,
+    max_retries: usize,
+) -> Result<String, SimpleError> {
     info!("Logging in with {}", username);
+    let _span = Span::new("locast_login", None);
     let credentials = json!({
         "username": username,
         "password": password
     });
 
-    let resp = crate::utils::post(LOGIN_URL, credentials, 10000)
+    let resp = crate::utils::post(LOGIN_URL, credentials, max_retries)
         .await
-        .unwrap();
+        .map_err(SimpleError::from)?;
 
     if !resp.status().is_success() {
-        panic!("Login failed");
+        return Err(SimpleError::new("Login failed"));
     } else {
         info!("Login succeeded!");
     }
 
-    resp.json::<HashMap<String, String>>().await.unwrap()["token"].clone()
+    match resp.json::<HashMap<String, String>>().await {
+        Ok(mut r) => r
+            .remove("token")
+            .ok_or_else(|| SimpleError::new("Login failed: no token received")),
+        Err(e) => Err(SimpleError::from(e)),
+    }
 }
 #[allow(non_snake_case)]
 #[derive(Deserialize, Debug)]
@@ -83,23 +464,37 @@ struct UserInfo {
 
 // Validate the locast user and make sure the user has donated and the donation didn't expire.
 // If invalid, panic.
-async fn validate_user(token: &str) {
-    let response = crate::utils::get(USER_URL, Some(token), 100).await;
-    let text = response.unwrap().text().await.unwrap();
-    let user_info: Result<UserInfo, serde_json::Error> = serde_json::from_str(&text);
+async fn validate_user(token: &str) -> UserInfo {
+    match check_user(token).await {
+        Ok(u) => u,
+        Err(e) => panic!("{}", e),
+    }
+}
 
+async fn check_user(token: &str) -> Result<UserInfo, SimpleError> {
+    let u = fetch_user(token).await?;
     let now = Utc::now().timestamp();
-    match user_info {
-        Err(e) => {
-            error!("Invalid response body: {}", text);
-            panic!("Error while validating user: {}", e)
-        }
-        Ok(u) => {
-            if !u.didDonate {
-                panic!("User didn't donate! Make sure you have an active donation at locast.org!")
-            } else if now > u.donationExpire.unwrap() / 1000 {
-                panic!("Donation expired! Make sure you have an active donation at locast.org!")
-            }
-        }
+    if !u.didDonate {
+        Err(SimpleError::new(
+            "User didn't donate! Make sure you have an active donation at locast.org!",
+        ))
+    } else if now > u.donationExpire.unwrap_or(0) / 1000 {
+        Err(SimpleError::new(
+            "Donation expired! Make sure you have an active donation at locast.org!",
+        ))
+    } else {
+        Ok(u)
     }
 }
+
+// Fetch the user info, including the donation status, of the user the token belongs to
+async fn fetch_user(token: &str) -> Result<UserInfo, SimpleError> {
+    let response = crate::utils::get(USER_URL, Some(token), 100)
+        .await
+        .map_err(SimpleError::from)?;
+    let text = response.text().await.map_err(SimpleError::from)?;
+    serde_json::from_str(&text).map_err(|e| {
+        error!("Invalid response body: {}", text);
+        SimpleError::new(format!("Error while validating user: {}", e))
+    })
+}
@@ -0,0 +1,9 @@
+#[cfg(unix)]
+mod unix;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(unix)]
+pub use unix::daemonize;
+#[cfg(windows)]
+pub use windows::{install, run, uninstall, wide};
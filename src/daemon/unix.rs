@@ -0,0 +1,38 @@
+use std::{fs, io, os::unix::io::AsRawFd, path::Path};
+
+/// Detach from the terminal and continue in the background. This has to happen before any threads are
+/// started (e.g. by the logger or the async runtime), since only the calling thread survives a fork.
+pub fn daemonize(pid_file: Option<&Path>) -> io::Result<()> {
+    // Fork twice, so the daemon isn't a session leader and can't acquire a controlling terminal
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    fork()?;
+
+    // Write the pid file while errors can still be reported on the terminal
+    if let Some(pid_file) = pid_file {
+        fs::write(pid_file, format!("{}\n", std::process::id()))?;
+    }
+
+    // Detach stdin, stdout and stderr
+    let dev_null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(dev_null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Fork and let the parent exit
+fn fork() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
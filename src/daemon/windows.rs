@@ -0,0 +1,215 @@
+use crate::{config::Config, http};
+use simple_error::SimpleError;
+use std::{
+    env,
+    ffi::{c_void, OsStr},
+    iter,
+    os::windows::ffi::OsStrExt,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc, OnceLock,
+    },
+};
+use windows_sys::{
+    core::PWSTR,
+    Win32::{
+        Foundation::{ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR},
+        System::Services::*,
+    },
+};
+
+static SERVICE_NAME: &str = "locast2tuner";
+static SERVICE_DESCRIPTION: &str = "Locast to Plex, Emby and Jellyfin tuner";
+
+/// Runs locast2tuner with a configuration until it's shut down
+pub type Runner = fn(Arc<Config>) -> Result<(), SimpleError>;
+
+/// What the service runs. This is set before control is handed to the service control manager,
+/// which calls `service_main` on a thread of its own.
+static SERVICE: OnceLock<(Arc<Config>, Runner)> = OnceLock::new();
+static STATUS_HANDLE: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Install locast2tuner as a service that starts automatically and runs with `config`'s config file
+pub fn install(config: &Config) -> Result<(), SimpleError> {
+    let exe = env::current_exe().map_err(SimpleError::from)?;
+    // Services run from the system directory, so the config file has to be an absolute path
+    let config_file = config.config_file.canonicalize().map_err(|e| {
+        SimpleError::new(format!(
+            "Unable to find config file {}: {}",
+            config.config_file.display(),
+            e
+        ))
+    })?;
+    let command = format!(
+        "\"{}\" --config \"{}\" service run",
+        exe.display(),
+        config_file.display()
+    );
+
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CREATE_SERVICE);
+        if manager.is_null() {
+            return Err(last_error(
+                "Unable to connect to the service control manager",
+            ));
+        }
+        let service = CreateServiceW(
+            manager,
+            wide(SERVICE_NAME).as_ptr(),
+            wide(SERVICE_NAME).as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            wide(&command).as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        );
+        if service.is_null() {
+            let e = last_error("Unable to install the service");
+            CloseServiceHandle(manager);
+            return Err(e);
+        }
+        let mut description = wide(SERVICE_DESCRIPTION);
+        let info = SERVICE_DESCRIPTIONW {
+            lpDescription: description.as_mut_ptr(),
+        };
+        ChangeServiceConfig2W(
+            service,
+            SERVICE_CONFIG_DESCRIPTION,
+            &info as *const SERVICE_DESCRIPTIONW as *const c_void,
+        );
+        CloseServiceHandle(service);
+        CloseServiceHandle(manager);
+    }
+    println!(
+        "Installed the {} service using {}. Start it with: sc start {}",
+        SERVICE_NAME,
+        config_file.display(),
+        SERVICE_NAME
+    );
+    Ok(())
+}
+
+/// Remove the service. A running service is removed once it has stopped.
+pub fn uninstall() -> Result<(), SimpleError> {
+    unsafe {
+        let manager = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CONNECT);
+        if manager.is_null() {
+            return Err(last_error(
+                "Unable to connect to the service control manager",
+            ));
+        }
+        let service = OpenServiceW(manager, wide(SERVICE_NAME).as_ptr(), SERVICE_ALL_ACCESS);
+        let result = if service.is_null() {
+            Err(last_error("Unable to find the service"))
+        } else if DeleteService(service) == 0 {
+            Err(last_error("Unable to uninstall the service"))
+        } else {
+            Ok(())
+        };
+        if !service.is_null() {
+            CloseServiceHandle(service);
+        }
+        CloseServiceHandle(manager);
+        result?;
+    }
+    println!("Uninstalled the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+/// Run as a service. This blocks until the service is stopped and only works when started by the
+/// service control manager.
+pub fn run(config: Arc<Config>, runner: Runner) -> Result<(), SimpleError> {
+    let _ = SERVICE.set((config, runner));
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW::default(),
+    ];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(last_error(
+            "Unable to run as a service (use \"sc start\" to start the service)",
+        ));
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let handle = RegisterServiceCtrlHandlerExW(
+        wide(SERVICE_NAME).as_ptr(),
+        Some(control_handler),
+        ptr::null(),
+    );
+    if handle.is_null() {
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::SeqCst);
+    set_status(SERVICE_RUNNING, 0, NO_ERROR);
+
+    let (config, runner) = SERVICE.get().unwrap();
+    let exit_code = match runner(config.clone()) {
+        Ok(()) => NO_ERROR,
+        Err(_) => ERROR_SERVICE_SPECIFIC_ERROR,
+    };
+    set_status(SERVICE_STOPPED, 0, exit_code);
+}
+
+/// Handle requests of the service control manager. Stopping the service shuts down gracefully, the
+/// same way SIGTERM does on Unix.
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            let drain_timeout = SERVICE.get().map_or(0, |(c, _)| c.drain_timeout);
+            let wait_hint = (drain_timeout + http::STOP_TIMEOUT) * 1000;
+            set_status(SERVICE_STOP_PENDING, wait_hint as u32, NO_ERROR);
+            http::request_stop();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Report the state of the service to the service control manager
+fn set_status(state: u32, wait_hint: u32, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: if exit_code == ERROR_SERVICE_SPECIFIC_ERROR {
+            1
+        } else {
+            0
+        },
+        dwCheckPoint: 0,
+        dwWaitHint: wait_hint,
+    };
+    unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::SeqCst), &status) };
+}
+
+fn last_error(message: &str) -> SimpleError {
+    SimpleError::new(format!("{}: {}", message, std::io::Error::last_os_error()))
+}
+
+/// Encode a string as a null terminated wide string
+pub fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
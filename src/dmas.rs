@@ -0,0 +1,111 @@
+use crate::{config::Config, service::Coordinates, utils::HttpError};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use serde::Deserialize;
+use simple_error::SimpleError;
+
+static DMA_URL: &str = "https://api.locastnet.org/api/dma";
+
+/// Minimal fuzzy match score for a city name that doesn't match a DMA name exactly
+static MIN_SCORE: i64 = 60;
+
+/// A locast DMA, as returned by the DMA list
+#[derive(Deserialize, Debug, Clone)]
+pub struct LocastDma {
+    pub id: i64,
+    pub name: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl LocastDma {
+    pub fn coordinates(&self) -> Option<Coordinates> {
+        Some(Coordinates {
+            latitude: self.latitude?,
+            longitude: self.longitude?,
+        })
+    }
+}
+
+/// Get the list of DMAs locast is available in
+pub async fn locast_dmas() -> Result<Vec<LocastDma>, SimpleError> {
+    match crate::utils::get(DMA_URL, None, 3).await {
+        Ok(r) => r.json().await.map_err(HttpError::from),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| SimpleError::new(format!("Unable to get locast DMAs: {}", e)))
+}
+
+/// Print the DMAs locast is available in and exit
+pub async fn run() -> Result<(), SimpleError> {
+    let mut dmas = locast_dmas().await?;
+    dmas.sort_by(|a, b| a.name.cmp(&b.name));
+    let width = dmas.iter().map(|d| d.name.len()).max().unwrap_or(0);
+    println!(
+        "{:>4}  {:width$}  COORDINATES",
+        "DMA",
+        "NAME",
+        width = width
+    );
+    for dma in dmas.iter().filter(|d| d.active) {
+        println!(
+            "{:>4}  {:width$}  {}",
+            dma.id,
+            dma.name,
+            dma.coordinates()
+                .map_or_else(|| "-".to_owned(), |c| c.to_string()),
+            width = width
+        );
+    }
+    Ok(())
+}
+
+/// Resolve `override_cities` to the coordinates of their DMAs and add those to `override_coordinates`.
+/// Names are matched case insensitively and, when no DMA has that exact name, with a fuzzy matcher.
+pub async fn resolve_cities(config: &mut Config) -> Result<(), SimpleError> {
+    if config.override_cities.is_empty() {
+        return Ok(());
+    }
+    let dmas: Vec<LocastDma> = locast_dmas()
+        .await?
+        .into_iter()
+        .filter(|d| d.active)
+        .collect();
+    for city in &config.override_cities {
+        let dma = find(&dmas, city)
+            .ok_or_else(|| SimpleError::new(format!("No locast DMA found for city '{}'", city)))?;
+        let coordinates = dma.coordinates().ok_or_else(|| {
+            SimpleError::new(format!("locast DMA {} has no coordinates", dma.name))
+        })?;
+        info!(
+            "Resolved city {} to DMA {} ({}) at {}",
+            city, dma.id, dma.name, coordinates
+        );
+        let coordinates = coordinates.to_string();
+        if !config.override_coordinates.contains(&coordinates) {
+            config.override_coordinates.push(coordinates);
+        }
+    }
+    Ok(())
+}
+
+fn find<'a>(dmas: &'a [LocastDma], city: &str) -> Option<&'a LocastDma> {
+    let city = city.trim();
+    if let Some(dma) = dmas.iter().find(|d| d.name.eq_ignore_ascii_case(city)) {
+        return Some(dma);
+    }
+    let matcher = SkimMatcherV2::default();
+    let dma = dmas
+        .iter()
+        .filter_map(|d| Some((d, matcher.fuzzy_match(&d.name, city)?)))
+        .filter(|(_, score)| *score >= MIN_SCORE)
+        .max_by_key(|(_, score)| *score)?
+        .0;
+    warn!("City '{}' isn't a locast DMA, using {}", city, dma.name);
+    Some(dma)
+}
@@ -1,22 +1,127 @@
 use actix_web::{dev::HttpResponseBuilder, error, http::header, http::StatusCode, HttpResponse};
 use derive_more::{Display, Error};
+use serde::Serialize;
 
 #[derive(Debug, Display, Error)]
 pub enum AppError {
+    #[display(fmt = "bad request")]
+    BadRequest(#[error(not(source))] String),
+    /// The request isn't authenticated. The flag indicates whether the client should be asked for
+    /// basic authentication credentials.
+    #[display(fmt = "unauthorized")]
+    Unauthorized(#[error(not(source))] bool),
+    /// The client's IP address isn't allowed to use the endpoint
+    #[display(fmt = "forbidden")]
+    Forbidden(#[error(not(source))] String),
     #[display(fmt = "not found")]
     NotFound,
+    #[display(fmt = "channel remapping is only available when multiplexing")]
+    RemapUnavailable,
+    #[display(fmt = "internal server error")]
+    InternalError,
+    #[display(fmt = "locast returned an invalid response")]
+    UpstreamError,
+    #[display(fmt = "all tuners are in use")]
+    TunersInUse,
+    #[display(fmt = "too many streams for this client")]
+    ClientStreamLimit,
+    #[display(fmt = "shutting down")]
+    ShuttingDown,
+}
+
+impl AppError {
+    /// Additional information about the error, if any
+    pub fn details(&self) -> Option<String> {
+        match self {
+            AppError::BadRequest(details) | AppError::Forbidden(details) => {
+                Some(details.to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Add error specific headers to a response
+    pub fn add_headers(&self, builder: &mut HttpResponseBuilder) {
+        // Let clients know why the stream was refused, the same way a real HDHomeRun would.
+        match *self {
+            AppError::TunersInUse => {
+                builder.insert_header(("X-HDHomeRun-Error", "805 All Tuners In Use"));
+            }
+            AppError::Unauthorized(true) => {
+                builder.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"locast2tuner\""));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The body of every error response returned by the API
+#[derive(Debug, Serialize)]
+pub struct ErrorEnvelope {
+    pub code: u16,
+    pub message: String,
+    pub details: Option<String>,
+    /// Id of the request, as returned in the `X-Request-Id` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Station the request was for, if any (e.g. `/watch/{id}`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub station_id: Option<String>,
+}
+
+impl ErrorEnvelope {
+    /// Create an envelope for `error`. Errors that aren't an `AppError` (e.g. errors raised by actix
+    /// itself) only carry their status code and message. The request id finds the error in the logs and
+    /// the traces.
+    pub fn new(
+        error: &error::Error,
+        request_id: Option<&str>,
+        station_id: Option<&str>,
+    ) -> ErrorEnvelope {
+        let status = error.as_response_error().status_code();
+        ErrorEnvelope {
+            code: status.as_u16(),
+            message: error.to_string(),
+            details: error.as_error::<AppError>().and_then(|e| e.details()),
+            request_id: request_id.map(str::to_owned),
+            station_id: station_id.map(str::to_owned),
+        }
+    }
 }
 
 impl error::ResponseError for AppError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code())
-            .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
-            .body(self.to_string())
+        let mut builder = HttpResponseBuilder::new(self.status_code());
+        self.add_headers(&mut builder);
+        builder.json(&ErrorEnvelope {
+            code: self.status_code().as_u16(),
+            message: self.to_string(),
+            details: self.details(),
+            request_id: None,
+            station_id: None,
+        })
     }
 
     fn status_code(&self) -> StatusCode {
         match *self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
             AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::RemapUnavailable => StatusCode::CONFLICT,
+            AppError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UpstreamError => StatusCode::BAD_GATEWAY,
+            AppError::TunersInUse => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::ClientStreamLimit => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 }
+
+/// Returns true if the client prefers an HTML page over JSON (e.g. a browser)
+pub fn prefers_html(headers: &header::HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
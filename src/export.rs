@@ -0,0 +1,176 @@
+use crate::{
+    config::{Config, Export},
+    credentials::LocastCredentials,
+    fcc_facilities::FCCFacilities,
+    http::templates::{self, ChannelFormat},
+    service::{station::Station, stationprovider::StationProvider, LocastService, Location},
+    utils::format_time_local_iso,
+};
+use simple_error::SimpleError;
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+
+/// Log in, fetch the stations and guide of a single location and write them to `export.out`. Files are
+/// named after the DMA of the location (e.g. "501-lineup.json" and "501-epg.json"). Returns the files
+/// that were written.
+pub async fn run(config: Arc<Config>, export: &Export) -> Result<Vec<PathBuf>, SimpleError> {
+    // Always fetch fresh data
+    let mut config = (*config).clone();
+    config.disable_station_cache = true;
+    let config = Arc::new(config);
+
+    let credentials = Arc::new(LocastCredentials::new(config.clone()).await);
+    let fcc_facilities = Arc::new(FCCFacilities::new(config.clone()).await);
+    let service = LocastService::new(
+        config.clone(),
+        credentials,
+        fcc_facilities,
+        export
+            .zipcode
+            .clone()
+            .map_or(Location::Ip, Location::Zipcode),
+    )
+    .await;
+
+    let stations_mutex = service.stations().await;
+    let mut stations = stations_mutex.lock().await.clone();
+    // Normalize the order, so exports of different runs or locations can be diffed
+    stations.sort_by_key(|s| s.id);
+    for station in stations.iter_mut() {
+        station.listings.sort_by_key(|l| l.startTime);
+    }
+
+    let dma = &service.geo().DMA;
+    fs::create_dir_all(&export.out).map_err(SimpleError::from)?;
+    let files = match export.format.as_str() {
+        "xmltv" => {
+            let bind_address = config.bind_address.split(',').next().unwrap_or_default();
+            let base_url = format!("http://{}:{}", bind_address.trim(), config.port);
+            config
+                .channel_format
+                .parse::<ChannelFormat>()
+                .unwrap_or(ChannelFormat::Decimal)
+                .apply(&mut stations);
+            vec![(
+                format!("{}-epg.xml", dma),
+                templates::epg_xml(Arc::new(stations), base_url, Arc::new(HashMap::new()))
+                    .collect(),
+            )]
+        }
+        "csv" => vec![
+            (format!("{}-lineup.csv", dma), lineup_csv(&stations)),
+            (format!("{}-epg.csv", dma), epg_csv(&stations)),
+        ],
+        _ => {
+            let lineup: Vec<Station> = stations
+                .iter()
+                .map(|s| Station {
+                    listings: Vec::new(),
+                    ..s.clone()
+                })
+                .collect();
+            vec![
+                (format!("{}-lineup.json", dma), to_json(&lineup)?),
+                (format!("{}-epg.json", dma), to_json(&stations)?),
+            ]
+        }
+    };
+
+    let mut written = Vec::new();
+    for (name, contents) in files {
+        let path = export.out.join(name);
+        fs::write(&path, contents).map_err(SimpleError::from)?;
+        info!("Exported {}", path.display());
+        written.push(path);
+    }
+    Ok(written)
+}
+
+fn to_json(stations: &[Station]) -> Result<String, SimpleError> {
+    serde_json::to_string_pretty(stations).map_err(SimpleError::from)
+}
+
+/// One row per station
+fn lineup_csv(stations: &[Station]) -> String {
+    let mut rows = vec![csv_row(&[
+        "station_id",
+        "call_sign",
+        "name",
+        "channel",
+        "channel_locast",
+        "channel_fcc",
+        "city",
+        "dma",
+        "timezone",
+        "active",
+        "listings",
+    ])];
+    for s in stations {
+        rows.push(csv_row(&[
+            &s.id.to_string(),
+            &s.callSign,
+            &s.name,
+            s.channel.as_deref().unwrap_or(""),
+            s.channel_locast.as_deref().unwrap_or(""),
+            s.channel_fcc.as_deref().unwrap_or(""),
+            s.city.as_deref().unwrap_or(""),
+            &s.dma.to_string(),
+            s.timezone.as_deref().unwrap_or(""),
+            &s.active.to_string(),
+            &s.listings.len().to_string(),
+        ]));
+    }
+    rows.join("")
+}
+
+/// One row per programme. Times are in the local time of the station.
+fn epg_csv(stations: &[Station]) -> String {
+    let mut rows = vec![csv_row(&[
+        "station_id",
+        "call_sign",
+        "channel",
+        "start",
+        "end",
+        "duration",
+        "program_id",
+        "title",
+        "episode_title",
+        "season",
+        "episode",
+        "genres",
+    ])];
+    for s in stations {
+        let timezone = s.tz();
+        for l in &s.listings {
+            rows.push(csv_row(&[
+                &s.id.to_string(),
+                &s.callSign,
+                s.channel.as_deref().unwrap_or(""),
+                &format_time_local_iso(l.startTime, &timezone),
+                &format_time_local_iso(l.startTime + l.duration * 1000, &timezone),
+                &l.duration.to_string(),
+                &l.programId,
+                &l.title,
+                l.episodeTitle.as_deref().unwrap_or(""),
+                &l.seasonNumber.map_or(String::new(), |n| n.to_string()),
+                &l.episodeNumber.map_or(String::new(), |n| n.to_string()),
+                l.genres.as_deref().unwrap_or(""),
+            ]));
+        }
+    }
+    rows.join("")
+}
+
+/// Format a CSV row (RFC 4180). Fields containing separators, quotes or newlines are quoted.
+fn csv_row(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            if f.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f.to_string()
+            }
+        })
+        .collect();
+    format!("{}\r\n", fields.join(","))
+}
@@ -1,13 +1,16 @@
-use crate::config::Config;
+use crate::{config::Config, utils::HttpError};
 use chrono::{DateTime, Utc};
 use futures::lock::Mutex;
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
-use log::info;
-use serde::Deserialize;
-use std::{collections::HashMap, sync::Arc, time::SystemTime, usize};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
 use std::{fs::File, io::prelude::*};
-use std::{io::BufReader, path::PathBuf};
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 use tokio::task;
 use tokio::time::{sleep, Duration};
 
@@ -20,11 +23,22 @@ static FAC_CALLSIGN: usize = 5;
 static FAC_CHANNEL: usize = 6;
 static TV_VIRTUAL_CHANNEL: usize = 28;
 
-static SERVICE_LIST: &'static [&str] = &["DT", "TX", "TV", "TB", "LD", "DC"];
+static SERVICE_LIST: &[&str] = &["DT", "TX", "TV", "TB", "LD", "DC"];
+
+// Sanity checks of FCC facility data, to detect truncated or corrupted files
+static MIN_DOWNLOAD_SIZE: usize = 500_000; // bytes
+static MIN_ROWS: usize = 10_000; // rows in a downloaded facility.dat
+static MIN_FACILITIES: usize = 100; // facilities in the cache file
+static MAX_MALFORMED: f64 = 0.01; // fraction of rows
+static MIN_RELATIVE_SIZE: f64 = 0.8; // fraction of the previously loaded facilities
 
-static MAX_FILE_AGE: u64 = 24 * 60 * 60; // 24 hours
 static CHECK_INTERVAL: u64 = 60 * 60; // 1 hour
 
+/// Cache file of the parsed facilities, in the cache directory
+static CACHE_FILE: &str = "facilities.json";
+/// Cache file of older versions, which held the raw FCC data
+static LEGACY_CACHE_FILE: &str = "facilities";
+
 static FACILITIES_URL: &str =
     "https://transition.fcc.gov/ftp/Bureaus/MB/Databases/cdbs/facility.zip";
 static DMA_URL: &str = "https://api.locastnet.org/api/dma";
@@ -33,119 +47,320 @@ static DMA_URL: &str = "https://api.locastnet.org/api/dma";
 // the `FacilitiesMap` will contain a mapping from (locast_id, call_sign) to (fac_channel, tv_virtual_channel)
 #[derive(Debug)]
 pub struct FCCFacilities {
-    config: Arc<Config>,
     facilities_map: FacilitiesMap,
+    health: Arc<Mutex<FacilitiesHealth>>,
+    cache_file: PathBuf,
 }
 
 // (locast_id, call_sign) --> (fac_channel, tv_virtual_channel)
-type FacilitiesMap = Arc<Mutex<HashMap<(i64, String), (String, String)>>>;
+type Facilities = HashMap<(i64, String), (String, String)>;
+type FacilitiesMap = Arc<Mutex<Facilities>>;
+
+/// A facility as stored in the cache file
+#[derive(Serialize, Deserialize)]
+struct CachedFacility {
+    locast_id: i64,
+    call_sign: String,
+    fac_channel: String,
+    tv_virtual_channel: String,
+}
+
+/// State of the facility data. When the facility data can't be loaded or is corrupt, the previously
+/// loaded data is kept and the facilities are marked as degraded.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct FacilitiesHealth {
+    pub degraded: bool,
+    /// Why the facilities are degraded
+    pub reason: Option<String>,
+    /// Amount of facilities in use
+    pub facilities: usize,
+    /// Unix timestamp of the last time the facilities were loaded successfully
+    pub last_loaded: Option<i64>,
+    /// Why the last download couldn't be written to the cache file
+    pub cache_error: Option<String>,
+}
 
 impl FCCFacilities {
     /// Create a new facilities. Normally this only has to be done once.
     pub async fn new(config: Arc<Config>) -> FCCFacilities {
-        // Make sure we have a complete facilities object before returning
-        let facilities_map = Arc::new(Mutex::new(
-            load(&config.cache_directory.join("facilities")).await,
-        ));
+        let facilities_map = Arc::new(Mutex::new(HashMap::new()));
+        let health = Arc::new(Mutex::new(FacilitiesHealth::default()));
+        let cache_file = config.cache_directory.join(CACHE_FILE);
+        let _ = std::fs::remove_file(config.cache_directory.join(LEGACY_CACHE_FILE));
+
+        // A cached copy is good enough to start with, even if it has expired. It's refreshed in the
+        // background. Without one, make sure we have a complete facilities object before returning.
+        match load_cache_file(&cache_file) {
+            Ok(facilities) => {
+                info!("Using cached FCC facilities at {}", cache_file.display());
+                let mut health = health.lock().await;
+                health.facilities = facilities.len();
+                health.last_loaded = Some(Utc::now().timestamp());
+                *facilities_map.lock().await = facilities;
+            }
+            Err(e) => {
+                if cache_file.exists() {
+                    warn!(
+                        "Cached FCC facilities at {} are corrupt: {}",
+                        cache_file.display(),
+                        e
+                    );
+                }
+                reload(&facilities_map, &health, &cache_file).await;
+            }
+        }
 
-        // Start a background thread that will update the facilities periodically
-        start_updater_thread(&facilities_map, &config);
+        // Start a background thread that will update the facilities when the cache expires
+        start_updater_thread(&facilities_map, &health, &cache_file, config.fcc_cache_ttl);
 
         // Build and return
         FCCFacilities {
-            config,
             facilities_map,
+            health,
+            cache_file,
         }
     }
 
-    /// Look up facilities based on a locast_id (or locast dma), call_sign and potential sub_channel
-    pub async fn lookup(&self, locast_dma: i64, call_sign: &str, sub_channel: &str) -> String {
+    /// Age (in seconds) of the cached facilities file, if it exists
+    pub fn cache_age(&self) -> Option<u64> {
+        let modified = self.cache_file.metadata().ok()?.modified().ok()?;
+        SystemTime::now()
+            .duration_since(modified)
+            .ok()
+            .map(|d| d.as_secs())
+    }
+
+    /// State of the facility data
+    pub async fn health(&self) -> FacilitiesHealth {
+        self.health.lock().await.clone()
+    }
+
+    /// Look up facilities based on a locast_id (or locast dma), call_sign and potential sub_channel.
+    /// The virtual channel is preferred over the RF channel (fac_channel). Returns None if the FCC
+    /// doesn't know about the station.
+    pub async fn lookup(
+        &self,
+        locast_dma: i64,
+        call_sign: &str,
+        sub_channel: &str,
+    ) -> Option<String> {
         let facilities_map = self.facilities_map.lock().await;
-        let (fac_channel, tv_virtual_channel) = facilities_map
-            .get(&(locast_dma, call_sign.to_string()))
-            .unwrap(); // This should exist
+        let (fac_channel, tv_virtual_channel) =
+            facilities_map.get(&(locast_dma, call_sign.to_string()))?;
 
-        if tv_virtual_channel.is_empty() {
+        Some(if tv_virtual_channel.is_empty() {
             fac_channel.to_owned()
         } else if sub_channel.is_empty() {
-            format!("{}.1", fac_channel.as_str()) // default to x.1 if there is no sub_channel
+            format!("{}.1", major_channel(tv_virtual_channel)) // default to x.1 if there is no sub_channel
         } else {
-            format!("{}.{}", fac_channel.as_str(), sub_channel)
+            format!("{}.{}", major_channel(tv_virtual_channel), sub_channel)
+        })
+    }
+
+    /// Amount of facilities in a locast DMA
+    pub async fn dma_facilities(&self, locast_dma: i64) -> usize {
+        let facilities_map = self.facilities_map.lock().await;
+        facilities_map
+            .keys()
+            .filter(|(dma, _)| *dma == locast_dma)
+            .count()
+    }
+
+    /// Look up the major virtual channel (e.g. 4 for 4.1) for a call sign, if the FCC knows about it
+    pub async fn virtual_channel(&self, locast_dma: i64, call_sign: &str) -> Option<String> {
+        let facilities_map = self.facilities_map.lock().await;
+        let (_, tv_virtual_channel) = facilities_map.get(&(locast_dma, call_sign.to_string()))?;
+        if tv_virtual_channel.is_empty() {
+            None
+        } else {
+            Some(major_channel(tv_virtual_channel).to_owned())
         }
     }
 }
 
-/// Start an thread that will update the facilities map regularly and store them
-/// in the cache directory
-fn start_updater_thread(facilities_map: &FacilitiesMap, config: &Arc<Config>) {
+/// Return the major part of a channel number (e.g. 4.1 --> 4)
+fn major_channel(channel: &str) -> &str {
+    channel.split('.').next().unwrap_or(channel)
+}
+
+/// Start an thread that will update the facilities map when the cache file is older than `ttl`
+/// seconds and store them in the cache directory
+fn start_updater_thread(
+    facilities_map: &FacilitiesMap,
+    health: &Arc<Mutex<FacilitiesHealth>>,
+    cache_file: &Path,
+    ttl: u64,
+) {
     let facilities_map = facilities_map.clone();
-    let config = config.clone();
+    let health = health.clone();
+    let cache_file = cache_file.to_owned();
 
     task::spawn(async move {
         loop {
+            if path_expired(&cache_file, ttl) {
+                info!("Reloading FCC facilities..");
+                reload(&facilities_map, &health, &cache_file).await;
+            }
             sleep(Duration::from_secs(CHECK_INTERVAL)).await;
-
-            info!("Reloading FCC facilities..");
-            let cache_file = config.cache_directory.join("facilities");
-            let new_facilties = load(&cache_file).await;
-            let mut facilities = facilities_map.lock().await;
-            *facilities = new_facilties;
         }
     });
 }
 
-/// Check if a path has expired, based on `MAX_FILE_AGE`
-fn path_expired(path: &PathBuf) -> bool {
-    let modified = path.metadata().unwrap().modified().unwrap();
-    SystemTime::now()
-        .duration_since(modified)
-        .unwrap()
-        .as_secs()
-        > MAX_FILE_AGE
+/// (Re)load the facilities into `facilities_map`. If loading fails, the current facilities are kept
+/// and `health` is marked as degraded.
+async fn reload(
+    facilities_map: &FacilitiesMap,
+    health: &Arc<Mutex<FacilitiesHealth>>,
+    cache_file: &Path,
+) {
+    let previous = facilities_map.lock().await.len();
+    let result = load(cache_file, previous).await;
+
+    let mut facilities = facilities_map.lock().await;
+    let mut health = health.lock().await;
+    match result {
+        Ok((new_facilities, reason, cache_error)) => {
+            *facilities = new_facilities;
+            health.degraded = reason.is_some();
+            health.reason = reason;
+            health.last_loaded = Some(Utc::now().timestamp());
+            health.cache_error = cache_error;
+        }
+        Err(e) => {
+            warn!(
+                "Unable to load FCC facilities, keeping {} previously loaded facilities: {}",
+                facilities.len(),
+                e
+            );
+            health.degraded = true;
+            health.reason = Some(e);
+        }
+    }
+    health.facilities = facilities.len();
 }
 
-/// Load facilities from `cache_file`
-async fn load<'a>(cache_file: &PathBuf) -> HashMap<(i64, String), (String, String)> {
-    // First get the locast_dmas from locast.org
-    let locast_dmas: Vec<LocastDMA> = crate::utils::get(DMA_URL, None, 100)
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap();
-
-    let lines: Vec<Result<String, std::io::Error>>;
-    // Using cached facilities if possible.
-    let downloaded = if cache_file.exists() && !path_expired(&cache_file) {
-        info!("Using cached FCC facilities at {}", cache_file.display());
-        lines = BufReader::new(File::open(cache_file).unwrap())
-            .lines()
-            .collect::<Vec<Result<String, std::io::Error>>>();
-        false
-    } else {
-        info!("Downloading FCC facilities");
-        let zipfile = crate::utils::get(FACILITIES_URL, None, 100)
-            .await
-            .unwrap()
-            .bytes()
-            .await
-            .unwrap();
-
-        lines = BufReader::new(
-            zip::ZipArchive::new(std::io::Cursor::new(zipfile))
-                .unwrap()
-                .by_name("facility.dat")
-                .unwrap(),
-        )
-        .lines()
-        .collect::<Vec<Result<String, std::io::Error>>>();
-        true
+/// Check if a path is older than `ttl` seconds. Paths that don't exist have expired.
+fn path_expired(path: &Path, ttl: u64) -> bool {
+    match path.metadata().and_then(|m| m.modified()) {
+        Ok(modified) => SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|age| age.as_secs() > ttl),
+        Err(_) => true,
+    }
+}
+
+/// Download the facilities and store them in `cache_file`. `previous` is the amount of facilities
+/// currently loaded and is used to detect truncated downloads. When the download fails or is corrupt,
+/// the cache file is used even if it has expired, in which case the reason is returned along with the
+/// facilities. When the download can't be cached, the error is returned as well.
+async fn load(
+    cache_file: &Path,
+    previous: usize,
+) -> Result<(Facilities, Option<String>, Option<String>), String> {
+    info!("Downloading FCC facilities");
+    let error = match download(previous).await {
+        Ok(facilities_map) => {
+            let cache_error = match write_cache_file(cache_file, &facilities_map) {
+                Ok(()) => None,
+                Err(e) => {
+                    warn!("Unable to cache FCC facilities: {}", e);
+                    Some(e)
+                }
+            };
+            return Ok((facilities_map, None, cache_error));
+        }
+        Err(e) => format!("unable to download FCC facilities: {}", e),
     };
+    if !cache_file.exists() {
+        return Err(error);
+    }
+
+    warn!(
+        "{}, falling back to cached facilities at {}",
+        error,
+        cache_file.display()
+    );
+    let facilities_map = load_cache_file(cache_file)
+        .map_err(|e| format!("{}; cached facilities are corrupt: {}", error, e))?;
+    Ok((facilities_map, Some(error), None))
+}
+
+/// Download the FCC facilities and map them to locast DMAs
+async fn download(previous: usize) -> Result<Facilities, String> {
+    let locast_dmas: Vec<LocastDMA> = match crate::utils::get(DMA_URL, None, 100).await {
+        Ok(r) => r.json().await.map_err(HttpError::from),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| format!("unable to get locast DMAs: {}", e))?;
 
-    let mut loaded_lines: Vec<String> = Vec::new();
-    let mut facilities_map: HashMap<(i64, String), (String, String)> = HashMap::new();
-    for line in lines.into_iter().map(|l| l.unwrap()) {
-        let parts: Vec<&str> = line.split("|").collect();
+    let zipfile = match crate::utils::get(FACILITIES_URL, None, 100).await {
+        Ok(r) => r.bytes().await.map_err(HttpError::from),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| e.to_string())?;
+    if zipfile.len() < MIN_DOWNLOAD_SIZE {
+        return Err(format!("download is only {} bytes", zipfile.len()));
+    }
+
+    // Reading the file verifies its checksum, so truncated or corrupted archives result in read errors
+    let mut archive =
+        zip::ZipArchive::new(std::io::Cursor::new(zipfile)).map_err(|e| e.to_string())?;
+    let file = archive.by_name("facility.dat").map_err(|e| e.to_string())?;
+    let facilities_map = parse(BufReader::new(file).lines(), &locast_dmas)?;
+
+    // A sudden drop in facilities is more likely a broken file than the FCC deleting stations
+    if (facilities_map.len() as f64) < previous as f64 * MIN_RELATIVE_SIZE {
+        return Err(format!(
+            "only {} facilities found, while {} were loaded before",
+            facilities_map.len(),
+            previous
+        ));
+    }
+    Ok(facilities_map)
+}
+
+/// Load the parsed facilities from a cache file
+fn load_cache_file(cache_file: &Path) -> Result<Facilities, String> {
+    let file = File::open(cache_file).map_err(|e| e.to_string())?;
+    let cached: Vec<CachedFacility> =
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())?;
+    if cached.len() < MIN_FACILITIES {
+        return Err(format!("only {} facilities found", cached.len()));
+    }
+    Ok(cached
+        .into_iter()
+        .map(|f| {
+            (
+                (f.locast_id, f.call_sign),
+                (f.fac_channel, f.tv_virtual_channel),
+            )
+        })
+        .collect())
+}
+
+/// Parse lines of FCC facility data. Malformed lines are skipped, but if there are more than
+/// `MAX_MALFORMED` of them, or less than `MIN_ROWS` lines, the data is considered corrupt.
+fn parse(
+    lines: impl Iterator<Item = std::io::Result<String>>,
+    locast_dmas: &[LocastDMA],
+) -> Result<Facilities, String> {
+    let mut rows = 0;
+    let mut malformed = 0;
+    let mut facilities_map: Facilities = HashMap::new();
+    for line in lines {
+        let line = line.map_err(|e| format!("unable to read line {}: {}", rows + 1, e))?;
+        rows += 1;
+
+        let parts: Vec<&str> = line.split('|').collect();
+        if parts.len()
+            <= *[LIC_EXPIRATION_DATE, NIELSEN_DMA, TV_VIRTUAL_CHANNEL]
+                .iter()
+                .max()
+                .unwrap()
+        {
+            malformed += 1;
+            continue;
+        }
 
         let lic_expiration_date = parts[LIC_EXPIRATION_DATE];
         let nielsen_dma = parts[NIELSEN_DMA];
@@ -158,62 +373,86 @@ async fn load<'a>(cache_file: &PathBuf) -> HashMap<(i64, String), (String, Strin
         let tv_virtual_channel = &parts[TV_VIRTUAL_CHANNEL];
 
         if fac_status == "LICEN"
-            && lic_expiration_date != ""
-            && nielsen_dma != ""
+            && !lic_expiration_date.is_empty()
+            && !nielsen_dma.is_empty()
             && SERVICE_LIST.contains(&fac_service)
         {
             let s = format!("{} 23:59:59 +0000", lic_expiration_date);
-            if DateTime::parse_from_str(&s, "%m/%d/%Y %T %z").unwrap() >= Utc::now() {
-                let call_sign = fac_call_sign.split("-").collect::<Vec<&str>>()[0];
+            let expiration = match DateTime::parse_from_str(&s, "%m/%d/%Y %T %z") {
+                Ok(e) => e,
+                Err(_) => {
+                    malformed += 1;
+                    continue;
+                }
+            };
+            if expiration >= Utc::now() {
+                let call_sign = fac_call_sign.split('-').collect::<Vec<&str>>()[0];
 
                 // Get the locast_id based on the Nielsen DMA
-                let locast_id = nielsen_dma_to_locast_id(nielsen_dma, &locast_dmas);
-                if locast_id.is_some() {
+                if let Some(locast_id) = nielsen_dma_to_locast_id(nielsen_dma, locast_dmas) {
                     facilities_map.insert(
-                        (locast_id.unwrap(), call_sign.to_owned()),
+                        (locast_id, call_sign.to_owned()),
                         (fac_channel.to_string(), tv_virtual_channel.to_string()),
                     );
-                    loaded_lines.push(line);
                 }
             }
         }
     }
 
-    // Only write lines that matter to the cache file.
-    if downloaded {
-        write_cache_file(cache_file, loaded_lines.join("\n").as_bytes());
+    if rows < MIN_ROWS {
+        return Err(format!("only {} rows found", rows));
     }
-
-    facilities_map
+    if malformed as f64 > rows as f64 * MAX_MALFORMED {
+        return Err(format!("{} out of {} rows are malformed", malformed, rows));
+    }
+    if malformed > 0 {
+        warn!("Skipped {} malformed rows in FCC facilities", malformed);
+    }
+    Ok(facilities_map)
 }
 
 /// Try to find a locast_id by matching a Nielsen DMA with a Locast DMA name. This uses a fuzzy matcher.
-fn nielsen_dma_to_locast_id(nielsen_dma: &str, locast_dmas: &Vec<LocastDMA>) -> Option<i64> {
+fn nielsen_dma_to_locast_id(nielsen_dma: &str, locast_dmas: &[LocastDMA]) -> Option<i64> {
     let matcher = SkimMatcherV2::default();
     let mut matches: Vec<(i64, i64)> = locast_dmas
         .iter()
         .map(|l| (l.id, l.name.to_lowercase()))
-        .map(|(locast_id, name)| (locast_id, matcher.fuzzy_match(&nielsen_dma, &name)))
+        .map(|(locast_id, name)| (locast_id, matcher.fuzzy_match(nielsen_dma, &name)))
         .filter(|(_, ratio)| ratio.is_some() && ratio.unwrap() > 115)
         .map(|(locast_id, ratio)| (locast_id, ratio.unwrap()))
         .collect();
 
-    matches.sort_by(|a, b| b.1.cmp(&a.1)); // Reversed sort
+    matches.sort_by_key(|m| std::cmp::Reverse(m.1));
     Some(matches.first()?.0)
 }
 
-/// Write the cache file to `cache_path`
-fn write_cache_file(cache_file: &PathBuf, contents: &[u8]) {
+/// Write the parsed facilities to `cache_path`
+/// The file is written next to `cache_path` first and then moved into place, so an interrupted write
+/// doesn't leave a truncated cache file behind.
+fn write_cache_file(cache_file: &Path, facilities: &Facilities) -> Result<(), String> {
+    let cached: Vec<CachedFacility> = facilities
+        .iter()
+        .map(
+            |((locast_id, call_sign), (fac_channel, tv_virtual_channel))| CachedFacility {
+                locast_id: *locast_id,
+                call_sign: call_sign.to_owned(),
+                fac_channel: fac_channel.to_owned(),
+                tv_virtual_channel: tv_virtual_channel.to_owned(),
+            },
+        )
+        .collect();
+    let contents = serde_json::to_vec(&cached).map_err(|e| e.to_string())?;
     let display = cache_file.display();
-    let mut file = match File::create(&cache_file) {
-        Err(why) => panic!("Couldn't create {}: {}", display, why),
-        Ok(file) => file,
-    };
-
-    match file.write_all(contents) {
-        Err(why) => panic!("couldn't write to {}: {}", display, why),
-        Ok(_) => info!("Cached FCC facilities to {}", display),
-    }
+    let tmp_file = cache_file.with_extension("tmp");
+    let mut file = File::create(&tmp_file)
+        .map_err(|why| format!("couldn't create {}: {}", tmp_file.display(), why))?;
+    file.write_all(&contents)
+        .and_then(|_| file.sync_all())
+        .map_err(|why| format!("couldn't write to {}: {}", tmp_file.display(), why))?;
+    std::fs::rename(&tmp_file, cache_file)
+        .map_err(|why| format!("couldn't write to {}: {}", display, why))?;
+    info!("Cached FCC facilities to {}", display);
+    Ok(())
 }
 
 /// Struct used to deserialize Locast DMA json
@@ -0,0 +1,135 @@
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// File in the cache directory the audit log is appended to
+static AUDIT_FILE: &str = "audit.log";
+
+/// A single state-changing admin call
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    /// Unix timestamp
+    pub time: i64,
+    /// User name when using basic authentication, "api_key" when using the API key, or "anonymous"
+    pub user: String,
+    /// Address of the client that made the call
+    pub address: Option<String>,
+    /// What was done, e.g. "remap" or "adhoc_remove"
+    pub action: String,
+    /// What it was done to, e.g. "channel.1234"
+    pub target: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// Append-only log of admin mutations (channel remapping, ad hoc channels, share links, recordings),
+/// stored as one JSON object per line in the cache directory.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    pub fn new(cache_directory: &Path) -> AuditLog {
+        let path = cache_directory.join(AUDIT_FILE);
+        let file = fs::create_dir_all(cache_directory)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+        let file = match file {
+            Ok(f) => Some(f),
+            Err(e) => {
+                warn!(
+                    "Unable to open audit log {}, admin changes won't be recorded: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+        AuditLog {
+            path,
+            file: Mutex::new(file),
+        }
+    }
+
+    /// Record that the client that made `req` did `action` to `target`, changing it from `old` to
+    /// `new`
+    pub fn record<O: Serialize, N: Serialize>(
+        &self,
+        req: &HttpRequest,
+        action: &str,
+        target: &str,
+        old: Option<O>,
+        new: Option<N>,
+    ) {
+        let entry = AuditEntry {
+            time: Utc::now().timestamp(),
+            user: user(req),
+            address: req.peer_addr().map(|a| a.ip().to_string()),
+            action: action.to_owned(),
+            target: target.to_owned(),
+            old: old.and_then(|o| serde_json::to_value(o).ok()),
+            new: new.and_then(|n| serde_json::to_value(n).ok()),
+        };
+        info!(
+            "Audit: {} ({}) {} {}",
+            entry.user,
+            entry.address.as_deref().unwrap_or("unknown"),
+            entry.action,
+            entry.target
+        );
+
+        let mut file = self.file.lock().unwrap();
+        if let Some(f) = file.as_mut() {
+            let line = serde_json::to_string(&entry).unwrap();
+            if let Err(e) = writeln!(f, "{}", line) {
+                warn!(
+                    "Unable to write to audit log {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// The last `limit` entries, oldest first
+    pub fn entries(&self, limit: usize) -> Vec<AuditEntry> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(_) => return Vec::new(),
+        };
+        let entries: Vec<AuditEntry> = BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|l| serde_json::from_str(&l).ok())
+            .collect();
+        let skip = entries.len().saturating_sub(limit);
+        entries.into_iter().skip(skip).collect()
+    }
+}
+
+/// Who made `req`: the basic authentication user name, "api_key" or "anonymous"
+fn user(req: &HttpRequest) -> String {
+    let basic_user = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b| base64::decode(b.trim()).ok())
+        .and_then(|b| String::from_utf8(b).ok())
+        .and_then(|c| c.split_once(':').map(|(u, _)| u.to_owned()));
+    if let Some(user) = basic_user {
+        return user;
+    }
+    if req.headers().contains_key("X-API-Key") {
+        "api_key".to_owned()
+    } else {
+        "anonymous".to_owned()
+    }
+}
@@ -0,0 +1,159 @@
+use crate::config::Config;
+use actix_web::{dev::ServiceRequest, http::header::AUTHORIZATION};
+
+/// Access control for the HTTP servers. When `http_username`/`http_password` or `api_key` are
+/// configured, every request has to either use HTTP basic authentication or carry the API key in the
+/// `X-API-Key` header. The key isn't accepted in the query string, where it would end up in logs. Paths
+/// that start with one of the prefixes in `auth_allowlist`, share links and the health probes are always
+/// allowed.
+pub struct Auth {
+    credentials: Option<String>,
+    api_key: Option<String>,
+    allowlist: Vec<String>,
+}
+
+impl Auth {
+    pub fn new(config: &Config) -> Auth {
+        let credentials = match (&config.http_username, &config.http_password) {
+            (Some(u), Some(p)) => Some(base64::encode(format!("{}:{}", u, p))),
+            _ => None,
+        };
+        Auth {
+            credentials,
+            api_key: config.api_key.clone(),
+            allowlist: config.auth_allowlist.clone(),
+        }
+    }
+
+    /// Returns true if authentication is configured
+    pub fn enabled(&self) -> bool {
+        self.credentials.is_some() || self.api_key.is_some()
+    }
+
+    /// Returns true if basic authentication is configured, so clients should be asked for credentials
+    pub fn basic(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    /// Check if a request is allowed
+    pub fn allowed(&self, req: &ServiceRequest) -> bool {
+        if !self.enabled() || self.allowlist.iter().any(|p| req.path().starts_with(p)) {
+            return true;
+        }
+        // Share links are signed, which is checked when they're used
+        if super::share::share_path(req.path()) || super::probe_path(req.path()) {
+            return true;
+        }
+
+        if let Some(credentials) = &self.credentials {
+            let basic = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Basic "));
+            if basic.is_some_and(|b| constant_time_eq(b.trim(), credentials)) {
+                return true;
+            }
+        }
+
+        if let Some(api_key) = &self.api_key {
+            let key = req.headers().get("X-API-Key").and_then(|h| h.to_str().ok());
+            if key.is_some_and(|k| constant_time_eq(k, api_key)) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Compare two strings without leaking how much of them matched through timing
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn auth(username: Option<&str>, api_key: Option<&str>, allowlist: &[&str]) -> Auth {
+        Auth::new(&Config {
+            http_username: username.map(|u| u.to_owned()),
+            http_password: username.map(|_| "secret".to_owned()),
+            api_key: api_key.map(|k| k.to_owned()),
+            auth_allowlist: allowlist.iter().map(|p| p.to_string()).collect(),
+            ..Config::default()
+        })
+    }
+
+    fn basic(username: &str, password: &str) -> (actix_web::http::HeaderName, String) {
+        let encoded = base64::encode(format!("{}:{}", username, password));
+        (AUTHORIZATION, format!("Basic {}", encoded))
+    }
+
+    #[test]
+    fn constant_time_eq_compares_strings() {
+        assert!(constant_time_eq("", ""));
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "Secret"));
+        assert!(!constant_time_eq("secret", "secre"));
+        assert!(!constant_time_eq("secret", "secrets"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+
+    #[test]
+    fn everything_is_allowed_without_authentication() {
+        let auth = auth(None, None, &[]);
+        assert!(!auth.enabled());
+        assert!(auth.allowed(&TestRequest::with_uri("/config").to_srv_request()));
+    }
+
+    #[test]
+    fn basic_authentication() {
+        let auth = auth(Some("admin"), None, &[]);
+        assert!(auth.basic());
+        let req = |header| {
+            TestRequest::with_uri("/config")
+                .insert_header(header)
+                .to_srv_request()
+        };
+        assert!(auth.allowed(&req(basic("admin", "secret"))));
+        assert!(!auth.allowed(&req(basic("admin", "wrong"))));
+        assert!(!auth.allowed(&req(basic("other", "secret"))));
+        assert!(!auth.allowed(&TestRequest::with_uri("/config").to_srv_request()));
+    }
+
+    #[test]
+    fn api_key_only_in_header() {
+        let auth = auth(None, Some("key"), &[]);
+        assert!(!auth.basic());
+        let allowed = |key| {
+            auth.allowed(
+                &TestRequest::with_uri("/config")
+                    .insert_header(("X-API-Key", key))
+                    .to_srv_request(),
+            )
+        };
+        assert!(allowed("key"));
+        assert!(!allowed("wrong"));
+        assert!(!auth.allowed(&TestRequest::with_uri("/config?api_key=key").to_srv_request()));
+    }
+
+    #[test]
+    fn allowlist_and_share_links() {
+        let auth = auth(Some("admin"), Some("key"), &["/discover.json", "/watch/"]);
+        let allowed = |uri| auth.allowed(&TestRequest::with_uri(uri).to_srv_request());
+        assert!(allowed("/discover.json"));
+        assert!(allowed("/watch/1234"));
+        assert!(allowed("/share/abcdef"));
+        assert!(allowed("/tuner/denver/share/abcdef"));
+        assert!(allowed("/healthz"));
+        assert!(allowed("/tuner/denver/readyz"));
+        assert!(!allowed("/config"));
+        assert!(!allowed("/lineup.json"));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::service::station::Station;
+use actix_web::{http::header::USER_AGENT, web, HttpRequest};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+/// A client that fetched the lineup less than this many seconds before it changed keeps getting the
+/// lineup it was served (e.g. to finish a channel scan), for at most this many seconds after the change.
+static PIN_DURATION: i64 = 1800;
+/// Maximum amount of streams kept in the history of a client
+static MAX_STREAM_HISTORY: usize = 20;
+/// Maximum amount of clients that are tracked. When exceeded, the least recently seen client is dropped.
+static MAX_CLIENTS: usize = 100;
+
+/// Keeps track of the distinct clients (e.g. Plex or Emby servers) that use a tuner. A client is
+/// identified by its IP address, user agent and `DeviceAuth`. For every client we remember which
+/// lineup generation it was served, so a lineup refresh doesn't renumber channels while the client is
+/// in the middle of e.g. a channel scan.
+pub struct ClientTracker {
+    state: Arc<Mutex<TrackerState>>,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    generation: u64,
+    fingerprint: u64,
+    /// The stations of the current lineup generation, shared by the clients that were served it
+    current: Arc<Vec<Station>>,
+    clients: HashMap<String, Client>,
+}
+
+/// State of a single client. Timestamps are unix timestamps in seconds.
+#[derive(Serialize, Clone)]
+pub struct Client {
+    pub id: String,
+    pub address: String,
+    pub user_agent: Option<String>,
+    pub device_auth: Option<String>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// Lineup generation this client is served
+    pub lineup_generation: u64,
+    /// HDHomeRun transcode profile the client requested for its last stream (if any)
+    pub profile: Option<String>,
+    pub streams: VecDeque<StreamRecord>,
+    /// When the client last fetched the lineup
+    pub lineup_fetched: Option<i64>,
+    /// When the client was pinned to an outdated lineup generation
+    pub pinned_since: Option<i64>,
+    #[serde(skip)]
+    lineup: Arc<Vec<Station>>,
+}
+
+/// A stream that was served to a client
+#[derive(Serialize, Clone)]
+pub struct StreamRecord {
+    pub station_id: String,
+    pub started: i64,
+    pub ended: Option<i64>,
+}
+
+/// Overview of all clients, served at `/clients.json`
+#[derive(Serialize)]
+pub struct ClientsReport {
+    pub lineup_generation: u64,
+    pub clients: Vec<Client>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ClientQuery {
+    DeviceAuth: Option<String>,
+    transcode: Option<String>,
+}
+
+impl ClientTracker {
+    pub fn new() -> ClientTracker {
+        ClientTracker {
+            state: Arc::new(Mutex::new(TrackerState::default())),
+        }
+    }
+
+    /// Register a request from a client (e.g. a tuner probe) and return its id
+    pub fn seen(&self, req: &HttpRequest) -> String {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        client(&mut state, req, generation).id.clone()
+    }
+
+    /// Return the lineup to serve to the client that made `req` and register the lineup fetch. If the
+    /// lineup changed shortly after the client last fetched it, the client keeps getting the stations it
+    /// was served before, until the pin expires `PIN_DURATION` seconds after it was created. Otherwise
+    /// the current lineup is returned and becomes the one this client is served.
+    pub fn lineup<'a>(&self, req: &HttpRequest, stations: &'a [Station]) -> Cow<'a, [Station]> {
+        self.serve(req, stations, true)
+    }
+
+    /// Return the lineup the client that made `req` is currently served (e.g. for the guide), without
+    /// registering a lineup fetch
+    pub fn pinned<'a>(&self, req: &HttpRequest, stations: &'a [Station]) -> Cow<'a, [Station]> {
+        self.serve(req, stations, false)
+    }
+
+    fn serve<'a>(
+        &self,
+        req: &HttpRequest,
+        stations: &'a [Station],
+        fetch: bool,
+    ) -> Cow<'a, [Station]> {
+        let mut state = self.state.lock().unwrap();
+        let fingerprint = fingerprint(stations);
+        if fingerprint != state.fingerprint || state.current.is_empty() {
+            if fingerprint != state.fingerprint {
+                state.fingerprint = fingerprint;
+                state.generation += 1;
+            }
+            state.current = Arc::new(stations.to_vec());
+        }
+        let generation = state.generation;
+        let current = state.current.clone();
+
+        let now = Utc::now().timestamp();
+        let client = client(&mut state, req, generation);
+        if client.lineup_generation != generation && !client.lineup.is_empty() {
+            // The pin is created the first time the client is served after the change
+            if client.pinned_since.is_none()
+                && client
+                    .lineup_fetched
+                    .is_some_and(|t| now - t < PIN_DURATION)
+            {
+                client.pinned_since = Some(now);
+            }
+            if client.pinned_since.is_some_and(|t| now - t < PIN_DURATION) {
+                debug!(
+                    "Serving lineup generation {} to client {} (current: {})",
+                    client.lineup_generation, client.id, generation
+                );
+                return Cow::Owned(client.lineup.to_vec());
+            }
+        }
+
+        if fetch || client.lineup_generation != generation {
+            client.lineup_generation = generation;
+            client.lineup = current;
+            client.pinned_since = None;
+        }
+        if fetch {
+            client.lineup_fetched = Some(now);
+        }
+        Cow::Borrowed(stations)
+    }
+
+    /// Record a stream for the client that made `req`. The stream is marked as ended when the returned
+    /// guard is dropped.
+    pub fn stream(&self, req: &HttpRequest, station_id: &str) -> ClientStream {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        let client = client(&mut state, req, generation);
+        client.profile = query(req).and_then(|q| q.transcode);
+        let started = Utc::now().timestamp();
+        client.streams.push_back(StreamRecord {
+            station_id: station_id.to_owned(),
+            started,
+            ended: None,
+        });
+        while client.streams.len() > MAX_STREAM_HISTORY {
+            client.streams.pop_front();
+        }
+        ClientStream {
+            state: Some(self.state.clone()),
+            client_id: client.id.clone(),
+            started,
+            station_id: station_id.to_owned(),
+        }
+    }
+
+    /// All known clients, most recently seen first
+    pub fn report(&self) -> ClientsReport {
+        let state = self.state.lock().unwrap();
+        let mut clients: Vec<Client> = state.clients.values().cloned().collect();
+        clients.sort_by_key(|c| Reverse(c.last_seen));
+        ClientsReport {
+            lineup_generation: state.generation,
+            clients,
+        }
+    }
+}
+
+/// Marks a stream in the client history as ended when dropped
+pub struct ClientStream {
+    state: Option<Arc<Mutex<TrackerState>>>,
+    client_id: String,
+    station_id: String,
+    started: i64,
+}
+
+impl ClientStream {
+    /// Stop tracking the stream, leaving it without an end time (e.g. when we don't serve the stream
+    /// ourselves)
+    pub fn detach(mut self) {
+        self.state = None;
+    }
+}
+
+impl Drop for ClientStream {
+    fn drop(&mut self) {
+        let mut state = match &self.state {
+            Some(s) => s.lock().unwrap(),
+            None => return,
+        };
+        if let Some(record) = state.clients.get_mut(&self.client_id).and_then(|c| {
+            c.streams
+                .iter_mut()
+                .rev()
+                .find(|s| s.station_id == self.station_id && s.started == self.started)
+        }) {
+            record.ended = Some(Utc::now().timestamp());
+        }
+    }
+}
+
+/// Look up (or create) the client that made `req` and update when it was last seen
+fn client<'a>(state: &'a mut TrackerState, req: &HttpRequest, generation: u64) -> &'a mut Client {
+    let address = req
+        .peer_addr()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_default();
+    let user_agent = req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_owned());
+    let device_auth = query(req).and_then(|q| q.DeviceAuth);
+    let id = Uuid::new_v5(
+        &Uuid::NAMESPACE_OID,
+        format!(
+            "{}|{}|{}",
+            address,
+            user_agent.as_deref().unwrap_or(""),
+            device_auth.as_deref().unwrap_or("")
+        )
+        .as_bytes(),
+    )
+    .to_string()[..8]
+        .to_owned();
+
+    if !state.clients.contains_key(&id) && state.clients.len() >= MAX_CLIENTS {
+        if let Some(oldest) = state
+            .clients
+            .values()
+            .min_by_key(|c| c.last_seen)
+            .map(|c| c.id.clone())
+        {
+            state.clients.remove(&oldest);
+        }
+    }
+
+    let now = Utc::now().timestamp();
+    let client = state.clients.entry(id.clone()).or_insert_with(|| {
+        info!(
+            "New client {} ({}, {})",
+            id,
+            address,
+            user_agent.as_deref().unwrap_or("unknown user agent")
+        );
+        Client {
+            id,
+            address,
+            user_agent,
+            device_auth,
+            first_seen: now,
+            last_seen: now,
+            lineup_generation: generation,
+            profile: None,
+            streams: VecDeque::new(),
+            lineup_fetched: None,
+            pinned_since: None,
+            lineup: Arc::new(Vec::new()),
+        }
+    });
+    client.last_seen = now;
+    client
+}
+
+fn query(req: &HttpRequest) -> Option<ClientQuery> {
+    web::Query::<ClientQuery>::from_query(req.query_string())
+        .ok()
+        .map(|q| q.into_inner())
+}
+
+/// The channel number a station is served with
+fn channel_of(station: &Station) -> Option<&String> {
+    station
+        .channel_remapped
+        .as_ref()
+        .or(station.channel.as_ref())
+}
+
+/// Fingerprint of the numbering of a lineup. This changes whenever a station is added, removed or
+/// renumbered.
+fn fingerprint(stations: &[Station]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for s in stations {
+        (s.id, s.active, channel_of(s)).hash(&mut hasher);
+    }
+    hasher.finish()
+}
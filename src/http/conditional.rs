@@ -0,0 +1,136 @@
+use crate::service::{station::Station, xmltv::Programmes, ServiceStatus};
+use actix_web::{
+    dev::HttpResponseBuilder,
+    http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+/// Number of entity tags to remember when they were first served
+const MAX_TAGS: usize = 256;
+
+/// Remembers when an entity tag was first served, so Last-Modified also moves forward when e.g. the
+/// remapping changes without a refresh.
+#[derive(Default)]
+pub struct EntityTags {
+    first_served: Mutex<HashMap<u64, i64>>,
+}
+
+impl EntityTags {
+    pub fn new() -> EntityTags {
+        EntityTags::default()
+    }
+
+    fn first_served(&self, tag: u64, modified: i64) -> i64 {
+        let mut first_served = self.first_served.lock().unwrap();
+        if first_served.len() >= MAX_TAGS && !first_served.contains_key(&tag) {
+            if let Some(oldest) = first_served
+                .iter()
+                .min_by_key(|(_, t)| **t)
+                .map(|(k, _)| *k)
+            {
+                first_served.remove(&oldest);
+            }
+        }
+        // Tags served before a restart were served at `modified` at the earliest
+        let now = if first_served.is_empty() {
+            modified
+        } else {
+            Utc::now().timestamp()
+        };
+        *first_served.entry(tag).or_insert_with(|| now.max(modified))
+    }
+}
+
+/// Validators of a response that is built from the stations of the services, so clients can skip
+/// downloading it again with If-None-Match or If-Modified-Since.
+pub struct Validators {
+    etag: String,
+    last_modified: i64,
+}
+
+impl Validators {
+    /// Validators for the response to `req`, built from the stations in `lineup` (as served to the
+    /// client that made `req`) and `overrides`. The guide generation of every service is part of the
+    /// entity tag.
+    pub fn new(
+        req: &HttpRequest,
+        services: &[ServiceStatus],
+        lineup: &[Station],
+        overrides: Option<&Programmes>,
+        tags: &EntityTags,
+    ) -> Validators {
+        let mut hasher = DefaultHasher::new();
+        (super::base_url(req), req.uri().to_string()).hash(&mut hasher);
+        for s in services {
+            (&s.dma, s.modified).hash(&mut hasher);
+        }
+        for s in lineup {
+            (
+                s.id,
+                s.active,
+                &s.name,
+                &s.callSign,
+                &s.callSign_remapped,
+                &s.channel,
+                &s.channel_remapped,
+                s.listings.len(),
+            )
+                .hash(&mut hasher);
+        }
+        if let Some(overrides) = overrides {
+            let mut ids: Vec<&i64> = overrides.keys().collect();
+            ids.sort();
+            for id in ids {
+                (id, &overrides[id]).hash(&mut hasher);
+            }
+        }
+        let tag = hasher.finish();
+        let modified = services.iter().map(|s| s.modified).max().unwrap_or(0);
+        Validators {
+            etag: format!("\"{:016x}\"", tag),
+            last_modified: tags.first_served(tag, modified),
+        }
+    }
+
+    /// Returns true if the client already has this version of the response. If-None-Match takes
+    /// precedence over If-Modified-Since.
+    pub fn not_modified(&self, req: &HttpRequest) -> bool {
+        let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok());
+        if let Some(if_none_match) = header(IF_NONE_MATCH) {
+            return if_none_match.split(',').any(|t| {
+                let t = t.trim();
+                t == "*" || t.strip_prefix("W/").unwrap_or(t) == self.etag
+            });
+        }
+        header(IF_MODIFIED_SINCE)
+            .and_then(|h| DateTime::parse_from_rfc2822(h).ok())
+            .is_some_and(|since| self.last_modified <= since.timestamp())
+    }
+
+    /// Response for a client that already has this version
+    pub fn not_modified_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::NotModified();
+        self.headers(&mut response);
+        response.finish()
+    }
+
+    /// Add the ETag and Last-Modified headers to `response`
+    pub fn headers<'a>(
+        &self,
+        response: &'a mut HttpResponseBuilder,
+    ) -> &'a mut HttpResponseBuilder {
+        let last_modified = Utc
+            .timestamp(self.last_modified, 0)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        response
+            .append_header((ETAG, self.etag.clone()))
+            .append_header((LAST_MODIFIED, last_modified))
+    }
+}
@@ -0,0 +1,42 @@
+use actix_web::{dev::Extensions, rt::net::TcpStream, HttpRequest};
+use socket2::{SockRef, Socket};
+use std::{any::Any, io::ErrorKind, mem::MaybeUninit, sync::Arc};
+
+/// Duplicate of the socket of a client connection, used to notice that a client went away while it's
+/// being streamed to. Without this, a disconnect only shows when writing to the connection fails.
+#[derive(Clone)]
+pub struct Connection(Arc<Socket>);
+
+/// Attach the `Connection` to the first request of every plain HTTP connection. Connection data is
+/// moved into the first request, so later requests on a kept alive connection (and HTTPS
+/// connections) don't get one.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let stream = match connection.downcast_ref::<TcpStream>() {
+        Some(s) => s,
+        None => return,
+    };
+    match SockRef::from(stream).try_clone() {
+        Ok(socket) if socket.set_nonblocking(true).is_ok() => {
+            extensions.insert(Connection(Arc::new(socket)));
+        }
+        Ok(_) => {}
+        Err(e) => debug!("Unable to watch connection for disconnects: {}", e),
+    }
+}
+
+impl Connection {
+    /// The connection `req` came in on, if it's being watched
+    pub fn of(req: &HttpRequest) -> Option<Connection> {
+        req.extensions().get::<Connection>().cloned()
+    }
+
+    /// Returns true if the client closed the connection
+    pub fn closed(&self) -> bool {
+        let mut buf = [MaybeUninit::uninit(); 1];
+        match self.0.peek(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::config::Config;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        Method,
+    },
+    HttpResponse,
+};
+
+/// Paths (relative to a tuner) that browsers are allowed to fetch cross-origin. Admin and configuration
+/// endpoints are deliberately not included.
+static CORS_PATHS: &[&str] = &[
+    "/discover.json",
+    "/epg",
+    "/epg.xml",
+    "/lineup.json",
+    "/lineup_status.json",
+    "/tuner.m3u",
+    "/widget/nowplaying.json",
+];
+/// How long (in seconds) browsers can cache the result of a preflight request
+static MAX_AGE: &str = "3600";
+
+/// Cross-origin resource sharing for browser based consumers (e.g. dashboards). Only requests from
+/// `cors_origins` to the JSON and playlist endpoints get CORS headers.
+pub struct Cors {
+    origins: Vec<String>,
+    credentials: bool,
+}
+
+impl Cors {
+    pub fn new(config: &Config) -> Cors {
+        Cors {
+            origins: config.cors_origins.clone(),
+            credentials: config.cors_credentials,
+        }
+    }
+
+    /// The value for `Access-Control-Allow-Origin` if `req` is a CORS request that is allowed
+    pub fn allow_origin(&self, req: &ServiceRequest) -> Option<HeaderValue> {
+        if self.origins.is_empty() || !cors_path(req.path()) {
+            return None;
+        }
+        let origin = req.headers().get(header::ORIGIN)?;
+        let origin_str = origin.to_str().ok()?;
+        if self
+            .origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin_str))
+        {
+            Some(origin.clone())
+        } else if self.origins.iter().any(|o| o == "*") {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            None
+        }
+    }
+
+    /// Answer a preflight request. Preflight requests never carry credentials, so they are answered
+    /// before authentication.
+    pub fn preflight(&self, req: ServiceRequest, origin: HeaderValue) -> ServiceResponse {
+        let mut response = HttpResponse::NoContent();
+        response
+            .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, HEAD, OPTIONS"))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, MAX_AGE));
+        if let Some(headers) = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .cloned()
+        {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, headers));
+        }
+        let mut response = response.finish();
+        self.decorate(response.headers_mut(), origin);
+        req.into_response(response)
+    }
+
+    /// Add CORS headers to a response
+    pub fn decorate(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+/// Returns true if `req` is a CORS preflight request
+pub fn is_preflight(req: &ServiceRequest) -> bool {
+    req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Returns true if CORS is allowed for `path`. Paths can have a tuner prefix in single port mode.
+fn cors_path(path: &str) -> bool {
+    CORS_PATHS.iter().any(|p| path.ends_with(p))
+        || (path.contains("/watch/") && (path.ends_with(".m3u") || path.ends_with(".m3u8")))
+        || path.contains("/segment/")
+        || path.contains("/timeshift/")
+}
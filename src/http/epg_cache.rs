@@ -0,0 +1,94 @@
+use crate::service::{station::Station, ServiceStatus};
+use actix_web::{http::header::ACCEPT_ENCODING, HttpRequest};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+/// Serialized `/epg` of a version of the stations
+pub struct CachedEpg {
+    pub json: bytes::Bytes,
+    pub gzip: bytes::Bytes,
+}
+
+/// Copy of `/epg` (and a gzipped copy) that is kept until the stations change. The JSON of all stations
+/// and their listings gets large when multiplexing many locations, so it's only serialized and compressed
+/// once per refresh instead of on every request.
+#[derive(Default)]
+pub struct EpgCache {
+    cached: Mutex<Option<(u64, Arc<CachedEpg>)>>,
+}
+
+impl EpgCache {
+    /// The serialized stations, serialized again if they changed since they were cached
+    pub fn get(&self, services: &[ServiceStatus], stations: &[Station]) -> Arc<CachedEpg> {
+        let version = version(services, stations);
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((v, epg)) = &*cached {
+            if *v == version {
+                return epg.clone();
+            }
+        }
+
+        let json = serde_json::to_vec(stations).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let gzip = encoder
+            .write_all(&json)
+            .and_then(|_| encoder.finish())
+            .unwrap();
+        debug!(
+            "Cached EPG of {} stations ({} KB, {} KB gzipped)",
+            stations.len(),
+            json.len() / 1024,
+            gzip.len() / 1024
+        );
+        let epg = Arc::new(CachedEpg {
+            json: json.into(),
+            gzip: gzip.into(),
+        });
+        *cached = Some((version, epg.clone()));
+        epg
+    }
+}
+
+/// Version of the stations. Listings only change when a service is refreshed (which updates its
+/// `modified`), while stations can also change through remapping and ad hoc channels.
+fn version(services: &[ServiceStatus], stations: &[Station]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for s in services {
+        (&s.dma, s.modified).hash(&mut hasher);
+    }
+    for s in stations {
+        (
+            s.id,
+            s.active,
+            &s.callSign_remapped,
+            &s.channel,
+            &s.channel_remapped,
+            s.remapped,
+            s.listings.len(),
+        )
+            .hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Returns true if the client accepts gzip compressed responses
+pub fn accepts_gzip(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(ACCEPT_ENCODING)
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let quality = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && quality > 0.0
+        })
+}
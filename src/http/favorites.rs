@@ -0,0 +1,124 @@
+use crate::{errors::AppError, service::station::Station};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// File in the cache directory that holds the favorite stations
+static FAVORITES_FILE: &str = "favorites.json";
+
+/// Group that favorites are added to in `/tuner.m3u`
+pub static FAVORITES_GROUP: &str = "Favorites";
+
+/// Favorite stations by station id, in the order they are listed in. Favorites are listed first in
+/// `/tuner.m3u` and `/lineup.json`. They are stored in the cache directory, so they survive a restart.
+pub struct Favorites {
+    path: PathBuf,
+    station_ids: Mutex<Vec<i64>>,
+}
+
+impl Favorites {
+    pub fn new(cache_directory: &Path) -> Favorites {
+        let path = cache_directory.join(FAVORITES_FILE);
+        let station_ids = if path.exists() {
+            match File::open(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()))
+            {
+                Ok(ids) => ids,
+                Err(e) => {
+                    warn!("Unable to read favorites from {}: {}", path.display(), e);
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        Favorites {
+            path,
+            station_ids: Mutex::new(station_ids),
+        }
+    }
+
+    pub fn station_ids(&self) -> Vec<i64> {
+        self.station_ids.lock().unwrap().clone()
+    }
+
+    /// Replace the favorites and their order. Returns the previous favorites.
+    pub fn set(&self, station_ids: Vec<i64>) -> Result<Vec<i64>, AppError> {
+        let mut current = self.station_ids.lock().unwrap();
+        let mut unique = Vec::with_capacity(station_ids.len());
+        for id in station_ids {
+            if !unique.contains(&id) {
+                unique.push(id);
+            }
+        }
+        self.save(&unique)?;
+        Ok(std::mem::replace(&mut *current, unique))
+    }
+
+    /// Add a station to the end of the favorites, if it isn't a favorite yet
+    pub fn add(&self, station_id: i64) -> Result<(), AppError> {
+        let mut current = self.station_ids.lock().unwrap();
+        if current.contains(&station_id) {
+            return Ok(());
+        }
+        let mut station_ids = current.clone();
+        station_ids.push(station_id);
+        self.save(&station_ids)?;
+        *current = station_ids;
+        Ok(())
+    }
+
+    pub fn remove(&self, station_id: i64) -> Result<(), AppError> {
+        let mut current = self.station_ids.lock().unwrap();
+        if !current.contains(&station_id) {
+            return Err(AppError::NotFound);
+        }
+        let station_ids: Vec<i64> = current
+            .iter()
+            .copied()
+            .filter(|id| *id != station_id)
+            .collect();
+        self.save(&station_ids)?;
+        *current = station_ids;
+        Ok(())
+    }
+
+    fn save(&self, station_ids: &[i64]) -> Result<(), AppError> {
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&self.path))
+            .and_then(|f| serde_json::to_writer_pretty(f, station_ids).map_err(|e| e.into()));
+        match result {
+            Ok(()) => {
+                info!("Saved favorites to {}", self.path.display());
+                Ok(())
+            }
+            Err(e) => {
+                error!("Unable to save favorites to {}: {}", self.path.display(), e);
+                Err(AppError::InternalError)
+            }
+        }
+    }
+}
+
+/// Position of a station when listing favorites first: the index in `favorites` for favorites, and
+/// after all favorites for other stations.
+pub fn rank(favorites: &[i64], station_id: i64) -> usize {
+    favorites
+        .iter()
+        .position(|id| *id == station_id)
+        .unwrap_or(usize::MAX)
+}
+
+/// Copy of `stations` with the favorites first, in the order of `favorites`. Other stations keep their
+/// order.
+pub fn first(stations: &[Station], favorites: &[i64]) -> Vec<Station> {
+    let mut stations = stations.to_vec();
+    stations.sort_by_key(|s| rank(favorites, s.id));
+    stations
+}
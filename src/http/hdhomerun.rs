@@ -0,0 +1,411 @@
+use crate::config::Config;
+use actix_web::rt::net::UdpSocket;
+use std::{
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+/// Port HDHomeRun devices listen on for discovery and control requests
+const DISCOVERY_PORT: u16 = 65001;
+
+const TYPE_DISCOVER_REQUEST: u16 = 0x0002;
+const TYPE_DISCOVER_REPLY: u16 = 0x0003;
+const TYPE_GETSET_REQUEST: u16 = 0x0004;
+const TYPE_GETSET_REPLY: u16 = 0x0005;
+
+const TAG_DEVICE_TYPE: u8 = 0x01;
+const TAG_DEVICE_ID: u8 = 0x02;
+const TAG_GETSET_NAME: u8 = 0x03;
+const TAG_GETSET_VALUE: u8 = 0x04;
+const TAG_ERROR_MESSAGE: u8 = 0x05;
+const TAG_TUNER_COUNT: u8 = 0x10;
+const TAG_LINEUP_URL: u8 = 0x27;
+const TAG_BASE_URL: u8 = 0x2A;
+const TAG_DEVICE_AUTH: u8 = 0x2B;
+
+/// A tag of a packet and its value
+type Tag = (u8, Vec<u8>);
+
+const DEVICE_TYPE_TUNER: u32 = 0x0000_0001;
+const WILDCARD: u32 = 0xFFFF_FFFF;
+
+/// A tuner that is announced over the HDHomeRun discovery protocol
+pub struct Device {
+    pub device_id: u32,
+    pub port: u16,
+    /// Tuner prefix (single port mode) or virtual device prefix
+    pub prefix: String,
+}
+
+/// Answer HDHomeRun discovery and getset requests on UDP port 65001, for clients that don't discover
+/// tuners over HTTP (e.g. the official HDHomeRun apps). Every device is announced with the URL of its
+/// HTTP server. Only reading the system variables is supported.
+pub async fn respond(config: Arc<Config>, devices: Vec<Device>) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(
+                "Unable to listen for HDHomeRun discovery on UDP port {}: {}",
+                DISCOVERY_PORT, e
+            );
+            return;
+        }
+    };
+    info!(
+        "Answering HDHomeRun discovery on UDP port {}",
+        DISCOVERY_PORT
+    );
+
+    let mut buffer = [0u8; 1460];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buffer).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Unable to receive HDHomeRun request: {}", e);
+                continue;
+            }
+        };
+        let (packet_type, tags) = match decode(&buffer[..len]) {
+            Some(p) => p,
+            None => {
+                debug!("Ignoring invalid HDHomeRun packet from {}", peer);
+                continue;
+            }
+        };
+        let replies = match packet_type {
+            TYPE_DISCOVER_REQUEST => discover(&config, &devices, &tags, peer).await,
+            TYPE_GETSET_REQUEST => getset(&config, &devices, &tags),
+            _ => continue,
+        };
+        for reply in replies {
+            if let Err(e) = socket.send_to(&reply, peer).await {
+                warn!("Unable to send HDHomeRun reply to {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+/// Device id as reported in `/discover.json`: the first 8 hex digits of the UUID plus their
+/// HDHomeRun checksum
+pub fn device_id(uuid: &str) -> u32 {
+    let id = usize::from_str_radix(&uuid[..8], 16).unwrap();
+    crate::utils::hdhr_checksum(id).wrapping_add(id) as u32
+}
+
+/// Discover replies of the devices that match the requested device type and id
+async fn discover(
+    config: &Config,
+    devices: &[Device],
+    tags: &[Tag],
+    peer: SocketAddr,
+) -> Vec<Vec<u8>> {
+    let device_type = tag_u32(tags, TAG_DEVICE_TYPE).unwrap_or(WILDCARD);
+    if device_type != WILDCARD && device_type != DEVICE_TYPE_TUNER {
+        return Vec::new();
+    }
+    let requested_id = tag_u32(tags, TAG_DEVICE_ID).unwrap_or(WILDCARD);
+    let ip = local_ip(peer).await;
+    debug!("HDHomeRun discovery from {}", peer);
+
+    devices
+        .iter()
+        .filter(|d| requested_id == WILDCARD || requested_id == d.device_id)
+        .map(|d| {
+            let base_url = match &config.external_url {
+                Some(url) => format!("{}{}", url.trim_end_matches('/'), d.prefix),
+                None => format!("{}://{}:{}{}", config.scheme(), ip, d.port, d.prefix),
+            };
+            let mut payload = Vec::new();
+            put_tag(
+                &mut payload,
+                TAG_DEVICE_TYPE,
+                &DEVICE_TYPE_TUNER.to_be_bytes(),
+            );
+            put_tag(&mut payload, TAG_DEVICE_ID, &d.device_id.to_be_bytes());
+            put_tag(&mut payload, TAG_TUNER_COUNT, &[config.tuner_count]);
+            put_tag(&mut payload, TAG_DEVICE_AUTH, b"locast2dvr");
+            put_tag(
+                &mut payload,
+                TAG_LINEUP_URL,
+                format!("{}/lineup.json", base_url).as_bytes(),
+            );
+            put_tag(&mut payload, TAG_BASE_URL, base_url.as_bytes());
+            encode(TYPE_DISCOVER_REPLY, &payload)
+        })
+        .collect()
+}
+
+/// Reply to a getset request. System variables can be read, everything else is refused.
+fn getset(config: &Config, devices: &[Device], tags: &[Tag]) -> Vec<Vec<u8>> {
+    let name = match tag_string(tags, TAG_GETSET_NAME) {
+        Some(n) => n,
+        None => return Vec::new(),
+    };
+    // Requests for another device aren't answered, so they can time out
+    if let Some(id) = tag_u32(tags, TAG_DEVICE_ID) {
+        if id != WILDCARD && !devices.iter().any(|d| d.device_id == id) {
+            return Vec::new();
+        }
+    }
+
+    let mut payload = Vec::new();
+    put_tag(&mut payload, TAG_GETSET_NAME, &nul_terminated(&name));
+    let value = match name.as_str() {
+        "/sys/model" => Some(config.device_firmware.clone()),
+        "/sys/hwmodel" => Some(config.device_model.clone()),
+        "/sys/version" => Some(config.device_version.clone()),
+        "/sys/features" => Some("channelmap: us-bcast\n".to_owned()),
+        "/sys/copyright" => Some("locast2tuner".to_owned()),
+        _ => None,
+    };
+    match value {
+        Some(_) if tag_string(tags, TAG_GETSET_VALUE).is_some() => put_tag(
+            &mut payload,
+            TAG_ERROR_MESSAGE,
+            &nul_terminated("ERROR: read only variable"),
+        ),
+        Some(value) => put_tag(&mut payload, TAG_GETSET_VALUE, &nul_terminated(&value)),
+        None => put_tag(
+            &mut payload,
+            TAG_ERROR_MESSAGE,
+            &nul_terminated("ERROR: unknown getset variable"),
+        ),
+    }
+    vec![encode(TYPE_GETSET_REPLY, &payload)]
+}
+
+/// Address of the interface `peer` is reached through, so the announced URLs work for it
+async fn local_ip(peer: SocketAddr) -> IpAddr {
+    let ip = async {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(peer).await?;
+        Ok::<_, io::Error>(socket.local_addr()?.ip())
+    };
+    ip.await.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Build a packet: type, payload length, payload and the CRC32 of all of it (little endian)
+fn encode(packet_type: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + 8);
+    packet.extend_from_slice(&packet_type.to_be_bytes());
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(payload);
+    let crc = crc32(&packet);
+    packet.extend_from_slice(&crc.to_le_bytes());
+    packet
+}
+
+/// Parse a packet into its type and tags. Returns None if it's truncated or its CRC doesn't match.
+fn decode(packet: &[u8]) -> Option<(u16, Vec<Tag>)> {
+    if packet.len() < 8 {
+        return None;
+    }
+    let (data, crc) = packet.split_at(packet.len() - 4);
+    if crc32(data).to_le_bytes() != crc {
+        return None;
+    }
+    let packet_type = u16::from_be_bytes([data[0], data[1]]);
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let mut payload = data.get(4..4 + length)?;
+
+    let mut tags = Vec::new();
+    while !payload.is_empty() {
+        let tag = payload[0];
+        let (length, header) = match *payload.get(1)? {
+            l if l & 0x80 == 0 => (l as usize, 2),
+            l => ((l & 0x7F) as usize | (*payload.get(2)? as usize) << 7, 3),
+        };
+        let value = payload.get(header..header + length)?;
+        tags.push((tag, value.to_vec()));
+        payload = &payload[header + length..];
+    }
+    Some((packet_type, tags))
+}
+
+/// Append a tag. Lengths over 127 bytes take two bytes.
+fn put_tag(payload: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    payload.push(tag);
+    if value.len() <= 127 {
+        payload.push(value.len() as u8);
+    } else {
+        payload.push((value.len() & 0x7F) as u8 | 0x80);
+        payload.push((value.len() >> 7) as u8);
+    }
+    payload.extend_from_slice(value);
+}
+
+fn tag_u32(tags: &[Tag], tag: u8) -> Option<u32> {
+    tags.iter()
+        .find(|(t, _)| *t == tag)
+        .and_then(|(_, v)| Some(u32::from_be_bytes(v.as_slice().try_into().ok()?)))
+}
+
+fn tag_string(tags: &[Tag], tag: u8) -> Option<String> {
+    tags.iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, v)| String::from_utf8_lossy(v).trim_end_matches('\0').to_owned())
+}
+
+fn nul_terminated(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// CRC32 (IEEE 802.3), as used by the HDHomeRun protocol
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Discover request for any tuner, as sent by libhdhomerun
+    const DISCOVER_REQUEST: [u8; 20] = [
+        0x00, 0x02, 0x00, 0x0c, 0x01, 0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x04, 0xff, 0xff, 0xff,
+        0xff, 0x4e, 0x50, 0x7f, 0x35,
+    ];
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn decode_discover_request() {
+        let (packet_type, tags) = decode(&DISCOVER_REQUEST).unwrap();
+        assert_eq!(packet_type, TYPE_DISCOVER_REQUEST);
+        assert_eq!(
+            tags,
+            vec![
+                (TAG_DEVICE_TYPE, vec![0x00, 0x00, 0x00, 0x01]),
+                (TAG_DEVICE_ID, vec![0xff, 0xff, 0xff, 0xff]),
+            ]
+        );
+        assert_eq!(tag_u32(&tags, TAG_DEVICE_TYPE), Some(DEVICE_TYPE_TUNER));
+        assert_eq!(tag_u32(&tags, TAG_DEVICE_ID), Some(WILDCARD));
+        assert_eq!(tag_u32(&tags, TAG_TUNER_COUNT), None);
+    }
+
+    #[test]
+    fn encode_discover_request() {
+        let mut payload = Vec::new();
+        put_tag(
+            &mut payload,
+            TAG_DEVICE_TYPE,
+            &DEVICE_TYPE_TUNER.to_be_bytes(),
+        );
+        put_tag(&mut payload, TAG_DEVICE_ID, &WILDCARD.to_be_bytes());
+        assert_eq!(encode(TYPE_DISCOVER_REQUEST, &payload), DISCOVER_REQUEST);
+    }
+
+    #[test]
+    fn decode_rejects_bad_crc() {
+        let mut packet = DISCOVER_REQUEST;
+        packet[9] ^= 0x01;
+        assert!(decode(&packet).is_none());
+        let mut packet = DISCOVER_REQUEST;
+        packet[19] ^= 0x01;
+        assert!(decode(&packet).is_none());
+    }
+
+    #[test]
+    fn decode_rejects_truncated_packets() {
+        assert!(decode(&[]).is_none());
+        assert!(decode(&DISCOVER_REQUEST[..7]).is_none());
+        assert!(decode(&DISCOVER_REQUEST[..19]).is_none());
+
+        // Valid CRCs, but the payload is shorter than its length
+        let mut packet = encode(TYPE_DISCOVER_REQUEST, &DISCOVER_REQUEST[4..16]);
+        packet[3] = 0x0d;
+        let data_len = packet.len() - 4;
+        let crc = crc32(&packet[..data_len]).to_le_bytes();
+        packet[data_len..].copy_from_slice(&crc);
+        assert!(decode(&packet).is_none());
+
+        // A tag value that's cut off, and a two byte length without its second byte
+        assert!(decode(&encode(
+            TYPE_DISCOVER_REQUEST,
+            &[TAG_DEVICE_ID, 0x04, 0xff, 0xff]
+        ))
+        .is_none());
+        assert!(decode(&encode(TYPE_DISCOVER_REQUEST, &[TAG_DEVICE_ID])).is_none());
+        assert!(decode(&encode(TYPE_DISCOVER_REQUEST, &[TAG_BASE_URL, 0x80])).is_none());
+    }
+
+    #[test]
+    fn two_byte_tag_lengths() {
+        let mut payload = Vec::new();
+        put_tag(&mut payload, TAG_BASE_URL, &[b'a'; 127]);
+        assert_eq!(payload[..2], [TAG_BASE_URL, 0x7f]);
+
+        for len in &[128, 200, 1000] {
+            let value = vec![b'a'; *len];
+            let mut payload = Vec::new();
+            put_tag(&mut payload, TAG_LINEUP_URL, &value);
+            assert_eq!(
+                payload[..3],
+                [TAG_LINEUP_URL, (len & 0x7f) as u8 | 0x80, (len >> 7) as u8]
+            );
+            put_tag(&mut payload, TAG_TUNER_COUNT, &[2]);
+
+            let (_, tags) = decode(&encode(TYPE_DISCOVER_REPLY, &payload)).unwrap();
+            assert_eq!(
+                tags,
+                vec![(TAG_LINEUP_URL, value), (TAG_TUNER_COUNT, vec![2])]
+            );
+        }
+    }
+
+    #[test]
+    fn getset_reads_system_variables() {
+        let config = Config {
+            device_firmware: "hdhomerun3_atsc".to_owned(),
+            ..Config::default()
+        };
+        let devices = vec![Device {
+            device_id: 0x1234_5678,
+            port: 6077,
+            prefix: String::new(),
+        }];
+        let request = |tags: &[(u8, &[u8])]| {
+            let tags: Vec<Tag> = tags.iter().map(|(t, v)| (*t, v.to_vec())).collect();
+            getset(&config, &devices, &tags)
+                .first()
+                .map(|reply| decode(reply).unwrap().1)
+        };
+
+        let reply = request(&[(TAG_GETSET_NAME, b"/sys/model\0")]).unwrap();
+        assert_eq!(
+            tag_string(&reply, TAG_GETSET_VALUE).as_deref(),
+            Some("hdhomerun3_atsc")
+        );
+        let reply = request(&[
+            (TAG_GETSET_NAME, b"/sys/model\0"),
+            (TAG_GETSET_VALUE, b"x\0"),
+        ]);
+        assert!(tag_string(&reply.unwrap(), TAG_ERROR_MESSAGE).is_some());
+        let reply = request(&[(TAG_GETSET_NAME, b"/tuner0/channel\0")]);
+        assert!(tag_string(&reply.unwrap(), TAG_ERROR_MESSAGE).is_some());
+
+        // Requests for other devices aren't answered
+        let other = 0x1111_1111u32.to_be_bytes();
+        assert!(request(&[(TAG_GETSET_NAME, b"/sys/model\0"), (TAG_DEVICE_ID, &other)]).is_none());
+        assert!(request(&[]).is_none());
+    }
+}
@@ -0,0 +1,332 @@
+use super::{clients::ClientStream, limits::ClientSlot, sessions::StreamSession};
+use crate::{credentials::AccountLease, errors::AppError};
+use reqwest::Url;
+use ring::hmac;
+use simple_error::SimpleError;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a resolved media playlist URL of a station is reused before it's resolved again. Locast
+/// stream URLs expire after a few hours.
+const URL_LIFETIME: Duration = Duration::from_secs(7200);
+/// A session ends when its client hasn't fetched the playlist or a segment for this long
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often sessions are checked for their timeout
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A client and the station it's playing
+type SessionKey = (Option<IpAddr>, String);
+
+/// Serves media playlists of locast with segment URIs that point to the `/segment/{token}` proxy
+/// route. A token is the station id and segment URL, signed with a key that's generated at startup,
+/// so the proxy can't be used to fetch anything but segments of playlists it served.
+pub struct HlsProxy {
+    key: hmac::Key,
+    /// Media playlist URL per station, with the time it was resolved
+    urls: Mutex<HashMap<String, (String, Instant)>>,
+    sessions: Mutex<HashMap<SessionKey, HlsSession>>,
+}
+
+/// A client playing a station through the proxy. Clients only poll the playlist and fetch segments, so
+/// the session holds a tuner, an account and a stream of the client until they stop polling.
+pub struct HlsSession {
+    lease: Arc<AccountLease>,
+    client_slot: ClientSlot,
+    _session: StreamSession,
+    _client: ClientStream,
+    polled: Instant,
+}
+
+impl HlsSession {
+    pub fn new(
+        session: StreamSession,
+        lease: AccountLease,
+        client_slot: ClientSlot,
+        client: ClientStream,
+    ) -> HlsSession {
+        HlsSession {
+            lease: Arc::new(lease),
+            client_slot,
+            _session: session,
+            _client: client,
+            polled: Instant::now(),
+        }
+    }
+}
+
+impl HlsProxy {
+    pub fn new() -> HlsProxy {
+        let key = rand::random::<[u8; 32]>();
+        HlsProxy {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            urls: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register a poll of `client` playing `station_id`. Returns the account lease of the session, or
+    /// `None` if the client doesn't have a session for the station.
+    pub fn poll(&self, client: Option<IpAddr>, station_id: &str) -> Option<Arc<AccountLease>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&(client, station_id.to_owned()))?;
+        session.polled = Instant::now();
+        Some(session.lease.clone())
+    }
+
+    /// Start a session of `client` playing `station_id`. If another request started one in the
+    /// meantime, that one is kept. Returns the account lease of the session.
+    pub fn start(
+        &self,
+        client: Option<IpAddr>,
+        station_id: &str,
+        session: HlsSession,
+    ) -> Arc<AccountLease> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .entry((client, station_id.to_owned()))
+            .or_insert(session);
+        session.lease.clone()
+    }
+
+    /// End the session of `client` playing `station_id`
+    pub fn stop(&self, client: Option<IpAddr>, station_id: &str) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&(client, station_id.to_owned()));
+    }
+
+    /// Take `bytes` from the bandwidth of `client` playing `station_id`. Returns how long to wait
+    /// before sending them, or `None` if the client doesn't have a session for the station.
+    pub fn throttle(
+        &self,
+        client: Option<IpAddr>,
+        station_id: &str,
+        bytes: usize,
+    ) -> Option<Duration> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&(client, station_id.to_owned()))?;
+        session.polled = Instant::now();
+        Some(session.client_slot.throttle(bytes))
+    }
+
+    /// End sessions of clients that stopped polling. Runs forever.
+    pub async fn expire_sessions(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(SESSION_CHECK_INTERVAL).await;
+            self.sessions.lock().unwrap().retain(|(_, id), s| {
+                let active = s.polled.elapsed() < SESSION_TIMEOUT;
+                if !active {
+                    debug!("HLS session of {} timed out", id);
+                }
+                active
+            });
+        }
+    }
+
+    /// The media playlist URL of `station_id`, if it was resolved recently
+    pub fn url(&self, station_id: &str) -> Option<String> {
+        self.urls
+            .lock()
+            .unwrap()
+            .get(station_id)
+            .filter(|(_, resolved)| resolved.elapsed() < URL_LIFETIME)
+            .map(|(url, _)| url.clone())
+    }
+
+    /// Remember the media playlist URL of `station_id`
+    pub fn set_url(&self, station_id: &str, url: &str) {
+        let mut urls = self.urls.lock().unwrap();
+        urls.retain(|_, (_, resolved)| resolved.elapsed() < URL_LIFETIME);
+        urls.insert(station_id.to_owned(), (url.to_owned(), Instant::now()));
+    }
+
+    /// Forget the media playlist URL of `station_id`, e.g. because it stopped working
+    pub fn forget_url(&self, station_id: &str) {
+        self.urls.lock().unwrap().remove(station_id);
+    }
+
+    /// Token for the segment at `url` of `station_id`
+    fn token(&self, station_id: &str, url: &str) -> String {
+        let payload = format!("{} {}", station_id, url);
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        format!(
+            "{}.{}",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Verify a token. Returns the station id and the URL of the segment.
+    pub fn verify(&self, token: &str) -> Result<(String, String), AppError> {
+        let invalid = || AppError::Forbidden("Invalid segment token".to_owned());
+        let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let payload =
+            base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let signature =
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        hmac::verify(&self.key, &payload, &signature).map_err(|_| invalid())?;
+        let payload = String::from_utf8(payload).map_err(|_| invalid())?;
+        let (station_id, url) = payload.split_once(' ').ok_or_else(invalid)?;
+        Ok((station_id.to_owned(), url.to_owned()))
+    }
+
+    /// Rewrite the media playlist `m3u_data` of `station_id`, fetched from `playlist_url`, so its
+    /// segments (and keys or initialization sections) are fetched through `{segment_base}/{token}`.
+    /// All other lines are kept as is.
+    pub fn rewrite(
+        &self,
+        m3u_data: &str,
+        station_id: &str,
+        playlist_url: &str,
+        segment_base: &str,
+    ) -> Result<String, SimpleError> {
+        let base_url = Url::parse(playlist_url)
+            .map_err(|e| SimpleError::new(format!("Unable to parse url! {}", e)))?;
+        let proxied = |uri: &str| -> Result<String, SimpleError> {
+            let absolute = base_url
+                .join(uri)
+                .map_err(|e| SimpleError::new(format!("Unable to parse url! {}", e)))?;
+            Ok(format!(
+                "{}/{}",
+                segment_base,
+                self.token(station_id, absolute.as_str())
+            ))
+        };
+
+        let mut playlist = String::with_capacity(m3u_data.len() * 2);
+        for line in m3u_data.lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with('#') {
+                playlist.push_str(&proxied(line)?);
+            } else if line.starts_with("#EXT-X-KEY:") || line.starts_with("#EXT-X-MAP:") {
+                playlist.push_str(&rewrite_uri_attribute(line, &proxied)?);
+            } else {
+                playlist.push_str(line);
+            }
+            playlist.push('\n');
+        }
+        Ok(playlist)
+    }
+}
+
+/// Replace the value of the `URI` attribute of a tag
+fn rewrite_uri_attribute<F>(line: &str, proxied: &F) -> Result<String, SimpleError>
+where
+    F: Fn(&str) -> Result<String, SimpleError>,
+{
+    let start = match line.find("URI=\"") {
+        Some(i) => i + 5,
+        None => return Ok(line.to_owned()),
+    };
+    let end = match line[start..].find('"') {
+        Some(i) => start + i,
+        None => return Ok(line.to_owned()),
+    };
+    Ok(format!(
+        "{}{}{}",
+        &line[..start],
+        proxied(&line[start..end])?,
+        &line[end..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PLAYLIST_URL: &str = "https://cdn.example.com/live/1234/playlist.m3u8?token=abc";
+    static SEGMENT_BASE: &str = "http://127.0.0.1:6077/segment";
+
+    /// The tokens of all proxied URIs in a rewritten playlist
+    fn tokens(playlist: &str) -> Vec<&str> {
+        playlist
+            .split(['\n', '"'])
+            .filter_map(|l| l.strip_prefix(SEGMENT_BASE))
+            .map(|t| t.trim_start_matches('/'))
+            .collect()
+    }
+
+    #[test]
+    fn rewrite_proxies_segments_keys_and_maps() {
+        let proxy = HlsProxy::new();
+        let m3u = "#EXTM3U\n\
+                   #EXT-X-TARGETDURATION:6\n\
+                   #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x1\n\
+                   #EXT-X-MAP:URI=\"/init.mp4\"\n\
+                   \n\
+                   #EXTINF:6.0,\n\
+                   segment1.ts\r\n\
+                   #EXTINF:6.0,\n\
+                   https://other.example.com/segment2.ts\n";
+        let playlist = proxy
+            .rewrite(m3u, "1234", PLAYLIST_URL, SEGMENT_BASE)
+            .unwrap();
+
+        let lines: Vec<&str> = playlist.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "#EXTM3U");
+        assert_eq!(lines[1], "#EXT-X-TARGETDURATION:6");
+        assert!(
+            lines[2].starts_with("#EXT-X-KEY:METHOD=AES-128,URI=\"http://127.0.0.1:6077/segment/")
+        );
+        assert!(lines[2].ends_with("\",IV=0x1"));
+        assert!(lines[3].starts_with("#EXT-X-MAP:URI=\""));
+
+        let urls: Vec<(String, String)> = tokens(&playlist)
+            .into_iter()
+            .map(|t| proxy.verify(t).unwrap())
+            .collect();
+        let expected = [
+            "https://cdn.example.com/live/1234/key.bin",
+            "https://cdn.example.com/init.mp4",
+            "https://cdn.example.com/live/1234/segment1.ts",
+            "https://other.example.com/segment2.ts",
+        ];
+        assert_eq!(urls.len(), expected.len());
+        for ((station_id, url), expected) in urls.iter().zip(&expected) {
+            assert_eq!(station_id, "1234");
+            assert_eq!(url, expected);
+        }
+    }
+
+    #[test]
+    fn rewrite_rejects_invalid_playlist_url() {
+        let proxy = HlsProxy::new();
+        assert!(proxy
+            .rewrite("#EXTM3U\nsegment.ts\n", "1234", "not a url", SEGMENT_BASE)
+            .is_err());
+    }
+
+    #[test]
+    fn verify_rejects_tampered_tokens() {
+        let proxy = HlsProxy::new();
+        let token = proxy.token("1234", "https://cdn.example.com/segment.ts");
+        assert_eq!(
+            proxy.verify(&token).unwrap(),
+            (
+                "1234".to_owned(),
+                "https://cdn.example.com/segment.ts".to_owned()
+            )
+        );
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let other =
+            base64::encode_config("1234 https://evil.example.com/", base64::URL_SAFE_NO_PAD);
+        assert!(proxy.verify(&format!("{}.{}", other, signature)).is_err());
+        assert!(proxy.verify(&format!("{}.{}", payload, "AAAA")).is_err());
+        assert!(proxy.verify(payload).is_err());
+        assert!(proxy.verify("").is_err());
+        assert!(proxy.verify("!!!.???").is_err());
+
+        // Tokens are signed with a key that's generated at startup
+        assert!(HlsProxy::new().verify(&token).is_err());
+    }
+}
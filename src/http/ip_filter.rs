@@ -0,0 +1,228 @@
+use crate::config::Config;
+use actix_web::dev::ServiceRequest;
+use serde::{Serialize, Serializer};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// An IP network in CIDR notation (e.g. 192.168.1.0/24 or 2001:db8::/32). A plain address is a network
+/// of a single address.
+#[derive(Debug, Clone, Copy)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Returns true if `ip` is part of this network
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || "expected an IP address or a network in CIDR notation".to_owned();
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+/// Restricts all endpoints to clients from `ip_allowlist` (if set) that aren't in `ip_denylist`. Only
+/// the health probes are open to every address, since they're used by e.g. container orchestrators from
+/// addresses that don't use the tuner. Everything else is filtered, so new endpoints are never exposed
+/// by accident.
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Create the filter from the networks in the config, which are validated when it's loaded
+    pub fn new(config: &Config) -> IpFilter {
+        IpFilter {
+            allow: config.ip_allowlist.clone(),
+            deny: config.ip_denylist.clone(),
+        }
+    }
+
+    /// Check if a request is allowed. Returns the address of the client if it isn't.
+    pub fn check(&self, req: &ServiceRequest) -> Result<(), IpAddr> {
+        if (self.allow.is_empty() && self.deny.is_empty()) || super::probe_path(req.path()) {
+            return Ok(());
+        }
+        let ip = match req.peer_addr().map(|a| a.ip()) {
+            // IPv4 clients connected to a dual stack socket show up as IPv4 mapped IPv6 addresses
+            Some(IpAddr::V6(ip)) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|n| n.contains(&ip));
+        if allowed && !self.deny.iter().any(|n| n.contains(&ip)) {
+            Ok(())
+        } else {
+            Err(ip)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::net::SocketAddr;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_networks() {
+        assert!("192.168.1.0/24".parse::<IpNet>().is_ok());
+        assert!(" 2001:db8::/32 ".parse::<IpNet>().is_ok());
+        assert!("0.0.0.0/0".parse::<IpNet>().is_ok());
+        assert!("::/0".parse::<IpNet>().is_ok());
+        assert!("10.0.0.1/33".parse::<IpNet>().is_err());
+        assert!("::1/129".parse::<IpNet>().is_err());
+        assert!("10.0.0.1/-1".parse::<IpNet>().is_err());
+        assert!("10.0.0.1/".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        let lan = net("192.168.1.0/24");
+        assert!(lan.contains(&ip("192.168.1.0")));
+        assert!(lan.contains(&ip("192.168.1.255")));
+        assert!(!lan.contains(&ip("192.168.2.1")));
+
+        let all = net("0.0.0.0/0");
+        assert!(all.contains(&ip("0.0.0.0")));
+        assert!(all.contains(&ip("255.255.255.255")));
+        assert!(!all.contains(&ip("::1")));
+
+        // A plain address is a /32
+        let host = net("203.0.113.7");
+        assert!(host.contains(&ip("203.0.113.7")));
+        assert!(!host.contains(&ip("203.0.113.8")));
+        assert!(net("203.0.113.7/32").contains(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        let doc = net("2001:db8::/32");
+        assert!(doc.contains(&ip("2001:db8::1")));
+        assert!(doc.contains(&ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!doc.contains(&ip("2001:db9::1")));
+
+        let all = net("::/0");
+        assert!(all.contains(&ip("::")));
+        assert!(all.contains(&ip("ffff::1")));
+        assert!(!all.contains(&ip("127.0.0.1")));
+
+        let host = net("::1");
+        assert!(host.contains(&ip("::1")));
+        assert!(!host.contains(&ip("::2")));
+        assert!(net("::1/128").contains(&ip("::1")));
+
+        // IPv4 mapped addresses are IPv6 addresses, check() maps them back to IPv4
+        assert!(!net("192.168.1.0/24").contains(&ip("::ffff:192.168.1.5")));
+    }
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let list = |l: &[&str]| l.iter().map(|n| net(n)).collect();
+        IpFilter::new(&Config {
+            ip_allowlist: list(allow),
+            ip_denylist: list(deny),
+            ..Config::default()
+        })
+    }
+
+    fn check(filter: &IpFilter, peer: &str, path: &str) -> Result<(), IpAddr> {
+        let req = TestRequest::with_uri(path)
+            .peer_addr(SocketAddr::new(ip(peer), 50000))
+            .to_srv_request();
+        filter.check(&req)
+    }
+
+    #[test]
+    fn check_allow_and_deny() {
+        let filter = filter(&["192.168.1.0/24"], &["192.168.1.66"]);
+        assert!(check(&filter, "192.168.1.5", "/lineup.json").is_ok());
+        assert!(check(&filter, "192.168.1.66", "/lineup.json").is_err());
+        assert_eq!(check(&filter, "10.0.0.1", "/config"), Err(ip("10.0.0.1")));
+        // IPv4 clients on a dual stack socket
+        assert!(check(&filter, "::ffff:192.168.1.5", "/watch/1234").is_ok());
+        assert_eq!(
+            check(&filter, "::ffff:10.0.0.1", "/watch/1234"),
+            Err(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn check_filters_all_paths_except_probes() {
+        let filter = filter(&["192.168.1.0/24"], &[]);
+        for path in &[
+            "/",
+            "/preview/1234",
+            "/share/abcdef",
+            "/share/abcdef.m3u",
+            "/city/803/tuner.m3u",
+            "/city/803/epg.xml",
+            "/record/1234",
+            "/recordings/1.ts",
+            "/tuner/denver/lineup.json",
+        ] {
+            assert!(check(&filter, "10.0.0.1", path).is_err(), "{}", path);
+        }
+        assert!(check(&filter, "10.0.0.1", "/healthz").is_ok());
+        assert!(check(&filter, "10.0.0.1", "/tuner/denver/readyz").is_ok());
+    }
+
+    #[test]
+    fn no_lists_allow_everything() {
+        assert!(check(&filter(&[], &[]), "10.0.0.1", "/config").is_ok());
+        // Only a denylist
+        let filter = filter(&[], &["10.0.0.0/8"]);
+        assert!(check(&filter, "10.0.0.1", "/").is_err());
+        assert!(check(&filter, "192.168.1.5", "/").is_ok());
+    }
+}
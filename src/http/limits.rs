@@ -0,0 +1,175 @@
+use crate::config::Config;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Usage of every client that is streaming
+type Clients = Arc<Mutex<HashMap<IpAddr, ClientUsage>>>;
+
+/// Limits the concurrent streams and the bandwidth of every client IP address, across all tuners.
+pub struct ClientLimits {
+    clients: Clients,
+    /// Maximum amount of concurrent streams per client, 0 for no limit
+    max_streams: usize,
+    /// Maximum bandwidth per client in bytes per second, 0 for no limit
+    bandwidth: f64,
+}
+
+/// Streams and bandwidth used by a single client
+struct ClientUsage {
+    streams: usize,
+    bucket: TokenBucket,
+}
+
+impl ClientLimits {
+    pub fn new(config: &Config) -> ClientLimits {
+        ClientLimits {
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            max_streams: config.client_stream_limit,
+            bandwidth: config.client_bandwidth_limit as f64 * 1000.0 / 8.0,
+        }
+    }
+
+    /// Start a stream for the client at `ip`. Returns `None` if the client already has
+    /// `client_stream_limit` streams. Clients without an address (e.g. Unix sockets) aren't limited.
+    pub fn start(&self, ip: Option<IpAddr>) -> Option<ClientSlot> {
+        let ip = match ip {
+            Some(ip) if self.max_streams > 0 || self.bandwidth > 0.0 => ip,
+            _ => return Some(ClientSlot::unlimited()),
+        };
+        let mut clients = self.clients.lock().unwrap();
+        let usage = clients.entry(ip).or_insert_with(|| ClientUsage {
+            streams: 0,
+            bucket: TokenBucket::new(self.bandwidth),
+        });
+        if self.max_streams > 0 && usage.streams >= self.max_streams {
+            return None;
+        }
+        usage.streams += 1;
+        Some(ClientSlot {
+            client: Some((ip, self.clients.clone())),
+        })
+    }
+}
+
+/// A stream of a client, which counts towards the limits of the client until it's dropped
+pub struct ClientSlot {
+    client: Option<(IpAddr, Clients)>,
+}
+
+impl ClientSlot {
+    fn unlimited() -> ClientSlot {
+        ClientSlot { client: None }
+    }
+
+    /// Take `bytes` from the bandwidth of the client. Returns how long to wait before sending them, so
+    /// the client stays within its bandwidth. All streams of a client share its bandwidth.
+    pub fn throttle(&self, bytes: usize) -> Duration {
+        match &self.client {
+            Some((ip, clients)) => clients
+                .lock()
+                .unwrap()
+                .get_mut(ip)
+                .map_or(Duration::ZERO, |u| u.bucket.take(bytes as f64)),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        if let Some((ip, clients)) = &self.client {
+            let mut clients = clients.lock().unwrap();
+            if let Some(usage) = clients.get_mut(ip) {
+                usage.streams -= 1;
+                if usage.streams == 0 {
+                    clients.remove(ip);
+                }
+            }
+        }
+    }
+}
+
+/// Token bucket that refills at `rate` bytes per second and holds up to a second worth of bytes. A
+/// rate of 0 doesn't limit anything.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take `amount` tokens. The bucket may go into debt, which is paid off by waiting for the
+    /// returned duration.
+    fn take(&mut self, amount: f64) -> Duration {
+        if self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - amount;
+        self.updated = now;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(d: Duration, secs: f64) -> bool {
+        (d.as_secs_f64() - secs).abs() < 0.01
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = TokenBucket::new(0.0);
+        assert_eq!(bucket.take(1e9), Duration::ZERO);
+        assert_eq!(bucket.take(1e9), Duration::ZERO);
+    }
+
+    #[test]
+    fn burst_of_one_second() {
+        let mut bucket = TokenBucket::new(1000.0);
+        assert_eq!(bucket.take(600.0), Duration::ZERO);
+        assert_eq!(bucket.take(400.0), Duration::ZERO);
+        // The bucket is empty, so the next bytes have to wait for it to refill
+        assert!(approx(bucket.take(500.0), 0.5));
+    }
+
+    #[test]
+    fn debt_adds_up() {
+        let mut bucket = TokenBucket::new(1000.0);
+        assert!(approx(bucket.take(3000.0), 2.0));
+        assert!(approx(bucket.take(1000.0), 3.0));
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_rate() {
+        let mut bucket = TokenBucket::new(1000.0);
+        bucket.take(1000.0);
+        bucket.updated -= Duration::from_millis(500);
+        assert_eq!(bucket.take(400.0), Duration::ZERO);
+        assert!(approx(bucket.take(200.0), 0.1));
+
+        // Idle time doesn't build up more than a second worth of tokens
+        bucket.updated -= Duration::from_secs(10);
+        assert_eq!(bucket.take(1000.0), Duration::ZERO);
+        assert!(approx(bucket.take(1000.0), 1.0));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{errors::AppError, service::stationprovider::StationProvider, utils::get};
+use actix_web::web;
+use chrono::Utc;
+use futures::{lock::Mutex, stream, StreamExt};
+use image::{imageops::FilterType, ImageFormat};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Logos are resized to fit within LOGO_SIZE x LOGO_SIZE pixels
+static LOGO_SIZE: u32 = 360;
+/// Cached logos are downloaded again after a week
+static LOGO_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+/// Amount of logos that are downloaded at the same time when pre-warming
+static PREWARM_CONCURRENCY: usize = 4;
+/// How often (in seconds) we check if stations have been refreshed, to pre-warm their logos
+static PREWARM_POLL_INTERVAL: u64 = 60;
+
+/// Progress of the last logo pre-warm, as reported at `/diagnostics/logos.json`
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct PrewarmStatus {
+    pub running: bool,
+    /// Unix timestamps of the start and end of the last pre-warm
+    pub started: Option<i64>,
+    pub finished: Option<i64>,
+    /// Logos of all stations
+    pub total: usize,
+    /// Logos that were already cached
+    pub cached: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+}
+
+/// On-disk cache of station logos. Logos are downloaded from locast, resized and stored as PNG
+/// in `<cache_dir>/logos/<station_id>.png`.
+pub struct LogoCache {
+    directory: PathBuf,
+    // Make sure a logo is only downloaded once when multiple requests come in at the same time
+    download_locks: Mutex<HashMap<i64, Arc<Mutex<()>>>>,
+    prewarm: Mutex<PrewarmStatus>,
+}
+
+impl LogoCache {
+    pub fn new(cache_directory: &Path) -> LogoCache {
+        LogoCache {
+            directory: cache_directory.join("logos"),
+            download_locks: Mutex::new(HashMap::new()),
+            prewarm: Mutex::new(PrewarmStatus::default()),
+        }
+    }
+
+    /// Get the logo for `station_id` as PNG, downloading it from `url` if it isn't cached yet
+    pub async fn get(&self, station_id: i64, url: &str) -> Result<Vec<u8>, AppError> {
+        let path = self.directory.join(format!("{}.png", station_id));
+        if let Some(logo) = read_cached(&path) {
+            return Ok(logo);
+        }
+
+        let lock = self
+            .download_locks
+            .lock()
+            .await
+            .entry(station_id)
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+        // Another request might have downloaded the logo while we were waiting for the lock
+        if let Some(logo) = read_cached(&path) {
+            return Ok(logo);
+        }
+
+        debug!("Downloading logo for station {} from {}", station_id, url);
+        let data = match get(url, None, 3).await {
+            Ok(r) => r.bytes().await.map_err(|_| AppError::UpstreamError)?,
+            Err(_) => return Err(AppError::UpstreamError),
+        };
+
+        // Decoding and resizing is CPU bound, so keep it off the async workers
+        let logo = match web::block(move || resize(&data)).await {
+            Ok(Ok(logo)) => logo,
+            Ok(Err(e)) => {
+                warn!("Unable to process logo for station {}: {}", station_id, e);
+                return Err(AppError::UpstreamError);
+            }
+            Err(_) => return Err(AppError::InternalError),
+        };
+
+        if let Err(e) = fs::create_dir_all(&self.directory).and_then(|_| fs::write(&path, &logo)) {
+            warn!("Unable to cache logo in {}: {}", path.display(), e);
+        }
+        Ok(logo)
+    }
+
+    /// Download the logos of `stations` (station id and logo URL) that aren't cached yet,
+    /// `PREWARM_CONCURRENCY` at a time
+    pub async fn prewarm(&self, stations: Vec<(i64, String)>) {
+        *self.prewarm.lock().await = PrewarmStatus {
+            running: true,
+            started: Some(Utc::now().timestamp()),
+            total: stations.len(),
+            ..PrewarmStatus::default()
+        };
+
+        stream::iter(stations)
+            .for_each_concurrent(PREWARM_CONCURRENCY, |(station_id, url)| async move {
+                let path = self.directory.join(format!("{}.png", station_id));
+                let result = if fresh(&path) {
+                    None
+                } else {
+                    Some(self.get(station_id, &url).await)
+                };
+                let mut status = self.prewarm.lock().await;
+                match result {
+                    None => status.cached += 1,
+                    Some(Ok(_)) => status.downloaded += 1,
+                    Some(Err(_)) => status.failed += 1,
+                }
+            })
+            .await;
+
+        let mut status = self.prewarm.lock().await;
+        status.running = false;
+        status.finished = Some(Utc::now().timestamp());
+        info!(
+            "Pre-warmed logos: {} cached, {} downloaded, {} failed",
+            status.cached, status.downloaded, status.failed
+        );
+    }
+
+    pub async fn prewarm_status(&self) -> PrewarmStatus {
+        self.prewarm.lock().await.clone()
+    }
+}
+
+/// Pre-warm the logos of all stations of `services` whenever their stations have been refreshed (and
+/// at startup), so the first guide load doesn't have to download all of them
+pub async fn prewarm_on_refresh<T: StationProvider>(logos: Arc<LogoCache>, services: Vec<T>) {
+    let mut last_refresh = Vec::new();
+    loop {
+        let mut refreshed = Vec::new();
+        for service in &services {
+            refreshed.extend(service.status().await.into_iter().map(|s| s.last_refresh));
+        }
+
+        if refreshed != last_refresh {
+            let mut stations = Vec::new();
+            for service in &services {
+                let stations_mutex = service.stations().await;
+                stations.extend(stations_mutex.lock().await.iter().filter_map(|s| {
+                    s.logoUrl
+                        .as_ref()
+                        .or(s.logo226Url.as_ref())
+                        .map(|url| (s.id, url.to_owned()))
+                }));
+            }
+            stations.sort_by_key(|(id, _)| *id);
+            stations.dedup_by_key(|(id, _)| *id);
+            logos.prewarm(stations).await;
+            last_refresh = refreshed;
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(PREWARM_POLL_INTERVAL)).await;
+    }
+}
+
+/// Returns true if a logo is cached and not older than `LOGO_MAX_AGE`
+fn fresh(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default()
+                <= Duration::from_secs(LOGO_MAX_AGE)
+        })
+}
+
+/// Read a cached logo, unless it doesn't exist or is older than `LOGO_MAX_AGE`
+fn read_cached(path: &Path) -> Option<Vec<u8>> {
+    if !fresh(path) {
+        return None;
+    }
+    fs::read(path).ok()
+}
+
+/// Decode an image, scale it down to fit `LOGO_SIZE` and encode it as PNG
+fn resize(data: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let mut image = image::load_from_memory(data)?;
+    if image.width() > LOGO_SIZE || image.height() > LOGO_SIZE {
+        image = image.resize(LOGO_SIZE, LOGO_SIZE, FilterType::Lanczos3);
+    }
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
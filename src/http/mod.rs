@@ -1,87 +1,480 @@
-mod templates;
+mod audit;
+mod auth;
+mod clients;
+mod conditional;
+mod connection;
+mod cors;
+mod epg_cache;
+mod favorites;
+mod hdhomerun;
+mod hls;
+pub mod ip_filter;
+mod limits;
+mod logos;
+mod mqtt;
+pub mod playlist;
+mod ports;
+mod prefetch;
+mod proxy;
+mod recordings;
+mod scan;
+mod sessions;
+pub mod setup;
+pub mod share;
+mod shutdown;
+mod station_stats;
+mod streams;
+mod systemd;
+pub mod templates;
+mod timeshift;
+mod tls;
+mod widget;
 use crate::{
     config::Config,
-    service::{station::ChannelRemapEntry, stationprovider::StationProvider},
-    utils::Or,
+    credentials::{AccountLease, AccountStatus},
+    errors::{prefers_html, AppError, ErrorEnvelope},
+    fcc_facilities::FacilitiesHealth,
+    logging,
+    notify::{self, Event},
+    service::{
+        adhoc::AdhocRequest,
+        coverage::EpgCoverage,
+        station::{ChannelRemapEntry, Station},
+        stationprovider::StationProvider,
+        xmltv::EpgOverrides,
+        ServiceStatus, StationRoute,
+    },
+    telemetry::{self, Span},
+    utils::{HttpError, Or},
+};
+use actix_web::{dev::HttpResponseBuilder, dev::Server, web, App};
+use actix_web::{
+    dev::{Service, ServiceRequest},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Logger,
+    HttpMessage,
 };
-use actix_web::middleware::Logger;
-use actix_web::{dev::Server, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web::{middleware::Compat, Error};
-use actix_web::{middleware::Condition, ResponseError};
+use actix_web::{middleware::Condition, HttpRequest, HttpResponse, HttpServer, Responder};
+use audit::AuditLog;
+use auth::Auth;
 use chrono::{DateTime, Utc};
-use futures::{future, lock::Mutex, stream, Stream};
+use clients::{ClientStream, ClientTracker};
+use conditional::{EntityTags, Validators};
+use connection::Connection;
+use cors::Cors;
+use epg_cache::EpgCache;
+use favorites::Favorites;
+use futures::{future, stream, Stream, StreamExt};
+use hls::{HlsProxy, HlsSession};
+use ip_filter::IpFilter;
+use itertools::Itertools;
+use limits::{ClientLimits, ClientSlot};
 use log::info;
+use logos::LogoCache;
+use playlist::{mark_discontinuity, media_playlist, BufferLimits, Segment, ServedSegment};
+use prefetch::Prefetcher;
 use prettytable::{cell, format, row, Table};
-use reqwest::{header::LOCATION, Url};
-use serde::Serialize;
-use std::{collections::HashMap, convert::TryFrom};
+use proxy::Origin;
+use reqwest::header::{CACHE_CONTROL, CONTENT_ENCODING, LOCATION, VARY};
+use scan::StationScan;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sessions::{SessionTracker, StreamSession};
+use share::{ShareLink, ShareRequest, Shares};
+use shutdown::Shutdown;
+use socket2::{Domain, Protocol, Socket, Type};
+use station_stats::StationStats;
 use std::{collections::VecDeque, sync::Arc};
-use string_builder::Builder;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs},
+};
+use streams::{ActiveStreams, StreamHandle, StreamStatus};
+use templates::{ChannelFormat, ChannelName, M3uFormat, M3uGroup, M3uSort};
+use timeshift::Timeshifts;
 use uuid::Uuid;
+use widget::NowPlaying;
 
-const NETWORKS: [&'static str; 6] = ["ABC", "CBS", "NBC", "FOX", "CW", "PBS"];
+pub use shutdown::{request_stop, STOP_TIMEOUT};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Struct that is passed to HTTP handlers that contains config, the service that can be used to
 /// lookup locast data, etc.
 struct AppState<T: StationProvider> {
     config: Arc<Config>,
     service: T,
-    station_scan: Mutex<bool>,
+    /// All services, so tuners can report on other locations
+    tuners: Arc<Vec<T>>,
+    /// Channel scan started by the media server
+    station_scan: Arc<StationScan>,
+    bindings: Arc<Vec<SocketAddr>>,
+    sessions: SessionTracker,
+    /// Stream and bandwidth limits per client IP address, shared by all tuners
+    limits: Arc<ClientLimits>,
+    timeshifts: Arc<Timeshifts>,
+    hls: Arc<HlsProxy>,
+    /// Streams of all tuners
+    streams: Arc<ActiveStreams>,
+    /// Stream statistics per station, of all tuners
+    station_stats: Arc<StationStats>,
+    clients: ClientTracker,
+    /// When the entity tags of the EPG and lineup were first served
+    entity_tags: EntityTags,
+    epg_cache: EpgCache,
+    logos: Arc<LogoCache>,
+    shares: Arc<Shares>,
+    favorites: Arc<Favorites>,
+    audit: Arc<AuditLog>,
+    epg_overrides: Arc<EpgOverrides>,
+    shutdown: Arc<Shutdown>,
+    started: DateTime<Utc>,
+    /// Set if this is an additional virtual device of the multiplexer
+    virtual_device: Option<VirtualDevice>,
+}
+
+impl<T: StationProvider> AppState<T> {
+    /// Name of the device, as reported to media servers
+    fn device_name(&self) -> String {
+        let geo = self.service.geo();
+        let name = match &self.config.device_name_template {
+            Some(template) => template
+                .replace("{city}", &geo.name)
+                .replace("{dma}", &geo.DMA)
+                .replace("{zipcode}", &self.service.zipcode())
+                .trim()
+                .to_owned(),
+            None => geo.name.clone(),
+        };
+        match &self.virtual_device {
+            Some(d) => format!("{} {}", name, d.number),
+            None => name,
+        }
+    }
+
+    /// Format of the channel numbers in the lineup, playlists and guide
+    fn channel_format(&self) -> ChannelFormat {
+        self.config
+            .channel_format
+            .parse()
+            .unwrap_or(ChannelFormat::Decimal)
+    }
+}
+
+/// Additional device that exposes the multiplexer (see `virtual_devices`), so media servers can use more
+/// tuners. Every virtual device has its own UUID (derived from the base UUID) and tuners.
+struct VirtualDevice {
+    number: u8,
+    uuid: String,
+}
+
+impl VirtualDevice {
+    fn new(config: &Config, number: u8) -> VirtualDevice {
+        let uuid = Uuid::new_v5(
+            &Uuid::parse_str(&config.uuid).unwrap(),
+            format!("device.{}", number).as_bytes(),
+        );
+        VirtualDevice {
+            number,
+            uuid: uuid.to_string(),
+        }
+    }
 }
 
+static BIND_RETRIES: usize = 3;
+static BIND_RETRY_DELAY: u64 = 1;
+
+/// Error code for an address family that isn't supported (e.g. IPv6 is disabled)
+#[cfg(unix)]
+const EAFNOSUPPORT: i32 = libc::EAFNOSUPPORT;
+#[cfg(windows)]
+const EAFNOSUPPORT: i32 = 10047; // WSAEAFNOSUPPORT
+
 /// Start the HTTP server that will handle media server requests
 pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
     services: Vec<T>,
     config: Arc<Config>,
 ) -> std::io::Result<()> {
     let reporting_services = services.clone();
-    // Start a server for each service that is passed in
-    let servers: Vec<Server> = services
+    let tuners = Arc::new(services.clone());
+
+    // In single port mode, all locations are served from one server, each under its own path prefix
+    let single_port = config.single_port && !config.multiplex;
+    let prefixes: Vec<String> = services
+        .iter()
+        .map(|s| {
+            if single_port {
+                format!("/tuner/{}", s.geo().DMA)
+            } else {
+                String::new()
+            }
+        })
+        .collect();
+    if single_port && prefixes.iter().unique().count() < prefixes.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Multiple locations share a DMA, so they can't be served from a single port",
+        ));
+    }
+
+    // Bind listeners for each server. Every server listens on all bind addresses.
+    let bind_addresses: Vec<&str> = config.bind_address.split(',').map(|a| a.trim()).collect();
+    let ports = ports::plan(&services, &config, &bind_addresses, single_port).await?;
+    let mut listeners = Vec::new();
+    let mut server_bindings = Vec::new();
+    for port in ports {
+        let l = bind_listeners(&bind_addresses, port).await?;
+        server_bindings.push(
+            l.iter()
+                .map(|l| l.local_addr())
+                .collect::<std::io::Result<Vec<SocketAddr>>>()?,
+        );
+        listeners.push(l);
+    }
+    let bindings = Arc::new(server_bindings.concat());
+
+    let tls = tls_config(&config)?;
+
+    // Logos are cached on disk and shared between all servers
+    let logos = Arc::new(LogoCache::new(&config.cache_directory));
+    let epg_overrides = Arc::new(EpgOverrides::new(&config));
+    let shares = Arc::new(Shares::new(&config.cache_directory));
+    let favorites = Arc::new(Favorites::new(&config.cache_directory));
+    let audit = Arc::new(AuditLog::new(&config.cache_directory));
+    let started = Utc::now();
+    let auth = Arc::new(Auth::new(&config));
+    let cors = Arc::new(Cors::new(&config));
+    let ip_filter = Arc::new(IpFilter::new(&config));
+    let origin = web::Data::new(Origin::new(&config));
+    let shutdown = Arc::new(Shutdown::new());
+    let limits = Arc::new(ClientLimits::new(&config));
+    let timeshifts = Arc::new(Timeshifts::new(&config));
+    let hls = Arc::new(HlsProxy::new());
+    let streams = Arc::new(ActiveStreams::new());
+    let station_stats = Arc::new(StationStats::new(&config.cache_directory));
+
+    // Construct some app_state for each service we can pass around
+    let app_state = |service: T, virtual_device: Option<VirtualDevice>| {
+        web::Data::new(AppState::<T> {
+            config: config.clone(),
+            service,
+            tuners: tuners.clone(),
+            station_scan: Arc::new(StationScan::new()),
+            bindings: bindings.clone(),
+            sessions: SessionTracker::new(config.tuner_count as usize),
+            limits: limits.clone(),
+            timeshifts: timeshifts.clone(),
+            hls: hls.clone(),
+            streams: streams.clone(),
+            station_stats: station_stats.clone(),
+            clients: ClientTracker::new(),
+            entity_tags: EntityTags::new(),
+            epg_cache: EpgCache::default(),
+            logos: logos.clone(),
+            shares: shares.clone(),
+            favorites: favorites.clone(),
+            audit: audit.clone(),
+            epg_overrides: epg_overrides.clone(),
+            shutdown: shutdown.clone(),
+            started,
+            virtual_device,
+        })
+    };
+    let mut app_states: Vec<(String, web::Data<AppState<T>>)> = services
         .into_iter()
-        .enumerate()
-        .map(|(i, service)| {
-            // Create port and address
-            let port = config.port + i as u16;
-            let bind_address = &config.bind_address;
-            info!(
-                "Starting http server for {} on http://{}:{}",
-                service.geo().name,
-                bind_address,
-                port
-            );
+        .zip(prefixes.iter().cloned())
+        .map(|(service, prefix)| (prefix, app_state(service, None)))
+        .collect();
 
-            // Construct some app_state we can pass around
-            let app_state = web::Data::new(AppState::<T> {
-                config: config.clone(),
-                service: service.clone(),
-                station_scan: Mutex::new(false),
-            });
+    // The multiplexer is the first device. Additional virtual devices are served under "/device/N".
+    if config.multiplex {
+        let service = app_states[0].1.service.clone();
+        for number in 2..=config.virtual_devices {
+            let device = VirtualDevice::new(&config, number);
+            app_states.push((
+                format!("/device/{}", number),
+                app_state(service.clone(), Some(device)),
+            ));
+        }
+    }
+    let devices: Vec<(String, String)> = app_states
+        .iter()
+        .map(|(prefix, s)| {
+            let uuid = s.virtual_device.as_ref().map(|d| d.uuid.clone());
+            (uuid.unwrap_or_else(|| s.service.uuid()), prefix.clone())
+        })
+        .collect();
+
+    let all_app_states: Vec<web::Data<AppState<T>>> =
+        app_states.iter().map(|(_, s)| s.clone()).collect();
+
+    // Start a server for each service that is passed in, or a single one for all of them
+    let groups: Vec<Vec<(String, web::Data<AppState<T>>)>> = if single_port || config.multiplex {
+        vec![app_states]
+    } else {
+        app_states.into_iter().map(|s| vec![s]).collect()
+    };
+    let servers: Vec<Server> = groups
+        .into_iter()
+        .zip(listeners)
+        .map(|(app_states, listeners)| {
+            for listener in &listeners {
+                for (prefix, app_state) in &app_states {
+                    info!(
+                        "Starting http server for {} on {}://{}{}",
+                        app_state.service.geo().name,
+                        config.scheme(),
+                        listener.local_addr().unwrap(),
+                        prefix
+                    );
+                }
+            }
 
             let verbose = config.verbose;
+            let auth = auth.clone();
+            let cors = cors.clone();
+            let ip_filter = ip_filter.clone();
+            let origin = origin.clone();
 
-            HttpServer::new(move || {
-                App::new()
+            let server = HttpServer::new(move || {
+                let auth = auth.clone();
+                let cors = cors.clone();
+                let ip_filter = ip_filter.clone();
+                let app = App::new()
+                    // Reject unauthenticated requests if authentication is configured
+                    .wrap_fn(move |req, srv| {
+                        if auth.allowed(&req) {
+                            future::Either::Left(srv.call(req))
+                        } else {
+                            let basic = auth.basic();
+                            future::Either::Right(future::ok(
+                                req.error_response(AppError::Unauthorized(basic)),
+                            ))
+                        }
+                    })
+                    // Restrict tuner endpoints to the configured IP ranges, before authenticating
+                    .wrap_fn(move |req, srv| match ip_filter.check(&req) {
+                        Ok(()) => future::Either::Left(srv.call(req)),
+                        Err(ip) => {
+                            debug!("Refusing {} {} from {}", req.method(), req.path(), ip);
+                            future::Either::Right(future::ok(req.error_response(
+                                AppError::Forbidden(format!(
+                                    "{} is not allowed to use this tuner",
+                                    ip
+                                )),
+                            )))
+                        }
+                    })
+                    // Render errors as a JSON envelope or an HTML page
+                    .wrap_fn(|req, srv| {
+                        let html = prefers_html(req.headers());
+                        let request = format!("{} {}", req.method(), req.path());
+                        let request_id = req.extensions().get::<RequestId>().cloned();
+                        let response = srv.call(req);
+                        async move {
+                            let response = response.await?;
+                            let error = match response.response().error() {
+                                Some(e) => {
+                                    let station_id = station_id(response.request());
+                                    error_response(
+                                        e,
+                                        html,
+                                        &request,
+                                        request_id.as_ref().map(|id| id.0.as_str()),
+                                        station_id,
+                                    )
+                                }
+                                None => return Ok(response),
+                            };
+                            Ok(response.into_response(error))
+                        }
+                    })
+                    // Answer CORS preflight requests and add CORS headers to allowed requests
+                    .wrap_fn(move |req, srv| {
+                        let origin = cors.allow_origin(&req);
+                        if let Some(origin) = &origin {
+                            if cors::is_preflight(&req) {
+                                let response = cors.preflight(req, origin.clone());
+                                return future::Either::Right(future::ok(response));
+                            }
+                        }
+                        let response = srv.call(req);
+                        let cors = cors.clone();
+                        future::Either::Left(async move {
+                            let mut response = response.await?;
+                            if let Some(origin) = origin {
+                                cors.decorate(response.headers_mut(), origin);
+                            }
+                            Ok(response)
+                        })
+                    })
                     // Log HTTP requests if verbosity > 0
                     .wrap(Condition::new(verbose > 0, Compat::new(Logger::default())))
-                    .app_data(app_state.clone())
-                    .route("/", web::get().to(device_xml::<T>))
-                    .route("/config", web::get().to(show_config::<T>))
-                    .route("/device.xml", web::get().to(device_xml::<T>))
-                    .route("/discover.json", web::get().to(discover::<T>))
-                    .route("/epg.xml", web::get().to(epg_xml::<T>))
-                    .route("/epg", web::get().to(epg::<T>))
-                    .route("/lineup_status.json", web::get().to(lineup_status::<T>))
-                    .route("/lineup.json", web::get().to(lineup_json::<T>))
-                    .route("/lineup.post", web::post().to(lineup_post))
-                    .route("/lineup.xml", web::get().to(lineup_xml::<T>))
-                    .route("/map.json", web::get().to(map_json::<T>))
-                    .route("/tuner.m3u", web::get().to(tuner_m3u::<T>))
-                    .service(web::resource("/watch/{id}.m3u").route(web::get().to(watch_m3u::<T>)))
-                    .service(web::resource("/watch/{id}").route(web::get().to(watch::<T>)))
-            })
-            .bind((bind_address.to_owned(), port))
-            .unwrap()
-            .run()
+                    // Trace HTTP requests
+                    .wrap_fn(|req, srv| {
+                        let request_id = RequestId::of(&req);
+                        let mut span = Span::new("http_request", None);
+                        span.set_attribute("http.method", req.method().to_string());
+                        span.set_attribute("http.path", req.path().to_owned());
+                        span.set_attribute("http.request_id", request_id.0.clone());
+                        req.extensions_mut().insert(request_id.clone());
+                        // Spans started while handling the request (e.g. its stream) are its children
+                        let response = telemetry::in_scope(span.parent(), srv.call(req));
+                        async move {
+                            let mut response = response.await;
+                            if let Ok(r) = response.as_mut() {
+                                span.set_attribute(
+                                    "http.status_code",
+                                    r.status().as_str().to_owned(),
+                                );
+                                if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                                    r.headers_mut()
+                                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                                }
+                            }
+                            span.end();
+                            response
+                        }
+                    })
+                    .app_data(origin.clone())
+                    .app_data(
+                        web::JsonConfig::default()
+                            .error_handler(|e, _| AppError::BadRequest(e.to_string()).into()),
+                    )
+                    .app_data(
+                        web::FormConfig::default()
+                            .error_handler(|e, _| AppError::BadRequest(e.to_string()).into()),
+                    );
+
+                app_states
+                    .iter()
+                    .fold(app, |app, (prefix, app_state)| {
+                        if prefix.is_empty() {
+                            app.app_data(app_state.clone()).configure(routes::<T>)
+                        } else {
+                            app.service(
+                                web::scope(prefix)
+                                    .app_data(app_state.clone())
+                                    .app_data(TunerPrefix(prefix.clone()))
+                                    .configure(routes::<T>),
+                            )
+                        }
+                    })
+                    .default_service(web::route().to(not_found))
+            });
+            // Signals are handled below, so streams can be drained first
+            let server = server.disable_signals().on_connect(connection::on_connect);
+            listeners
+                .into_iter()
+                .fold(server, |server, listener| {
+                    match &tls {
+                        Some(tls) => server.listen_rustls(listener, tls.clone()),
+                        None => server.listen(listener),
+                    }
+                    .unwrap()
+                })
+                .run()
         })
         .collect();
 
@@ -106,11 +499,12 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
         }
         info!("");
         info!("Multiplexer:");
-        let url = format!("http://{}:{}", config.bind_address, config.port);
         let mut table = Table::new();
         table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
         table.set_titles(row!["UID", "URL"]);
-        table.add_row(row![reporting_services[0].uuid(), url]);
+        for (uuid, prefix) in &devices {
+            table.add_row(row![uuid, urls(&config, &server_bindings[0], prefix)]);
+        }
         for line in table.to_string().lines() {
             info!(" {}", line);
         }
@@ -119,10 +513,9 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
         let mut table = Table::new();
         table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
         table.set_titles(row!["City", "Zip code", "DMA", "UUID", "Timezone", "URL"]);
-        for is in reporting_services.iter().enumerate() {
-            let (i, s) = is;
-            let port = config.port + i as u16;
-            let url = format!("http://{}:{}", config.bind_address, port);
+        for (i, s) in reporting_services.iter().enumerate() {
+            let binding = &server_bindings[if single_port { 0 } else { i }];
+            let url = urls(&config, binding, &prefixes[i]);
             table.add_row(row![
                 s.geo().name,
                 s.zipcode(),
@@ -137,32 +530,657 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
         }
     }
 
+    // Announce the tuners to clients that use the HDHomeRun discovery protocol
+    if config.hdhr_discovery {
+        let hdhr_devices = devices
+            .iter()
+            .enumerate()
+            .map(|(i, (uuid, prefix))| hdhomerun::Device {
+                device_id: hdhomerun::device_id(uuid),
+                port: server_bindings[if single_port || config.multiplex {
+                    0
+                } else {
+                    i
+                }][0]
+                    .port(),
+                prefix: prefix.clone(),
+            })
+            .collect();
+        actix_rt::spawn(hdhomerun::respond(config.clone(), hdhr_devices));
+    }
+
+    // Fetch the EPG overrides in the background
+    actix_rt::spawn(epg_overrides.clone().refresh());
+
+    // End HLS sessions of clients that stopped playing
+    actix_rt::spawn(hls.clone().expire_sessions());
+
+    // Download logos in the background after every station refresh
+    actix_rt::spawn(logos::prewarm_on_refresh(logos, reporting_services.clone()));
+
+    // Remove recordings that are past their retention
+    actix_rt::spawn(recordings::enforce_retention(config.clone()));
+
+    // Publish sensors to Home Assistant over MQTT
+    let mqtt_app_states = all_app_states.clone();
+    actix_rt::spawn(mqtt::publish(
+        config.clone(),
+        reporting_services.clone(),
+        move || mqtt_app_states.iter().map(|s| s.sessions.active()).sum(),
+        config.tuner_count as usize * all_app_states.len(),
+    ));
+
+    // Shut down gracefully on SIGTERM/SIGINT
+    actix_rt::spawn(shutdown::on_signal(
+        shutdown,
+        servers.clone(),
+        config.drain_timeout,
+        move || all_app_states.iter().map(|s| s.sessions.active()).sum(),
+    ));
+
     info!("locast2tuner started..");
+    systemd::notify("READY=1\nSTATUS=Serving tuners");
+    notify::notify(Event::Startup {
+        version: VERSION.to_owned(),
+    });
     future::try_join_all(servers).await?;
     Ok(())
 }
 
+/// Path prefix of a tuner in single port mode (e.g. "/tuner/501") or of a virtual device (e.g.
+/// "/device/2")
+struct TunerPrefix(String);
+
+/// Routes of a single tuner
+fn routes<T: 'static + StationProvider>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::get().to(device_xml::<T>))
+        .route("/admin", web::get().to(admin::<T>))
+        .route("/admin", web::post().to(admin_post::<T>))
+        .route("/audit.json", web::get().to(audit_json::<T>))
+        .service(
+            web::resource("/channels/adhoc")
+                .route(web::get().to(adhoc_channels::<T>))
+                .route(web::post().to(adhoc_post::<T>)),
+        )
+        .service(web::resource("/channels/adhoc/{id}").route(web::delete().to(adhoc_delete::<T>)))
+        .service(web::resource("/city/{dma}/epg.xml").route(web::get().to(city_epg_xml::<T>)))
+        .service(web::resource("/city/{dma}/tuner.m3u").route(web::get().to(city_tuner_m3u::<T>)))
+        .route("/clients.json", web::get().to(clients_json::<T>))
+        .route("/config", web::get().to(show_config::<T>))
+        .route("/device.xml", web::get().to(device_xml::<T>))
+        .route("/log_level", web::get().to(log_level))
+        .route("/log_level", web::post().to(log_level_post::<T>))
+        .route("/diagnostics/epg.json", web::get().to(diagnostics_epg::<T>))
+        .route(
+            "/diagnostics/facilities",
+            web::get().to(diagnostics_facilities::<T>),
+        )
+        .route(
+            "/diagnostics/logos.json",
+            web::get().to(diagnostics_logos::<T>),
+        )
+        .route("/discover.json", web::get().to(discover::<T>))
+        .route("/epg.xml", web::get().to(epg_xml::<T>))
+        .service(
+            web::resource("/favorites")
+                .route(web::get().to(favorites_json::<T>))
+                .route(web::put().to(favorites_put::<T>)),
+        )
+        .service(
+            web::resource("/favorites/{id}")
+                .route(web::put().to(favorite_put::<T>))
+                .route(web::delete().to(favorite_delete::<T>)),
+        )
+        .route("/epg", web::get().to(epg::<T>))
+        .service(web::resource("/logo/{station_id}.png").route(web::get().to(logo::<T>)))
+        .route("/lineup_status.json", web::get().to(lineup_status::<T>))
+        .route("/lineup.json", web::get().to(lineup_json::<T>))
+        .route("/lineup/diff.json", web::get().to(lineup_diff::<T>))
+        .route(
+            "/lineup_conflicts.json",
+            web::get().to(lineup_conflicts::<T>),
+        )
+        .route("/lineup.post", web::post().to(lineup_post::<T>))
+        .route("/lineup.xml", web::get().to(lineup_xml::<T>))
+        .route("/map.json", web::get().to(map_json::<T>))
+        .service(web::resource("/preview/{id}").route(web::get().to(preview::<T>)))
+        .route("/record/{id}", web::post().to(record::<T>))
+        .route("/recordings", web::get().to(recordings_json::<T>))
+        .service(
+            web::resource("/recordings/{name}")
+                .route(web::get().to(recording::<T>))
+                .route(web::delete().to(recording_delete::<T>)),
+        )
+        .route("/routing.json", web::get().to(routing_json::<T>))
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz::<T>))
+        .route("/share", web::post().to(share::<T>))
+        .service(web::resource("/share/{token}.m3u").route(web::get().to(share_m3u::<T>)))
+        .service(web::resource("/share/{token}").route(web::get().to(share_watch::<T>)))
+        .service(web::resource("/segment/{token}").route(web::get().to(segment::<T>)))
+        .route("/stats/stations.json", web::get().to(stats_stations::<T>))
+        .route("/status", web::get().to(status::<T>))
+        .route("/status.json", web::get().to(status::<T>))
+        .route("/streams", web::get().to(streams::<T>))
+        .route("/streams.html", web::get().to(streams_html::<T>))
+        .service(
+            web::resource("/map/{channel_id}")
+                .route(web::post().to(map_post::<T>))
+                .route(web::delete().to(map_delete::<T>)),
+        )
+        .route("/tuner.m3u", web::get().to(tuner_m3u::<T>))
+        .service(
+            web::resource("/timeshift/{id}/{sequence}.ts")
+                .route(web::get().to(timeshift_segment::<T>)),
+        )
+        .service(web::resource("/watch/{id}.m3u").route(web::get().to(watch_m3u::<T>)))
+        .service(web::resource("/watch/{id}.m3u8").route(web::get().to(watch_m3u8::<T>)))
+        .service(web::resource("/watch/{id}").route(web::get().to(watch::<T>)))
+        .route(
+            "/widget/nowplaying.html",
+            web::get().to(widget_nowplaying_html::<T>),
+        )
+        .route(
+            "/widget/nowplaying.json",
+            web::get().to(widget_nowplaying_json::<T>),
+        );
+}
+
+/// Load the TLS certificate and key if HTTPS is configured
+fn tls_config(config: &Config) -> std::io::Result<Option<rustls::ServerConfig>> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Ok(Some(tls::server_config(cert, key)?)),
+        _ => Ok(None),
+    }
+}
+
+/// The URL clients used to reach us (e.g. "https://192.168.1.10:6077"), including the tuner prefix in
+/// single port mode. Behind a reverse proxy, this is `external_url` or the URL from the forwarding
+/// headers of the proxy.
+fn base_url(req: &HttpRequest) -> String {
+    let origin = match req.app_data::<web::Data<Origin>>() {
+        Some(origin) => origin.of(req),
+        None => Origin::default().of(req),
+    };
+    format!("{}{}", origin, tuner_prefix(req))
+}
+
+/// `path` without the tuner prefix (single port mode) or virtual device prefix (e.g. "/tuner/501/epg"
+/// becomes "/epg")
+fn relative_path(path: &str) -> &str {
+    match path
+        .strip_prefix("/tuner/")
+        .or_else(|| path.strip_prefix("/device/"))
+    {
+        Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => path,
+    }
+}
+
+/// Returns true if `path` is one of the health probes, which are open to everyone so e.g. container
+/// orchestrators can use them
+fn probe_path(path: &str) -> bool {
+    matches!(relative_path(path), "/healthz" | "/readyz")
+}
+
+/// The path prefix of the tuner that handles `req`, or "" when not in single port mode
+fn tuner_prefix(req: &HttpRequest) -> &str {
+    req.app_data::<TunerPrefix>().map_or("", |p| &p.0)
+}
+
+/// Clients that have used this tuner, with their lineup generation and stream history
+async fn clients_json<T: 'static + StationProvider>(data: web::Data<AppState<T>>) -> HttpResponse {
+    HttpResponse::Ok().json(data.clients.report())
+}
+
+/// Build the response for a failed request. Clients that prefer HTML (e.g. browsers) get an error page,
+/// all other clients get an `ErrorEnvelope` in JSON. The request id and station id are logged with the
+/// error, so errors reported by users (or a failed tune in a media server) can be found in the logs.
+fn error_response(
+    error: &Error,
+    html: bool,
+    request: &str,
+    request_id: Option<&str>,
+    station_id: Option<&str>,
+) -> HttpResponse {
+    let envelope = ErrorEnvelope::new(error, request_id, station_id);
+    let status = error.as_response_error().status_code();
+    let request_id = request_id.unwrap_or("-");
+    let context = match station_id {
+        Some(station_id) => format!("request {}, station {}", request_id, station_id),
+        None => format!("request {}", request_id),
+    };
+    if status.is_server_error() {
+        error!("{} failed ({}): {}", request, context, error);
+    } else {
+        debug!("{} failed ({}): {}", request, context, error);
+    }
+
+    let mut builder = HttpResponseBuilder::new(status);
+    if let Some(e) = error.as_error::<AppError>() {
+        e.add_headers(&mut builder);
+    }
+    if html {
+        builder
+            .content_type("text/html; charset=utf-8")
+            .body(templates::error_html(&envelope))
+    } else {
+        builder.json(&envelope)
+    }
+}
+
+async fn not_found() -> Result<HttpResponse, AppError> {
+    Err(AppError::NotFound)
+}
+
+/// The URLs a tuner can be reached on, for reporting
+fn urls(config: &Config, bindings: &[SocketAddr], prefix: &str) -> String {
+    bindings
+        .iter()
+        .map(|b| format!("{}://{}{}", config.scheme(), b, prefix))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Parse bind addresses. IPv6 addresses can be written with or without brackets. Host names (e.g.
+/// "localhost") are resolved to all of their addresses.
+fn parse_bind_addresses(addresses: &[&str]) -> std::io::Result<Vec<IpAddr>> {
+    let mut ips = Vec::new();
+    for a in addresses {
+        let resolved = match a.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => (*a, 0)
+                .to_socket_addrs()
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid bind address {}: {}", a, e),
+                    )
+                })?
+                .map(|s| s.ip())
+                .collect(),
+        };
+        for ip in resolved {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    Ok(ips)
+}
+
+/// Bind TCP listeners on `port` to all `addresses`. IPv6 addresses can be written with or without
+/// brackets (e.g. "[::]" or "::"). Addresses that are in use are retried `BIND_RETRIES` times, since they
+/// might still be used by a previous instance that's shutting down. Other errors won't go away, so they
+/// fail right away.
+async fn bind_listeners(addresses: &[&str], port: u16) -> std::io::Result<Vec<TcpListener>> {
+    // Sockets passed by systemd (socket activation) take the place of the bind addresses
+    let activated = systemd::listeners(port)?;
+    if !activated.is_empty() {
+        return Ok(activated);
+    }
+
+    let ips = parse_bind_addresses(addresses)?;
+    for attempt in 1..=BIND_RETRIES {
+        match bind_all(&ips, port) {
+            Ok(listeners) => return Ok(listeners),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < BIND_RETRIES => {
+                warn!(
+                    "Unable to bind to port {} (attempt {}/{}): {}",
+                    port, attempt, BIND_RETRIES, e
+                );
+                tokio::time::sleep(tokio::time::Duration::from_secs(BIND_RETRY_DELAY)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Bind TCP listeners on `port` to all `ips`. An IPv6 address that can't be bound because IPv6 isn't
+/// available falls back to its IPv4 equivalent ("::" to "0.0.0.0" and "::1" to "127.0.0.1"). Any other
+/// address that can't be bound fails, so we never listen on other addresses than configured.
+fn bind_all(ips: &[IpAddr], port: u16) -> std::io::Result<Vec<TcpListener>> {
+    // An IPv6 socket normally accepts IPv4 connections as well. If IPv4 addresses are bound
+    // separately, IPv6 sockets are restricted to IPv6, so they don't conflict.
+    let v6_only = ips.iter().any(|ip| ip.is_ipv4());
+
+    let mut listeners = Vec::new();
+    for ip in ips {
+        let address = SocketAddr::new(*ip, port);
+        match bind(address, v6_only) {
+            Ok(l) => listeners.push(l),
+            Err(e) if family_unsupported(&address, &e) => match ipv4_equivalent(ip) {
+                Some(v4) if ips.contains(&v4) => {
+                    warn!("Unable to bind to {} ({}), using {} only", address, e, v4)
+                }
+                Some(v4) => {
+                    warn!(
+                        "Unable to bind to {} ({}), binding to {}:{} instead",
+                        address, e, v4, port
+                    );
+                    listeners.push(bind(SocketAddr::new(v4, port), false)?);
+                }
+                None => {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        format!("Unable to bind to {}: {}", address, e),
+                    ))
+                }
+            },
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("Unable to bind to {}: {}", address, e),
+                ))
+            }
+        }
+    }
+    if listeners.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("None of the bind addresses can be bound on port {}", port),
+        ));
+    }
+    Ok(listeners)
+}
+
+/// Returns true if binding to `address` failed because it's an IPv6 address and IPv6 isn't available
+fn family_unsupported(address: &SocketAddr, e: &std::io::Error) -> bool {
+    address.is_ipv6()
+        && (e.kind() == std::io::ErrorKind::AddrNotAvailable
+            || e.raw_os_error() == Some(EAFNOSUPPORT))
+}
+
+/// The IPv4 address that means the same as an IPv6 wildcard or loopback address
+fn ipv4_equivalent(ip: &IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V6(v6) if v6.is_unspecified() => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpAddr::V6(v6) if v6.is_loopback() => Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        _ => None,
+    }
+}
+
+/// Bind a single TCP listener
+fn bind(address: SocketAddr, v6_only: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    uptime: i64,
+    bindings: Vec<String>,
+    active_streams: usize,
+    tuner_count: u8,
+    accounts: Vec<AccountStatus>,
+    services: Vec<ServiceStatus>,
+    fcc_facilities_age: Option<u64>,
+}
+
+/// Runtime diagnostics, including the addresses the HTTP servers are bound to, the state of the
+/// locast accounts and services. Durations are in seconds.
+async fn status<T: 'static + StationProvider>(data: web::Data<AppState<T>>) -> HttpResponse {
+    let response = Status {
+        version: VERSION,
+        uptime: (Utc::now() - data.started).num_seconds(),
+        bindings: data.bindings.iter().map(|b| b.to_string()).collect(),
+        active_streams: data.sessions.active(),
+        tuner_count: data.config.tuner_count,
+        accounts: data.service.account_status().await,
+        services: data.service.status().await,
+        fcc_facilities_age: data.service.fcc_facilities().cache_age(),
+    };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Liveness probe. Answering at all means the HTTP server is alive.
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "alive" }))
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: Vec<ReadinessCheck>,
+    /// Reasons of the checks that failed
+    failures: Vec<String>,
+    fcc_facilities: FacilitiesHealth,
+}
+
+#[derive(Serialize)]
+struct ReadinessCheck {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl ReadinessCheck {
+    fn new(name: &'static str, failure: Option<String>) -> ReadinessCheck {
+        ReadinessCheck {
+            name,
+            ok: failure.is_none(),
+            reason: failure,
+        }
+    }
+}
+
+/// Readiness probe. Ready means the locast accounts can log in and have an active donation, stations of
+/// every location are loaded, FCC facilities are available and no shutdown is in progress. A degraded
+/// status means locast2tuner is ready, but serving e.g. stale FCC facility data. Only state that's
+/// already known is checked, so probing doesn't cause requests to locast.
+async fn readyz<T: 'static + StationProvider>(data: web::Data<AppState<T>>) -> HttpResponse {
+    let now = Utc::now().timestamp();
+    let accounts: Vec<String> = data
+        .service
+        .account_status()
+        .await
+        .into_iter()
+        .filter_map(|a| {
+            if let Some(e) = a.login_error {
+                Some(format!("{} can't log in ({})", a.username, e))
+            } else if !a.donated || a.donation_expire.is_some_and(|e| e < now) {
+                Some(format!("{} has no active donation", a.username))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let empty: Vec<String> = data
+        .service
+        .status()
+        .await
+        .into_iter()
+        .filter(|s| s.stations == 0)
+        .map(|s| {
+            if s.loading {
+                format!("{} ({}, still loading)", s.name, s.dma)
+            } else {
+                format!("{} ({})", s.name, s.dma)
+            }
+        })
+        .collect();
+    let facilities = data.service.fcc_facilities().health().await;
+
+    let checks = vec![
+        ReadinessCheck::new("login", (!accounts.is_empty()).then(|| accounts.join(", "))),
+        ReadinessCheck::new(
+            "stations",
+            (!empty.is_empty()).then(|| format!("No stations loaded for {}", empty.join(", "))),
+        ),
+        ReadinessCheck::new(
+            "fcc_facilities",
+            (facilities.facilities == 0).then(|| {
+                facilities
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| "No FCC facilities loaded".to_owned())
+            }),
+        ),
+        ReadinessCheck::new(
+            "shutdown",
+            data.shutdown.draining().then(|| "Shutting down".to_owned()),
+        ),
+    ];
+    let failures: Vec<String> = checks
+        .iter()
+        .filter_map(|c| c.reason.as_ref().map(|r| format!("{}: {}", c.name, r)))
+        .collect();
+    let ready = failures.is_empty();
+    let response = Readiness {
+        status: match (ready, facilities.degraded) {
+            (false, _) => "not ready",
+            (true, true) => "degraded",
+            (true, false) => "ready",
+        },
+        checks,
+        failures,
+        fcc_facilities: facilities,
+    };
+    if ready {
+        HttpResponse::Ok().json(&response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(&response)
+    }
+}
+
 async fn device_xml<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
-    let host = req.connection_info().host().to_string();
-    let result = templates::device_xml::<T>(&data.config, &data.service, host);
+    data.clients.seen(&req);
+    let base_url = base_url(&req);
+    let uuid = match &data.virtual_device {
+        Some(d) => d.uuid.clone(),
+        None => data.service.uuid(),
+    };
+    let result = templates::device_xml(&data.config, &data.device_name(), &uuid, base_url);
     HttpResponse::Ok().content_type("text/xml").body(result)
 }
 
 async fn lineup_xml<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
-    let host = req.connection_info().host().to_string();
+    let base_url = base_url(&req);
     let stations_mutex = data.service.stations();
     let stations = stations_mutex.await;
-    let result = templates::lineup_xml(&*stations.lock().await, host);
+    let stations = stations.lock().await;
+    let mut lineup = data.clients.lineup(&req, &stations).into_owned();
+    data.channel_format().apply(&mut lineup);
+    let result = templates::lineup_xml(&lineup, base_url);
     HttpResponse::Ok().content_type("text/xml").body(result)
 }
 
-async fn epg_xml<T: StationProvider>(data: web::Data<AppState<T>>) -> impl Responder {
+/// The guide is streamed one station at a time, so a large guide (e.g. when multiplexing many cities)
+/// is never rendered in memory as a whole.
+async fn epg_xml<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let base_url = base_url(&req);
+    let services = data.service.status().await;
     let stations_mutex = data.service.stations();
     let stations = stations_mutex.await;
-    let result = templates::epg_xml(&*stations.lock().await);
-    HttpResponse::Ok().content_type("text/xml").body(result)
+    let overrides = data.epg_overrides.programmes();
+    // Copy the lineup, so the stations aren't locked while a slow client reads the guide
+    let (stations, validators) = {
+        let stations = stations.lock().await;
+        let lineup = data.clients.pinned(&req, &stations);
+        let validators = Validators::new(
+            &req,
+            &services,
+            &lineup,
+            Some(&overrides),
+            &data.entity_tags,
+        );
+        if validators.not_modified(&req) {
+            return validators.not_modified_response();
+        }
+        let mut lineup = lineup.into_owned();
+        data.channel_format().apply(&mut lineup);
+        (Arc::new(lineup), validators)
+    };
+    let chunks = templates::epg_xml(stations, base_url, overrides)
+        .map(|chunk| Ok::<_, Error>(bytes::Bytes::from(chunk)));
+    validators
+        .headers(&mut HttpResponse::Ok())
+        .content_type("text/xml")
+        .streaming(stream::iter(chunks))
+}
+
+/// Guide of a single multiplexed location, by DMA
+async fn city_epg_xml<T: 'static + StationProvider>(
+    req: HttpRequest,
+    dma: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let base_url = base_url(&req);
+    let (services, mut stations) = city_stations(data, &dma).await?;
+    data.channel_format().apply(&mut stations);
+    let overrides = data.epg_overrides.programmes();
+    let validators = Validators::new(
+        &req,
+        &services,
+        &stations,
+        Some(&overrides),
+        &data.entity_tags,
+    );
+    if validators.not_modified(&req) {
+        return Ok(validators.not_modified_response());
+    }
+    let chunks = templates::epg_xml(Arc::new(stations), base_url, overrides)
+        .map(|chunk| Ok::<_, Error>(bytes::Bytes::from(chunk)));
+    Ok(validators
+        .headers(&mut HttpResponse::Ok())
+        .content_type("text/xml")
+        .streaming(stream::iter(chunks)))
+}
+
+/// Status and (remapped) stations of the multiplexed location with DMA `dma`. Only the multiplexer
+/// serves single locations, since every other tuner serves just one.
+async fn city_stations<T: StationProvider>(
+    data: &AppState<T>,
+    dma: &str,
+) -> Result<(Vec<ServiceStatus>, Vec<Station>), AppError> {
+    if !data.config.multiplex {
+        return Err(AppError::NotFound);
+    }
+    let services: Vec<ServiceStatus> = data
+        .service
+        .status()
+        .await
+        .into_iter()
+        .filter(|s| s.dma == dma)
+        .collect();
+    if services.is_empty() {
+        return Err(AppError::NotFound);
+    }
+    let station_ids: HashSet<i64> = data
+        .service
+        .routing()
+        .await
+        .into_iter()
+        .filter(|r| r.dma == dma)
+        .map(|r| r.station_id)
+        .collect();
+    let stations = data
+        .service
+        .stations()
+        .await
+        .lock()
+        .await
+        .iter()
+        .filter(|s| station_ids.contains(&s.id))
+        .cloned()
+        .collect();
+    Ok((services, stations))
 }
 
 #[derive(Serialize)]
@@ -182,13 +1200,15 @@ struct DiscoverData {
 
 async fn discover<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
-    let host = req.connection_info().host().to_string();
-    let uuid = &data.config.uuid;
-    let device_id = usize::from_str_radix(&uuid[..8], 16).unwrap();
-    let checksum = crate::utils::hdhr_checksum(device_id); // TODO: FIX!
-    let valid_id = format!("{:x}", checksum + device_id);
+    data.clients.seen(&req);
+    let base_url = base_url(&req);
+    let uuid = data
+        .virtual_device
+        .as_ref()
+        .map_or(&data.config.uuid, |d| &d.uuid);
+    let valid_id = format!("{:x}", hdhomerun::device_id(uuid));
     let response = DiscoverData {
-        FriendlyName: data.service.geo().name.clone(),
+        FriendlyName: data.device_name(),
         Manufacturer: "locast2dvr".to_string(),
         ModelNumber: data.config.device_model.clone(),
         FirmwareName: data.config.device_firmware.clone(),
@@ -196,8 +1216,8 @@ async fn discover<T: 'static + StationProvider>(req: HttpRequest) -> HttpRespons
         FirmwareVersion: data.config.device_version.clone(),
         DeviceID: valid_id,
         DeviceAuth: "locast2dvr".to_string(),
-        BaseURL: format!("http://{}", host),
-        LineupURL: format!("http://{}/lineup.json", host),
+        LineupURL: format!("{}/lineup.json", base_url),
+        BaseURL: base_url,
     };
 
     HttpResponse::Ok().json(&response)
@@ -208,23 +1228,39 @@ async fn discover<T: 'static + StationProvider>(req: HttpRequest) -> HttpRespons
 struct LineupStatus {
     ScanInProgress: bool,
     Progress: u8,
-    Found: u8,
+    Found: usize,
     SourceList: Option<Vec<String>>,
 }
-async fn lineup_status<T: StationProvider>(data: web::Data<AppState<T>>) -> impl Responder {
-    let station_scan = data.station_scan.lock().await;
-    let response = if *station_scan {
+/// Progress of the running channel scan, or the result of the last one
+async fn lineup_status<T: StationProvider>(
+    req: HttpRequest,
+    data: web::Data<AppState<T>>,
+) -> impl Responder {
+    data.clients.seen(&req);
+    let scan = data.station_scan.state();
+    let services = data.service.status().await;
+    let loading = services.iter().filter(|s| s.loading).count();
+    let response = if loading > 0 {
+        // Stations are still being loaded (`lazy_start`), so media servers wait instead of finding
+        // an empty lineup
+        LineupStatus {
+            ScanInProgress: true,
+            Progress: (100 * (services.len() - loading) / services.len()) as u8,
+            Found: services.iter().map(|s| s.stations).sum(),
+            SourceList: None,
+        }
+    } else if scan.in_progress {
         LineupStatus {
             ScanInProgress: true,
-            Progress: 50,
-            Found: 6,
+            Progress: scan.progress(),
+            Found: scan.found,
             SourceList: None,
         }
     } else {
         LineupStatus {
             ScanInProgress: false,
-            Progress: 50,
-            Found: 6,
+            Progress: 100,
+            Found: data.service.stations().await.lock().await.len(),
             SourceList: Some(vec!["Antenna".to_string()]),
         }
     };
@@ -232,7 +1268,7 @@ async fn lineup_status<T: StationProvider>(data: web::Data<AppState<T>>) -> impl
 }
 
 impl Or for String {
-    fn or<'a>(&'a self, other: &'a str) -> &str {
+    fn or<'a>(&'a self, other: &'a str) -> &'a str {
         if !self.is_empty() {
             self
         } else {
@@ -240,52 +1276,131 @@ impl Or for String {
         }
     }
 }
-async fn tuner_m3u<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
-    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
-    let host = req.connection_info().host().to_string();
-    let mut builder = Builder::default();
-    builder.append("#EXTM3U\n");
-    let stations_mutex = data.service.stations();
-    let stations = stations_mutex.await;
 
-    for station in stations.lock().await.iter().filter(|s| s.active) {
-        let call_sign_or_name = &station.callSign.or(&station.name).to_string();
-        let call_sign = station
-            .callSign_remapped
-            .as_ref()
-            .unwrap_or(call_sign_or_name);
-        let city = station.city.as_ref().unwrap();
-        let logo = &station
-            .logoUrl
-            .as_ref()
-            .or(station.logo226Url.as_ref())
-            .unwrap();
-        let channel = &station
-            .channel_remapped
-            .as_ref()
-            .unwrap_or(station.channel.as_ref().unwrap());
-        let groups = if NETWORKS.contains(&call_sign.as_str()) {
-            format!("{};Network", &city,)
+impl Or for str {
+    fn or<'a>(&'a self, other: &'a str) -> &'a str {
+        if !self.is_empty() {
+            self
         } else {
-            city.to_owned()
-        };
+            other
+        }
+    }
+}
+#[derive(Deserialize)]
+struct M3uQuery {
+    format: Option<String>,
+    sort: Option<String>,
+    group: Option<String>,
+}
 
-        let tvg_name = if data.config.multiplex {
-            format!("{} ({})", call_sign, city)
-        } else {
-            call_sign.to_string()
+impl M3uQuery {
+    /// Format, sort order and grouping of the playlist, falling back to the configured defaults
+    fn options(&self, config: &Config) -> Result<(M3uFormat, M3uSort, M3uGroup), AppError> {
+        let format = match &self.format {
+            Some(f) => f.parse().map_err(AppError::BadRequest)?,
+            None => M3uFormat::Plex,
         };
+        let sort = self
+            .sort
+            .as_ref()
+            .unwrap_or(&config.m3u_sort)
+            .parse()
+            .map_err(AppError::BadRequest)?;
+        let group = self
+            .group
+            .as_ref()
+            .unwrap_or(&config.m3u_group)
+            .parse()
+            .map_err(AppError::BadRequest)?;
+        Ok((format, sort, group))
+    }
+}
 
-        builder.append(format!(
-            "#EXTINF:-1 tvg-id=\"channel.{}\" tvg-name=\"{}\" tvg-logo=\"{}\" tvg-chno=\"{}\" group-title=\"{}\", {}",
-            &station.id, &call_sign, &logo, &channel, &groups, &tvg_name
-        ));
+/// Playlist of all stations. `format` selects the flavor (plex, channels or tvheadend). `sort` and
+/// `group` override `m3u_sort` and `m3u_group`.
+async fn tuner_m3u<T: 'static + StationProvider>(
+    req: HttpRequest,
+    query: web::Query<M3uQuery>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let (format, sort, group) = query.options(&data.config)?;
+    let base_url = base_url(&req);
+    let services = data.service.status().await;
+    let stations_mutex = data.service.stations();
+    let stations = stations_mutex.await;
+    let stations = stations.lock().await;
+    let favorites = data.favorites.station_ids();
+    let mut lineup = favorites::first(&data.clients.lineup(&req, &stations), &favorites);
+    data.channel_format().apply(&mut lineup);
+    let validators = Validators::new(&req, &services, &lineup, None, &data.entity_tags);
+    if validators.not_modified(&req) {
+        return Ok(validators.not_modified_response());
+    }
+    let result = templates::tuner_m3u(
+        &lineup,
+        &base_url,
+        &ChannelName::new(
+            data.config.channel_name_template.as_ref(),
+            data.config.multiplex,
+        ),
+        format,
+        sort,
+        &group,
+        &favorites,
+    );
+    Ok(validators.headers(&mut HttpResponse::Ok()).body(result))
+}
 
-        let url = format!("http://{}/watch/{}.m3u", &host, &station.id);
-        builder.append(format!("\n{}\n\n", url));
+/// Playlist of a single multiplexed location, by DMA. Takes the same options as `tuner_m3u`.
+async fn city_tuner_m3u<T: 'static + StationProvider>(
+    req: HttpRequest,
+    dma: web::Path<String>,
+    query: web::Query<M3uQuery>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let (format, sort, group) = query.options(&data.config)?;
+    let base_url = base_url(&req);
+    let (services, stations) = city_stations(data, &dma).await?;
+    let favorites = data.favorites.station_ids();
+    let mut stations = favorites::first(&stations, &favorites);
+    data.channel_format().apply(&mut stations);
+    let validators = Validators::new(&req, &services, &stations, None, &data.entity_tags);
+    if validators.not_modified(&req) {
+        return Ok(validators.not_modified_response());
     }
+    let name = ChannelName::new(data.config.channel_name_template.as_ref(), true);
+    let result = templates::tuner_m3u(
+        &stations, &base_url, &name, format, sort, &group, &favorites,
+    );
+    Ok(validators.headers(&mut HttpResponse::Ok()).body(result))
+}
+
+/// Serve a station logo from the local logo cache
+async fn logo<T: 'static + StationProvider>(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let station_id: i64 = match req.match_info().get("station_id").unwrap().parse() {
+        Ok(id) => id,
+        Err(_) => return Err(AppError::NotFound),
+    };
+
+    let stations_mutex = data.service.stations();
+    let stations = stations_mutex.await;
+    let url = match stations
+        .lock()
+        .await
+        .iter()
+        .find(|s| s.id == station_id)
+        .and_then(|s| s.logoUrl.as_ref().or(s.logo226Url.as_ref()))
+    {
+        Some(url) => url.to_owned(),
+        None => return Err(AppError::NotFound),
+    };
 
-    HttpResponse::Ok().body(builder.string().unwrap())
+    let logo = data.logos.get(station_id, &url).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .append_header((CACHE_CONTROL, "max-age=86400"))
+        .body(logo))
 }
 
 #[derive(Serialize)]
@@ -298,17 +1413,26 @@ struct LineupJson {
 
 async fn lineup_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
-    let host = req.connection_info().host().to_string();
+    let base_url = base_url(&req);
+    let services = data.service.status().await;
     let stations_mutex = data.service.stations();
     let stations = stations_mutex.await;
 
-    let lineup: Vec<LineupJson> = stations
-        .lock()
-        .await
+    let stations = stations.lock().await;
+    let mut lineup = favorites::first(
+        &data.clients.lineup(&req, &stations),
+        &data.favorites.station_ids(),
+    );
+    data.channel_format().apply(&mut lineup);
+    let validators = Validators::new(&req, &services, &lineup, None, &data.entity_tags);
+    if validators.not_modified(&req) {
+        return validators.not_modified_response();
+    }
+    let lineup: Vec<LineupJson> = lineup
         .iter()
         .filter(|s| s.active)
         .map(|station| {
-            let url = format!("http://{}/watch/{}", &host, &station.id);
+            let url = format!("{}/watch/{}", &base_url, &station.id);
             LineupJson {
                 GuideNumber: station
                     .channel_remapped
@@ -321,7 +1445,41 @@ async fn lineup_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResp
         })
         .collect();
 
-    HttpResponse::Ok().json(lineup)
+    validators.headers(&mut HttpResponse::Ok()).json(lineup)
+}
+
+/// Lineup changes of the last station refreshes
+async fn lineup_diff<T: 'static + StationProvider>(data: web::Data<AppState<T>>) -> HttpResponse {
+    HttpResponse::Ok().json(data.service.lineup_diffs().await)
+}
+
+/// Channel numbers that are used by more than one station, with suggested fixes
+async fn lineup_conflicts<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(data.service.channel_conflicts().await)
+}
+
+#[derive(Serialize)]
+struct RouteJson {
+    #[serde(flatten)]
+    route: StationRoute,
+    /// Port of the tuner that serves the station
+    port: u16,
+}
+
+/// Service (location) every station is routed to, by station id
+async fn routing_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let port = req.app_config().local_addr().port();
+    let routes: BTreeMap<i64, RouteJson> = data
+        .service
+        .routing()
+        .await
+        .into_iter()
+        .map(|route| (route.station_id, RouteJson { route, port }))
+        .collect();
+    HttpResponse::Ok().json(&routes)
 }
 
 async fn map_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
@@ -333,239 +1491,1679 @@ async fn map_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpRespons
         .lock()
         .await
         .iter()
-        .map(|station| {
-            (
-                format!("channel.{}", station.id).to_string(),
-                ChannelRemapEntry {
-                    original_call_sign: station.callSign.clone(),
-                    remap_call_sign: station
-                        .callSign_remapped
-                        .clone()
-                        .or(Some(station.callSign.clone()))
-                        .unwrap(),
-                    original_channel: station.channel.clone().unwrap(),
-                    remap_channel: station
-                        .channel_remapped
-                        .clone()
-                        .or(Some(station.channel.clone().unwrap()))
-                        .unwrap(),
-                    city: station.city.clone().unwrap(),
-                    active: station.active,
-                    remapped: station.remapped.or(Some(false)).unwrap(),
-                },
-            )
-        })
+        .map(|station| (format!("channel.{}", station.id), station.into()))
         .collect();
 
     let j = serde_json::to_string(&lineup).unwrap();
     HttpResponse::Ok().content_type("text/json").body(j)
 }
+/// Update the remapping of a single channel. `channel_id` is the key used in map.json
+/// (e.g. `channel.1234`) and the body is a JSON encoded `ChannelRemapEntry`.
+async fn map_post<T: 'static + StationProvider>(
+    req: HttpRequest,
+    entry: web::Json<ChannelRemapEntry>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let key = req.match_info().get("channel_id").unwrap().to_owned();
+    let stations_mutex = data.service.stations();
+    let stations = stations_mutex.await;
+
+    let old = match stations
+        .lock()
+        .await
+        .iter()
+        .find(|s| format!("channel.{}", s.id) == key)
+    {
+        Some(s) => ChannelRemapEntry::from(s),
+        None => return Err(AppError::NotFound),
+    };
+
+    let entry = entry.into_inner();
+    let mut entries = HashMap::new();
+    entries.insert(key.clone(), entry.clone());
+    data.service.update_channel_remap(entries).await?;
+    data.audit
+        .record(&req, "remap", &key, Some(old), Some(entry.clone()));
+    Ok(HttpResponse::Ok().json(&entry))
+}
+
+/// Remove the remapping of a single channel
+async fn map_delete<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let key = req.match_info().get("channel_id").unwrap();
+    let old = data
+        .service
+        .stations()
+        .await
+        .lock()
+        .await
+        .iter()
+        .find(|s| format!("channel.{}", s.id) == key)
+        .map(ChannelRemapEntry::from);
+    data.service.remove_channel_remap(key).await?;
+    data.audit
+        .record(&req, "remap_remove", key, old, None::<ChannelRemapEntry>);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Web UI that lists all stations and allows for editing the channel remapping.
+async fn admin<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let stations_mutex = data.service.stations();
+    let stations = stations_mutex.await;
+    let result = templates::admin_html(
+        &data.config,
+        &stations.lock().await,
+        &data.favorites.station_ids(),
+    );
+    HttpResponse::Ok().content_type("text/html").body(result)
+}
+
+/// Preview a station in the browser, to check if it works without going through a media server
+async fn preview<T: 'static + StationProvider>(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id: i64 = match req.match_info().get("id").unwrap().parse() {
+        Ok(id) => id,
+        Err(_) => return Err(AppError::NotFound),
+    };
+    let stations_mutex = data.service.stations().await;
+    let stations = stations_mutex.lock().await;
+    let station = match stations.iter().find(|s| s.id == id) {
+        Some(s) => s,
+        None => return Err(AppError::NotFound),
+    };
+    let playlist_url = format!("{}/watch/{}.m3u", base_url(&req), id);
+    Ok(HttpResponse::Ok()
+        .content_type("text/html")
+        .body(templates::preview_html(station, &playlist_url)))
+}
+
+/// Handle the form that is posted by the admin UI. Form fields are named `channel.<id>.<field>`.
+/// Only stations that are part of the submitted form are updated.
+async fn admin_post<T: 'static + StationProvider>(
+    req: HttpRequest,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let stations_mutex = data.service.stations();
+    let stations = stations_mutex.await;
+
+    let changes: Vec<(String, ChannelRemapEntry, ChannelRemapEntry)> = stations
+        .lock()
+        .await
+        .iter()
+        .filter_map(|station| {
+            let key = format!("channel.{}", station.id);
+            let remap_channel = form.get(&format!("{}.remap_channel", key))?;
+            let remap_call_sign = form.get(&format!("{}.remap_call_sign", key))?;
+
+            let old = ChannelRemapEntry::from(station);
+            let mut entry = old.clone();
+            entry.remap_channel = remap_channel.trim().or(&entry.original_channel).to_owned();
+            entry.remap_call_sign = remap_call_sign
+                .trim()
+                .or(&entry.original_call_sign)
+                .to_owned();
+            entry.active = form.contains_key(&format!("{}.active", key));
+            entry.remapped = !entry.active
+                || entry.remap_channel != entry.original_channel
+                || entry.remap_call_sign != entry.original_call_sign;
+            Some((key, old, entry))
+        })
+        .collect();
+
+    // Favorites keep their order, new favorites are added at the end
+    let favorite: HashMap<i64, bool> = stations
+        .lock()
+        .await
+        .iter()
+        .filter(|s| form.contains_key(&format!("channel.{}.remap_channel", s.id)))
+        .map(|s| {
+            (
+                s.id,
+                form.contains_key(&format!("channel.{}.favorite", s.id)),
+            )
+        })
+        .collect();
+    let old_favorites = data.favorites.station_ids();
+    let mut favorites: Vec<i64> = old_favorites
+        .iter()
+        .copied()
+        .filter(|id| favorite.get(id).copied().unwrap_or(true))
+        .collect();
+    for (id, _) in favorite.iter().filter(|(_, f)| **f).sorted() {
+        if !favorites.contains(id) {
+            favorites.push(*id);
+        }
+    }
+    if favorites != old_favorites {
+        data.favorites.set(favorites.clone())?;
+        data.audit.record(
+            &req,
+            "favorites",
+            "favorites",
+            Some(old_favorites),
+            Some(favorites),
+        );
+    }
+
+    // Stations can only be remapped when multiplexing
+    if data.config.multiplex {
+        let entries: HashMap<String, ChannelRemapEntry> = changes
+            .iter()
+            .map(|(key, _, entry)| (key.clone(), entry.clone()))
+            .collect();
+        data.service.update_channel_remap(entries).await?;
+        for (key, old, new) in changes {
+            if serde_json::to_value(&old).ok() != serde_json::to_value(&new).ok() {
+                data.audit.record(&req, "remap", &key, Some(old), Some(new));
+            }
+        }
+    }
+    Ok(HttpResponse::SeeOther()
+        .append_header((LOCATION, format!("{}/admin", tuner_prefix(&req))))
+        .finish())
+}
+
+/// Favorite stations by station id, in the order they are listed in
+async fn favorites_json<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(data.favorites.station_ids())
+}
+
+/// Replace the favorites with the posted station ids, in that order
+async fn favorites_put<T: 'static + StationProvider>(
+    req: HttpRequest,
+    station_ids: web::Json<Vec<i64>>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let station_ids = station_ids.into_inner();
+    {
+        let stations = data.service.stations().await;
+        let stations = stations.lock().await;
+        if let Some(id) = station_ids
+            .iter()
+            .find(|id| !stations.iter().any(|s| s.id == **id))
+        {
+            return Err(AppError::BadRequest(format!("Unknown station {}", id)));
+        }
+    }
+    let old = data.favorites.set(station_ids)?;
+    let new = data.favorites.station_ids();
+    data.audit
+        .record(&req, "favorites", "favorites", Some(old), Some(&new));
+    Ok(HttpResponse::Ok().json(new))
+}
+
+/// Add a station to the end of the favorites
+async fn favorite_put<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id: i64 = match req.match_info().get("id").unwrap().parse() {
+        Ok(id) => id,
+        Err(_) => return Err(AppError::NotFound),
+    };
+    let stations = data.service.stations().await;
+    if !stations.lock().await.iter().any(|s| s.id == id) {
+        return Err(AppError::NotFound);
+    }
+    data.favorites.add(id)?;
+    data.audit.record(
+        &req,
+        "favorite_add",
+        &format!("channel.{}", id),
+        None::<Value>,
+        None::<Value>,
+    );
+    Ok(HttpResponse::Ok().json(data.favorites.station_ids()))
+}
+
+async fn favorite_delete<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id: i64 = match req.match_info().get("id").unwrap().parse() {
+        Ok(id) => id,
+        Err(_) => return Err(AppError::NotFound),
+    };
+    data.favorites.remove(id)?;
+    data.audit.record(
+        &req,
+        "favorite_remove",
+        &format!("channel.{}", id),
+        None::<Value>,
+        None::<Value>,
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    limit: Option<usize>,
+}
+
+/// The most recent admin changes, oldest first. Defaults to the last 1000 entries.
+async fn audit_json<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+    query: web::Query<AuditQuery>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(data.audit.entries(query.limit.unwrap_or(1000)))
+}
+
+/// List the ad hoc channels that haven't expired
+async fn adhoc_channels<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(data.service.adhoc_channels().await)
+}
+
+/// Create a temporary channel for a locast station or event id. The body is a JSON encoded
+/// `AdhocRequest`.
+async fn adhoc_post<T: 'static + StationProvider>(
+    req: HttpRequest,
+    request: web::Json<AdhocRequest>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let channel = data.service.add_adhoc_channel(request.into_inner()).await?;
+    data.audit.record(
+        &req,
+        "adhoc_add",
+        &format!("channel.{}", channel.station.id),
+        None::<Value>,
+        Some(serde_json::json!({
+            "call_sign": channel.station.callSign,
+            "channel": channel.station.channel,
+            "expires": channel.expires,
+        })),
+    );
+    Ok(HttpResponse::Created().json(&channel))
+}
+
+/// Remove an ad hoc channel before it expires
+async fn adhoc_delete<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id: i64 = match req.match_info().get("id").unwrap().parse() {
+        Ok(id) => id,
+        Err(_) => return Err(AppError::NotFound),
+    };
+    data.service.remove_adhoc_channel(id).await?;
+    data.audit.record(
+        &req,
+        "adhoc_remove",
+        &format!("channel.{}", id),
+        None::<Value>,
+        None::<Value>,
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize)]
+struct EpgCoverageReport {
+    days: u8,
+    chronically_empty: usize,
+    stations: Vec<StationEpgCoverage>,
+}
+
+#[derive(Serialize)]
+struct StationEpgCoverage {
+    #[serde(flatten)]
+    coverage: EpgCoverage,
+    /// Whether the guide of this station is replaced by an external XMLTV source
+    epg_override: bool,
+}
+
+/// Guide coverage of every station, as computed after the last EPG refresh
+async fn diagnostics_epg<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    let stations: Vec<StationEpgCoverage> = data
+        .service
+        .epg_coverage()
+        .await
+        .into_iter()
+        .map(|coverage| StationEpgCoverage {
+            epg_override: data
+                .config
+                .epg_overrides
+                .iter()
+                .any(|o| o.station_id == coverage.station_id),
+            coverage,
+        })
+        .collect();
+    HttpResponse::Ok().json(&EpgCoverageReport {
+        days: data.config.days,
+        chronically_empty: stations
+            .iter()
+            .filter(|s| s.coverage.chronically_empty)
+            .count(),
+        stations,
+    })
+}
+
+/// Progress of pre-warming the logo cache
+async fn diagnostics_logos<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(&data.logos.prewarm_status().await)
+}
+
+#[derive(Serialize)]
+struct ChannelMismatch {
+    id: i64,
+    city: String,
+    call_sign: String,
+    locast_channel: String,
+    fcc_channel: String,
+    channel: String,
+}
+
+/// List all stations where the channel reported by locast differs from the FCC virtual channel
+async fn diagnostics_facilities<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let stations_mutex = data.service.stations();
+    let stations = stations_mutex.await;
+
+    let mismatches: Vec<ChannelMismatch> = stations
+        .lock()
+        .await
+        .iter()
+        .filter_map(
+            |station| match (&station.channel_locast, &station.channel_fcc) {
+                (Some(locast_channel), Some(fcc_channel)) if locast_channel != fcc_channel => {
+                    Some(ChannelMismatch {
+                        id: station.id,
+                        city: station.city.clone().unwrap(),
+                        call_sign: station.callSign.clone(),
+                        locast_channel: locast_channel.clone(),
+                        fcc_channel: fcc_channel.clone(),
+                        channel: station.channel.clone().unwrap(),
+                    })
+                }
+                _ => None,
+            },
+        )
+        .collect();
+
+    HttpResponse::Ok().json(mismatches)
+}
+
 async fn show_config<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
     let mut config = (*req.app_data::<web::Data<AppState<T>>>().unwrap().config).clone();
 
     if req.query_string() != "show_password" {
         config.password = "*******".to_string();
+        for account in config.extra_accounts.iter_mut() {
+            account.password = "*******".to_string();
+        }
+        for account in config.location_accounts.iter_mut() {
+            account.password = "*******".to_string();
+        }
+        if config.http_password.is_some() {
+            config.http_password = Some("*******".to_string());
+        }
+        if config.api_key.is_some() {
+            config.api_key = Some("*******".to_string());
+        }
+        if config.mqtt_password.is_some() {
+            config.mqtt_password = Some("*******".to_string());
+        }
     }
 
     let result = toml::to_string(&config).unwrap();
     HttpResponse::Ok().content_type("text/plain").body(result)
 }
 
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    /// Module to change the level of (e.g. "locast2tuner::http"), or the default level if not set
+    target: Option<String>,
+    level: Option<String>,
+    /// Go back to the configured levels
+    #[serde(default)]
+    reset: bool,
+}
+
+#[derive(Serialize)]
+struct LogLevels {
+    /// Levels changed at runtime, as "target=level"
+    levels: Vec<String>,
+}
+
+/// Log levels that were changed at runtime
+async fn log_level() -> HttpResponse {
+    HttpResponse::Ok().json(&LogLevels {
+        levels: logging::runtime_levels(),
+    })
+}
+
+/// Change the log level of a module (or the default level) without restarting
+async fn log_level_post<T: 'static + StationProvider>(
+    req: HttpRequest,
+    request: web::Json<LogLevelRequest>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let old = logging::runtime_levels();
+    if request.reset {
+        logging::reset_runtime_levels();
+        info!("Log levels reset to the configured levels");
+    } else {
+        let level = match &request.level {
+            Some(l) => logging::parse_level(l).map_err(AppError::BadRequest)?,
+            None => return Err(AppError::BadRequest("level is required".to_owned())),
+        };
+        let target = request.target.as_ref().map(|t| t.trim().to_owned());
+        info!(
+            "Log level of {} set to {}",
+            target.as_deref().unwrap_or("all modules"),
+            logging::level_name(level)
+        );
+        logging::set_runtime_level(target, level);
+    }
+    let levels = logging::runtime_levels();
+    data.audit.record(
+        &req,
+        "log_level",
+        request.target.as_deref().unwrap_or("default"),
+        Some(&old),
+        Some(&levels),
+    );
+    Ok(HttpResponse::Ok().json(&LogLevels { levels }))
+}
+
 /// EPG in json format. This is pretty much the whole Vec<Station> we have built in memory.
-/// Note that no additional filter is applied.
-async fn epg<T: StationProvider>(data: web::Data<AppState<T>>) -> impl Responder {
+/// Note that no additional filter is applied. The JSON is cached until the stations change, and
+/// served gzipped to clients that accept it.
+async fn epg<T: StationProvider>(req: HttpRequest, data: web::Data<AppState<T>>) -> impl Responder {
+    let services = data.service.status().await;
     let stations_mutex = data.service.stations();
     let stations = &*stations_mutex.await;
-    HttpResponse::Ok().json(stations.lock().await)
+    let epg = data.epg_cache.get(&services, &stations.lock().await);
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/json")
+        .append_header((VARY, "Accept-Encoding"));
+    if epg_cache::accepts_gzip(&req) {
+        response
+            .append_header((CONTENT_ENCODING, "gzip"))
+            .body(epg.gzip.clone())
+    } else {
+        response.body(epg.json.clone())
+    }
+}
+
+/// Live status of every stream that's being served
+async fn streams<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    let data = req.app_data::<web::Data<AppState<T>>>().unwrap();
+    HttpResponse::Ok().json(data.streams.list())
+}
+
+/// Stream statistics per station and per day
+async fn stats_stations<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    let stations_mutex = data.service.stations().await;
+    let stations = stations_mutex.lock().await;
+    HttpResponse::Ok().json(data.station_stats.report(|id| {
+        stations
+            .iter()
+            .find(|s| s.id.to_string() == id)
+            .map(|s| s.callSign_remapped.as_ref().unwrap_or(&s.callSign).clone())
+    }))
 }
 
-async fn watch_m3u<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+async fn streams_html<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    let data = req.app_data::<web::Data<AppState<T>>>().unwrap();
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(templates::streams_html(&data.streams.list()))
+}
+
+/// What the major network affiliates in each location are airing, for embedding in dashboards
+async fn widget_nowplaying<T: 'static + StationProvider>(req: &HttpRequest) -> NowPlaying {
+    let data = req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let mut stations = Vec::new();
+    for tuner in data.tuners.iter() {
+        stations.extend(tuner.stations().await.lock().await.iter().cloned());
+    }
+    widget::now_playing(&stations, &base_url(req))
+}
+
+async fn widget_nowplaying_html<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    let now_playing = widget_nowplaying::<T>(&req).await;
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(templates::nowplaying_html(&now_playing))
+}
+
+async fn widget_nowplaying_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(&widget_nowplaying::<T>(&req).await)
+}
+
+async fn watch_m3u<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
     let id = req.match_info().get("id").unwrap();
-    let service = &req.app_data::<web::Data<AppState<T>>>().unwrap().service;
-    match service.station_stream_uri(id).await {
-        Ok(url_mutex) => {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let service = &data.service;
+    // The media server streams from locast directly, so we can't tell when the stream ends
+    data.clients.stream(&req, id).detach();
+    // The media server connects to the stream directly, so the lease is only held while
+    // looking up the stream URI.
+    let lease = service.lease_account(id).await?;
+    let url_mutex = service.station_stream_uri(id, &lease).await?;
+    let url = url_mutex.lock().await;
+
+    Ok(HttpResponse::TemporaryRedirect()
+        .append_header((LOCATION, url.as_str()))
+        .finish())
+}
+
+/// HLS media playlist of a station. With timeshifting, it's served from the timeshift buffer of the
+/// station. Otherwise it's the playlist of locast, with segments proxied through `/segment/{token}`.
+async fn watch_m3u8<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id = req.match_info().get("id").unwrap().to_owned();
+    // Station ids are used in paths of timeshift buffers
+    if id.parse::<i64>().is_err() {
+        return Err(AppError::NotFound);
+    }
+    if !data.timeshifts.enabled() {
+        return proxied_m3u8::<T>(&req, &id).await;
+    }
+
+    let buffer = match data.timeshifts.get(&id) {
+        Some(b) => b,
+        None => {
+            if data.shutdown.draining() {
+                return Err(AppError::ShuttingDown);
+            }
+            let session = match data.sessions.start() {
+                Some(s) => s,
+                None => {
+                    warn!(
+                        "Refusing timeshift buffer for {}: all {} tuners are in use",
+                        id, data.config.tuner_count
+                    );
+                    return Err(AppError::TunersInUse);
+                }
+            };
+            let service = &data.service;
+            let lease = service.lease_account(&id).await?;
+            let url_mutex = service.station_stream_uri(&id, &lease).await?;
             let url = url_mutex.lock().await;
+            let client = data.clients.stream(&req, &id);
+            // The buffer is shared by all clients, and keeps going after this request's connection
+            // closes
+            let client_slot = data.limits.start(None).unwrap();
+            req.extensions_mut().remove::<Connection>();
+            let segments =
+                get_segments::<T>(&id, &url, req.clone(), lease, session, client_slot, client);
 
-            HttpResponse::TemporaryRedirect()
-                .append_header((LOCATION, &*url.as_str()))
-                .finish()
+            let (buffer, created) = data.timeshifts.create(&id).map_err(|e| {
+                warn!("Unable to create timeshift buffer for {}: {}", id, e);
+                AppError::InternalError
+            })?;
+            if created {
+                actix_rt::spawn(
+                    data.timeshifts
+                        .clone()
+                        .fill(buffer.clone(), Box::pin(segments)),
+                );
+            }
+            buffer
         }
-        Err(e) => e.error_response(),
+    };
+    buffer.touch();
+
+    // Clients need at least one segment to start playing
+    for _ in 0..30 {
+        if buffer.ready() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
     }
+
+    let segment_url = format!("{}/timeshift/{}", base_url(&req), id);
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .body(buffer.playlist(&segment_url)))
+}
+
+/// The media playlist of a station, with segment URIs rewritten to the segment proxy. The first poll
+/// of a client starts a session, which uses a tuner until the client stops polling. The media playlist
+/// URL is reused for a while, so clients polling the playlist don't hit the locast API every few
+/// seconds.
+async fn proxied_m3u8<T: 'static + StationProvider>(
+    req: &HttpRequest,
+    id: &str,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let client = req.peer_addr().map(|a| a.ip());
+    let (lease, started) = match data.hls.poll(client, id) {
+        Some(lease) => (lease, false),
+        None => (start_hls_session::<T>(req, id).await?, true),
+    };
+    let result = proxied_playlist::<T>(req, id, &lease).await;
+    // Don't hold a tuner for a stream that doesn't play
+    if result.is_err() && started {
+        data.hls.stop(client, id);
+    }
+    result
+}
+
+/// Start an HLS session of the client that made `req`, which counts towards `tuner_count` and the
+/// limits of the client
+async fn start_hls_session<T: 'static + StationProvider>(
+    req: &HttpRequest,
+    id: &str,
+) -> Result<Arc<AccountLease>, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    if data.shutdown.draining() {
+        warn!("Refusing stream for {}: shutting down", id);
+        return Err(AppError::ShuttingDown);
+    }
+    let client = req.peer_addr().map(|a| a.ip());
+    let client_slot = match data.limits.start(client) {
+        Some(s) => s,
+        None => {
+            warn!(
+                "Refusing stream for {}: client already has {} streams",
+                id, data.config.client_stream_limit
+            );
+            return Err(AppError::ClientStreamLimit);
+        }
+    };
+    let session = match data.sessions.start() {
+        Some(s) => s,
+        None => {
+            warn!(
+                "Refusing stream for {}: all {} tuners are in use",
+                id, data.config.tuner_count
+            );
+            return Err(AppError::TunersInUse);
+        }
+    };
+    let session = HlsSession::new(
+        session,
+        data.service.lease_account(id).await?,
+        client_slot,
+        data.clients.stream(req, id),
+    );
+    Ok(data.hls.start(client, id, session))
 }
 
-async fn watch<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+/// Fetch the media playlist of a station and rewrite it to the segment proxy
+async fn proxied_playlist<T: 'static + StationProvider>(
+    req: &HttpRequest,
+    id: &str,
+    lease: &AccountLease,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let resolve = || async {
+        let url_mutex = data.service.station_stream_uri(id, lease).await?;
+        let url = url_mutex.lock().await.clone();
+        data.hls.set_url(id, &url);
+        Ok::<_, AppError>(url)
+    };
+
+    let cached = data.hls.url(id);
+    let resolved = cached.is_none();
+    let mut url = match cached {
+        Some(url) => url,
+        None => resolve().await?,
+    };
+    let m3u_data = match fetch_playlist(&url, None).await {
+        Ok(d) => d,
+        // The cached URL might have expired, resolve it again
+        Err(e) if !resolved => {
+            debug!("Media playlist of {} failed, resolving again: {}", id, e);
+            data.hls.forget_url(id);
+            data.service.forget_stream_uri(id).await;
+            url = resolve().await?;
+            fetch_playlist(&url, None)
+                .await
+                .map_err(|_| AppError::UpstreamError)?
+        }
+        Err(_) => return Err(AppError::UpstreamError),
+    };
+
+    let segment_base = format!("{}/segment", base_url(req));
+    let playlist = data
+        .hls
+        .rewrite(&m3u_data, id, &url, &segment_base)
+        .map_err(|e| {
+            warn!("Unable to rewrite media playlist of {}: {}", id, e);
+            AppError::UpstreamError
+        })?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .body(playlist))
+}
+
+/// A segment of a proxied media playlist. Segments are only served to clients with a session for the
+/// station, and count towards their bandwidth.
+async fn segment<T: 'static + StationProvider>(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let (id, url) = data.hls.verify(req.match_info().get("token").unwrap())?;
+    let client = req.peer_addr().map(|a| a.ip());
+    if data.hls.poll(client, &id).is_none() {
+        return Err(AppError::NotFound);
+    }
+    let response = crate::utils::get(&url, None, 10)
+        .await
+        .and_then(|r| r.error_for_status().map_err(HttpError::from))
+        .map_err(|e| {
+            warn!("Unable to fetch segment {}: {}", url, e);
+            AppError::UpstreamError
+        })?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("video/mp2t")
+        .to_owned();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|_| AppError::UpstreamError)?;
+    let delay = data
+        .hls
+        .throttle(client, &id, bytes.len())
+        .ok_or(AppError::NotFound)?;
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    Ok(HttpResponse::Ok().content_type(content_type).body(bytes))
+}
+
+/// A segment of a timeshift buffer
+async fn timeshift_segment<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
     let id = req.match_info().get("id").unwrap();
-    let service = &req.app_data::<web::Data<AppState<T>>>().unwrap().service;
-    match service.station_stream_uri(id).await {
-        Ok(url_mutex) => {
-            let url = url_mutex.lock().await;
-            let stream = get_stream::<T>(&*url, req);
+    let sequence = req
+        .match_info()
+        .get("sequence")
+        .unwrap()
+        .parse::<u64>()
+        .map_err(|_| AppError::NotFound)?;
+    let buffer = data.timeshifts.get(id).ok_or(AppError::NotFound)?;
+    buffer.touch();
+    let path = buffer.segment_path(sequence).ok_or(AppError::NotFound)?;
+    recordings::serve(&req, path, false).await
+}
+
+async fn watch<T: 'static + StationProvider>(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let id = req.match_info().get("id").unwrap().to_owned();
+    stream_station::<T>(req, &id).await
+}
+
+/// Stream a station through one of the tuners
+async fn stream_station<T: 'static + StationProvider>(
+    req: HttpRequest,
+    id: &str,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+
+    // Refuse new streams while shutting down
+    if data.shutdown.draining() {
+        warn!("Refusing stream for {}: shutting down", id);
+        return Err(AppError::ShuttingDown);
+    }
+
+    // Refuse the stream if the client already has `client_stream_limit` streams
+    let client_slot = match data.limits.start(req.peer_addr().map(|a| a.ip())) {
+        Some(s) => s,
+        None => {
+            warn!(
+                "Refusing stream for {}: client already has {} streams",
+                id, data.config.client_stream_limit
+            );
+            return Err(AppError::ClientStreamLimit);
+        }
+    };
+
+    // Refuse the stream if we're already serving `tuner_count` streams
+    let session = match data.sessions.start() {
+        Some(s) => s,
+        None => {
+            warn!(
+                "Refusing stream for {}: all {} tuners are in use",
+                id, data.config.tuner_count
+            );
+            return Err(AppError::TunersInUse);
+        }
+    };
+
+    let service = &data.service;
+    let tuned: Result<_, AppError> = async {
+        let lease = service.lease_account(id).await?;
+        let url_mutex = service.station_stream_uri(id, &lease).await?;
+        Ok((lease, url_mutex))
+    }
+    .await;
+    let (lease, url_mutex) = match tuned {
+        Ok(t) => t,
+        Err(e) => {
+            data.station_stats.tune_failed(id);
+            return Err(e);
+        }
+    };
+    let url = url_mutex.lock().await;
+    let client = data.clients.stream(&req, id);
+    let stream = get_stream::<T>(id, &url, req.clone(), lease, session, client_slot, client);
+
+    Ok(HttpResponse::Ok()
+        .content_type("video/mpeg; codecs='avc1.4D401E'")
+        .streaming(Box::pin(stream)))
+}
+
+#[derive(Deserialize)]
+struct RecordQuery {
+    /// Seconds to record
+    duration: u64,
+}
+
+/// A recording that was started, as returned by `/record/{id}`
+#[derive(Serialize)]
+struct RecordingStarted {
+    name: String,
+    station_id: i64,
+    duration: u64,
+    /// Unix timestamp of the time the recording ends
+    ends: i64,
+}
+
+/// Record a station for `duration` seconds. The recording uses one of the tuners while it's running.
+async fn record<T: 'static + StationProvider>(
+    req: HttpRequest,
+    query: web::Query<RecordQuery>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id = req.match_info().get("id").unwrap().to_owned();
+    if query.duration == 0 {
+        return Err(AppError::BadRequest(
+            "duration has to be at least 1 second".to_owned(),
+        ));
+    }
+    if data.shutdown.draining() {
+        return Err(AppError::ShuttingDown);
+    }
 
-            HttpResponse::Ok()
-                .content_type("video/mpeg; codecs='avc1.4D401E'")
-                .streaming(Box::pin(stream))
+    let station = {
+        let stations_mutex = data.service.stations().await;
+        let stations = stations_mutex.lock().await;
+        match stations.iter().find(|s| s.id.to_string() == id) {
+            Some(s) => s.clone(),
+            None => return Err(AppError::NotFound),
         }
-        Err(e) => e.error_response(),
+    };
+
+    let session = match data.sessions.start() {
+        Some(s) => s,
+        None => {
+            warn!(
+                "Refusing recording of {}: all {} tuners are in use",
+                id, data.config.tuner_count
+            );
+            return Err(AppError::TunersInUse);
+        }
+    };
+    let service = &data.service;
+    let lease = service.lease_account(&id).await?;
+    let url_mutex = service.station_stream_uri(&id, &lease).await?;
+    let url = url_mutex.lock().await;
+    let client = data.clients.stream(&req, &id);
+    // Recordings aren't limited per client, and keep going after this request's connection closes
+    let client_slot = data.limits.start(None).unwrap();
+    req.extensions_mut().remove::<Connection>();
+    let stream = get_stream::<T>(&id, &url, req.clone(), lease, session, client_slot, client);
+
+    let started = Utc::now();
+    let call_sign = station
+        .callSign_remapped
+        .as_ref()
+        .unwrap_or(&station.callSign);
+    let name = recordings::name(call_sign, started);
+    actix_rt::spawn(recordings::record(
+        data.config.clone(),
+        name.clone(),
+        Box::pin(stream),
+        std::time::Duration::from_secs(query.duration),
+    ));
+    data.audit.record(
+        &req,
+        "record",
+        &format!("channel.{}", station.id),
+        None::<Value>,
+        Some(serde_json::json!({ "name": name, "duration": query.duration })),
+    );
+
+    Ok(HttpResponse::Accepted().json(&RecordingStarted {
+        name,
+        station_id: station.id,
+        duration: query.duration,
+        ends: started.timestamp() + query.duration as i64,
+    }))
+}
+
+/// Completed recordings
+async fn recordings_json<T: 'static + StationProvider>(
+    data: web::Data<AppState<T>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(recordings::list(&data.config))
+}
+
+#[derive(Deserialize)]
+struct RecordingQuery {
+    format: Option<String>,
+}
+
+/// Download a recording, either as is (resumable) or remuxed to MP4 with `?format=mp4`
+async fn recording<T: 'static + StationProvider>(
+    req: HttpRequest,
+    query: web::Query<RecordingQuery>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let path = recordings::path(&data.config, req.match_info().get("name").unwrap())?;
+    match query.format.as_deref() {
+        None | Some("ts") => recordings::serve(&req, path, true).await,
+        Some("mp4") => recordings::serve_mp4(path),
+        Some(f) => Err(AppError::BadRequest(format!(
+            "Unknown format {}, use ts or mp4",
+            f
+        ))),
+    }
+}
+
+async fn recording_delete<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let name = req.match_info().get("name").unwrap();
+    let path = recordings::path(&data.config, name)?;
+    recordings::delete(&path)?;
+    data.audit
+        .record(&req, "recording_remove", name, None::<Value>, None::<Value>);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Create a link that gives access to a single station until it expires
+async fn share<T: 'static + StationProvider>(
+    req: HttpRequest,
+    request: web::Json<ShareRequest>,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let stations_mutex = data.service.stations().await;
+    if !stations_mutex
+        .lock()
+        .await
+        .iter()
+        .any(|s| s.id == request.station_id)
+    {
+        return Err(AppError::NotFound);
+    }
+
+    let expires = share::expires(
+        Utc::now().timestamp(),
+        request.expires_in.unwrap_or(data.config.share_expiry),
+    )?;
+    let token = data.shares.token(request.station_id, expires);
+    let base_url = base_url(&req);
+    info!("Shared station {} until {}", request.station_id, expires);
+    data.audit.record(
+        &req,
+        "share",
+        &format!("channel.{}", request.station_id),
+        None::<Value>,
+        Some(serde_json::json!({ "expires": expires })),
+    );
+    Ok(HttpResponse::Created().json(&ShareLink {
+        station_id: request.station_id,
+        url: format!("{}/share/{}", base_url, token),
+        m3u_url: format!("{}/share/{}.m3u", base_url, token),
+        expires,
+    }))
+}
+
+/// Playlist with just the shared station
+async fn share_m3u<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let token = req.match_info().get("token").unwrap();
+    let station_id = data.shares.verify(token)?;
+    let stations_mutex = data.service.stations().await;
+    let stations = stations_mutex.lock().await;
+    let station = match stations.iter().find(|s| s.id == station_id) {
+        Some(s) => s,
+        None => return Err(AppError::NotFound),
+    };
+    let url = format!("{}/share/{}", base_url(&req), token);
+    Ok(HttpResponse::Ok()
+        .content_type("audio/x-mpegurl")
+        .body(templates::share_m3u(station, &url)))
+}
+
+/// Stream the shared station
+async fn share_watch<T: 'static + StationProvider>(
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let station_id = data.shares.verify(req.match_info().get("token").unwrap())?;
+    stream_station::<T>(req, &station_id.to_string()).await
+}
+
+/// Station a request is for, for the routes that take a station id
+fn station_id(req: &HttpRequest) -> Option<&str> {
+    let path = relative_path(req.path());
+    let station_route = ["/watch/", "/preview/", "/record/", "/timeshift/"]
+        .iter()
+        .any(|p| path.starts_with(p));
+    match req.match_info().get("station_id") {
+        Some(id) => Some(id),
+        None if station_route => req.match_info().get("id"),
+        None => None,
+    }
+}
+
+/// Header with the id of a request, which is taken from the request if the client sent one
+static REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Id of a request, used to find its traces. Returned in the `X-Request-Id` header.
+#[derive(Clone)]
+struct RequestId(String);
+
+impl RequestId {
+    fn of(req: &ServiceRequest) -> RequestId {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .filter(|h| !h.is_empty() && h.len() <= 64);
+        RequestId(match id {
+            Some(id) => id.to_owned(),
+            None => Uuid::new_v4().to_string()[0..8].to_string(),
+        })
     }
 }
 
 struct StreamState {
+    station_id: String,
     segments: VecDeque<Segment>,
+    limits: Option<BufferLimits>,
+    /// Downloads of the segments that are served next
+    prefetcher: Prefetcher,
     url: String,
     stream_id: String,
     start_time: DateTime<Utc>,
     seconds_served: f32,
+    /// Start of the timing that segments are paced by. Reset at discontinuities, where the durations
+    /// of the segments before and after don't add up.
+    pace_start: DateTime<Utc>,
+    /// Seconds of video served since `pace_start`
+    pace_seconds: f32,
     req: HttpRequest,
     count_down: f32,
+    lease: AccountLease,
+    /// Connection of the client, to stop streaming as soon as the client goes away
+    connection: Option<Connection>,
+    bytes_served: usize,
+    segments_served: usize,
+    /// When the last segment was served, to end the stream when no new segments show up
+    last_served: DateTime<Utc>,
+    /// Playlist fetches in a row that didn't add any segments
+    stale_fetches: u64,
+    /// Whether the stream URL was resolved again because the playlist went stale
+    reresolved: bool,
+    /// Whether the variant of the current stream URL was looked up
+    variant_known: bool,
+    /// Bytes downloaded from locast and how long that took, to measure the throughput
+    downloaded_bytes: usize,
+    download_time: std::time::Duration,
+    /// Why the stream ended. Streams that are dropped by actix ended because writing to the client
+    /// failed.
+    end_reason: &'static str,
+    /// Limits of the client this is streamed to
+    client_slot: ClientSlot,
+    /// Live status, as reported at `/streams`
+    status: StreamHandle,
+    station_stats: Arc<StationStats>,
+    /// Span of the whole stream, which its playlist fetches and segment downloads are children of
+    span: Span,
+    _session: StreamSession,
+    _client: ClientStream,
+}
+
+impl StreamState {
+    /// Resolve the stream URL of the station again, which gets a fresh master playlist from locast.
+    /// Returns false if that fails.
+    async fn resolve_url<T: 'static + StationProvider>(&mut self) -> bool {
+        let service = &self
+            .req
+            .app_data::<web::Data<AppState<T>>>()
+            .unwrap()
+            .service;
+        service.forget_stream_uri(&self.station_id).await;
+        match service
+            .station_stream_uri(&self.station_id, &self.lease)
+            .await
+        {
+            Ok(url_mutex) => {
+                let url = url_mutex.lock().await;
+                debug!("Stream {} - New URL: {}", self.stream_id, &*url);
+                self.url = url.to_owned();
+                self.count_down = COUNT_DOWN;
+                self.variant_known = false;
+                let url = self.url.clone();
+                self.status.update(|s| s.playlist_url = url);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Report the variant of the master playlist that the stream URL is for
+    async fn update_variant<T: 'static + StationProvider>(&mut self) {
+        let service = &self
+            .req
+            .app_data::<web::Data<AppState<T>>>()
+            .unwrap()
+            .service;
+        let variant = service.stream_variant(&self.station_id).await;
+        match &variant {
+            Some(v) => debug!(
+                "Stream {} - streaming variant of {} bps ({}) from {}",
+                self.stream_id,
+                v.bandwidth,
+                v.resolution.as_deref().unwrap_or("unknown resolution"),
+                self.url
+            ),
+            None => debug!("Stream {} - streaming {}", self.stream_id, self.url),
+        }
+        self.status.update(|s| s.variant = variant);
+        self.variant_known = true;
+    }
+
+    /// End the stream for `reason`
+    fn end<R>(&mut self, reason: &'static str) -> Option<R> {
+        self.end_reason = reason;
+        None
+    }
+
+    /// Returns true if no segment has been served for longer than `timeout` seconds (0 disables)
+    fn idle(&self, timeout: u64) -> bool {
+        timeout > 0 && (Utc::now() - self.last_served).num_seconds() >= timeout as i64
+    }
+
+    /// Sleep for `duration`, waking up every second to check if the client is still there. Returns
+    /// false if the client disconnected.
+    async fn sleep(&self, duration: std::time::Duration) -> bool {
+        let step = std::time::Duration::from_secs(1);
+        let deadline = tokio::time::Instant::now() + duration;
+        loop {
+            if self.connection.as_ref().is_some_and(|c| c.closed()) {
+                return false;
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            tokio::time::sleep(step.min(deadline - now)).await;
+        }
+    }
+}
+
+impl Drop for StreamState {
+    fn drop(&mut self) {
+        self.span
+            .set_attribute("stream.end_reason", self.end_reason.to_owned());
+        self.span
+            .set_attribute("stream.bytes", self.bytes_served.to_string());
+        self.span
+            .set_attribute("stream.segments", self.segments_served.to_string());
+        self.span
+            .set_attribute("stream.seconds", self.seconds_served.to_string());
+        let failed = !matches!(self.end_reason, "client disconnected" | "shutting down");
+        if failed {
+            notify::stream_failed();
+        }
+        self.station_stats.ended(
+            &self.station_id,
+            (Utc::now() - self.start_time).num_seconds().max(0) as u64,
+            failed,
+        );
+        info!(
+            "Stream {} - ended ({}) after {}s, served {} bytes in {} segments",
+            self.stream_id,
+            self.end_reason,
+            (Utc::now() - self.start_time).num_seconds(),
+            self.bytes_served,
+            self.segments_served
+        );
+    }
 }
 
 static COUNT_DOWN: f32 = 9900.0; // 2:45h
 fn get_stream<T: 'static + StationProvider>(
+    station_id: &str,
     url: &str,
     req: HttpRequest,
+    lease: AccountLease,
+    session: StreamSession,
+    client_slot: ClientSlot,
+    client: ClientStream,
 ) -> impl Stream<Item = Result<bytes::Bytes, Error>> {
+    get_segments::<T>(station_id, url, req, lease, session, client_slot, client)
+        .map(|segment| segment.map(|s| s.bytes))
+}
+
+/// The segments of a station, as they're served by `get_stream`
+fn get_segments<T: 'static + StationProvider>(
+    station_id: &str,
+    url: &str,
+    req: HttpRequest,
+    lease: AccountLease,
+    session: StreamSession,
+    client_slot: ClientSlot,
+    client: ClientStream,
+) -> impl Stream<Item = Result<ServedSegment, Error>> {
     // Build helper struct
+    let connection = Connection::of(&req);
+    let stream_id = Uuid::new_v4().to_string()[0..7].to_string();
+    let data = req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let status = data.streams.register(StreamStatus::new(
+        &req,
+        &stream_id,
+        station_id,
+        data.device_name(),
+        lease.username(),
+        url,
+    ));
+    let mut span = Span::new("stream", Some(&stream_id));
+    span.set_attribute("station.id", station_id.to_owned());
+    span.set_attribute("stream.device", data.device_name());
+    span.set_attribute("stream.account", lease.username().to_owned());
+    if let Some(request_id) = req.extensions().get::<RequestId>() {
+        span.set_attribute("http.request_id", request_id.0.clone());
+    }
+    if let Some(addr) = req.peer_addr() {
+        span.set_attribute("client.ip", addr.ip().to_string());
+    }
+    data.station_stats.tuned(station_id);
+    let station_stats = data.station_stats.clone();
     let state = StreamState {
+        station_id: station_id.to_owned(),
         segments: VecDeque::new(),
+        limits: None,
+        prefetcher: Prefetcher::new(data.config.segment_prefetch),
         url: url.to_owned(),
-        stream_id: Uuid::new_v4().to_string()[0..7].to_string(),
+        stream_id,
         start_time: Utc::now(),
         seconds_served: 0.0,
+        pace_start: Utc::now(),
+        pace_seconds: 0.0,
         count_down: COUNT_DOWN,
         req,
+        lease,
+        connection,
+        bytes_served: 0,
+        segments_served: 0,
+        last_served: Utc::now(),
+        stale_fetches: 0,
+        reresolved: false,
+        variant_known: false,
+        downloaded_bytes: 0,
+        download_time: std::time::Duration::ZERO,
+        end_reason: "client disconnected",
+        client_slot,
+        status,
+        station_stats,
+        span,
+        _session: session,
+        _client: client,
     };
+    info!(
+        "Stream {} - using locast account {}",
+        state.stream_id,
+        state.lease.username()
+    );
 
     stream::unfold(state, |mut state| async move {
-        // Refresh initial URL if we've been streaming for `COUNTDOWN seconds`
-        if state.count_down < 0.0 {
-            debug!("Stream {} -  URL expired: {}", state.stream_id, state.url);
-
-            // Get the service and stream id from the state
-            let id = state.req.match_info().get("id").unwrap();
-            let service = &state
+        let idle_timeout = state
+            .req
+            .app_data::<web::Data<AppState<T>>>()
+            .unwrap()
+            .config
+            .stream_idle_timeout;
+        loop {
+            // End the stream at a segment boundary when shutting down
+            let shutdown = &state
                 .req
                 .app_data::<web::Data<AppState<T>>>()
                 .unwrap()
-                .service;
-
-            // Grab a new URL for this stream. If this fails, we end the stream.
-            match service.station_stream_uri(id).await {
-                Ok(url_mutex) => {
-                    let url = url_mutex.lock().await;
-                    debug!("Stream {} - New URL: {}", state.stream_id, &*url);
-                    state.url = (&*url).to_owned();
-                    state.count_down = COUNT_DOWN;
-                }
-                Err(_) => return None,
+                .shutdown;
+            if shutdown.stopping() {
+                info!(
+                    "Stream {} - shutting down, stopping stream",
+                    state.stream_id
+                );
+                return state.end("shutting down");
+            }
+            if state.connection.as_ref().is_some_and(|c| c.closed()) {
+                return state.end("client disconnected");
             }
-        }
 
-        let m3u_data = match crate::utils::get(&state.url, None, 5).await {
-            Err(e) => {
-                warn!("Unable to get m3u data, stopping stream.. {}", e);
-                return None;
+            // Refresh initial URL if we've been streaming for `COUNTDOWN seconds`
+            if state.count_down < 0.0 {
+                debug!("Stream {} -  URL expired: {}", state.stream_id, state.url);
+
+                // Grab a new URL for this stream. If this fails, we end the stream.
+                if !state.resolve_url::<T>().await {
+                    return state.end("unable to refresh the stream URL");
+                }
             }
-            Ok(r) => r.text().await.unwrap(),
-        };
 
-        let media_playlist = match hls_m3u8::MediaPlaylist::try_from(m3u_data.as_str()) {
-            Ok(p) => p,
-            Err(e) => {
-                warn!("Unable to fetch media playlist, stopping stream.. {}", e);
-                return None;
+            if !state.variant_known {
+                state.update_variant::<T>().await;
             }
-        };
 
-        for media_segment in media_playlist.segments {
-            let (_i, ms) = media_segment;
-            let absolute_uri = match Url::parse(&state.url) {
-                Ok(u) => u,
+            let m3u_data = match fetch_playlist(&state.url, Some(&state.span)).await {
+                Ok(d) => d,
                 Err(e) => {
-                    warn!("Unable to parse url! {}", e);
-                    return None;
+                    // The stream URL might have expired. Grab a new URL and retry once before giving
+                    // up. If locast rejected the token, login again first.
+                    warn!(
+                        "Stream {} - Unable to get m3u data, retrying.. {}",
+                        state.stream_id, e
+                    );
+                    state.status.update(|s| s.stalls += 1);
+                    state.span.add_event("stall");
+                    if e.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
+                        if let Err(e) = state.lease.relogin().await {
+                            warn!("Stream {} - Unable to log in again: {}", state.stream_id, e);
+                            return state.end("unable to log in");
+                        }
+                    }
+                    if !state.resolve_url::<T>().await {
+                        return state.end("unable to refresh the stream URL");
+                    }
+
+                    match fetch_playlist(&state.url, Some(&state.span)).await {
+                        Ok(d) => d,
+                        Err(e) => {
+                            warn!("Unable to get m3u data, stopping stream.. {}", e);
+                            return state.end("unable to get the playlist");
+                        }
+                    }
                 }
-            }
-            .join(ms.uri())
-            .unwrap();
+            };
 
-            let s = Segment {
-                url: absolute_uri.to_string(),
-                played: false,
-                duration: ms.duration.duration(),
+            let playlist = match media_playlist(&m3u_data, &state.url) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("{}, stopping stream..", e);
+                    return state.end("invalid playlist");
+                }
             };
-            if !state.segments.contains(&s) {
-                info!("Stream {} - added segment {:?}", state.stream_id, &s.url);
-                state.segments.push_back(s);
+
+            let config = &state
+                .req
+                .app_data::<web::Data<AppState<T>>>()
+                .unwrap()
+                .config;
+            let limits = BufferLimits::new(
+                playlist.target_duration,
+                playlist.segments.len(),
+                config.segment_buffer_min,
+                config.segment_buffer_max,
+            );
+            if state.limits != Some(limits) {
+                debug!(
+                    "Stream {} - buffering up to {} segments, draining {} (target duration {}s, window {})",
+                    state.stream_id,
+                    limits.max,
+                    limits.drain,
+                    playlist.target_duration.as_secs_f32(),
+                    playlist.segments.len()
+                );
+                state.limits = Some(limits);
             }
-        }
 
-        if state.segments.len() >= 30 {
-            info!("Stream {} - draining 10 segments", state.stream_id);
-            state.segments.drain(0..10);
-        }
+            let mut added = 0;
+            for s in playlist.segments {
+                if !state.segments.contains(&s) {
+                    info!("Stream {} - added segment {:?}", state.stream_id, &s.url);
+                    state.segments.push_back(s);
+                    added += 1;
+                }
+            }
 
-        // Find first unplayed segment
-        let first = match state.segments.iter_mut().find(|s| !s.played) {
-            Some(s) => s,
-            None => {
-                warn!("No first segment found. Stopping stream..");
-                return None;
+            // Locast sometimes keeps serving a stale playlist. When that happens, resolve the
+            // stream URL again to get a fresh master playlist. If that doesn't help either, give up.
+            if added > 0 {
+                state.stale_fetches = 0;
+                state.reresolved = false;
+            } else {
+                state.stale_fetches += 1;
+            }
+            let watchdog_fetches = config.stream_watchdog_fetches;
+            if watchdog_fetches > 0 && state.stale_fetches >= watchdog_fetches {
+                if state.reresolved {
+                    warn!(
+                        "Stream {} - playlist is still stale after resolving the stream again, stopping stream..",
+                        state.stream_id
+                    );
+                    return state.end("stale playlist");
+                }
+                warn!(
+                    "Stream {} - no new segments in {} playlist fetches, resolving the stream again",
+                    state.stream_id, state.stale_fetches
+                );
+                if !state.resolve_url::<T>().await {
+                    return state.end("unable to refresh the stream URL");
+                }
+                state.stale_fetches = 0;
+                state.reresolved = true;
+                continue;
             }
-        };
 
-        let runtime = Utc::now() - state.start_time;
-        let target_diff = 0.5 * first.duration.as_secs_f32();
+            let drained = limits.apply(&mut state.segments);
+            if drained > 0 {
+                info!("Stream {} - drained {} segments", state.stream_id, drained);
+            }
 
-        let wait = if state.seconds_served > 0.0 {
-            state.seconds_served - target_diff - (runtime.num_milliseconds() as f32 / 1000.0)
-        } else {
-            0.0
-        };
+            // Find first unplayed segment. If there isn't one, wait for the playlist to catch up,
+            // unless we've been waiting for longer than the idle timeout.
+            let first_index = match state.segments.iter().position(|s| !s.played) {
+                Some(i) => i,
+                None if state.idle(idle_timeout) => {
+                    warn!(
+                        "Stream {} - no new segments for {}s, stopping stream..",
+                        state.stream_id, idle_timeout
+                    );
+                    return state.end("idle timeout");
+                }
+                None => {
+                    debug!("Stream {} - no new segments yet", state.stream_id);
+                    state.status.update(|s| s.underruns += 1);
+                    state.span.add_event("underrun");
+                    if !state.sleep(playlist.target_duration / 2).await {
+                        return state.end("client disconnected");
+                    }
+                    continue;
+                }
+            };
+            state.prefetcher.prefetch(&state.segments);
+            let first = &state.segments[first_index];
+            let (url, duration) = (first.url.clone(), first.duration);
+            // The first segment of a stream has nothing to be discontinuous with
+            let discontinuity = first.discontinuity && state.segments_served > 0;
+            if discontinuity {
+                info!(
+                    "Stream {} - discontinuity at segment {:?}, resetting timing",
+                    state.stream_id, &url
+                );
+                state.pace_start = Utc::now();
+                state.pace_seconds = 0.0;
+                state.span.add_event("discontinuity");
+            }
 
-        info!(
-            "Serving {} ({} s) in {}s",
-            &first.url,
-            first.duration.as_secs_f32(),
-            wait
-        );
+            let runtime = Utc::now() - state.pace_start;
+            let target_diff = 0.5 * duration.as_secs_f32();
 
-        if wait > 0.0 {
-            tokio::time::sleep(tokio::time::Duration::from_secs_f32(wait)).await;
-        }
+            let wait = if state.pace_seconds > 0.0 {
+                state.pace_seconds - target_diff - (runtime.num_milliseconds() as f32 / 1000.0)
+            } else {
+                0.0
+            };
 
-        let chunk = match crate::utils::get(&first.url, None, 10).await {
-            Err(e) => {
-                warn!("No bytes fetched.. Stopping stream.. {}", e);
-                return None;
+            info!(
+                "Serving {} ({} s) in {}s",
+                &url,
+                duration.as_secs_f32(),
+                wait
+            );
+
+            if wait > 0.0 && !state.sleep(std::time::Duration::from_secs_f32(wait)).await {
+                return state.end("client disconnected");
             }
-            Ok(r) => r.bytes().await.unwrap().to_vec(),
-        };
 
-        first.played = true;
-        info!(
-            "Stream {} - playing: segment {:?}",
-            state.stream_id, first.url
-        );
+            let mut span = state.span.child("segment_download");
+            span.set_attribute("stream.id", state.stream_id.clone());
+            span.set_attribute("segment.url", url.clone());
+            span.set_attribute("segment.duration", duration.as_secs_f32().to_string());
+            span.set_attribute("segment.wait", wait.max(0.0).to_string());
+            let fetch_start = std::time::Instant::now();
+            let mut chunk = match state.prefetcher.fetch(&url).await {
+                Err(e) if state.idle(idle_timeout) => {
+                    warn!("No bytes fetched.. Stopping stream.. {}", e);
+                    return state.end("unable to get segments");
+                }
+                Err(e) => {
+                    warn!(
+                        "Stream {} - no bytes fetched, retrying.. {}",
+                        state.stream_id, e
+                    );
+                    state.status.update(|s| s.stalls += 1);
+                    state.span.add_event("stall");
+                    continue;
+                }
+                Ok(download) => {
+                    state.downloaded_bytes += download.bytes.len();
+                    state.download_time += download.duration;
+                    debug!(
+                        "Stream {} - downloaded {} bytes in {}ms",
+                        state.stream_id,
+                        download.bytes.len(),
+                        download.duration.as_millis()
+                    );
+                    download.bytes.to_vec()
+                }
+            };
+            span.set_attribute("segment.bytes", chunk.len().to_string());
+            span.end();
+            state
+                .station_stats
+                .segment(&state.station_id, fetch_start.elapsed());
+            if discontinuity {
+                mark_discontinuity(&mut chunk);
+            }
+
+            state.segments[first_index].played = true;
+            state.last_served = Utc::now();
+            state.bytes_served += chunk.len();
+            state.segments_served += 1;
+            info!("Stream {} - playing: segment {:?}", state.stream_id, url);
+
+            state.seconds_served += duration.as_secs_f32();
+            state.pace_seconds += duration.as_secs_f32();
+            state.count_down -= duration.as_secs_f32();
+            let throughput_kbps = match state.download_time.as_secs_f64() {
+                t if t > 0.0 => (state.downloaded_bytes as f64 * 8.0 / 1000.0 / t) as u64,
+                _ => 0,
+            };
+            state.status.update(|s| {
+                s.seconds_served = state.seconds_served;
+                s.bytes_served = state.bytes_served;
+                s.segments_served = state.segments_served;
+                s.current_segment = Some(url.clone());
+                s.throughput_kbps = throughput_kbps;
+            });
 
-        state.seconds_served += first.duration.as_secs_f32();
-        state.count_down -= first.duration.as_secs_f32();
-        Some((Ok(actix_web::web::Bytes::from(chunk)), state))
+            // Keep the client within its bandwidth
+            let delay = state.client_slot.throttle(chunk.len());
+            if !delay.is_zero() {
+                debug!(
+                    "Stream {} - throttling segment for {}s",
+                    state.stream_id,
+                    delay.as_secs_f32()
+                );
+                if !state.sleep(delay).await {
+                    return state.end("client disconnected");
+                }
+            }
+            let segment = ServedSegment {
+                bytes: actix_web::web::Bytes::from(chunk),
+                duration,
+                discontinuity,
+            };
+            return Some((Ok(segment), state));
+        }
     })
 }
 
-async fn lineup_post(_req: HttpRequest) -> impl Responder {
-    HttpResponse::NoContent()
+/// Fetch a media playlist, traced as a child of the span of its stream (if any). Unsuccessful HTTP
+/// responses are treated as errors.
+async fn fetch_playlist(url: &str, stream: Option<&Span>) -> Result<String, HttpError> {
+    let _span = match stream {
+        Some(stream) => stream.child("playlist_fetch"),
+        None => Span::new("playlist_fetch", None),
+    };
+    crate::utils::get(url, None, 5)
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+        .map_err(HttpError::from)
 }
 
-#[derive(Debug)]
-struct Segment {
-    url: String,
-    played: bool,
-    duration: std::time::Duration,
+#[derive(Deserialize)]
+struct LineupPostQuery {
+    scan: Option<String>,
 }
-impl PartialEq for Segment {
-    fn eq(&self, other: &Self) -> bool {
-        self.url == other.url
+
+/// Start (`scan=start`) or abort (`scan=abort`) a channel scan, which refreshes the stations of the
+/// tuner from locast
+async fn lineup_post<T: 'static + StationProvider>(
+    req: HttpRequest,
+    query: web::Query<LineupPostQuery>,
+) -> Result<HttpResponse, AppError> {
+    let data = req.app_data::<web::Data<AppState<T>>>().unwrap();
+    match query.scan.as_deref() {
+        Some("start") if !data.station_scan.start(data.service.services()) => {
+            debug!("Channel scan already in progress")
+        }
+        Some("start") => {}
+        Some("abort") => data.station_scan.abort(),
+        Some(other) => {
+            return Err(AppError::BadRequest(format!(
+                "Unknown scan action: {}",
+                other
+            )))
+        }
+        None => {}
     }
+    Ok(HttpResponse::NoContent().finish())
 }
@@ -0,0 +1,308 @@
+use crate::{config::Config, service::stationprovider::StationProvider};
+use actix_web::rt::net::TcpStream;
+use chrono::Utc;
+use futures::{select, FutureExt};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{interval, sleep, timeout},
+};
+
+/// How often the state is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Keep alive interval negotiated with the broker. A ping is sent every half interval.
+const KEEP_ALIVE: u16 = 60;
+/// Wait this long before reconnecting to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Give up connecting after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publish the state of locast2tuner to an MQTT broker, with Home Assistant discovery topics so it
+/// shows up as a device with sensors for the active streams, tuners in use, guide age and the
+/// availability of every location. `active_streams` returns the amount of streams that are being
+/// served and `tuner_count` is the total amount of tuners of all devices.
+pub async fn publish<T, F>(
+    config: Arc<Config>,
+    services: Vec<T>,
+    active_streams: F,
+    tuner_count: usize,
+) where
+    T: 'static + StationProvider,
+    F: Fn() -> usize,
+{
+    let broker = match &config.mqtt_broker {
+        Some(b) => b.clone(),
+        None => return,
+    };
+    let topics = Topics::new(&config);
+    loop {
+        match run(
+            &broker,
+            &config,
+            &topics,
+            &services,
+            &active_streams,
+            tuner_count,
+        )
+        .await
+        {
+            Ok(()) => return,
+            Err(e) => warn!(
+                "MQTT connection to {} failed, reconnecting in {}s: {}",
+                broker,
+                RECONNECT_DELAY.as_secs(),
+                e
+            ),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect, announce the entities and publish the state whenever it changes, until the connection
+/// fails
+async fn run<T: StationProvider, F: Fn() -> usize>(
+    broker: &str,
+    config: &Config,
+    topics: &Topics,
+    services: &[T],
+    active_streams: &F,
+    tuner_count: usize,
+) -> io::Result<()> {
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(broker))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out connecting"))??;
+    stream
+        .write_all(&connect_packet(config, &topics.availability))
+        .await?;
+    let mut connack = [0; 4];
+    timeout(CONNECT_TIMEOUT, stream.read_exact(&mut connack))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no CONNACK"))??;
+    if connack[0] != 0x20 || connack[3] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("broker refused the connection (code {})", connack[3]),
+        ));
+    }
+    info!("Connected to MQTT broker {}", broker);
+
+    let cities = cities(services).await;
+    for (topic, payload) in topics.discovery(config, &cities) {
+        stream
+            .write_all(&publish_packet(&topic, &payload.to_string(), true))
+            .await?;
+    }
+    stream
+        .write_all(&publish_packet(&topics.availability, "online", true))
+        .await?;
+
+    let (mut reader, mut writer) = stream.into_split();
+    let mut last_state = Value::Null;
+    let mut poll = interval(POLL_INTERVAL);
+    let mut ping = interval(Duration::from_secs(KEEP_ALIVE as u64 / 2));
+    let mut buffer = [0; 256];
+    loop {
+        select! {
+            _ = poll.tick().fuse() => {
+                let state = state(config, services, active_streams(), tuner_count).await;
+                if state != last_state {
+                    writer
+                        .write_all(&publish_packet(&topics.state, &state.to_string(), true))
+                        .await?;
+                    last_state = state;
+                }
+            }
+            _ = ping.tick().fuse() => writer.write_all(&[0xc0, 0x00]).await?,
+            // The broker only sends ping responses. Drain them, and notice when it goes away.
+            read = reader.read(&mut buffer).fuse() => {
+                if read? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "broker closed the connection",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Locations by DMA
+async fn cities<T: StationProvider>(services: &[T]) -> BTreeMap<String, String> {
+    let mut cities = BTreeMap::new();
+    for service in services {
+        for status in service.status().await {
+            cities.insert(status.dma, status.name);
+        }
+    }
+    cities
+}
+
+/// State of locast2tuner, as published to the state topic. A location is available if its stations
+/// were refreshed in time.
+async fn state<T: StationProvider>(
+    config: &Config,
+    services: &[T],
+    active_streams: usize,
+    tuner_count: usize,
+) -> Value {
+    let now = Utc::now().timestamp();
+    let max_age = (2 * config.cache_timeout + config.refresh_jitter) as i64;
+    let mut guide_age = 0;
+    let mut cities = BTreeMap::new();
+    for service in services {
+        for status in service.status().await {
+            let age = now - status.last_refresh;
+            guide_age = guide_age.max(age);
+            let available = status.stations > 0 && age <= max_age;
+            cities.insert(status.dma, if available { "ON" } else { "OFF" });
+        }
+    }
+    json!({
+        "active_streams": active_streams,
+        "tuners_in_use": (active_streams * 100).checked_div(tuner_count).unwrap_or(0),
+        "guide_age": guide_age,
+        "cities": cities,
+    })
+}
+
+struct Topics {
+    /// Identifies this instance in topics and unique ids
+    node_id: String,
+    state: String,
+    availability: String,
+}
+
+impl Topics {
+    fn new(config: &Config) -> Topics {
+        let node_id = format!("locast2tuner_{}", &config.uuid[..8]);
+        Topics {
+            state: format!("{}/state", node_id),
+            availability: format!("{}/availability", node_id),
+            node_id,
+        }
+    }
+
+    /// Home Assistant discovery topics and their configuration
+    fn discovery(
+        &self,
+        config: &Config,
+        cities: &BTreeMap<String, String>,
+    ) -> Vec<(String, Value)> {
+        let device = json!({
+            "identifiers": [self.node_id],
+            "name": config.device_model.clone() + " (locast2tuner)",
+            "manufacturer": "locast2tuner",
+            "model": config.device_model,
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let entity = |component: &str, object_id: &str, name: &str, template: String| {
+            (
+                format!(
+                    "{}/{}/{}/{}/config",
+                    config.mqtt_discovery_prefix, component, self.node_id, object_id
+                ),
+                json!({
+                    "name": name,
+                    "unique_id": format!("{}_{}", self.node_id, object_id),
+                    "state_topic": self.state,
+                    "availability_topic": self.availability,
+                    "value_template": template,
+                    "device": device,
+                }),
+            )
+        };
+
+        let mut entities = vec![
+            entity(
+                "sensor",
+                "active_streams",
+                "Active streams",
+                "{{ value_json.active_streams }}".to_owned(),
+            ),
+            entity(
+                "sensor",
+                "tuners_in_use",
+                "Tuners in use",
+                "{{ value_json.tuners_in_use }}".to_owned(),
+            ),
+            entity(
+                "sensor",
+                "guide_age",
+                "Guide age",
+                "{{ value_json.guide_age }}".to_owned(),
+            ),
+        ];
+        entities[1].1["unit_of_measurement"] = json!("%");
+        entities[2].1["unit_of_measurement"] = json!("s");
+        entities[2].1["device_class"] = json!("duration");
+        for (dma, name) in cities {
+            let (topic, mut payload) = entity(
+                "binary_sensor",
+                &format!("city_{}", dma),
+                name,
+                format!("{{{{ value_json.cities['{}'] }}}}", dma),
+            );
+            payload["device_class"] = json!("connectivity");
+            entities.push((topic, payload));
+        }
+        entities
+    }
+}
+
+/// CONNECT packet (MQTT 3.1.1) with a retained last will that marks the device as offline
+fn connect_packet(config: &Config, availability: &str) -> Vec<u8> {
+    let mut flags = 0x02 | 0x04 | 0x20; // clean session, last will, retain last will
+    let mut payload = Vec::new();
+    put_string(&mut payload, &format!("locast2tuner-{}", &config.uuid[..8]));
+    put_string(&mut payload, availability);
+    put_string(&mut payload, "offline");
+    if let Some(username) = &config.mqtt_username {
+        flags |= 0x80;
+        put_string(&mut payload, username);
+        if let Some(password) = &config.mqtt_password {
+            flags |= 0x40;
+            put_string(&mut payload, password);
+        }
+    }
+
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+    body.extend(payload);
+    packet(0x10, body)
+}
+
+/// PUBLISH packet with QoS 0
+fn publish_packet(topic: &str, payload: &str, retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, topic);
+    body.extend_from_slice(payload.as_bytes());
+    packet(0x30 | retain as u8, body)
+}
+
+/// Prefix `body` with the fixed header: the packet type and the remaining length
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn put_string(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+}
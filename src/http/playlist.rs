@@ -0,0 +1,204 @@
+use reqwest::Url;
+use simple_error::SimpleError;
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    time::Duration,
+};
+
+/// Seconds of already played segments the segment buffer should be able to remember, so segments
+/// that are still listed in the playlist aren't played twice.
+static BUFFER_SECONDS: f32 = 120.0;
+/// Size of an MPEG-TS packet
+const TS_PACKET_SIZE: usize = 188;
+
+/// A parsed media playlist
+#[derive(Debug)]
+pub struct MediaPlaylist {
+    pub target_duration: Duration,
+    pub segments: Vec<Segment>,
+}
+
+/// A segment of a media playlist
+#[derive(Debug)]
+pub struct Segment {
+    pub url: String,
+    pub played: bool,
+    pub duration: Duration,
+    /// Preceded by `#EXT-X-DISCONTINUITY`, e.g. at the start or end of an ad break. The timestamps
+    /// of the segment don't continue those of the previous one.
+    pub discontinuity: bool,
+}
+
+/// A segment as it's served to clients
+pub struct ServedSegment {
+    pub bytes: bytes::Bytes,
+    pub duration: Duration,
+    pub discontinuity: bool,
+}
+
+impl PartialEq for Segment {
+    fn eq(&self, other: &Self) -> bool {
+        self.url == other.url
+    }
+}
+
+/// Parse a media playlist. Segment URLs are made absolute, based on the URL the playlist was fetched from.
+pub fn media_playlist(m3u_data: &str, playlist_url: &str) -> Result<MediaPlaylist, SimpleError> {
+    let media_playlist = hls_m3u8::MediaPlaylist::try_from(m3u_data)
+        .map_err(|e| SimpleError::new(format!("Unable to parse media playlist: {}", e)))?;
+    let base_url = Url::parse(playlist_url)
+        .map_err(|e| SimpleError::new(format!("Unable to parse url! {}", e)))?;
+
+    let segments = media_playlist
+        .segments
+        .into_iter()
+        .map(|(_i, ms)| {
+            let absolute_uri = base_url
+                .join(ms.uri())
+                .map_err(|e| SimpleError::new(format!("Unable to parse url! {}", e)))?;
+            Ok(Segment {
+                url: absolute_uri.to_string(),
+                played: false,
+                duration: ms.duration.duration(),
+                discontinuity: ms.has_discontinuity,
+            })
+        })
+        .collect::<Result<Vec<Segment>, SimpleError>>()?;
+
+    Ok(MediaPlaylist {
+        target_duration: media_playlist.target_duration,
+        segments,
+    })
+}
+
+/// How many segments a stream keeps in its buffer, and how many are drained once it's full. These
+/// depend on the playlist: a window of many short segments needs a bigger buffer than a window of a
+/// few long ones.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BufferLimits {
+    pub max: usize,
+    pub drain: usize,
+}
+
+impl BufferLimits {
+    /// Derive the limits from the target duration and the amount of segments in the playlist (the
+    /// window). The buffer always holds more than the window, so that segments that are still in the
+    /// playlist are never drained. The result is bounded by `min` and `max`.
+    pub fn new(target_duration: Duration, window: usize, min: usize, max: usize) -> BufferLimits {
+        let target = target_duration.as_secs_f32().max(1.0);
+        let by_time = (BUFFER_SECONDS / target).ceil() as usize;
+        let limit = by_time.max(window * 2).clamp(min, max);
+        BufferLimits {
+            max: limit,
+            drain: limit.saturating_sub(window).max(1),
+        }
+    }
+
+    /// Drain played segments from the front of the buffer when it's full. Returns the amount of
+    /// drained segments.
+    pub fn apply(&self, segments: &mut VecDeque<Segment>) -> usize {
+        if segments.len() < self.max {
+            return 0;
+        }
+        let played = segments.iter().take_while(|s| s.played).count();
+        let drain = self.drain.min(played);
+        segments.drain(0..drain);
+        drain
+    }
+}
+
+/// Set the discontinuity indicator of the MPEG-TS packets of a segment that follows a discontinuity,
+/// so players reset their clocks instead of glitching on the jump in timestamps and continuity
+/// counters. The indicator is set in the first packet of every PID, if that packet has an adaptation
+/// field. Returns the amount of packets that were marked.
+pub fn mark_discontinuity(ts: &mut [u8]) -> usize {
+    let mut pids = HashSet::new();
+    let mut marked = 0;
+    for packet in ts.chunks_exact_mut(TS_PACKET_SIZE) {
+        // Not a transport stream (anymore)
+        if packet[0] != 0x47 {
+            break;
+        }
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        if !pids.insert(pid) {
+            continue;
+        }
+        // Adaptation field control says there's an adaptation field, and it has a flags byte
+        if packet[3] & 0x20 != 0 && packet[4] > 0 {
+            packet[5] |= 0x80;
+            marked += 1;
+        }
+    }
+    marked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(played: usize, unplayed: usize) -> VecDeque<Segment> {
+        (0..played + unplayed)
+            .map(|i| Segment {
+                url: format!("https://example.com/{}.ts", i),
+                played: i < played,
+                duration: Duration::from_secs(6),
+                discontinuity: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn apply_keeps_buffer_below_max() {
+        let limits = BufferLimits { max: 10, drain: 4 };
+        let mut buffer = segments(9, 0);
+        assert_eq!(limits.apply(&mut buffer), 0);
+        assert_eq!(buffer.len(), 9);
+    }
+
+    #[test]
+    fn apply_drains_played_segments_from_the_front() {
+        let limits = BufferLimits { max: 10, drain: 4 };
+        let mut buffer = segments(8, 2);
+        assert_eq!(limits.apply(&mut buffer), 4);
+        assert_eq!(buffer.len(), 6);
+        assert_eq!(buffer[0].url, "https://example.com/4.ts");
+    }
+
+    #[test]
+    fn apply_never_drains_unplayed_segments() {
+        let limits = BufferLimits { max: 10, drain: 4 };
+        let mut buffer = segments(2, 10);
+        assert_eq!(limits.apply(&mut buffer), 2);
+        assert!(buffer.iter().all(|s| !s.played));
+
+        let mut buffer = segments(0, 12);
+        assert_eq!(limits.apply(&mut buffer), 0);
+        assert_eq!(buffer.len(), 12);
+    }
+
+    #[test]
+    fn new_keeps_the_window_in_the_buffer() {
+        // 120 seconds of 6 second segments
+        let limits = BufferLimits::new(Duration::from_secs(6), 5, 4, 100);
+        assert_eq!(limits, BufferLimits { max: 20, drain: 15 });
+
+        // Many short segments: twice the window
+        let limits = BufferLimits::new(Duration::from_secs(2), 100, 4, 1000);
+        assert_eq!(
+            limits,
+            BufferLimits {
+                max: 200,
+                drain: 100
+            }
+        );
+
+        // Bounded by min and max, and always drains at least one segment
+        let limits = BufferLimits::new(Duration::from_secs(10), 30, 4, 50);
+        assert_eq!(limits, BufferLimits { max: 50, drain: 20 });
+        let limits = BufferLimits::new(Duration::from_secs(10), 60, 4, 50);
+        assert_eq!(limits, BufferLimits { max: 50, drain: 1 });
+        let limits = BufferLimits::new(Duration::from_secs(60), 1, 8, 50);
+        assert_eq!(limits, BufferLimits { max: 8, drain: 7 });
+    }
+}
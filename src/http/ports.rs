@@ -0,0 +1,166 @@
+use super::{bind_all, parse_bind_addresses, systemd, BIND_RETRIES, BIND_RETRY_DELAY};
+use crate::{config::Config, service::stationprovider::StationProvider};
+use prettytable::{cell, format, row, Table};
+use std::{collections::HashMap, io::ErrorKind, net::IpAddr};
+
+/// Planned port of a single server
+struct Assignment {
+    name: String,
+    zipcode: String,
+    dma: String,
+    port: Option<u16>,
+    /// Whether the port comes from `port_map`
+    mapped: bool,
+    problem: Option<String>,
+}
+
+/// Plan the port of every server before anything is bound. When multiplexing or in single port mode,
+/// there's a single server on `port`. Otherwise every location gets the port from `port_map` (by zip code
+/// or DMA) or the next port from `port`, skipping ports that are mapped to other locations. If a port is
+/// out of range, used twice or not available (after `BIND_RETRIES` attempts), the complete plan is
+/// logged and nothing is started. With `auto_port`, ports that aren't mapped and aren't available are
+/// replaced by the next free port instead.
+pub async fn plan<T: StationProvider>(
+    services: &[T],
+    config: &Config,
+    bind_addresses: &[&str],
+    single_port: bool,
+) -> std::io::Result<Vec<u16>> {
+    let mut assignments: Vec<Assignment> = if config.multiplex || single_port {
+        vec![Assignment {
+            name: services
+                .iter()
+                .map(|s| s.geo().name.clone())
+                .collect::<Vec<String>>()
+                .join(", "),
+            zipcode: String::new(),
+            dma: String::new(),
+            port: Some(config.port),
+            mapped: false,
+            problem: None,
+        }]
+    } else {
+        services
+            .iter()
+            .map(|s| {
+                let zipcode = s.zipcode();
+                let dma = s.geo().DMA.clone();
+                let mapped = config
+                    .port_map
+                    .iter()
+                    .find(|m| m.location == zipcode || m.location == dma)
+                    .map(|m| m.port);
+                Assignment {
+                    name: s.geo().name.clone(),
+                    zipcode,
+                    dma,
+                    port: mapped,
+                    mapped: mapped.is_some(),
+                    problem: None,
+                }
+            })
+            .collect()
+    };
+
+    // Locations without a mapped port get the next port that isn't mapped
+    let reserved: Vec<u16> = assignments.iter().filter_map(|a| a.port).collect();
+    let mut next = Some(config.port);
+    for assignment in assignments.iter_mut().filter(|a| a.port.is_none()) {
+        while next.is_some_and(|p| reserved.contains(&p)) {
+            next = next.and_then(|p| p.checked_add(1));
+        }
+        match next {
+            Some(port) => assignment.port = Some(port),
+            None => assignment.problem = Some("no port left".to_owned()),
+        }
+        next = next.and_then(|p| p.checked_add(1));
+    }
+
+    let mut seen: HashMap<u16, String> = HashMap::new();
+    for assignment in assignments.iter_mut() {
+        if let Some(port) = assignment.port {
+            if let Some(other) = seen.get(&port) {
+                assignment.problem = Some(format!("also assigned to {}", other));
+            } else {
+                seen.insert(port, assignment.name.clone());
+            }
+        }
+    }
+
+    // Check if the ports can be bound. Ports might still be in use by a previous instance that's
+    // shutting down, so we give them some time.
+    let ips = parse_bind_addresses(bind_addresses)?;
+    for attempt in 1..=BIND_RETRIES {
+        let mut unavailable = false;
+        let mut taken: Vec<u16> = assignments.iter().filter_map(|a| a.port).collect();
+        for assignment in assignments.iter_mut().filter(|a| a.problem.is_none()) {
+            let port = assignment.port.unwrap();
+            match available(&ips, port) {
+                Ok(()) => {}
+                // Only ports that are in use are retried, other errors won't go away
+                Err(e) if attempt == BIND_RETRIES || e.kind() != ErrorKind::AddrInUse => {
+                    match alternative(&ips, port, &taken)
+                        .filter(|_| config.auto_port && !assignment.mapped)
+                    {
+                        Some(alternative) => {
+                            warn!(
+                                "Port {} for {} is not available ({}), using port {} instead",
+                                port, assignment.name, e, alternative
+                            );
+                            assignment.port = Some(alternative);
+                            taken.push(alternative);
+                        }
+                        None => assignment.problem = Some(format!("not available: {}", e)),
+                    }
+                }
+                Err(_) => unavailable = true,
+            }
+        }
+        if !unavailable {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(BIND_RETRY_DELAY)).await;
+    }
+
+    if assignments.iter().all(|a| a.problem.is_none()) {
+        return Ok(assignments.iter().map(|a| a.port.unwrap()).collect());
+    }
+
+    error!("Unable to assign ports, nothing was started:");
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["City", "Zip code", "DMA", "Port", "Source", "Problem"]);
+    for a in &assignments {
+        table.add_row(row![
+            a.name,
+            a.zipcode,
+            a.dma,
+            a.port.map_or("-".to_owned(), |p| p.to_string()),
+            if a.mapped { "port_map" } else { "port" },
+            a.problem.as_deref().unwrap_or("")
+        ]);
+    }
+    for line in table.to_string().lines() {
+        error!(" {}", line);
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        "Unable to assign ports",
+    ))
+}
+
+/// The first port after `port` that isn't `taken` by another server and can be bound
+fn alternative(ips: &[IpAddr], port: u16, taken: &[u16]) -> Option<u16> {
+    (port.checked_add(1)?..=u16::MAX)
+        .filter(|p| !taken.contains(p))
+        .find(|p| available(ips, *p).is_ok())
+}
+
+/// Check if `port` can be bound, the same way `bind_listeners` would. Sockets passed by systemd are
+/// always available.
+fn available(ips: &[IpAddr], port: u16) -> std::io::Result<()> {
+    if !systemd::listeners(port)?.is_empty() {
+        return Ok(());
+    }
+    bind_all(ips, port).map(|_| ())
+}
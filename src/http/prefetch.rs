@@ -0,0 +1,81 @@
+use super::playlist::Segment;
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+/// Downloads of the segments of a stream that are ahead of the one that's being served. Segments
+/// are downloaded in parallel in the background, so a slow download doesn't stall the stream.
+pub struct Prefetcher {
+    /// Segments that are downloaded ahead of the next one
+    depth: usize,
+    downloads: HashMap<String, JoinHandle<Result<Download, String>>>,
+}
+
+impl Prefetcher {
+    pub fn new(depth: usize) -> Prefetcher {
+        Prefetcher {
+            depth,
+            downloads: HashMap::new(),
+        }
+    }
+
+    /// Start downloading the next unplayed segment and the `depth` segments after it, unless
+    /// prefetching is disabled. Downloads of segments that left the buffer are canceled.
+    pub fn prefetch(&mut self, segments: &VecDeque<Segment>) {
+        if self.depth == 0 {
+            return;
+        }
+        self.downloads.retain(|url, download| {
+            let buffered = segments.iter().any(|s| !s.played && &s.url == url);
+            if !buffered {
+                download.abort();
+            }
+            buffered
+        });
+        for segment in segments.iter().filter(|s| !s.played).take(self.depth + 1) {
+            if !self.downloads.contains_key(&segment.url) {
+                let url = segment.url.clone();
+                self.downloads
+                    .insert(url.clone(), tokio::task::spawn(download(url)));
+            }
+        }
+    }
+
+    /// The contents of the segment at `url`, from its prefetched download if there is one
+    pub async fn fetch(&mut self, url: &str) -> Result<Download, String> {
+        match self.downloads.remove(url) {
+            Some(download) => download.await.map_err(|e| e.to_string())?,
+            None => download(url.to_owned()).await,
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        for download in self.downloads.values() {
+            download.abort();
+        }
+    }
+}
+
+/// A downloaded segment
+pub struct Download {
+    pub bytes: Bytes,
+    /// How long it took to download the segment, to measure the throughput of locast's CDN
+    pub duration: Duration,
+}
+
+async fn download(url: String) -> Result<Download, String> {
+    let start = Instant::now();
+    let response = crate::utils::get(&url, None, 10)
+        .await
+        .map_err(|e| e.to_string())?;
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    Ok(Download {
+        bytes,
+        duration: start.elapsed(),
+    })
+}
@@ -0,0 +1,105 @@
+use super::ip_filter::IpNet;
+use crate::config::Config;
+use actix_web::{http::header::HOST, HttpRequest};
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Determines the scheme and host clients use to reach us, for the URLs in device.xml, lineups,
+/// playlists and guides. Behind a reverse proxy that's either a fixed `external_url`, or the host and
+/// scheme from the X-Forwarded-Host/X-Forwarded-Proto (or Forwarded) headers the proxy sets. When
+/// `trusted_proxies` is set, those headers are only honored for requests from these proxies.
+#[derive(Default)]
+pub struct Origin {
+    external_url: Option<String>,
+    /// `None` trusts all clients
+    trusted_proxies: Option<Vec<IpNet>>,
+}
+
+impl Origin {
+    pub fn new(config: &Config) -> Origin {
+        Origin {
+            external_url: config
+                .external_url
+                .as_ref()
+                .map(|u| u.trim_end_matches('/').to_owned()),
+            trusted_proxies: config.trusted_proxies.clone(),
+        }
+    }
+
+    /// Scheme and host (and the path of `external_url`) of `req`, e.g. "https://192.168.1.10:6077"
+    pub fn of(&self, req: &HttpRequest) -> String {
+        if let Some(url) = &self.external_url {
+            return url.clone();
+        }
+        let (scheme, host) = if self.trusted(req) {
+            let info = req.connection_info();
+            (info.scheme().to_owned(), info.host().to_owned())
+        } else {
+            let config = req.app_config();
+            let scheme = if config.secure() { "https" } else { "http" };
+            let host = req
+                .headers()
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .map_or_else(|| config.host().to_owned(), |h| h.to_owned());
+            (scheme.to_owned(), host)
+        };
+        // A bare IPv6 address has to be enclosed in brackets to be used in a URL
+        let host = match host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("[{}]", ip),
+            Err(_) => host,
+        };
+        format!("{}://{}", scheme, host)
+    }
+
+    /// Returns true if forwarding headers of `req` can be used
+    fn trusted(&self, req: &HttpRequest) -> bool {
+        let trusted_proxies = match &self.trusted_proxies {
+            Some(t) => t,
+            None => return true,
+        };
+        let ip = match req.peer_addr().map(|a| a.ip()) {
+            // IPv4 clients connected to a dual stack socket show up as IPv4 mapped IPv6 addresses
+            Some(IpAddr::V6(ip)) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            Some(ip) => ip,
+            None => return false,
+        };
+        trusted_proxies.iter().any(|n| n.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::net::SocketAddr;
+
+    fn origin(trusted_proxies: Option<&[&str]>) -> Origin {
+        Origin::new(&Config {
+            trusted_proxies: trusted_proxies
+                .map(|l| l.iter().map(|n| n.parse().unwrap()).collect()),
+            ..Config::default()
+        })
+    }
+
+    fn trusted(origin: &Origin, peer: &str) -> bool {
+        let req = TestRequest::default()
+            .peer_addr(SocketAddr::new(peer.parse().unwrap(), 50000))
+            .to_http_request();
+        origin.trusted(&req)
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        assert!(trusted(&origin(None), "203.0.113.7"));
+
+        let origin = origin(Some(&["172.18.0.0/16"]));
+        assert!(trusted(&origin, "172.18.0.2"));
+        assert!(trusted(&origin, "::ffff:172.18.0.2"));
+        assert!(!trusted(&origin, "203.0.113.7"));
+    }
+
+    #[test]
+    fn empty_trusted_proxies_trust_no_one() {
+        assert!(!trusted(&origin(Some(&[])), "172.18.0.2"));
+    }
+}
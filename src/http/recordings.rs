@@ -0,0 +1,432 @@
+use crate::{config::Config, errors::AppError};
+use actix_web::{
+    dev::HttpResponseBuilder,
+    http::header::{ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_RANGE, ETAG, IF_RANGE, RANGE},
+    http::StatusCode,
+    web, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Recordings are served in chunks of this size
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Recordings that are still being written have this extension appended, and aren't served
+pub const PARTIAL_EXTENSION: &str = "part";
+
+/// How often recordings are checked against the retention policy
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A completed recording in `recordings_dir`
+#[derive(Serialize, Debug)]
+pub struct Recording {
+    pub name: String,
+    pub size: u64,
+    /// Unix timestamp of the time the recording was completed
+    pub modified: i64,
+}
+
+/// Completed recordings, oldest first
+pub fn list(config: &Config) -> Vec<Recording> {
+    let entries = match fs::read_dir(&config.recordings_dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    let mut recordings: Vec<Recording> = entries
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let metadata = e.metadata().ok()?;
+            if !metadata.is_file() || name.ends_with(&format!(".{}", PARTIAL_EXTENSION)) {
+                return None;
+            }
+            Some(Recording {
+                name,
+                size: metadata.len(),
+                modified: timestamp(metadata.modified().ok()?),
+            })
+        })
+        .collect();
+    recordings.sort_by_key(|r| r.modified);
+    recordings
+}
+
+/// Path of the recording called `name`. Names are plain file names, so recordings can't be used to
+/// reach files outside of `recordings_dir`.
+pub fn path(config: &Config, name: &str) -> Result<PathBuf, AppError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+        && !name.ends_with(&format!(".{}", PARTIAL_EXTENSION));
+    if !valid {
+        return Err(AppError::NotFound);
+    }
+    let path = config.recordings_dir.join(name);
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+/// Name of a new recording of `station` (e.g. a call sign), started at `started`. The start time has
+/// millisecond resolution, so recordings of a station started in the same second don't collide.
+pub fn name(station: &str, started: DateTime<Utc>) -> String {
+    let station: String = station
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}_{}.ts", station, started.format("%Y%m%d-%H%M%S%3f"))
+}
+
+/// Write `stream` to the recording called `name` until the stream ends or `duration` has passed. The
+/// recording is written to a `.part` file, which is renamed once the recording is complete.
+pub async fn record<S>(config: Arc<Config>, name: String, mut stream: S, duration: Duration)
+where
+    S: Stream<Item = Result<web::Bytes, actix_web::Error>> + Unpin,
+{
+    let path = config.recordings_dir.join(&name);
+    let partial = config
+        .recordings_dir
+        .join(format!("{}.{}", name, PARTIAL_EXTENSION));
+    // Never truncate a recording that's still being written
+    let mut file = match fs::create_dir_all(&config.recordings_dir).and_then(|_| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+    }) {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("Unable to create recording {}: {}", partial.display(), e);
+            return;
+        }
+    };
+    info!("Recording {} for {}s", name, duration.as_secs());
+
+    let deadline = tokio::time::Instant::now() + duration;
+    let mut size = 0;
+    loop {
+        let chunk = match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(Some(Ok(chunk))) => chunk,
+            // The duration passed, or the stream ended early (e.g. when shutting down). Either way, keep
+            // what was recorded so far.
+            _ => break,
+        };
+        size += chunk.len();
+        let result = web::block(move || file.write_all(&chunk).map(|_| file)).await;
+        file = match result {
+            Ok(Ok(f)) => f,
+            _ => {
+                warn!(
+                    "Unable to write to recording {}, stopping",
+                    partial.display()
+                );
+                break;
+            }
+        };
+    }
+    // Stop streaming before the recording is completed
+    drop(stream);
+
+    match fs::rename(&partial, &path) {
+        Ok(_) => info!("Recorded {} ({} bytes)", name, size),
+        Err(e) => warn!("Unable to complete recording {}: {}", partial.display(), e),
+    }
+}
+
+/// Serve a recording (or any other MPEG-TS file). Supports a single byte range (`Range: bytes=...`),
+/// guarded by `If-Range`, so interrupted downloads can be resumed. With `download`, browsers save the
+/// file under its own name.
+pub async fn serve(
+    req: &HttpRequest,
+    path: PathBuf,
+    download: bool,
+) -> Result<HttpResponse, AppError> {
+    let metadata = fs::metadata(&path).map_err(|_| AppError::NotFound)?;
+    let size = metadata.len();
+    let etag = format!(
+        "\"{:x}-{:x}\"",
+        size,
+        metadata.modified().map(timestamp).unwrap_or_default()
+    );
+
+    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok());
+    // Only honour the range if the client still has the same version of the file
+    let range = match header(IF_RANGE) {
+        Some(if_range) if if_range != etag => None,
+        _ => header(RANGE),
+    };
+    let (start, end) = match range.map(|r| parse_range(r, size)) {
+        None | Some(Range::Ignored) => (0, size),
+        Some(Range::Satisfiable(start, end)) => (start, end),
+        Some(Range::Unsatisfiable) => {
+            return Ok(HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+                .append_header((CONTENT_RANGE, format!("bytes */{}", size)))
+                .finish());
+        }
+    };
+
+    let mut file = File::open(&path).map_err(|_| AppError::NotFound)?;
+    file.seek(SeekFrom::Start(start))
+        .map_err(|_| AppError::InternalError)?;
+
+    let mut response = if start == 0 && end == size {
+        HttpResponse::Ok()
+    } else {
+        let mut response = HttpResponse::PartialContent();
+        response.append_header((
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, size),
+        ));
+        response
+    };
+    if download {
+        attachment(&mut response, &path);
+    }
+    Ok(response
+        .append_header((ACCEPT_RANGES, "bytes"))
+        .append_header((ETAG, etag))
+        .content_type("video/mp2t")
+        .no_chunking(end - start)
+        .streaming(Box::pin(read_chunks(file, end - start))))
+}
+
+/// Serve a recording remuxed to MP4 by ffmpeg. The MP4 is fragmented, so it can be written while
+/// ffmpeg is still reading the recording. Remuxed downloads can't be resumed.
+pub fn serve_mp4(path: PathBuf) -> Result<HttpResponse, AppError> {
+    let mut child = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-i"])
+        .arg(&path)
+        .args([
+            "-c",
+            "copy",
+            "-movflags",
+            "frag_keyframe+empty_moov",
+            "-f",
+            "mp4",
+            "pipe:1",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            warn!("Unable to start ffmpeg to remux {}: {}", path.display(), e);
+            AppError::InternalError
+        })?;
+    let stdout = child.stdout.take().ok_or(AppError::InternalError)?;
+
+    let mut response = HttpResponse::Ok();
+    attachment(&mut response, &path.with_extension("mp4"));
+    Ok(response
+        .content_type("video/mp4")
+        .streaming(Box::pin(remux_chunks(Remux { child, stdout }))))
+}
+
+/// Remove a recording
+pub fn delete(path: &Path) -> Result<(), AppError> {
+    fs::remove_file(path).map_err(|e| {
+        warn!("Unable to remove recording {}: {}", path.display(), e);
+        AppError::InternalError
+    })?;
+    info!("Removed recording {}", path.display());
+    Ok(())
+}
+
+/// Remove recordings that are older than `recordings_retention` days, every hour
+pub async fn enforce_retention(config: Arc<Config>) {
+    if config.recordings_retention == 0 {
+        return;
+    }
+    let retention = chrono::Duration::days(config.recordings_retention as i64);
+    loop {
+        let cutoff = (Utc::now() - retention).timestamp();
+        for recording in list(&config).into_iter().filter(|r| r.modified < cutoff) {
+            info!(
+                "Recording {} is older than {} days",
+                recording.name, config.recordings_retention
+            );
+            let _ = delete(&config.recordings_dir.join(&recording.name));
+        }
+        tokio::time::sleep(RETENTION_INTERVAL).await;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// Start (inclusive) and end (exclusive) of the requested bytes
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    /// Not a single byte range, the whole file is served
+    Ignored,
+}
+
+/// Parse a `Range` header for a file of `size` bytes. Only single byte ranges are supported.
+fn parse_range(header: &str, size: u64) -> Range {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return Range::Ignored,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(r) => r,
+        None => return Range::Ignored,
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-500: the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => (size.saturating_sub(suffix), size),
+        // bytes=500-: everything from byte 500
+        (Ok(start), Err(_)) if end.is_empty() => (start, size),
+        (Ok(start), Ok(end)) if end >= start => (start, (end + 1).min(size)),
+        _ => return Range::Ignored,
+    };
+    if range.0 >= size || range.0 >= range.1 {
+        Range::Unsatisfiable
+    } else {
+        Range::Satisfiable(range.0, range.1)
+    }
+}
+
+/// Let browsers save the recording under its own name
+fn attachment(response: &mut HttpResponseBuilder, path: &Path) {
+    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+        response.append_header((
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", name.replace('"', "")),
+        ));
+    }
+}
+
+/// Read `length` bytes from `file` in chunks, without blocking the server
+fn read_chunks(
+    file: File,
+    length: u64,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    stream::unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let size = remaining.min(CHUNK_SIZE as u64) as usize;
+        let result = web::block(move || {
+            let mut buffer = vec![0; size];
+            let read = file.read(&mut buffer).map(|n| {
+                buffer.truncate(n);
+                buffer
+            });
+            (file, read)
+        })
+        .await;
+        match result {
+            Ok((file, Ok(buffer))) if !buffer.is_empty() => {
+                let remaining = remaining - buffer.len() as u64;
+                Some((Ok(web::Bytes::from(buffer)), (file, remaining)))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// ffmpeg process that's remuxing a recording. It's killed when the download stops.
+struct Remux {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Drop for Remux {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn remux_chunks(remux: Remux) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    stream::unfold(remux, |mut remux| async move {
+        let result = web::block(move || {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            let read = remux.stdout.read(&mut buffer).map(|n| {
+                buffer.truncate(n);
+                buffer
+            });
+            (remux, read)
+        })
+        .await;
+        match result {
+            Ok((remux, Ok(buffer))) if !buffer.is_empty() => {
+                Some((Ok(web::Bytes::from(buffer)), remux))
+            }
+            _ => None,
+        }
+    })
+}
+
+fn timestamp(time: SystemTime) -> i64 {
+    DateTime::<Utc>::from(time).timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_suffix_range() {
+        assert_eq!(
+            parse_range("bytes=-500", 1000),
+            Range::Satisfiable(500, 1000)
+        );
+        // A suffix longer than the file is the whole file
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            Range::Satisfiable(0, 1000)
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_open_ended_range() {
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            Range::Satisfiable(500, 1000)
+        );
+        assert_eq!(parse_range("bytes=0-", 1000), Range::Satisfiable(0, 1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_closed_range() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Range::Satisfiable(0, 500));
+        // The end is capped at the end of the file
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            Range::Satisfiable(500, 1000)
+        );
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_malformed_range() {
+        assert_eq!(parse_range("bytes=500-100", 1000), Range::Ignored);
+        assert_eq!(parse_range("bytes=0-1,5-10", 1000), Range::Ignored);
+        assert_eq!(parse_range("bytes=abc", 1000), Range::Ignored);
+        assert_eq!(parse_range("bytes=-", 1000), Range::Ignored);
+        assert_eq!(parse_range("items=0-10", 1000), Range::Ignored);
+    }
+
+    #[test]
+    fn names_have_millisecond_resolution() {
+        let started = Utc.ymd(2021, 5, 1).and_hms_milli(20, 0, 0, 250);
+        assert_eq!(name("KABC-DT", started), "KABC_DT_20210501-200000250.ts");
+        assert_ne!(
+            name("KABC", started),
+            name("KABC", started + chrono::Duration::milliseconds(1))
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use crate::service::stationprovider::ServiceArc;
+use futures::future::join_all;
+use std::sync::{Arc, Mutex};
+
+/// Channel scan of a tuner, as started by media servers through `/lineup.post?scan=start`. A scan
+/// refreshes the stations of every service (location) of the tuner.
+pub struct StationScan {
+    state: Mutex<ScanState>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ScanState {
+    /// Incremented for every scan, so refreshes of an aborted scan don't count towards the next one
+    id: u64,
+    pub in_progress: bool,
+    /// Locations that were scanned
+    pub scanned: usize,
+    /// Locations to scan
+    pub total: usize,
+    /// Stations found in the locations that were scanned
+    pub found: usize,
+}
+
+impl ScanState {
+    /// Percentage of locations that were scanned
+    pub fn progress(&self) -> u8 {
+        (self.scanned * 100).checked_div(self.total).unwrap_or(100) as u8
+    }
+}
+
+impl StationScan {
+    pub fn new() -> StationScan {
+        StationScan {
+            state: Mutex::new(ScanState::default()),
+        }
+    }
+
+    pub fn state(&self) -> ScanState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Start scanning `services` in the background. Returns false if a scan is already in progress.
+    pub fn start(self: &Arc<Self>, services: Vec<ServiceArc>) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.in_progress {
+                return false;
+            }
+            *state = ScanState {
+                id: state.id + 1,
+                in_progress: true,
+                scanned: 0,
+                total: services.len(),
+                found: 0,
+            };
+        }
+        info!("Channel scan started for {} location(s)", services.len());
+        let scan = self.clone();
+        let id = self.state().id;
+        actix_rt::spawn(async move {
+            join_all(services.iter().map(|service| scan.scan(id, service))).await;
+            let mut state = scan.state.lock().unwrap();
+            if state.in_progress && state.id == id {
+                state.in_progress = false;
+                info!("Channel scan finished, found {} stations", state.found);
+            }
+        });
+        true
+    }
+
+    async fn scan(&self, id: u64, service: &ServiceArc) {
+        service.refresh().await;
+        let found = service.stations().await.lock().await.len();
+        let mut state = self.state.lock().unwrap();
+        if state.in_progress && state.id == id {
+            state.scanned += 1;
+            state.found += found;
+        }
+    }
+
+    /// Stop reporting progress of the running scan. Refreshes that were started are finished in the
+    /// background.
+    pub fn abort(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.in_progress {
+            state.in_progress = false;
+            info!("Channel scan aborted");
+        }
+    }
+}
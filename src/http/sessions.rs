@@ -0,0 +1,52 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Keeps track of the amount of concurrent streams and makes sure `limit` isn't exceeded.
+pub struct SessionTracker {
+    active: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl SessionTracker {
+    pub fn new(limit: usize) -> SessionTracker {
+        SessionTracker {
+            active: Arc::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    /// Start a new session. Returns `None` if all sessions are in use.
+    pub fn start(&self) -> Option<StreamSession> {
+        let limit = self.limit;
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                if active < limit {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(StreamSession {
+            active: self.active.clone(),
+        })
+    }
+
+    /// The amount of active sessions
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+/// A single stream session. The session ends when this is dropped.
+pub struct StreamSession {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamSession {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
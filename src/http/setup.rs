@@ -0,0 +1,262 @@
+use super::{bind_all, systemd, templates, tls_config};
+use crate::{
+    config::Config,
+    credentials::check_account,
+    errors::AppError,
+    service::{check_location, Location},
+};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use futures::{channel::oneshot, lock::Mutex};
+use reqwest::header::LOCATION;
+use serde::Deserialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::{IpAddr, Ipv4Addr, TcpListener},
+    path::Path,
+};
+
+/// State of the setup wizard. Once a valid configuration has been submitted, it is sent through
+/// `done`, which stops the wizard.
+struct SetupState {
+    config: Config,
+    done: Mutex<Option<oneshot::Sender<Config>>>,
+}
+
+/// Fields of the setup form
+#[derive(Deserialize, Default)]
+pub struct SetupForm {
+    pub username: String,
+    pub password: String,
+    pub zipcodes: String,
+    pub multiplex: Option<String>,
+    pub tuner_count: String,
+}
+
+/// Run the first-run setup wizard. This serves a form at `/setup` that collects the locast credentials
+/// and basic options. These are validated against locast and written to the config file. Returns the
+/// updated configuration, so locast2tuner can start without a restart. The wizard isn't authenticated
+/// and asks for the locast password, so it's only served on loopback, regardless of `bind_address`.
+pub async fn run(config: Config) -> std::io::Result<Config> {
+    let listeners = loopback_listeners(config.port)?;
+    warn!(
+        "No locast credentials configured. Complete the setup at {}://{}/setup",
+        config.scheme(),
+        listeners[0].local_addr()?
+    );
+
+    let tls = tls_config(&config)?;
+
+    let (sender, receiver) = oneshot::channel();
+    let state = web::Data::new(SetupState {
+        config,
+        done: Mutex::new(Some(sender)),
+    });
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .app_data(
+                web::FormConfig::default()
+                    .error_handler(|e, _| AppError::BadRequest(e.to_string()).into()),
+            )
+            .route("/setup", web::get().to(setup))
+            .route("/setup", web::post().to(setup_post))
+            .default_service(web::route().to(redirect))
+    });
+    let server = listeners
+        .into_iter()
+        .try_fold(server, |server, listener| match &tls {
+            Some(tls) => server.listen_rustls(listener, tls.clone()),
+            None => server.listen(listener),
+        })?
+        .run();
+    // Don't keep systemd waiting for the setup to be completed
+    systemd::notify("READY=1\nSTATUS=Waiting for setup");
+
+    let config = receiver.await.map_err(std::io::Error::other)?;
+    server.stop(true).await;
+    info!("Setup completed, starting locast2tuner..");
+    Ok(config)
+}
+
+/// Listeners on the loopback address. Sockets passed by systemd are used if they're bound to loopback.
+fn loopback_listeners(port: u16) -> std::io::Result<Vec<TcpListener>> {
+    let activated = systemd::listeners(port)?;
+    if activated.is_empty() {
+        return bind_all(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], port);
+    }
+    let loopback: Vec<TcpListener> = activated
+        .into_iter()
+        .filter(|l| l.local_addr().is_ok_and(|a| a.ip().is_loopback()))
+        .collect();
+    if loopback.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            "The setup wizard is only served on loopback, but systemd passed no loopback socket",
+        ));
+    }
+    Ok(loopback)
+}
+
+/// Redirect everything to the setup form
+async fn redirect() -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header((LOCATION, "/setup"))
+        .finish()
+}
+
+async fn setup(data: web::Data<SetupState>) -> HttpResponse {
+    let form = SetupForm {
+        zipcodes: data
+            .config
+            .override_zipcodes
+            .as_ref()
+            .map(|z| z.join(","))
+            .unwrap_or_default(),
+        multiplex: data.config.multiplex.then(|| String::from("on")),
+        tuner_count: data.config.tuner_count.to_string(),
+        ..Default::default()
+    };
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(templates::setup_html(&form, None))
+}
+
+/// Validate the submitted form and write the config file. When something is wrong, the form is shown
+/// again with an error message.
+async fn setup_post(data: web::Data<SetupState>, form: web::Form<SetupForm>) -> HttpResponse {
+    let mut form = form.into_inner();
+    let config = match validate(&data.config, &form).await {
+        Ok(c) => c,
+        Err(e) => {
+            // Don't send the password back to the browser
+            form.password.clear();
+            return HttpResponse::BadRequest()
+                .content_type("text/html")
+                .body(templates::setup_html(&form, Some(&e)));
+        }
+    };
+
+    if let Err(e) = write_config(&config) {
+        error!(
+            "Unable to write config file {}: {}",
+            config.config_file.display(),
+            e
+        );
+        form.password.clear();
+        let message = format!(
+            "Unable to write config file {}: {}",
+            config.config_file.display(),
+            e
+        );
+        return HttpResponse::InternalServerError()
+            .content_type("text/html")
+            .body(templates::setup_html(&form, Some(&message)));
+    }
+    info!("Configuration written to {}", config.config_file.display());
+
+    let path = config.config_file.display().to_string();
+    if let Some(sender) = data.done.lock().await.take() {
+        let _ = sender.send(config);
+    }
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(templates::setup_done_html(&path))
+}
+
+/// Check the submitted values against locast and return the resulting configuration
+async fn validate(config: &Config, form: &SetupForm) -> Result<Config, String> {
+    let username = form.username.trim();
+    if username.is_empty() || form.password.is_empty() {
+        return Err(String::from("Username and password are required"));
+    }
+    let tuner_count = match form.tuner_count.trim().parse::<u8>() {
+        Ok(t) if t > 0 => t,
+        _ => {
+            return Err(String::from(
+                "Tuner count must be a number between 1 and 255",
+            ))
+        }
+    };
+    let zipcodes: Vec<String> = form
+        .zipcodes
+        .split(',')
+        .map(|z| z.trim().to_owned())
+        .filter(|z| !z.is_empty())
+        .collect();
+
+    check_account(username, &form.password)
+        .await
+        .map_err(|e| format!("Unable to log in to locast: {}", e))?;
+    if zipcodes.is_empty() {
+        check_location(&Location::Ip)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    for z in &zipcodes {
+        check_location(&Location::Zipcode(z.to_owned()))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let mut config = config.clone();
+    config.username = username.to_owned();
+    config.password = form.password.clone();
+    config.override_zipcodes = if zipcodes.is_empty() {
+        None
+    } else {
+        Some(zipcodes)
+    };
+    config.multiplex = form.multiplex.is_some();
+    config.tuner_count = tuner_count;
+    Ok(config)
+}
+
+/// Write the settings collected by the wizard to the config file. Settings that are already in the
+/// config file are preserved.
+fn write_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let path = &config.config_file;
+    let mut table = match fs::read_to_string(path) {
+        Ok(s) => s.parse::<toml::Value>()?,
+        Err(_) => toml::Value::Table(Default::default()),
+    };
+    let t = table
+        .as_table_mut()
+        .ok_or("config file is not a TOML table")?;
+    t.insert("username".into(), config.username.clone().into());
+    t.insert("password".into(), config.password.clone().into());
+    match &config.override_zipcodes {
+        Some(z) => t.insert("override_zipcodes".into(), z.clone().into()),
+        None => t.remove("override_zipcodes"),
+    };
+    t.insert("multiplex".into(), config.multiplex.into());
+    t.insert("tuner_count".into(), i64::from(config.tuner_count).into());
+
+    if let Some(dir) = path.parent().filter(|d| d != &Path::new("")) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = create_private(path)?;
+    file.write_all(toml::to_string(&table)?.as_bytes())?;
+    Ok(())
+}
+
+/// Create (or truncate) a file that only the owner can read, since it holds the locast password
+#[cfg(unix)]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    File::create(path)
+}
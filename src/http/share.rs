@@ -0,0 +1,145 @@
+use crate::errors::AppError;
+use chrono::Utc;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Write, path::Path};
+
+/// File in the cache directory that holds the key share links are signed with
+static KEY_FILE: &str = "share.key";
+/// Longest time in seconds a share link can be valid (a year)
+pub static MAX_SHARE_EXPIRY: u64 = 365 * 86400;
+
+/// Request to share a single station, as posted to `/share`
+#[derive(Deserialize, Debug)]
+pub struct ShareRequest {
+    pub station_id: i64,
+    /// Seconds the link is valid. Defaults to `share_expiry`.
+    pub expires_in: Option<u64>,
+}
+
+/// A share link for a single station. `url` streams the station, `m3u_url` is a playlist with just
+/// that station.
+#[derive(Serialize, Debug)]
+pub struct ShareLink {
+    pub station_id: i64,
+    pub url: String,
+    pub m3u_url: String,
+    /// Unix timestamp after which the link stops working
+    pub expires: i64,
+}
+
+/// Signs and verifies share tokens. A token is "<station_id>.<expires>.<signature>", where the
+/// signature is an HMAC of the station id and expiry. The key is stored in the cache directory, so
+/// links survive a restart. Removing the key file revokes all links.
+pub struct Shares {
+    key: hmac::Key,
+}
+
+impl Shares {
+    pub fn new(cache_directory: &Path) -> Shares {
+        let path = cache_directory.join(KEY_FILE);
+        let key = match fs::read_to_string(&path)
+            .ok()
+            .and_then(|k| base64::decode(k.trim()).ok())
+        {
+            Some(key) => key,
+            None => {
+                let key = rand::random::<[u8; 32]>().to_vec();
+                if let Err(e) = write_key(&path, &key) {
+                    warn!(
+                        "Unable to store share key in {}, share links won't survive a restart: {}",
+                        path.display(),
+                        e
+                    );
+                }
+                key
+            }
+        };
+        Shares {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+        }
+    }
+
+    /// Create a token for `station_id` that expires at `expires`
+    pub fn token(&self, station_id: i64, expires: i64) -> String {
+        let payload = format!("{}.{}", station_id, expires);
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Verify a token. Returns the station id it grants access to.
+    pub fn verify(&self, token: &str) -> Result<i64, AppError> {
+        let invalid = || AppError::Forbidden("Invalid share link".to_owned());
+        let (payload, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let signature =
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature).map_err(|_| invalid())?;
+
+        let (station_id, expires) = payload.split_once('.').ok_or_else(invalid)?;
+        let expires: i64 = expires.parse().map_err(|_| invalid())?;
+        if Utc::now().timestamp() >= expires {
+            return Err(AppError::Forbidden("Share link expired".to_owned()));
+        }
+        station_id.parse().map_err(|_| invalid())
+    }
+}
+
+/// Unix timestamp `expires_in` seconds after `now`. Links are valid for at most `MAX_SHARE_EXPIRY`
+/// seconds.
+pub fn expires(now: i64, expires_in: u64) -> Result<i64, AppError> {
+    let out_of_range = || {
+        AppError::BadRequest(format!(
+            "expires_in must be at most {} seconds",
+            MAX_SHARE_EXPIRY
+        ))
+    };
+    if expires_in > MAX_SHARE_EXPIRY {
+        return Err(out_of_range());
+    }
+    now.checked_add(expires_in as i64).ok_or_else(out_of_range)
+}
+
+/// Write the key, readable by the current user only
+fn write_key(path: &Path, key: &[u8]) -> std::io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)?
+        .write_all(base64::encode(key).as_bytes())
+}
+
+/// Returns true if `path` is a share link. These carry their own authorization, so they don't need
+/// authentication. Paths can have a tuner prefix in single port mode, or a virtual device prefix when
+/// multiplexing.
+pub fn share_path(path: &str) -> bool {
+    super::relative_path(path).starts_with("/share/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_is_capped() {
+        assert_eq!(expires(1_600_000_000, 86400).unwrap(), 1_600_086_400);
+        assert_eq!(
+            expires(1_600_000_000, MAX_SHARE_EXPIRY).unwrap(),
+            1_600_000_000 + MAX_SHARE_EXPIRY as i64
+        );
+        assert!(matches!(
+            expires(1_600_000_000, MAX_SHARE_EXPIRY + 1),
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(matches!(expires(0, u64::MAX), Err(AppError::BadRequest(_))));
+        assert!(matches!(expires(i64::MAX, 1), Err(AppError::BadRequest(_))));
+    }
+}
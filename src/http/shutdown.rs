@@ -0,0 +1,122 @@
+use actix_web::dev::Server;
+use futures::future::{self, Either};
+use lazy_static::lazy_static;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::{
+    sync::Notify,
+    time::{sleep, Duration, Instant},
+};
+
+/// How often we check if all streams have ended while draining
+static DRAIN_POLL_INTERVAL: u64 = 250;
+/// Seconds streams get to finish their current segment after draining
+pub static STOP_TIMEOUT: u64 = 30;
+
+lazy_static! {
+    static ref STOP_REQUESTED: Notify = Notify::new();
+}
+
+/// Request a graceful shutdown, the same way SIGTERM does (e.g. when a Windows service is stopped)
+pub fn request_stop() {
+    STOP_REQUESTED.notify_one();
+}
+
+/// State of a graceful shutdown. When draining, no new streams are accepted, but existing streams keep
+/// going. When stopping, existing streams end after the segment they are serving.
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+    stopping: AtomicBool,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Returns true if new streams should be refused
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if existing streams should end
+    pub fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// Wait for SIGTERM or SIGINT and shut `servers` down. Active streams (as counted by `active_streams`) get
+/// `drain_timeout` seconds to finish before they're ended after their current segment. A second signal
+/// skips draining.
+pub async fn on_signal(
+    shutdown: Arc<Shutdown>,
+    servers: Vec<Server>,
+    drain_timeout: u64,
+    active_streams: impl Fn() -> usize,
+) {
+    signal().await;
+    shutdown.draining.store(true, Ordering::SeqCst);
+    super::systemd::notify("STOPPING=1");
+    info!(
+        "Shutting down, waiting up to {}s for {} active stream(s) to finish..",
+        drain_timeout,
+        active_streams()
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(drain_timeout);
+    let drained = wait_for_streams(&active_streams, deadline);
+    let mut forced = matches!(
+        future::select(Box::pin(drained), Box::pin(signal())).await,
+        Either::Right(_)
+    );
+
+    // End the remaining streams at a segment boundary, so recordings aren't cut off mid-segment
+    shutdown.stopping.store(true, Ordering::SeqCst);
+    if !forced {
+        let deadline = Instant::now() + Duration::from_secs(STOP_TIMEOUT);
+        let stopped = wait_for_streams(&active_streams, deadline);
+        forced = matches!(
+            future::select(Box::pin(stopped), Box::pin(signal())).await,
+            Either::Right(_)
+        );
+    }
+    match active_streams() {
+        0 => info!("All streams ended"),
+        n => warn!("Stopping with {} active stream(s)", n),
+    }
+
+    crate::notify::send(crate::notify::Event::Shutdown).await;
+    future::join_all(servers.iter().map(|s| s.stop(!forced))).await;
+}
+
+/// Wait until there are no active streams or `deadline` has passed
+async fn wait_for_streams(active_streams: &impl Fn() -> usize, deadline: Instant) {
+    while active_streams() > 0 && Instant::now() < deadline {
+        sleep(Duration::from_millis(DRAIN_POLL_INTERVAL)).await;
+    }
+}
+
+/// Wait for SIGTERM, SIGINT or a call to `request_stop`
+async fn signal() {
+    future::select(Box::pin(os_signal()), Box::pin(STOP_REQUESTED.notified())).await;
+}
+
+/// Wait for SIGTERM or SIGINT
+#[cfg(unix)]
+async fn os_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    future::select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
+}
+
+/// Wait for Ctrl-C. Services don't have a console, so they only stop through `request_stop`.
+#[cfg(not(unix))]
+async fn os_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,222 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// File in the cache directory that holds the statistics
+static STATS_FILE: &str = "station_stats.json";
+
+/// Days of statistics that are kept per station
+static HISTORY_DAYS: i64 = 30;
+
+/// Stream statistics of a station for a single day
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Counters {
+    /// Streams that were started or failed to start
+    tunes: u64,
+    /// Streams that couldn't be started or ended because of a problem with locast
+    failures: u64,
+    /// Streams that ended, and how long they ran in total
+    sessions: u64,
+    session_seconds: u64,
+    /// Segments that were fetched, and how long it took to fetch them in total
+    segments: u64,
+    fetch_millis: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.tunes += other.tunes;
+        self.failures += other.failures;
+        self.sessions += other.sessions;
+        self.session_seconds += other.session_seconds;
+        self.segments += other.segments;
+        self.fetch_millis += other.fetch_millis;
+    }
+}
+
+/// Statistics of a station, as reported at `/stats/stations.json`
+#[derive(Debug, Serialize)]
+pub struct StationReport {
+    pub station_id: String,
+    pub call_sign: Option<String>,
+    pub tunes: u64,
+    pub failures: u64,
+    /// Share of the tunes that failed, between 0 and 1
+    pub failure_rate: f64,
+    pub average_session_seconds: f64,
+    pub average_fetch_millis: f64,
+    /// Statistics per day (YYYY-MM-DD), oldest first
+    pub days: Vec<DayReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DayReport {
+    pub date: String,
+    pub tunes: u64,
+    pub failures: u64,
+    pub failure_rate: f64,
+    pub average_session_seconds: f64,
+    pub average_fetch_millis: f64,
+}
+
+/// Stream statistics per station and per day, shared by all tuners. They're stored in the cache
+/// directory whenever a stream ends, so they survive a restart. Only the last `HISTORY_DAYS` days are
+/// kept.
+pub struct StationStats {
+    path: PathBuf,
+    /// Counters by station id and date
+    stations: Mutex<BTreeMap<String, BTreeMap<String, Counters>>>,
+}
+
+impl StationStats {
+    pub fn new(cache_directory: &Path) -> StationStats {
+        let path = cache_directory.join(STATS_FILE);
+        let stations = if path.exists() {
+            match File::open(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()))
+            {
+                Ok(stations) => stations,
+                Err(e) => {
+                    warn!(
+                        "Unable to read station statistics from {}: {}",
+                        path.display(),
+                        e
+                    );
+                    BTreeMap::new()
+                }
+            }
+        } else {
+            BTreeMap::new()
+        };
+        StationStats {
+            path,
+            stations: Mutex::new(stations),
+        }
+    }
+
+    /// A stream of `station_id` was started
+    pub fn tuned(&self, station_id: &str) {
+        self.update(station_id, |c| c.tunes += 1);
+    }
+
+    /// A stream of `station_id` couldn't be started
+    pub fn tune_failed(&self, station_id: &str) {
+        self.update(station_id, |c| {
+            c.tunes += 1;
+            c.failures += 1;
+        });
+        self.save();
+    }
+
+    /// A segment of `station_id` was fetched in `latency`
+    pub fn segment(&self, station_id: &str, latency: Duration) {
+        self.update(station_id, |c| {
+            c.segments += 1;
+            c.fetch_millis += latency.as_millis() as u64;
+        });
+    }
+
+    /// A stream of `station_id` ended after `seconds`. `failed` is true if it didn't end because the
+    /// client went away.
+    pub fn ended(&self, station_id: &str, seconds: u64, failed: bool) {
+        self.update(station_id, |c| {
+            c.sessions += 1;
+            c.session_seconds += seconds;
+            if failed {
+                c.failures += 1;
+            }
+        });
+        self.save();
+    }
+
+    /// Statistics of all stations, with the totals of the days that are kept. `call_sign` returns the
+    /// call sign of a station id, if it's still in the lineup.
+    pub fn report<F: Fn(&str) -> Option<String>>(&self, call_sign: F) -> Vec<StationReport> {
+        let stations = self.stations.lock().unwrap();
+        stations
+            .iter()
+            .map(|(station_id, days)| {
+                let mut total = Counters::default();
+                for counters in days.values() {
+                    total.add(counters);
+                }
+                let (failure_rate, average_session_seconds, average_fetch_millis) =
+                    averages(&total);
+                StationReport {
+                    station_id: station_id.clone(),
+                    call_sign: call_sign(station_id),
+                    tunes: total.tunes,
+                    failures: total.failures,
+                    failure_rate,
+                    average_session_seconds,
+                    average_fetch_millis,
+                    days: days
+                        .iter()
+                        .map(|(date, c)| {
+                            let (failure_rate, average_session_seconds, average_fetch_millis) =
+                                averages(c);
+                            DayReport {
+                                date: date.clone(),
+                                tunes: c.tunes,
+                                failures: c.failures,
+                                failure_rate,
+                                average_session_seconds,
+                                average_fetch_millis,
+                            }
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Update the counters of today, and drop days that are no longer kept
+    fn update<F: FnOnce(&mut Counters)>(&self, station_id: &str, f: F) {
+        let today = Utc::now().naive_utc().date();
+        let oldest = (today - ChronoDuration::days(HISTORY_DAYS - 1)).to_string();
+        let mut stations = self.stations.lock().unwrap();
+        f(stations
+            .entry(station_id.to_owned())
+            .or_default()
+            .entry(today.to_string())
+            .or_default());
+        for days in stations.values_mut() {
+            days.retain(|date, _| *date >= oldest);
+        }
+        stations.retain(|_, days| !days.is_empty());
+    }
+
+    fn save(&self) {
+        let stations = self.stations.lock().unwrap();
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&self.path))
+            .and_then(|f| serde_json::to_writer(f, &*stations).map_err(|e| e.into()));
+        if let Err(e) = result {
+            error!(
+                "Unable to save station statistics to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Failure rate, average session duration and average segment fetch latency of `counters`
+fn averages(counters: &Counters) -> (f64, f64, f64) {
+    let ratio = |a: u64, b: u64| if b == 0 { 0.0 } else { a as f64 / b as f64 };
+    (
+        ratio(counters.failures, counters.tunes),
+        ratio(counters.session_seconds, counters.sessions),
+        ratio(counters.fetch_millis, counters.segments),
+    )
+}
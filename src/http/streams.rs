@@ -0,0 +1,136 @@
+use crate::service::quality::SelectedVariant;
+use actix_web::{http::header::USER_AGENT, HttpRequest};
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Streams that are being served, shared by all tuners. Used to report on streams at `/streams`.
+pub struct ActiveStreams {
+    streams: Mutex<BTreeMap<String, Arc<Mutex<StreamStatus>>>>,
+}
+
+/// Live status of a single stream
+#[derive(Debug, Serialize, Clone)]
+pub struct StreamStatus {
+    pub stream_id: String,
+    pub station_id: String,
+    /// Name of the tuner (device) the stream is served by
+    pub device: String,
+    /// Path of the request that started the stream
+    pub path: String,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub account: String,
+    /// URL of the media playlist the stream is served from
+    pub playlist_url: String,
+    /// Variant of the master playlist that was selected, if the station has one
+    pub variant: Option<SelectedVariant>,
+    /// Unix timestamp of the start of the stream
+    pub started: i64,
+    /// Seconds of video served
+    pub seconds_served: f32,
+    pub bytes_served: usize,
+    pub segments_served: usize,
+    /// URL of the last segment that was served
+    pub current_segment: Option<String>,
+    /// Times the stream ran out of segments and had to wait for locast
+    pub underruns: u64,
+    /// Times fetching the playlist or a segment failed and had to be retried
+    pub stalls: u64,
+    /// Throughput of the segment downloads from locast (kbit/s), measured over all segments
+    pub throughput_kbps: u64,
+}
+
+impl StreamStatus {
+    /// Status of a new stream of `station_id`, started by `req`
+    pub fn new(
+        req: &HttpRequest,
+        stream_id: &str,
+        station_id: &str,
+        device: String,
+        account: &str,
+        playlist_url: &str,
+    ) -> StreamStatus {
+        StreamStatus {
+            stream_id: stream_id.to_owned(),
+            station_id: station_id.to_owned(),
+            device,
+            path: req.path().to_owned(),
+            client_ip: req.peer_addr().map(|a| a.ip().to_string()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_owned()),
+            account: account.to_owned(),
+            playlist_url: playlist_url.to_owned(),
+            variant: None,
+            started: Utc::now().timestamp(),
+            seconds_served: 0.0,
+            bytes_served: 0,
+            segments_served: 0,
+            current_segment: None,
+            underruns: 0,
+            stalls: 0,
+            throughput_kbps: 0,
+        }
+    }
+}
+
+impl ActiveStreams {
+    pub fn new() -> ActiveStreams {
+        ActiveStreams {
+            streams: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add a stream. It's removed when the returned handle is dropped.
+    pub fn register(self: &Arc<Self>, status: StreamStatus) -> StreamHandle {
+        let stream_id = status.stream_id.clone();
+        let status = Arc::new(Mutex::new(status));
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(stream_id.clone(), status.clone());
+        StreamHandle {
+            stream_id,
+            status,
+            streams: self.clone(),
+        }
+    }
+
+    /// Status of all active streams, oldest first
+    pub fn list(&self) -> Vec<StreamStatus> {
+        let mut streams: Vec<StreamStatus> = self
+            .streams
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.lock().unwrap().clone())
+            .collect();
+        streams.sort_by_key(|s| s.started);
+        streams
+    }
+}
+
+/// Handle to update the status of a stream
+pub struct StreamHandle {
+    stream_id: String,
+    status: Arc<Mutex<StreamStatus>>,
+    streams: Arc<ActiveStreams>,
+}
+
+impl StreamHandle {
+    pub fn update<F: FnOnce(&mut StreamStatus)>(&self, f: F) {
+        f(&mut self.status.lock().unwrap())
+    }
+}
+
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        self.streams.streams.lock().unwrap().remove(&self.stream_id);
+    }
+}
@@ -0,0 +1,93 @@
+use lazy_static::lazy_static;
+use std::net::TcpListener;
+
+/// First file descriptor systemd passes to a socket activated service (SD_LISTEN_FDS_START)
+#[cfg(unix)]
+static LISTEN_FDS_START: i32 = 3;
+
+lazy_static! {
+    /// Sockets passed by systemd through socket activation
+    static ref ACTIVATED: Vec<TcpListener> = activated_listeners();
+}
+
+/// Listeners passed by systemd for `port`. These are duplicates of the activated sockets, so they can
+/// be used (and closed) more than once, e.g. by the setup wizard and then by the tuners.
+pub fn listeners(port: u16) -> std::io::Result<Vec<TcpListener>> {
+    ACTIVATED
+        .iter()
+        .filter(|l| l.local_addr().is_ok_and(|a| a.port() == port))
+        .map(|l| l.try_clone())
+        .collect()
+}
+
+/// Take over the TCP sockets in LISTEN_FDS, if they were meant for this process (LISTEN_PID)
+#[cfg(unix)]
+fn activated_listeners() -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+        .filter_map(|fd| {
+            // systemd hands these descriptors over to us, nothing else owns them
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(address) => {
+                    info!("Using socket {} passed by systemd", address);
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("Ignoring socket {} passed by systemd: {}", fd, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn activated_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+/// Send a state change (e.g. "READY=1") to systemd. This does nothing unless locast2tuner runs as a
+/// systemd service with Type=notify.
+pub fn notify(state: &str) {
+    #[cfg(target_os = "linux")]
+    if let Some(socket) = std::env::var_os("NOTIFY_SOCKET") {
+        if let Err(e) = send(&socket, state) {
+            warn!("Unable to notify systemd: {}", e);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+/// Send `state` to the notification socket, which is either a path or an abstract socket (starting with
+/// "@")
+#[cfg(target_os = "linux")]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    };
+
+    let address = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
@@ -1,16 +1,31 @@
 use crate::utils::aspect_ratio;
 use crate::utils::format_date;
 use crate::utils::format_date_iso;
-use crate::utils::format_time;
+use crate::utils::format_time_local;
 use crate::utils::format_time_local_iso;
 use crate::utils::quality;
 use crate::utils::split;
-use crate::{config::Config, service::station::Station, service::stationprovider::StationProvider};
-use chrono_tz::Tz;
+use crate::utils::Or;
+use crate::{
+    config::Config,
+    errors::ErrorEnvelope,
+    http::favorites::{self, FAVORITES_GROUP},
+    http::setup::SetupForm,
+    http::streams::StreamStatus,
+    http::widget::NowPlaying,
+    service::enrichment::dd_progid,
+    service::station::{channel_key, Station},
+    service::xmltv::Programmes,
+};
 use format_xml::xml;
-use htmlescape::encode_minimal;
+use htmlescape::{encode_attribute, encode_minimal};
+use std::{str::FromStr, sync::Arc};
+use string_builder::Builder;
 
-pub fn device_xml<T: StationProvider>(config: &Config, service: &T, host: String) -> String {
+/// Major networks. Stations with one of these as call sign are network affiliates.
+pub const NETWORKS: [&str; 6] = ["ABC", "CBS", "NBC", "FOX", "CW", "PBS"];
+
+pub fn device_xml(config: &Config, name: &str, uuid: &str, base_url: String) -> String {
     let r = xml! {
         <root xmlns="urn:schemas-upnp-org:device-1-0">
         <specVersion>
@@ -19,129 +34,817 @@ pub fn device_xml<T: StationProvider>(config: &Config, service: &T, host: String
         </specVersion>
         <device>
           <deviceType>{"urn:schemas-upnp-org:device:MediaServer:1"}</deviceType>
-          <friendlyName>{service.geo().name}</friendlyName>
+          <friendlyName>{name}</friendlyName>
           <manufacturer>{"locast2tuner"}</manufacturer>
           <modelName>{config.device_model}</modelName>
           <modelNumber>{config.device_version}</modelNumber>
           <serialNumber/>
-          <UDN>{"uuid:"}{service.uuid()}</UDN>
+          <UDN>{"uuid:"}{uuid}</UDN>
         </device>
-        <URLBase>{"http://"}{host}</URLBase>
+        <URLBase>{base_url}</URLBase>
       </root>
     }
     .to_string();
     r
 }
 
-pub fn lineup_xml(stations: &Vec<Station>, host: String) -> String {
+pub fn lineup_xml(stations: &[Station], base_url: String) -> String {
     let r = xml! {
         <Lineup>
             for station in (stations.iter().filter(|s| s.active)) {
                 <Program>
-                    <GuideNumber>{encode_minimal(&station.channel_remapped.as_ref().unwrap_or(&station.channel.as_ref().unwrap()))}</GuideNumber>
+                    <GuideNumber>{encode_minimal(station.channel_remapped.as_ref().unwrap_or(station.channel.as_ref().unwrap()))}</GuideNumber>
                     <GuideName>{encode_minimal(&station.name)}</GuideName>
-                    <URL>{"http://"}{host}{"/watch/"}{station.id}</URL>
+                    <URL>{base_url}{"/watch/"}{station.id}</URL>
                 </Program>
             }
         </Lineup>
     }.to_string();
     r
 }
-pub fn epg_xml(stations: &Vec<Station>) -> String {
-    let xml_version = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
-    let doctype =
-        "<!DOCTYPE tv SYSTEM \"https://raw.githubusercontent.com/XMLTV/xmltv/master/xmltv.dtd\">\n";
-    let r = xml! {
-        <tv generator-info-name="locast2tuner">
-        for station in (stations.iter().filter(|s| s.active)) {
-            <channel id={format!("channel.{}",station.id)}>
-                <display-name lang="en">{encode_minimal(&station.callSign_remapped.as_ref().unwrap_or(&station.callSign))}</display-name>
-                <display-name lang="en">{format!("{} {}", encode_minimal(&station.channel_remapped.as_ref().unwrap_or(&station.channel.as_ref().unwrap())), encode_minimal(&station.callSign_remapped.as_ref().unwrap_or(&station.callSign)))}</display-name>
-                <display-name lang="en">{encode_minimal(&station.name)}</display-name>
-                <display-name lang="en">{encode_minimal(&station.channel_remapped.as_ref().unwrap_or(&station.channel.as_ref().unwrap()))}</display-name>
-                <display-name lang="en">{station.id}</display-name>
-                <icon src={encode_minimal(&station.logoUrl.as_ref().unwrap())} />
-            </channel>
-        }
-        for station in (stations){
-            let timezone = station.timezone.as_ref().unwrap().parse::<Tz>().unwrap();
-            for program in (&station.listings) {
-                <programme start={format_time(program.startTime)}  stop={format_time(program.startTime + program.duration * 1000)} channel={format!("channel.{}",station.id)}>
-                    <title lang="en">{encode_minimal(&program.title)}</title>
-                    if let Some(description) = (&program.description) {
-                        <desc lang="en">{encode_minimal(description)}</desc>
+/// XMLTV guide, rendered one station at a time, so the whole document never has to be in memory. Yields
+/// the header, the `<channel>` of every active station, the `<programme>`s of every station and the
+/// footer.
+pub struct EpgXml {
+    stations: Arc<Vec<Station>>,
+    base_url: String,
+    overrides: Arc<Programmes>,
+    position: usize,
+}
+
+pub fn epg_xml(
+    stations: Arc<Vec<Station>>,
+    base_url: String,
+    overrides: Arc<Programmes>,
+) -> EpgXml {
+    EpgXml {
+        stations,
+        base_url,
+        overrides,
+        position: 0,
+    }
+}
+
+impl Iterator for EpgXml {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let count = self.stations.len();
+        loop {
+            let position = self.position;
+            self.position += 1;
+            return match position {
+                0 => Some(format!(
+                    "{}{}{}",
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+                    "<!DOCTYPE tv SYSTEM \"https://raw.githubusercontent.com/XMLTV/xmltv/master/xmltv.dtd\">\n",
+                    "<tv generator-info-name=\"locast2tuner\">"
+                )),
+                p if p <= count => {
+                    let station = &self.stations[p - 1];
+                    if !station.active {
+                        continue;
                     }
-                    if (program.directors.is_some() || program.topCast.is_some()){
-                        <credits>
-                            if let Some(directors) = (&program.directors) {
-                                for director in (split(directors, ", ")){
-                                    <director>{encode_minimal(&director)}</director>
-                                }
+                    Some(epg_channel_xml(station, &self.base_url))
+                }
+                p if p <= 2 * count => Some(epg_programmes_xml(
+                    &self.stations[p - count - 1],
+                    &self.overrides,
+                )),
+                p if p == 2 * count + 1 => Some("</tv>".to_owned()),
+                _ => None,
+            };
+        }
+    }
+}
+
+fn epg_channel_xml(station: &Station, base_url: &str) -> String {
+    xml! {
+        <channel id={format!("channel.{}",station.id)}>
+            <display-name lang="en">{encode_minimal(station.callSign_remapped.as_ref().unwrap_or(&station.callSign))}</display-name>
+            <display-name lang="en">{format!("{} {}", encode_minimal(station.channel_remapped.as_ref().unwrap_or(station.channel.as_ref().unwrap())), encode_minimal(station.callSign_remapped.as_ref().unwrap_or(&station.callSign)))}</display-name>
+            <display-name lang="en">{encode_minimal(&station.name)}</display-name>
+            <display-name lang="en">{encode_minimal(station.channel_remapped.as_ref().unwrap_or(station.channel.as_ref().unwrap()))}</display-name>
+            <display-name lang="en">{station.id}</display-name>
+            <icon src={format!("{}/logo/{}.png", base_url, station.id)} />
+        </channel>
+    }
+    .to_string()
+}
+
+fn epg_programmes_xml(station: &Station, overrides: &Programmes) -> String {
+    let timezone = station.tz();
+    // Programmes from an external XMLTV source replace the locast listings
+    let listings = if overrides.contains_key(&station.id) {
+        &[][..]
+    } else {
+        &station.listings[..]
+    };
+    xml! {
+        if let Some(programmes) = (overrides.get(&station.id)) {
+            for programme in (programmes) {
+                {programme}
+            }
+        }
+        for program in (listings) {
+            <programme start={format_time_local(program.startTime, &timezone)}  stop={format_time_local(program.startTime + program.duration * 1000, &timezone)} channel={format!("channel.{}",station.id)}>
+                <title lang="en">{encode_minimal(&program.title)}</title>
+                if let Some(description) = (&program.description) {
+                    <desc lang="en">{encode_minimal(description)}</desc>
+                }
+                if (program.directors.is_some() || program.topCast.is_some()){
+                    <credits>
+                        if let Some(directors) = (&program.directors) {
+                            for director in (split(directors, ", ")){
+                                <director>{encode_minimal(&director)}</director>
                             }
-                            if let Some(actors) = (&program.topCast) {
-                                for actor in (split(actors, ", ")){
-                                    <actor>{encode_minimal(&actor)}</actor>
-                                }
+                        }
+                        if let Some(actors) = (&program.topCast) {
+                            for actor in (split(actors, ", ")){
+                                <actor>{encode_minimal(&actor)}</actor>
                             }
-                        </credits>
-                    }
-                    if let Some(release_date) = (program.releaseDate) {
-                        <date>{format_date(release_date)}</date>
-                    }
-                    if let Some(genres) = (&program.genres) {
-                        for genre in (split(genres, ", ")){
-                            <category lang="en">{encode_minimal(&genre)}</category>
                         }
+                    </credits>
+                }
+                if let Some(release_date) = (program.releaseDate) {
+                    <date>{format_date(release_date)}</date>
+                }
+                if let Some(genres) = (&program.genres) {
+                    for genre in (split(genres, ", ")){
+                        <category lang="en">{encode_minimal(&genre)}</category>
                     }
-                    <category lang="en">{encode_minimal(&program.showType)}</category>
-                    <length units="seconds">{program.duration}</length>
+                }
+                <category lang="en">{encode_minimal(&program.showType)}</category>
+                <length units="seconds">{program.duration}</length>
 
-                    if (program.preferredImage.is_some() && program.preferredImageHeight.is_some() && program.preferredImageWidth.is_some()){
-                        <icon src={encode_minimal(program.preferredImage.as_ref().unwrap())} height={program.preferredImageHeight.unwrap()} width={program.preferredImageWidth.unwrap()}/>
-                    }
+                if (program.preferredImage.is_some() && program.preferredImageHeight.is_some() && program.preferredImageWidth.is_some()){
+                    <icon src={encode_minimal(program.preferredImage.as_ref().unwrap())} height={program.preferredImageHeight.unwrap()} width={program.preferredImageWidth.unwrap()}/>
+                }
 
-                    if (program.episodeNumber.is_some() && program.seasonNumber.is_some()) {
-                        <episode-num system="xmltv_ns">{format!("{}.{}.", program.seasonNumber.unwrap() - 1, program.episodeNumber.unwrap() - 1)}</episode-num>
-                    } else if (program.episodeNumber.is_some()) {
-                        <episode-num system="xmltv_ns">{format!("0.{}.", program.episodeNumber.unwrap() - 1)}</episode-num>
-                    } else if (program.genres.is_some() && &program.genres.as_ref().unwrap().to_owned() == "News" || (program.entityType != "Movie" && program.isNew.is_some() && program.isNew.unwrap())) {
-                        <episode-num system="original-air-date">{format_time_local_iso(program.startTime, &timezone)}</episode-num>
-                    } else if (program.entityType != "Movie" && program.airdate.is_some()){
-                        <episode-num system="original-air-date">{format_date_iso(*program.airdate.as_ref().unwrap())}</episode-num>
-                    }
+                if (program.episodeNumber.is_some() && program.seasonNumber.is_some()) {
+                    <episode-num system="xmltv_ns">{format!("{}.{}.", program.seasonNumber.unwrap() - 1, program.episodeNumber.unwrap() - 1)}</episode-num>
+                } else if (program.episodeNumber.is_some()) {
+                    <episode-num system="xmltv_ns">{format!("0.{}.", program.episodeNumber.unwrap() - 1)}</episode-num>
+                } else if (program.genres.is_some() && &program.genres.as_ref().unwrap().to_owned() == "News" || (program.entityType != "Movie" && program.isNew.is_some() && program.isNew.unwrap())) {
+                    <episode-num system="original-air-date">{format_time_local_iso(program.startTime, &timezone)}</episode-num>
+                } else if (program.entityType != "Movie" && program.airdate.is_some()){
+                    <episode-num system="original-air-date">{format_date_iso(*program.airdate.as_ref().unwrap())}</episode-num>
+                }
 
-                    <episode-num system="dd_progid">{program.programId}</episode-num>
-                    if let Some(video_properties) = (&program.videoProperties){
-                        <video>
-                            <present>{"yes"}</present>
-                            <aspect>{aspect_ratio(video_properties)}</aspect>
-                            <quality>{quality(video_properties)}</quality>
-                        </video>
-                    }
+                <episode-num system="dd_progid">{encode_minimal(&dd_progid(&program.programId))}</episode-num>
+                if let Some(video_properties) = (&program.videoProperties){
+                    <video>
+                        <present>{"yes"}</present>
+                        <aspect>{aspect_ratio(video_properties)}</aspect>
+                        <quality>{quality(video_properties)}</quality>
+                    </video>
+                }
+
+                <audio>
+                <present>{"yes"}</present>
+                <stereo>{"stereo"}</stereo>
+                </audio>
 
-                    <audio>
-                    <present>{"yes"}</present>
-                    <stereo>{"stereo"}</stereo>
-                    </audio>
-
-                    if (program.isNew.is_some() && *program.isNew.as_ref().unwrap()){
-                        <new />
-                    } else if (program.airdate.is_some()) {
-                        <previously-shown start={format_date_iso(*program.airdate.as_ref().unwrap())}/>
-                    } else {
-                        <previously-shown />
+                if (program.isNew == Some(true) || program.isLive == Some(true) || program.isPremiere == Some(true)){
+                    <new />
+                } else if (program.airdate.is_some()) {
+                    <previously-shown start={format_date_iso(*program.airdate.as_ref().unwrap())}/>
+                } else {
+                    <previously-shown />
+                }
+                if (program.isLive == Some(true)) {
+                    <live />
+                }
+                if (program.isPremiere == Some(true)) {
+                    <premiere />
+                }
+
+                if let Some(rating) = (&program.rating) {
+                    <rating system="VCHIP">
+                    <value>{rating}</value>
+                    </rating>
+                }
+            </programme>
+        }
+    }
+    .to_string()
+}
+
+pub fn admin_html(config: &Config, stations: &[Station], favorites: &[i64]) -> String {
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - admin"}</title>
+            <style>{"body { font-family: sans-serif; } td, th { padding: 2px 8px; text-align: left; }"}</style>
+        </head>
+        <body>
+            <h1>{"Channels"}</h1>
+            if (config.remap) {
+                <p><b>{"Note:"}</b>{" remap is enabled, so the remappings below will be saved, but not used."}</p>
+            }
+            if (!config.multiplex) {
+                <p><b>{"Note:"}</b>{" channel remapping is only available when multiplexing."}</p>
+            }
+            if (config.remap_file.is_none()) {
+                <p><b>{"Note:"}</b>{" no remap_file is configured, so changes are lost when locast2tuner restarts."}</p>
+            }
+            <form method="post" action="admin">
+            <table>
+                <tr>
+                    <th>{"Active"}</th>
+                    <th>{"Favorite"}</th>
+                    <th>{"City"}</th>
+                    <th>{"Channel"}</th>
+                    <th>{"Call sign"}</th>
+                    <th>{"Remapped channel"}</th>
+                    <th>{"Remapped call sign"}</th>
+                    <th></th>
+                </tr>
+                for station in (stations) {
+                    let key = format!("channel.{}", station.id);
+                    <tr>
+                        <td>
+                            if (station.active) {
+                                <input type="checkbox" name={format!("{}.active", key)} checked="checked" />
+                            } else {
+                                <input type="checkbox" name={format!("{}.active", key)} />
+                            }
+                        </td>
+                        <td>
+                            if (favorites.contains(&station.id)) {
+                                <input type="checkbox" name={format!("{}.favorite", key)} checked="checked" />
+                            } else {
+                                <input type="checkbox" name={format!("{}.favorite", key)} />
+                            }
+                        </td>
+                        <td>{encode_minimal(station.city.as_ref().unwrap())}</td>
+                        <td>{encode_minimal(station.channel.as_ref().unwrap())}</td>
+                        <td>{encode_minimal(&station.callSign)}</td>
+                        <td><input type="text" name={format!("{}.remap_channel", key)} value={encode_attribute(station.channel_remapped.as_ref().unwrap_or(station.channel.as_ref().unwrap()))} /></td>
+                        <td><input type="text" name={format!("{}.remap_call_sign", key)} value={encode_attribute(station.callSign_remapped.as_ref().unwrap_or(&station.callSign))} /></td>
+                        <td><a href={format!("preview/{}", station.id)} target="_blank">{"Preview"}</a></td>
+                    </tr>
+                }
+            </table>
+            <input type="submit" value="Save" />
+            </form>
+        </body>
+        </html>
+    }
+    .to_string();
+    format!("<!DOCTYPE html>\n{}", r)
+}
+
+/// Page with an HLS.js player for a single station, streaming from `playlist_url`. Browsers with native
+/// HLS support (Safari) play the playlist directly.
+pub fn preview_html(station: &Station, playlist_url: &str) -> String {
+    let channel = station
+        .channel_remapped
+        .as_ref()
+        .or(station.channel.as_ref())
+        .map_or("", |c| c.as_str());
+    let name = format!("{} {}", channel, station.callSign);
+    let script = format!(
+        r#"
+var video = document.getElementById("video");
+var status = document.getElementById("status");
+var url = "{}";
+if (window.Hls && Hls.isSupported()) {{
+    var hls = new Hls();
+    hls.on(Hls.Events.ERROR, function (event, data) {{
+        if (data.fatal) {{
+            status.textContent = "Playback failed: " + data.details;
+        }}
+    }});
+    hls.loadSource(url);
+    hls.attachMedia(video);
+}} else if (video.canPlayType("application/vnd.apple.mpegurl")) {{
+    video.src = url;
+}} else {{
+    status.textContent = "This browser can't play HLS streams.";
+}}
+"#,
+        playlist_url
+    );
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - "}{encode_minimal(&name)}</title>
+            <style>{"body { font-family: sans-serif; } video { width: 100%; max-width: 960px; background: black; }"}</style>
+            <script src="https://cdn.jsdelivr.net/npm/hls.js@1"></script>
+        </head>
+        <body>
+            <h1>{encode_minimal(&name)}</h1>
+            <video id="video" controls="controls" autoplay="autoplay" muted="muted"></video>
+            <p id="status"></p>
+            <script>{script}</script>
+        </body>
+        </html>
+    }
+    .to_string();
+    format!("<!DOCTYPE html>\n{}", r)
+}
+
+pub fn nowplaying_html(now_playing: &NowPlaying) -> String {
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - now playing"}</title>
+            <meta http-equiv="refresh" content="60" />
+            <style>{"body { font-family: sans-serif; font-size: 12px; margin: 0; } td, th { padding: 2px 6px; text-align: left; vertical-align: top; } .channel, .time { color: gray; }"}</style>
+        </head>
+        <body>
+            <table>
+                <tr>
+                    <th></th>
+                    for network in (now_playing.networks) {
+                        <th>{network}</th>
                     }
+                </tr>
+                for market in (&now_playing.markets) {
+                    <tr>
+                        <th>{encode_minimal(&market.city)}</th>
+                        for network in (now_playing.networks) {
+                            <td>
+                                if let Some(affiliate) = (market.stations.iter().find(|a| a.network == *network)) {
+                                    <div class="channel">{encode_minimal(&affiliate.channel)}{" "}{encode_minimal(&affiliate.call_sign)}</div>
+                                    if let Some(programme) = (&affiliate.programme) {
+                                        <div>{encode_minimal(&programme.title)}</div>
+                                        <div class="time">{&programme.local_start}{" - "}{&programme.local_end}</div>
+                                    }
+                                }
+                            </td>
+                        }
+                    </tr>
+                }
+            </table>
+        </body>
+        </html>
+    }
+    .to_string();
+    format!("<!DOCTYPE html>\n{}", r)
+}
 
-                    if let Some(rating) = (&program.rating) {
-                        <rating system="VCHIP">
-                        <value>{rating}</value>
-                        </rating>
+pub fn streams_html(streams: &[StreamStatus]) -> String {
+    let started = |s: &StreamStatus| {
+        chrono::NaiveDateTime::from_timestamp(s.started, 0)
+            .format("%Y-%m-%d %H:%M:%S UTC")
+            .to_string()
+    };
+    let variant = |s: &StreamStatus| match &s.variant {
+        Some(v) => format!(
+            "{} kbit/s{}",
+            v.bandwidth / 1000,
+            v.resolution
+                .as_ref()
+                .map_or(String::new(), |r| format!(", {}", r))
+        ),
+        None => "-".to_owned(),
+    };
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - streams"}</title>
+            <meta http-equiv="refresh" content="10" />
+            <style>{"body { font-family: sans-serif; font-size: 12px; } td, th { padding: 2px 6px; text-align: left; vertical-align: top; } .segment { color: gray; word-break: break-all; }"}</style>
+        </head>
+        <body>
+            if (streams.is_empty()) {
+                <p>{"No active streams"}</p>
+            } else {
+                <table>
+                    <tr>
+                        <th>{"Stream"}</th>
+                        <th>{"Station"}</th>
+                        <th>{"Tuner"}</th>
+                        <th>{"Client"}</th>
+                        <th>{"User agent"}</th>
+                        <th>{"Started"}</th>
+                        <th>{"Served"}</th>
+                        <th>{"Underruns"}</th>
+                        <th>{"Stalls"}</th>
+                        <th>{"Variant"}</th>
+                        <th>{"Throughput"}</th>
+                        <th>{"Current segment"}</th>
+                    </tr>
+                    for stream in (streams) {
+                        <tr>
+                            <td>{encode_minimal(&stream.stream_id)}</td>
+                            <td>{encode_minimal(&stream.station_id)}</td>
+                            <td>{encode_minimal(&stream.device)}</td>
+                            <td>{encode_minimal(stream.client_ip.as_deref().unwrap_or("-"))}</td>
+                            <td>{encode_minimal(stream.user_agent.as_deref().unwrap_or("-"))}</td>
+                            <td>{started(stream)}</td>
+                            <td>{format!("{:.0}s, {} segments, {} bytes", stream.seconds_served, stream.segments_served, stream.bytes_served)}</td>
+                            <td>{stream.underruns}</td>
+                            <td>{stream.stalls}</td>
+                            <td>{variant(stream)}</td>
+                            <td>{format!("{} kbit/s", stream.throughput_kbps)}</td>
+                            <td class="segment">{encode_minimal(stream.current_segment.as_deref().unwrap_or("-"))}</td>
+                        </tr>
                     }
-                </programme>
+                </table>
             }
-        }
-        </tv>
+        </body>
+        </html>
+    }
+    .to_string();
+    format!("<!DOCTYPE html>\n{}", r)
+}
+
+pub fn error_html(envelope: &ErrorEnvelope) -> String {
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - error "}{envelope.code}</title>
+            <style>{"body { font-family: sans-serif; } .id { color: gray; }"}</style>
+        </head>
+        <body>
+            <h1>{envelope.code}{" - "}{encode_minimal(&envelope.message)}</h1>
+            if let Some(details) = (&envelope.details) {
+                <p>{encode_minimal(details)}</p>
+            }
+            if let Some(station_id) = (&envelope.station_id) {
+                <p class="id">{"Station: "}{encode_minimal(station_id)}</p>
+            }
+            if let Some(request_id) = (&envelope.request_id) {
+                <p class="id">{"Request id: "}{encode_minimal(request_id)}</p>
+            }
+        </body>
+        </html>
+    }
+    .to_string();
+    format!("<!DOCTYPE html>\n{}", r)
+}
+
+pub fn setup_html(form: &SetupForm, error: Option<&str>) -> String {
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - setup"}</title>
+            <style>{"body { font-family: sans-serif; } td { padding: 2px 8px; } .error { color: red; } .help { color: gray; }"}</style>
+        </head>
+        <body>
+            <h1>{"Welcome to locast2tuner"}</h1>
+            <p>{"Enter your locast.org account to get started. Your settings are validated with locast and saved to the config file."}</p>
+            if let Some(error) = (error) {
+                <p class="error">{encode_minimal(error)}</p>
+            }
+            <form method="post" action="/setup">
+            <table>
+                <tr>
+                    <td>{"Username"}</td>
+                    <td><input type="text" name="username" value={encode_attribute(&form.username)} /></td>
+                </tr>
+                <tr>
+                    <td>{"Password"}</td>
+                    <td><input type="password" name="password" /></td>
+                </tr>
+                <tr>
+                    <td>{"Zip codes"}</td>
+                    <td>
+                        <input type="text" name="zipcodes" value={encode_attribute(&form.zipcodes)} />
+                        <span class="help">{" Comma separated. Leave empty to use your current location."}</span>
+                    </td>
+                </tr>
+                <tr>
+                    <td>{"Multiplex"}</td>
+                    <td>
+                        if (form.multiplex.is_some()) {
+                            <input type="checkbox" name="multiplex" checked="checked" />
+                        } else {
+                            <input type="checkbox" name="multiplex" />
+                        }
+                        <span class="help">{" Serve all locations through a single tuner."}</span>
+                    </td>
+                </tr>
+                <tr>
+                    <td>{"Tuner count"}</td>
+                    <td><input type="text" name="tuner_count" value={encode_attribute(&form.tuner_count)} /></td>
+                </tr>
+            </table>
+            <input type="submit" value="Save" />
+            </form>
+        </body>
+        </html>
     }
     .to_string();
-    format!("{}{}{}", xml_version, doctype, r)
+    format!("<!DOCTYPE html>\n{}", r)
+}
+
+pub fn setup_done_html(config_file: &str) -> String {
+    let r = xml! {
+        <html>
+        <head>
+            <title>{"locast2tuner - setup"}</title>
+            <style>{"body { font-family: sans-serif; }"}</style>
+        </head>
+        <body>
+            <h1>{"Setup complete"}</h1>
+            <p>{"Your settings were saved to "}<code>{encode_minimal(config_file)}</code>{". locast2tuner is starting and will be available at this address in a moment."}</p>
+        </body>
+        </html>
+    }
+    .to_string();
+    format!("<!DOCTYPE html>\n{}", r)
+}
+
+/// Playlist with a single shared station, streamed from `url`
+pub fn share_m3u(station: &Station, url: &str) -> String {
+    let call_sign = station
+        .callSign_remapped
+        .as_ref()
+        .unwrap_or(&station.callSign);
+    let channel = station
+        .channel_remapped
+        .as_ref()
+        .or(station.channel.as_ref())
+        .map_or("", |c| c.as_str());
+    format!(
+        "#EXTM3U\n#EXTINF:-1 tvg-id=\"channel.{}\" tvg-name=\"{}\" tvg-chno=\"{}\", {} {}\n{}\n",
+        station.id, call_sign, channel, channel, call_sign, url
+    )
+}
+
+/// Flavor of `/tuner.m3u`. Clients look for slightly different attributes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum M3uFormat {
+    /// Generic playlist, as used by Plex and Emby
+    Plex,
+    /// Channels DVR, which matches guide data on `tvc-guide-stationid`
+    Channels,
+    /// TVHeadend, which numbers channels with `tvh-chnum` and prefers MPEG-TS streams
+    Tvheadend,
+}
+
+impl FromStr for M3uFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plex" => Ok(M3uFormat::Plex),
+            "channels" => Ok(M3uFormat::Channels),
+            "tvheadend" => Ok(M3uFormat::Tvheadend),
+            _ => Err(format!(
+                "Invalid format {}, expected plex, channels or tvheadend",
+                s
+            )),
+        }
+    }
+}
+
+/// Order of the stations in `/tuner.m3u`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum M3uSort {
+    /// The order of the lineup
+    Lineup,
+    Channel,
+    CallSign,
+    /// By city, and by channel number within a city
+    City,
+}
+
+impl FromStr for M3uSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lineup" => Ok(M3uSort::Lineup),
+            "channel" => Ok(M3uSort::Channel),
+            "call_sign" => Ok(M3uSort::CallSign),
+            "city" => Ok(M3uSort::City),
+            _ => Err(format!(
+                "Invalid sort order {}, expected lineup, channel, call_sign or city",
+                s
+            )),
+        }
+    }
+}
+
+/// How channel numbers are presented in the lineup, playlists and guide (`channel_format`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelFormat {
+    /// As is, e.g. `4.1`
+    Decimal,
+    /// Without the subchannel of the main channel, e.g. `4.1` becomes `4` and `4.2` stays `4.2`
+    Major,
+    /// Integers with the subchannel appended, e.g. `4.1` becomes `41` and `13.2` becomes `132`
+    Integer,
+}
+
+impl FromStr for ChannelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "decimal" => Ok(ChannelFormat::Decimal),
+            "major" => Ok(ChannelFormat::Major),
+            "integer" => Ok(ChannelFormat::Integer),
+            _ => Err(format!(
+                "Invalid channel format {}, expected decimal, major or integer",
+                s
+            )),
+        }
+    }
+}
+
+impl ChannelFormat {
+    /// Format a channel number. Channels that aren't `major.minor` numbers are left alone.
+    pub fn format(&self, channel: &str) -> String {
+        let parts = channel
+            .split_once('.')
+            .filter(|(major, minor)| major.parse::<u32>().is_ok() && minor.parse::<u32>().is_ok());
+        match (self, parts) {
+            (ChannelFormat::Major, Some((major, "1"))) => major.to_owned(),
+            (ChannelFormat::Integer, Some((major, minor))) => format!("{}{}", major, minor),
+            _ => channel.to_owned(),
+        }
+    }
+
+    /// Replace the (remapped) channel numbers of `stations` with formatted ones
+    pub fn apply(&self, stations: &mut [Station]) {
+        if *self == ChannelFormat::Decimal {
+            return;
+        }
+        for station in stations {
+            let channel = station
+                .channel_remapped
+                .as_ref()
+                .or(station.channel.as_ref())
+                .map(|c| self.format(c));
+            if channel.is_some() {
+                station.channel_remapped = channel;
+            }
+        }
+    }
+}
+
+/// How the `group-title` of a station in `/tuner.m3u` is composed. Groups are separated by `;`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum M3uGroup {
+    /// The city, and `Network` for network affiliates
+    City,
+    /// The network of affiliates, `Independent` for all other stations
+    Network,
+    /// A template with `{city}`, `{network}`, `{call_sign}`, `{channel}` and `{name}` placeholders.
+    /// Groups that end up empty (e.g. `{network}` of a station that isn't an affiliate) are left out.
+    Template(String),
+}
+
+impl FromStr for M3uGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "city" => Ok(M3uGroup::City),
+            "network" => Ok(M3uGroup::Network),
+            _ if s.contains('{') => Ok(M3uGroup::Template(s.to_owned())),
+            _ => Err(format!(
+                "Invalid grouping {}, expected city, network or a template like {{city}};{{network}}",
+                s
+            )),
+        }
+    }
+}
+
+impl M3uGroup {
+    fn groups(&self, station: &Station, call_sign: &str, city: &str, channel: &str) -> String {
+        let network = NETWORKS.iter().find(|n| **n == call_sign);
+        match self {
+            M3uGroup::City => match network {
+                Some(_) => format!("{};Network", city),
+                None => city.to_owned(),
+            },
+            M3uGroup::Network => network.unwrap_or(&"Independent").to_string(),
+            M3uGroup::Template(template) => template
+                .replace("{city}", city)
+                .replace("{network}", network.unwrap_or(&""))
+                .replace("{call_sign}", call_sign)
+                .replace("{channel}", channel)
+                .replace("{name}", &station.name)
+                .split(';')
+                .map(str::trim)
+                .filter(|g| !g.is_empty())
+                .collect::<Vec<_>>()
+                .join(";"),
+        }
+    }
+}
+
+/// Name of the stations in `/tuner.m3u`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelName {
+    CallSign,
+    /// The call sign followed by the city, to tell apart the stations of multiplexed locations
+    CallSignAndCity,
+    /// A template with `{call_sign}`, `{city}`, `{channel}` and `{name}` placeholders
+    /// (`channel_name_template`)
+    Template(String),
+}
+
+impl ChannelName {
+    pub fn new(template: Option<&String>, multiplex: bool) -> ChannelName {
+        match template {
+            Some(t) => ChannelName::Template(t.to_owned()),
+            None if multiplex => ChannelName::CallSignAndCity,
+            None => ChannelName::CallSign,
+        }
+    }
+
+    fn name(&self, station: &Station, call_sign: &str, city: &str, channel: &str) -> String {
+        match self {
+            ChannelName::CallSign => call_sign.to_owned(),
+            ChannelName::CallSignAndCity => format!("{} ({})", call_sign, city),
+            ChannelName::Template(template) => template
+                .replace("{call_sign}", call_sign)
+                .replace("{city}", city)
+                .replace("{channel}", channel)
+                .replace("{name}", &station.name)
+                .trim()
+                .to_owned(),
+        }
+    }
+}
+
+pub fn tuner_m3u(
+    stations: &[Station],
+    base_url: &str,
+    name: &ChannelName,
+    format: M3uFormat,
+    sort: M3uSort,
+    group: &M3uGroup,
+    favorites: &[i64],
+) -> String {
+    let mut builder = Builder::default();
+    builder.append("#EXTM3U\n");
+
+    let call_sign_of = |station: &Station| {
+        station
+            .callSign_remapped
+            .clone()
+            .unwrap_or_else(|| station.callSign.or(&station.name).to_string())
+    };
+    let channel_of = |station: &Station| {
+        station
+            .channel_remapped
+            .clone()
+            .unwrap_or_else(|| station.channel.clone().unwrap())
+    };
+    let mut stations: Vec<&Station> = stations.iter().filter(|s| s.active).collect();
+    match sort {
+        M3uSort::Lineup => {}
+        M3uSort::Channel => stations.sort_by_cached_key(|s| channel_key(&channel_of(s))),
+        M3uSort::CallSign => stations.sort_by_cached_key(|s| call_sign_of(s)),
+        M3uSort::City => {
+            stations.sort_by_cached_key(|s| (s.city.clone(), channel_key(&channel_of(s))))
+        }
+    }
+    // Favorites come first, no matter how the other stations are sorted
+    stations.sort_by_key(|s| favorites::rank(favorites, s.id));
+
+    for station in stations {
+        let call_sign = &call_sign_of(station);
+        let city = station.city.as_ref().unwrap();
+        let logo = format!("{}/logo/{}.png", base_url, &station.id);
+        let channel = &channel_of(station);
+        let mut groups = group.groups(station, call_sign, city, channel);
+        if favorites.contains(&station.id) {
+            groups = if groups.is_empty() {
+                FAVORITES_GROUP.to_owned()
+            } else {
+                format!("{};{}", FAVORITES_GROUP, groups)
+            };
+        }
+
+        let tvg_name = name.name(station, call_sign, city, channel);
+
+        let (extinf, url) = match format {
+            M3uFormat::Plex => (
+                format!(
+                    "#EXTINF:-1 tvg-id=\"channel.{}\" tvg-name=\"{}\" tvg-logo=\"{}\" tvg-chno=\"{}\" group-title=\"{}\", {}",
+                    &station.id, &call_sign, &logo, &channel, &groups, &tvg_name
+                ),
+                format!("{}/watch/{}.m3u", base_url, &station.id),
+            ),
+            M3uFormat::Channels => (
+                format!(
+                    "#EXTINF:-1 channel-id=\"channel.{}\" channel-number=\"{}\" tvg-logo=\"{}\" tvc-guide-stationid=\"{}\" tvc-guide-title=\"{}\" group-title=\"{}\", {}",
+                    &station.id, &channel, &logo, &station.stationId, &call_sign, &groups, &tvg_name
+                ),
+                format!("{}/watch/{}.m3u", base_url, &station.id),
+            ),
+            M3uFormat::Tvheadend => (
+                format!(
+                    "#EXTINF:-1 tvg-id=\"channel.{}\" tvg-name=\"{}\" tvg-logo=\"{}\" tvh-chnum=\"{}\" group-title=\"{}\", {}",
+                    &station.id, &call_sign, &logo, &channel, &groups, &tvg_name
+                ),
+                // TVHeadend works best with a plain MPEG-TS stream
+                format!("{}/watch/{}", base_url, &station.id),
+            ),
+        };
+        builder.append(extinf);
+        builder.append(format!("\n{}\n\n", url));
+    }
+
+    builder.string().unwrap()
 }
@@ -0,0 +1,240 @@
+use super::playlist::ServedSegment;
+use crate::config::Config;
+use actix_web::web;
+use futures::{Stream, StreamExt};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A timeshift buffer is stopped when its playlist wasn't requested for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a buffer checks whether it's still being watched
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Timeshift buffers of the stations that are being watched through their HLS playlist. Buffers are
+/// shared by all tuners.
+pub struct Timeshifts {
+    buffers: Mutex<HashMap<String, Arc<TimeshiftBuffer>>>,
+    dir: PathBuf,
+    /// How much of a station is kept, 0 if timeshifting is disabled
+    window: Duration,
+}
+
+impl Timeshifts {
+    pub fn new(config: &Config) -> Timeshifts {
+        let dir = config.cache_directory.join("timeshift");
+        // Buffers don't survive a restart
+        let _ = fs::remove_dir_all(&dir);
+        Timeshifts {
+            buffers: Mutex::new(HashMap::new()),
+            dir,
+            window: Duration::from_secs(config.timeshift_minutes * 60),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// The running buffer of `station_id`, if any
+    pub fn get(&self, station_id: &str) -> Option<Arc<TimeshiftBuffer>> {
+        self.buffers.lock().unwrap().get(station_id).cloned()
+    }
+
+    /// Create a buffer for `station_id`, which has to be filled with `fill`. If another request
+    /// created one in the meantime, that one is returned instead, together with `false`.
+    pub fn create(&self, station_id: &str) -> std::io::Result<(Arc<TimeshiftBuffer>, bool)> {
+        let mut buffers = self.buffers.lock().unwrap();
+        if let Some(buffer) = buffers.get(station_id) {
+            return Ok((buffer.clone(), false));
+        }
+        let dir = self.dir.join(station_id);
+        fs::create_dir_all(&dir)?;
+        let buffer = Arc::new(TimeshiftBuffer {
+            station_id: station_id.to_owned(),
+            dir,
+            window: self.window,
+            state: Mutex::new(BufferState::default()),
+            last_request: Mutex::new(Instant::now()),
+        });
+        buffers.insert(station_id.to_owned(), buffer.clone());
+        Ok((buffer, true))
+    }
+
+    /// Fill `buffer` with the segments of `stream` until nobody requested its playlist for a while or
+    /// the stream ends. The buffer is removed afterwards.
+    pub async fn fill<S>(self: Arc<Self>, buffer: Arc<TimeshiftBuffer>, mut stream: S)
+    where
+        S: Stream<Item = Result<ServedSegment, actix_web::Error>> + Unpin,
+    {
+        info!(
+            "Timeshift buffer for station {} started, keeping {} minutes",
+            buffer.station_id,
+            self.window.as_secs() / 60
+        );
+        loop {
+            if buffer.idle() {
+                info!(
+                    "Timeshift buffer for station {} isn't watched anymore",
+                    buffer.station_id
+                );
+                break;
+            }
+            match tokio::time::timeout(IDLE_CHECK_INTERVAL, stream.next()).await {
+                Ok(Some(Ok(segment))) => {
+                    if let Err(e) = buffer.push(segment).await {
+                        warn!(
+                            "Unable to write timeshift segment for station {}: {}",
+                            buffer.station_id, e
+                        );
+                        break;
+                    }
+                }
+                Ok(_) => break,
+                // No new segment yet
+                Err(_) => {}
+            }
+        }
+        drop(stream);
+
+        buffer.state.lock().unwrap().ended = true;
+        self.buffers.lock().unwrap().remove(&buffer.station_id);
+        let _ = fs::remove_dir_all(&buffer.dir);
+        info!("Timeshift buffer for station {} stopped", buffer.station_id);
+    }
+}
+
+/// The last `timeshift_minutes` of segments of a station, stored on disk
+pub struct TimeshiftBuffer {
+    pub station_id: String,
+    dir: PathBuf,
+    window: Duration,
+    state: Mutex<BufferState>,
+    last_request: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct BufferState {
+    segments: VecDeque<BufferedSegment>,
+    /// Sequence number of the next segment
+    next_sequence: u64,
+    /// Whether segments were removed from the start of the buffer
+    wrapped: bool,
+    /// Discontinuities that were removed from the start of the buffer
+    discontinuity_sequence: u64,
+    /// Whether the stream that fills the buffer ended
+    ended: bool,
+}
+
+#[derive(Clone)]
+struct BufferedSegment {
+    sequence: u64,
+    duration: Duration,
+    /// Whether the segment follows a discontinuity, e.g. an ad break
+    discontinuity: bool,
+}
+
+impl TimeshiftBuffer {
+    /// Remember that the buffer is being watched
+    pub fn touch(&self) {
+        *self.last_request.lock().unwrap() = Instant::now();
+    }
+
+    fn idle(&self) -> bool {
+        self.last_request.lock().unwrap().elapsed() >= IDLE_TIMEOUT
+    }
+
+    /// Returns true if the buffer has at least one segment
+    pub fn ready(&self) -> bool {
+        !self.state.lock().unwrap().segments.is_empty()
+    }
+
+    /// Path of the segment with `sequence`, if it's still in the buffer
+    pub fn segment_path(&self, sequence: u64) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state
+            .segments
+            .iter()
+            .any(|s| s.sequence == sequence)
+            .then(|| self.path(sequence))
+    }
+
+    fn path(&self, sequence: u64) -> PathBuf {
+        self.dir.join(format!("{}.ts", sequence))
+    }
+
+    /// Add a segment, and remove the oldest segments that fall outside of the window
+    async fn push(&self, segment: ServedSegment) -> std::io::Result<()> {
+        let sequence = self.state.lock().unwrap().next_sequence;
+        let path = self.path(sequence);
+        let bytes = segment.bytes;
+        web::block(move || fs::write(path, &bytes))
+            .await
+            .map_err(|_| std::io::Error::other("blocking write was canceled"))??;
+
+        let mut state = self.state.lock().unwrap();
+        state.next_sequence += 1;
+        state.segments.push_back(BufferedSegment {
+            sequence,
+            duration: segment.duration,
+            discontinuity: segment.discontinuity,
+        });
+        while state.segments.len() > 1
+            && state.segments.iter().map(|s| s.duration).sum::<Duration>() > self.window
+        {
+            let oldest = state.segments.pop_front().unwrap();
+            let _ = fs::remove_file(self.path(oldest.sequence));
+            if oldest.discontinuity {
+                state.discontinuity_sequence += 1;
+            }
+            state.wrapped = true;
+        }
+        Ok(())
+    }
+
+    /// HLS playlist of the buffer. Segment URLs are `{segment_url}/{sequence}.ts`. As long as no
+    /// segments were removed from the buffer, this is an EVENT playlist, so clients can seek back to the
+    /// start. After that it's a live playlist with a sliding window of `timeshift_minutes`.
+    pub fn playlist(&self, segment_url: &str) -> String {
+        let state = self.state.lock().unwrap();
+        let target_duration = state
+            .segments
+            .iter()
+            .map(|s| s.duration.as_secs_f32().ceil() as u64)
+            .max()
+            .unwrap_or(1);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target_duration,
+            state.segments.front().map_or(0, |s| s.sequence)
+        );
+        if !state.wrapped {
+            playlist.push_str("#EXT-X-PLAYLIST-TYPE:EVENT\n");
+        }
+        if state.discontinuity_sequence > 0 {
+            playlist.push_str(&format!(
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+                state.discontinuity_sequence
+            ));
+        }
+        for s in &state.segments {
+            if s.discontinuity {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\n{}/{}.ts\n",
+                s.duration.as_secs_f32(),
+                segment_url,
+                s.sequence
+            ));
+        }
+        if state.ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+        playlist
+    }
+}
@@ -0,0 +1,46 @@
+use rustls::{internal::pemfile, Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use std::{
+    fs::File,
+    io::{self, BufReader, Error, ErrorKind},
+};
+
+/// Build a rustls server configuration from a PEM encoded certificate chain and private key. The
+/// private key can either be a PKCS#8 or an RSA key.
+pub fn server_config(cert_file: &str, key_file: &str) -> io::Result<ServerConfig> {
+    let certs = load_certs(cert_file)?;
+    let key = load_key(key_file)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", cert_file, e)))?;
+    Ok(config)
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs =
+        pemfile::certs(&mut reader).map_err(|_| invalid(path, "unable to read certificates"))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader)
+        .map_err(|_| invalid(path, "unable to read private key"))?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader)
+            .map_err(|_| invalid(path, "unable to read private key"))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn invalid(path: &str, message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", path, message))
+}
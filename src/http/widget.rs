@@ -0,0 +1,130 @@
+use super::templates::NETWORKS;
+use crate::{
+    service::station::{channel_key, Station},
+    utils::format_clock_local,
+};
+use chrono::Utc;
+use chrono_tz::Tz;
+use serde::Serialize;
+
+/// What the major network affiliates in each market are currently airing, served at
+/// `/widget/nowplaying.json`
+#[derive(Serialize)]
+pub struct NowPlaying {
+    pub networks: &'static [&'static str],
+    pub markets: Vec<Market>,
+}
+
+#[derive(Serialize)]
+pub struct Market {
+    pub city: String,
+    pub dma: i64,
+    pub timezone: String,
+    pub stations: Vec<Affiliate>,
+}
+
+#[derive(Serialize)]
+pub struct Affiliate {
+    pub network: &'static str,
+    pub station_id: i64,
+    pub call_sign: String,
+    pub channel: String,
+    pub logo: String,
+    /// The programme that is currently airing, if there is guide data for it
+    pub programme: Option<Programme>,
+}
+
+/// A programme. Start and end are unix timestamps, local start and end are in the timezone of the
+/// market (HH:MM).
+#[derive(Serialize)]
+pub struct Programme {
+    pub title: String,
+    pub episode_title: Option<String>,
+    pub start: i64,
+    pub end: i64,
+    pub local_start: String,
+    pub local_end: String,
+}
+
+/// Build the now playing overview of `stations`. For every market, the affiliate of each network in
+/// `NETWORKS` with the lowest channel number is picked.
+pub fn now_playing(stations: &[Station], base_url: &str) -> NowPlaying {
+    let now = Utc::now().timestamp();
+    let mut markets: Vec<Market> = Vec::new();
+
+    for station in stations.iter().filter(|s| s.active) {
+        let network = match network_of(station) {
+            Some(n) => n,
+            None => continue,
+        };
+        let city = station.city.clone().unwrap_or_default();
+        let timezone = station.timezone.clone().unwrap_or_else(|| "UTC".to_owned());
+        let market = match markets.iter().position(|m| m.city == city) {
+            Some(i) => &mut markets[i],
+            None => {
+                markets.push(Market {
+                    city,
+                    dma: station.dma,
+                    timezone,
+                    stations: Vec::new(),
+                });
+                markets.last_mut().unwrap()
+            }
+        };
+
+        let channel = station
+            .channel_remapped
+            .as_ref()
+            .or(station.channel.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        if let Some(existing) = market.stations.iter().find(|a| a.network == network) {
+            if channel_key(&existing.channel) <= channel_key(&channel) {
+                continue;
+            }
+        }
+        market.stations.retain(|a| a.network != network);
+
+        let tz = market.timezone.parse::<Tz>().unwrap_or(Tz::UTC);
+        let programme = station
+            .listings
+            .iter()
+            .find(|l| l.startTime / 1000 <= now && now < l.startTime / 1000 + l.duration)
+            .map(|l| Programme {
+                title: l.title.clone(),
+                episode_title: l.episodeTitle.clone(),
+                start: l.startTime / 1000,
+                end: l.startTime / 1000 + l.duration,
+                local_start: format_clock_local(l.startTime, &tz),
+                local_end: format_clock_local(l.startTime + l.duration * 1000, &tz),
+            });
+
+        market.stations.push(Affiliate {
+            network,
+            station_id: station.id,
+            call_sign: station
+                .callSign_remapped
+                .as_ref()
+                .unwrap_or(&station.callSign)
+                .clone(),
+            channel,
+            logo: format!("{}/logo/{}.png", base_url, station.id),
+            programme,
+        });
+    }
+
+    for market in markets.iter_mut() {
+        market
+            .stations
+            .sort_by_key(|a| NETWORKS.iter().position(|n| *n == a.network));
+    }
+    NowPlaying {
+        networks: &NETWORKS,
+        markets,
+    }
+}
+
+/// The network a station is affiliated with. Locast uses the network as call sign for affiliates.
+fn network_of(station: &Station) -> Option<&'static str> {
+    NETWORKS.iter().find(|n| **n == station.callSign).copied()
+}
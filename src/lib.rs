@@ -0,0 +1,18 @@
+#![recursion_limit = "512"]
+#[macro_use]
+extern crate log;
+pub mod check;
+pub mod config;
+pub mod credentials;
+pub mod daemon;
+pub mod dmas;
+pub mod errors;
+pub mod export;
+pub mod fcc_facilities;
+pub mod http;
+pub mod logging;
+pub mod notify;
+pub mod selftest;
+pub mod service;
+pub mod telemetry;
+pub mod utils;
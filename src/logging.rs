@@ -1,29 +1,371 @@
 use crate::config;
+use chrono::{DateTime, Local, NaiveDate};
+use lazy_static::lazy_static;
 use slog::*;
 use slog_async::Async;
 use slog_term::{FullFormat, PlainDecorator, TermDecorator};
-use std::fs::OpenOptions;
-use std::sync::Arc;
-pub fn logger(log_level: Level, conf: &Arc<config::Config>) -> Logger {
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+lazy_static! {
+    /// Levels set at runtime through `POST /log_level`, by target (`None` for the default level).
+    /// These are applied on top of the configured filters.
+    static ref RUNTIME_LEVELS: RwLock<Vec<(Option<String>, Option<Level>)>> = RwLock::new(Vec::new());
+}
+
+/// Log levels per target (e.g. "locast2tuner::http" or "actix_web"). The most specific target that
+/// matches the target of a record decides whether it's logged. `None` disables logging.
+pub struct LogFilters {
+    default: Option<Level>,
+    targets: Vec<(String, Option<Level>)>,
+}
+
+impl LogFilters {
+    /// Build filters from `log_level` (or the verbosity), `log_filters` and the RUST_LOG environment
+    /// variable, in increasing order of precedence.
+    pub fn new(conf: &config::Config) -> LogFilters {
+        let mut filters = LogFilters {
+            default: match &conf.log_level {
+                Some(l) => parse_level(l).unwrap(),
+                // Log level 0 and 1 give info logging, but loglevel 1 adds HTTP logging.
+                // Level 2 is debug and anything else defaults to trace.
+                None => Some(match conf.verbose {
+                    0 | 1 => Level::Info,
+                    2 => Level::Debug,
+                    _ => Level::Trace,
+                }),
+            },
+            targets: Vec::new(),
+        };
+        for filter in &conf.log_filters {
+            let (target, level) = parse_filter(filter).unwrap();
+            filters.targets.push((target.unwrap(), level));
+        }
+
+        if let Ok(rust_log) = std::env::var("RUST_LOG") {
+            for filter in rust_log.split(',').filter(|f| !f.trim().is_empty()) {
+                match parse_filter(filter) {
+                    Ok((Some(target), level)) => filters.targets.push((target, level)),
+                    Ok((None, level)) => filters.default = level,
+                    Err(e) => println!("Ignoring RUST_LOG filter: {}", e),
+                }
+            }
+        }
+        filters
+    }
+
+    /// The level from which records with `target` are logged
+    fn level(&self, target: &str) -> Option<Level> {
+        let runtime = RUNTIME_LEVELS.read().unwrap();
+        let default = runtime
+            .iter()
+            .find(|(t, _)| t.is_none())
+            .map_or(self.default, |(_, l)| *l);
+        self.targets
+            .iter()
+            .map(|(t, l)| (t.as_str(), *l))
+            .chain(
+                runtime
+                    .iter()
+                    .filter_map(|(t, l)| t.as_deref().map(|t| (t, *l))),
+            )
+            .filter(|(t, _)| target == *t || target.starts_with(&format!("{}::", t)))
+            // Later filters override earlier ones for the same target, since the last maximum is
+            // returned
+            .max_by_key(|(t, _)| t.len())
+            .map_or(default, |(_, l)| l)
+    }
+}
+
+/// Change the level of `target` (or the default level if `None`) until the next restart
+pub fn set_runtime_level(target: Option<String>, level: Option<Level>) {
+    let mut runtime = RUNTIME_LEVELS.write().unwrap();
+    runtime.retain(|(t, _)| *t != target);
+    runtime.push((target, level));
+}
+
+/// Go back to the configured levels
+pub fn reset_runtime_levels() {
+    RUNTIME_LEVELS.write().unwrap().clear();
+}
+
+/// Levels set at runtime, as "target=level" (or just "level" for the default level)
+pub fn runtime_levels() -> Vec<String> {
+    RUNTIME_LEVELS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(target, level)| match target {
+            Some(t) => format!("{}={}", t, level_name(*level)),
+            None => level_name(*level).to_owned(),
+        })
+        .collect()
+}
+
+/// Name of a level, as accepted by `parse_level`
+pub fn level_name(level: Option<Level>) -> &'static str {
+    match level {
+        None => "off",
+        Some(Level::Critical) | Some(Level::Error) => "error",
+        Some(Level::Warning) => "warn",
+        Some(Level::Info) => "info",
+        Some(Level::Debug) => "debug",
+        Some(Level::Trace) => "trace",
+    }
+}
+
+/// Parse a log level. "off" disables logging.
+pub fn parse_level(level: &str) -> std::result::Result<Option<Level>, String> {
+    match level.trim().to_lowercase().as_str() {
+        "off" => Ok(None),
+        "error" => Ok(Some(Level::Error)),
+        "warn" | "warning" => Ok(Some(Level::Warning)),
+        "info" => Ok(Some(Level::Info)),
+        "debug" => Ok(Some(Level::Debug)),
+        "trace" => Ok(Some(Level::Trace)),
+        _ => Err(format!("invalid log level {}", level)),
+    }
+}
+
+/// Parse a log filter in the form "target=level" (or just "level")
+pub fn parse_filter(filter: &str) -> std::result::Result<(Option<String>, Option<Level>), String> {
+    match filter.split_once('=') {
+        Some((target, level)) => Ok((Some(target.trim().to_owned()), parse_level(level)?)),
+        None => Ok((None, parse_level(filter)?)),
+    }
+}
+
+/// A drain that only passes records that are allowed by `LogFilters`
+struct TargetFilter<D: Drain> {
+    drain: D,
+    filters: Arc<LogFilters>,
+}
+
+impl<D: Drain> Drain for TargetFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &Record,
+        values: &OwnedKVList,
+    ) -> std::result::Result<Self::Ok, Self::Err> {
+        // Records from the `log` crate have their target as tag
+        let target = if record.tag().is_empty() {
+            record.module()
+        } else {
+            record.tag()
+        };
+        match self.filters.level(target) {
+            Some(level) if record.level().is_at_least(level) => {
+                self.drain.log(record, values).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// When a log file is rotated
+enum Rotation {
+    Never,
+    /// When it grows beyond the given number of bytes
+    Size(u64),
+    /// When the (local) date changes
+    Daily,
+}
+
+/// A log file that can be rotated. Rotated files get a numbered suffix ("locast2tuner.log.1" being the
+/// most recent) and only the last `keep` of them are kept.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    date: NaiveDate,
+    rotation: Rotation,
+    keep: usize,
+    /// True if the next write starts a new record
+    record_start: bool,
+}
+
+impl RotatingFile {
+    fn new(path: &str, rotation: Rotation, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // Pick up where we left off, so a restart on a later day still rotates yesterday's log
+        let date = metadata
+            .modified()
+            .map(|m| DateTime::<Local>::from(m).naive_local().date())
+            .unwrap_or_else(|_| Local::now().naive_local().date());
+        Ok(RotatingFile {
+            path: PathBuf::from(path),
+            file,
+            size: metadata.len(),
+            date,
+            rotation,
+            keep,
+            record_start: true,
+        })
+    }
+
+    fn should_rotate(&self) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max_size) => self.size >= max_size,
+            Rotation::Daily => Local::now().naive_local().date() != self.date,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", i));
+            PathBuf::from(name)
+        };
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&rotated(self.keep))?;
+            for i in (1..self.keep).rev() {
+                if rotated(i).exists() {
+                    fs::rename(rotated(i), rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.date = Local::now().naive_local().date();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only rotate between records, so records are never split over two files
+        if self.record_start && self.should_rotate() {
+            // Keep logging to the current file if rotation fails, rather than taking the logger down
+            if let Err(e) = self.rotate() {
+                println!("Unable to rotate log file '{}': {}", self.path.display(), e);
+                self.size = 0;
+                self.date = Local::now().naive_local().date();
+            }
+        }
+        self.record_start = false;
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    /// Called after every record
+    fn flush(&mut self) -> io::Result<()> {
+        self.record_start = true;
+        self.file.flush()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Logs to the Windows event log (the Application log, with locast2tuner as source)
+#[cfg(target_family = "windows")]
+struct EventLog {
+    // Handles aren't Send, so the handle is stored as an integer
+    handle: isize,
+}
+
+#[cfg(target_family = "windows")]
+impl EventLog {
+    fn new() -> Option<EventLog> {
+        use windows_sys::Win32::System::EventLog::RegisterEventSourceW;
+        let handle = unsafe {
+            RegisterEventSourceW(
+                std::ptr::null(),
+                crate::daemon::wide("locast2tuner").as_ptr(),
+            )
+        };
+        if handle.is_null() {
+            println!(
+                "Unable to log to the event log: {}",
+                io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some(EventLog {
+            handle: handle as isize,
+        })
+    }
+}
+
+#[cfg(target_family = "windows")]
+impl Drain for EventLog {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> io::Result<()> {
+        use windows_sys::Win32::System::EventLog::{
+            ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+        };
+        let event_type = match record.level() {
+            Level::Critical | Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warning => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = crate::daemon::wide(&record.msg().to_string());
+        let strings = [message.as_ptr()];
+        let reported = unsafe {
+            ReportEventW(
+                self.handle as _,
+                event_type,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            )
+        };
+        if reported == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+pub fn logger(filters: LogFilters, conf: &Arc<config::Config>) -> Logger {
+    let filters = Arc::new(filters);
     let term_drain = match &conf.quiet {
         true => None,
         false => Some(
-            LevelFilter::new(
-                FullFormat::new(TermDecorator::new().build()).build().fuse(),
-                log_level,
-            )
+            TargetFilter {
+                drain: FullFormat::new(TermDecorator::new().build()).build().fuse(),
+                filters: filters.clone(),
+            }
             .fuse(),
         ),
     };
 
     let file_drain = match &conf.logfile {
         Some(log_path) => {
-            let file = match OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(log_path)
-            {
+            let rotation = match conf.log_rotate.as_deref() {
+                Some("size") => Rotation::Size(conf.log_max_size * 1024 * 1024),
+                Some("daily") => Rotation::Daily,
+                _ => Rotation::Never,
+            };
+            let file = match RotatingFile::new(log_path, rotation, conf.log_keep) {
                 Ok(f) => f,
                 Err(e) => {
                     println!("Unable to open log file '{}'!", log_path);
@@ -32,10 +374,10 @@ pub fn logger(log_level: Level, conf: &Arc<config::Config>) -> Logger {
             };
 
             Some(
-                LevelFilter::new(
-                    FullFormat::new(PlainDecorator::new(file)).build().fuse(),
-                    log_level,
-                )
+                TargetFilter {
+                    drain: FullFormat::new(PlainDecorator::new(file)).build().fuse(),
+                    filters: filters.clone(),
+                }
                 .fuse(),
             )
         }
@@ -45,16 +387,27 @@ pub fn logger(log_level: Level, conf: &Arc<config::Config>) -> Logger {
     #[cfg(target_family = "unix")]
     let syslog_drain = match &conf.syslog {
         true => Some(
-            slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)
-                .unwrap()
-                .fuse(),
+            TargetFilter {
+                drain: slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)
+                    .unwrap()
+                    .fuse(),
+                filters,
+            }
+            .fuse(),
         ),
         false => None,
     };
 
+    // There's no syslog on Windows, so log to the event log instead
     #[cfg(target_family = "windows")]
     let syslog_drain = match &conf.syslog {
-        true => Some(Discard),
+        true => EventLog::new().map(|drain| {
+            TargetFilter {
+                drain: drain.ignore_res(),
+                filters,
+            }
+            .fuse()
+        }),
         false => None,
     };
 
@@ -1,46 +1,65 @@
-#![recursion_limit = "256"]
 #[macro_use]
 extern crate log;
-mod config;
-mod credentials;
-mod errors;
-mod fcc_facilities;
-mod http;
-mod logging;
-mod service;
-mod utils;
-use itertools::Itertools;
-use service::multiplexer::Multiplexer;
+use futures::stream::{self, StreamExt};
+use locast2tuner::{
+    check, config, credentials, daemon, dmas, export, fcc_facilities, http, logging, notify,
+    selftest, service,
+    service::{
+        m3u::M3uService,
+        multiplexer::Multiplexer,
+        stationprovider::{ServiceArc, StationProvider},
+    },
+    telemetry, utils,
+};
 use simple_error::SimpleError;
-use std::env;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Instant;
+use std::{env, fs};
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-#[actix_web::main]
-async fn main() -> Result<(), SimpleError> {
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+fn main() -> Result<(), SimpleError> {
     // Create a configuration struct that we'll pass along throughout the application
     let conf = match config::Config::from_args_and_file() {
         Ok(c) => Arc::new(c),
         Err(e) => panic!("{}", e),
     };
 
+    // Detach from the terminal before any threads are started
+    #[cfg(unix)]
+    if conf.daemon {
+        if let Err(e) = daemon::daemonize(conf.pid_file.as_ref().map(std::path::Path::new)) {
+            panic!("Unable to run as a daemon: {}", e);
+        }
+    }
+
+    #[cfg(windows)]
+    match conf.service.as_deref() {
+        Some("install") => return daemon::install(&conf),
+        Some("uninstall") => return daemon::uninstall(),
+        Some("run") => return daemon::run(conf, |c| actix_web::rt::System::new().block_on(run(c))),
+        _ => {}
+    }
+
+    let result = actix_web::rt::System::new().block_on(run(conf.clone()));
+    if let (true, Some(pid_file)) = (conf.daemon, &conf.pid_file) {
+        let _ = fs::remove_file(pid_file);
+    }
+    result
+}
+
+async fn run(mut conf: Arc<config::Config>) -> Result<(), SimpleError> {
     // Enable the RUST_BACKTRACE=1 env variable.
     if conf.rust_backtrace {
         env::set_var("RUST_BACKTRACE", "1");
     }
 
-    // Log level 0 and 1 give info logging, but loglevel 1 adds HTTP logging.
-    // Level 2 is debug and anything else defaults to trace.
-    let log_level = match conf.verbose {
-        0 | 1 => slog::Level::Info,
-        2 => slog::Level::Debug,
-        _ => slog::Level::Trace,
-    };
-
     // Setup logging
-    let logger = crate::logging::logger(log_level, &conf);
+    let logger = logging::logger(logging::LogFilters::new(&conf), &conf);
     let _scope_guard = slog_scope::set_global_logger(logger);
-    let _log_guard = slog_stdlog::init().unwrap();
+    slog_stdlog::init().unwrap();
 
     info!(
         "locast2tuner {} on {} {} starting..",
@@ -51,44 +70,132 @@ async fn main() -> Result<(), SimpleError> {
 
     info!("UUID: {}", conf.clone().uuid);
 
+    // Retries, timeouts and circuit breaking for requests to locast and the FCC
+    utils::configure_http(&conf);
+
+    // Webhooks that are notified of important events
+    notify::configure(&conf);
+
+    // List the locast DMAs and exit
+    if conf.list_dmas {
+        return dmas::run().await;
+    }
+
+    // Resolve city names to coordinates
+    if !conf.override_cities.is_empty() {
+        let mut resolved = (*conf).clone();
+        dmas::resolve_cities(&mut resolved).await?;
+        conf = Arc::new(resolved);
+    }
+
+    // Check the configuration and exit
+    if conf.check_config {
+        return check::run(conf.clone()).await;
+    }
+
+    // Verify that streaming works and exit
+    if conf.selftest {
+        if conf.needs_setup() {
+            return Err(SimpleError::new("No locast credentials configured"));
+        }
+        return selftest::run(conf.clone()).await;
+    }
+
+    // Export the lineup and guide of a location and exit
+    if let Some(export) = &conf.export {
+        if conf.needs_setup() {
+            return Err(SimpleError::new("No locast credentials configured"));
+        }
+        return match export::run(conf.clone(), export).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(SimpleError::new(format!("Export failed: {}", e))),
+        };
+    }
+
+    // Without credentials, start the setup wizard and continue with the configuration it returns
+    if conf.needs_setup() {
+        conf = match http::setup::run((*conf).clone()).await {
+            Ok(c) => Arc::new(c),
+            Err(e) => return Err(SimpleError::new(format!("Setup failed: {}", e))),
+        };
+    }
+
+    // Export traces if configured
+    telemetry::init(&conf);
+
     // Login to locast and get credentials we pass around
     let credentials = Arc::new(credentials::LocastCredentials::new(conf.clone()).await);
+    actix_web::rt::spawn(credentials.clone().watch_donations(conf.clone()));
 
     // Load FCC facilities
     let fcc_facilities = Arc::new(fcc_facilities::FCCFacilities::new(conf.clone()).await);
 
-    // Create Locast Services
-    let services = if let Some(zipcodes) = &conf.override_zipcodes {
-        let services = zipcodes
-            .into_iter()
-            .map(|x| {
-                service::LocastService::new(
-                    conf.clone(),
-                    credentials.clone(),
-                    fcc_facilities.clone(),
-                    Some(x.to_string()),
-                )
-            })
-            .collect_vec();
-        futures::future::join_all(services).await
-    } else {
-        vec![service::LocastService::new(conf.clone(), credentials, fcc_facilities, None).await]
-    };
+    // Create Locast Services, `startup_concurrency` locations at a time
+    let locations = service::Location::configured(&conf);
+    let total = locations.len();
+    let done = AtomicUsize::new(0);
+    let startup = Instant::now();
+    let locast_services: Vec<_> = stream::iter(locations.into_iter().map(|location| {
+        let (conf, credentials, fcc_facilities) =
+            (conf.clone(), credentials.clone(), fcc_facilities.clone());
+        let done = &done;
+        async move {
+            info!("Starting {}..", location);
+            let start = Instant::now();
+            let service =
+                service::LocastService::new(conf, credentials, fcc_facilities, location).await;
+            info!(
+                "Started {} (DMA {}) in {:.1}s ({}/{})",
+                service.geo().name,
+                service.geo().DMA,
+                start.elapsed().as_secs_f32(),
+                done.fetch_add(1, Ordering::Relaxed) + 1,
+                total
+            );
+            service
+        }
+    }))
+    .buffered(conf.startup_concurrency)
+    .collect()
+    .await;
+    if total > 1 {
+        info!(
+            "Started {} locations in {:.1}s",
+            total,
+            startup.elapsed().as_secs_f32()
+        );
+    }
+    let mut services: Vec<ServiceArc> = locast_services
+        .into_iter()
+        .map(|s| Arc::new(s) as ServiceArc)
+        .collect();
+
+    // Add services for M3U sources
+    for source in &conf.m3u_sources {
+        let service = M3uService::new(
+            conf.clone(),
+            credentials.clone(),
+            fcc_facilities.clone(),
+            source.clone(),
+        )
+        .await;
+        services.push(Arc::new(service));
+    }
 
     // Create a multiplexer if necessary
-    if conf.multiplex {
+    let result = if conf.multiplex {
         if conf.remap {
             warn!("Channels will be remapped!");
         }
-        let mp = vec![Multiplexer::new(services, conf.clone())];
-        match http::start(mp, conf.clone()).await {
-            Ok(()) => Ok(()),
-            Err(_) => return Err(SimpleError::new("Failed to start servers")),
-        }
+        let mp = vec![Multiplexer::new(services, conf.clone())?];
+        http::start(mp, conf.clone()).await
     } else {
-        match http::start(services, conf.clone()).await {
-            Ok(()) => Ok(()),
-            Err(_) => return Err(SimpleError::new("Failed to start servers")),
-        }
+        http::start(services, conf.clone()).await
+    };
+
+    telemetry::shutdown();
+    match result {
+        Ok(()) => Ok(()),
+        Err(_) => Err(SimpleError::new("Failed to start servers")),
     }
 }
@@ -0,0 +1,253 @@
+use crate::config::Config;
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Window in which failed streams are counted
+const STREAM_ERROR_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Names of all events, as used in `webhook_events`
+pub const EVENTS: [&str; 6] = [
+    "startup",
+    "shutdown",
+    "login_failed",
+    "donation_expiring",
+    "epg_refresh_failed",
+    "stream_errors",
+];
+
+lazy_static! {
+    static ref NOTIFIER: RwLock<Notifier> = RwLock::new(Notifier::default());
+    static ref STREAM_ERRORS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+}
+
+/// Something important that happened, which webhooks are notified of
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Startup {
+        version: String,
+    },
+    Shutdown,
+    LoginFailed {
+        username: String,
+        error: String,
+    },
+    DonationExpiring {
+        username: String,
+        donation_expire: Option<i64>,
+        days_left: i64,
+    },
+    EpgRefreshFailed {
+        location: String,
+        error: String,
+    },
+    StreamErrors {
+        errors: usize,
+        minutes: u64,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Startup { .. } => "startup",
+            Event::Shutdown => "shutdown",
+            Event::LoginFailed { .. } => "login_failed",
+            Event::DonationExpiring { .. } => "donation_expiring",
+            Event::EpgRefreshFailed { .. } => "epg_refresh_failed",
+            Event::StreamErrors { .. } => "stream_errors",
+        }
+    }
+
+    /// Human readable description of the event
+    pub fn message(&self) -> String {
+        match self {
+            Event::Startup { version } => format!("locast2tuner {} started", version),
+            Event::Shutdown => "locast2tuner is shutting down".to_owned(),
+            Event::LoginFailed { username, error } => {
+                format!("Login to locast failed for {}: {}", username, error)
+            }
+            Event::DonationExpiring {
+                username,
+                days_left,
+                ..
+            } if *days_left < 0 => format!("Donation of {} expired", username),
+            Event::DonationExpiring {
+                username,
+                days_left,
+                ..
+            } => format!("Donation of {} expires in {} day(s)", username, days_left),
+            Event::EpgRefreshFailed { location, error } => {
+                format!("Unable to refresh stations for {}: {}", location, error)
+            }
+            Event::StreamErrors { errors, minutes } => {
+                format!("{} streams failed in the last {} minutes", errors, minutes)
+            }
+        }
+    }
+}
+
+/// Payload format of a webhook
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebhookFormat {
+    /// The event as JSON, with its name in `event` and a description in `message`
+    Generic,
+    Discord,
+    Slack,
+}
+
+/// A URL that's POSTed to when an event happens. Configured as `[format=]url`, e.g.
+/// `discord=https://discord.com/api/webhooks/...`. Without a format, Discord and Slack URLs are
+/// recognized by their host and all others are generic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub format: WebhookFormat,
+    pub url: String,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, url) = match s.split_once('=') {
+            Some(("generic", url)) => (Some(WebhookFormat::Generic), url),
+            Some(("discord", url)) => (Some(WebhookFormat::Discord), url),
+            Some(("slack", url)) => (Some(WebhookFormat::Slack), url),
+            _ => (None, s),
+        };
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|e| format!("Invalid webhook URL '{}': {}", url, e))?;
+        let format = format.unwrap_or(match parsed.host_str() {
+            Some("discord.com") | Some("discordapp.com") => WebhookFormat::Discord,
+            Some("hooks.slack.com") => WebhookFormat::Slack,
+            _ => WebhookFormat::Generic,
+        });
+        Ok(Webhook {
+            format,
+            url: url.to_owned(),
+        })
+    }
+}
+
+impl Webhook {
+    fn payload(&self, event: &Event) -> Value {
+        let message = format!("locast2tuner: {}", event.message());
+        match self.format {
+            WebhookFormat::Generic => {
+                let mut payload = serde_json::to_value(event).unwrap_or_else(|_| json!({}));
+                payload["message"] = Value::String(event.message());
+                payload
+            }
+            WebhookFormat::Discord => json!({ "content": message }),
+            WebhookFormat::Slack => json!({ "text": message }),
+        }
+    }
+}
+
+/// A webhook and the events it gets
+struct Subscription {
+    webhook: Webhook,
+    events: Vec<String>,
+}
+
+#[derive(Default)]
+struct Notifier {
+    subscriptions: Vec<Subscription>,
+    stream_error_threshold: usize,
+}
+
+/// Configure the webhooks that are notified of events
+pub fn configure(config: &Config) {
+    let events = if config.webhook_events.is_empty() {
+        EVENTS.iter().map(|e| e.to_string()).collect()
+    } else {
+        config.webhook_events.clone()
+    };
+    // Webhooks are validated when the config is loaded
+    let mut subscriptions: Vec<Subscription> = config
+        .webhooks
+        .iter()
+        .filter_map(|w| w.parse().ok())
+        .map(|webhook| Subscription {
+            webhook,
+            events: events.clone(),
+        })
+        .collect();
+    if let Some(url) = &config.donation_webhook {
+        subscriptions.push(Subscription {
+            webhook: Webhook {
+                format: WebhookFormat::Generic,
+                url: url.to_owned(),
+            },
+            events: vec!["donation_expiring".to_owned()],
+        });
+    }
+    *NOTIFIER.write().unwrap() = Notifier {
+        subscriptions,
+        stream_error_threshold: config.webhook_stream_errors as usize,
+    };
+}
+
+/// Notify the webhooks that subscribed to `event` in the background
+pub fn notify(event: Event) {
+    if !webhooks(&event).is_empty() {
+        tokio::task::spawn(send(event));
+    }
+}
+
+/// Notify the webhooks that subscribed to `event`, and wait until they're notified
+pub async fn send(event: Event) {
+    let webhooks = webhooks(&event);
+    let event = &event;
+    join_all(webhooks.iter().map(|webhook| async move {
+        match crate::utils::post(&webhook.url, webhook.payload(event), 3).await {
+            Ok(r) if r.status().is_success() => {}
+            Ok(r) => warn!("Webhook for {} failed: {}", event.name(), r.status()),
+            Err(e) => warn!("Webhook for {} failed: {}", event.name(), e),
+        }
+    }))
+    .await;
+}
+
+fn webhooks(event: &Event) -> Vec<Webhook> {
+    NOTIFIER
+        .read()
+        .unwrap()
+        .subscriptions
+        .iter()
+        .filter(|s| s.events.iter().any(|e| e == event.name()))
+        .map(|s| s.webhook.clone())
+        .collect()
+}
+
+/// Count a stream that ended because of an error. When `webhook_stream_errors` streams failed within
+/// an hour, the webhooks are notified and counting starts over.
+pub fn stream_failed() {
+    let threshold = NOTIFIER.read().unwrap().stream_error_threshold;
+    if threshold == 0 {
+        return;
+    }
+    let mut errors = STREAM_ERRORS.lock().unwrap();
+    errors.push_back(Instant::now());
+    while errors
+        .front()
+        .is_some_and(|e| e.elapsed() > STREAM_ERROR_WINDOW)
+    {
+        errors.pop_front();
+    }
+    if errors.len() >= threshold {
+        notify(Event::StreamErrors {
+            errors: errors.len(),
+            minutes: STREAM_ERROR_WINDOW.as_secs() / 60,
+        });
+        errors.clear();
+    }
+}
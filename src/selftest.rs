@@ -0,0 +1,204 @@
+use crate::{
+    config::Config,
+    credentials::{check_account, LocastCredentials},
+    fcc_facilities::FCCFacilities,
+    http::playlist::media_playlist,
+    service::{check_location, stationprovider::StationProvider, LocastService, Location},
+    utils::get,
+};
+use simple_error::SimpleError;
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Amount of segments that are downloaded
+static SEGMENTS: usize = 2;
+
+/// Outcome of a single step
+struct StepResult {
+    step: String,
+    ok: bool,
+    duration: Duration,
+    detail: String,
+}
+
+/// Verify that a stream works end-to-end without starting any servers: log in, load the stations of
+/// the first location, resolve the stream of one active station and download a few of its segments. A
+/// summary with the time every step took is printed to stdout. Returns an error if a step failed, so it
+/// can be used to validate deployments.
+pub async fn run(config: Arc<Config>) -> Result<(), SimpleError> {
+    let mut results = Vec::new();
+    let result = steps(config, &mut results).await;
+    print_summary(&results);
+    result
+}
+
+async fn steps(config: Arc<Config>, results: &mut Vec<StepResult>) -> Result<(), SimpleError> {
+    // Always fetch fresh stations
+    let mut config = (*config).clone();
+    config.disable_station_cache = true;
+    let config = Arc::new(config);
+
+    step(results, "login", async {
+        check_account(&config.username, &config.password).await?;
+        Ok(format!("Logged in as {}", config.username))
+    })
+    .await?;
+
+    let location = Location::configured(&config).remove(0);
+    let geo = step(results, "location", async {
+        let geo = check_location(&location).await?;
+        Ok((format!("{} (DMA {})", geo.name, geo.DMA), geo))
+    })
+    .await?;
+
+    let credentials = Arc::new(LocastCredentials::new(config.clone()).await);
+    let fcc_facilities = Arc::new(FCCFacilities::new(config.clone()).await);
+    let service = LocastService::new(config.clone(), credentials, fcc_facilities, location).await;
+
+    let station = step(results, "stations", async {
+        let stations = service.stations().await;
+        let stations = stations.lock().await;
+        let station = stations
+            .iter()
+            .find(|s| s.active)
+            .cloned()
+            .ok_or_else(|| SimpleError::new(format!("No active stations in {}", geo.name)))?;
+        Ok((
+            format!(
+                "{} stations, testing {} {}",
+                stations.len(),
+                station.channel.as_deref().unwrap_or("-"),
+                station.callSign
+            ),
+            station,
+        ))
+    })
+    .await?;
+
+    let lease = service
+        .lease_account(&station.id.to_string())
+        .await
+        .map_err(|e| SimpleError::new(e.to_string()))?;
+    let stream_uri = step(results, "stream", async {
+        let uri = service
+            .station_stream_uri(&station.id.to_string(), &lease)
+            .await
+            .map_err(|e| SimpleError::new(e.to_string()))?
+            .into_inner();
+        Ok((uri.clone(), uri))
+    })
+    .await?;
+
+    let playlist = step(results, "playlist", async {
+        let m3u_data = get(&stream_uri, None, 3)
+            .await
+            .and_then(|r| r.error_for_status().map_err(Into::into))
+            .map_err(SimpleError::from)?
+            .text()
+            .await
+            .map_err(SimpleError::from)?;
+        let playlist = media_playlist(&m3u_data, &stream_uri)?;
+        if playlist.segments.is_empty() {
+            return Err(SimpleError::new("Playlist has no segments"));
+        }
+        Ok((format!("{} segments", playlist.segments.len()), playlist))
+    })
+    .await?;
+
+    for (i, segment) in playlist.segments.iter().take(SEGMENTS).enumerate() {
+        step(results, &format!("segment {}", i + 1), async {
+            let bytes = get(&segment.url, None, 3)
+                .await
+                .and_then(|r| r.error_for_status().map_err(Into::into))
+                .map_err(SimpleError::from)?
+                .bytes()
+                .await
+                .map_err(SimpleError::from)?;
+            if bytes.is_empty() {
+                return Err(SimpleError::new("Segment is empty"));
+            }
+            Ok(format!(
+                "{} KB, {:.1}s of video",
+                bytes.len() / 1024,
+                segment.duration.as_secs_f32()
+            ))
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// Run a step and record its outcome. A step returns its detail and, optionally, a value for the next
+/// steps.
+async fn step<F, D, T>(results: &mut Vec<StepResult>, name: &str, f: F) -> Result<T, SimpleError>
+where
+    F: Future<Output = Result<D, SimpleError>>,
+    D: Into<Detail<T>>,
+{
+    let start = Instant::now();
+    let result = f.await;
+    let duration = start.elapsed();
+    match result {
+        Ok(d) => {
+            let Detail(detail, value) = d.into();
+            info!("Self-test step {} succeeded: {}", name, detail);
+            results.push(StepResult {
+                step: name.to_owned(),
+                ok: true,
+                duration,
+                detail,
+            });
+            Ok(value)
+        }
+        Err(e) => {
+            error!("Self-test step {} failed: {}", name, e);
+            results.push(StepResult {
+                step: name.to_owned(),
+                ok: false,
+                duration,
+                detail: e.to_string(),
+            });
+            Err(SimpleError::new(format!("Self-test failed at {}", name)))
+        }
+    }
+}
+
+/// Detail of a step, and the value it passes on to the next steps
+struct Detail<T>(String, T);
+
+impl From<String> for Detail<()> {
+    fn from(detail: String) -> Self {
+        Detail(detail, ())
+    }
+}
+
+impl<T> From<(String, T)> for Detail<T> {
+    fn from((detail, value): (String, T)) -> Self {
+        Detail(detail, value)
+    }
+}
+
+fn print_summary(results: &[StepResult]) {
+    let width = results.iter().map(|r| r.step.len()).max().unwrap_or(0);
+    println!(
+        "{:width$}  RESULT  {:8}  DETAIL",
+        "STEP",
+        "TIME",
+        width = width
+    );
+    for r in results {
+        println!(
+            "{:width$}  {:6}  {:>6}ms  {}",
+            r.step,
+            if r.ok { "OK" } else { "FAIL" },
+            r.duration.as_millis(),
+            r.detail,
+            width = width
+        );
+    }
+    let total: Duration = results.iter().map(|r| r.duration).sum();
+    println!("Total: {}ms", total.as_millis());
+}
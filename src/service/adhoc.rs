@@ -0,0 +1,212 @@
+use super::{
+    station::{Listing, Station},
+    Geo,
+};
+use crate::errors::AppError;
+use chrono::Utc;
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// Ad hoc channels are numbered from here if no channel is given
+static FIRST_CHANNEL: u32 = 900;
+/// Length (in seconds) of the synthetic guide entries of an ad hoc channel
+static GUIDE_BLOCK: i64 = 3600;
+
+/// Request to create an ad hoc channel for a locast station or event id, as posted to
+/// `/channels/adhoc`
+#[derive(Deserialize, Debug, Clone)]
+pub struct AdhocRequest {
+    /// Locast station or event id
+    pub id: i64,
+    pub name: String,
+    /// Channel number. If not set, the first free channel from 900 is used.
+    pub channel: Option<String>,
+    /// Title used in the guide. Defaults to the name of the channel.
+    pub title: Option<String>,
+    /// Seconds after which the channel is removed. Defaults to `adhoc_expiry`.
+    pub expires_in: Option<u64>,
+    /// DMA of the location to add the channel to when multiplexing. Defaults to the first location.
+    pub dma: Option<String>,
+}
+
+/// A temporary channel, which is part of the lineup and guide until it expires
+#[derive(Serialize, Debug, Clone)]
+pub struct AdhocChannel {
+    pub station: Station,
+    /// Unix timestamp after which the channel is removed
+    pub expires: i64,
+}
+
+impl AdhocChannel {
+    fn expired(&self, now: i64) -> bool {
+        now >= self.expires
+    }
+}
+
+/// Ad hoc channels of a single location
+#[derive(Debug, Default)]
+pub struct AdhocChannels {
+    channels: Mutex<Vec<AdhocChannel>>,
+}
+
+impl AdhocChannels {
+    pub fn new() -> AdhocChannels {
+        AdhocChannels::default()
+    }
+
+    /// Add (or replace) an ad hoc channel. `stations` are the regular stations of the location, which
+    /// the id and channel number of the ad hoc channel can't clash with.
+    pub async fn add(
+        &self,
+        request: &AdhocRequest,
+        stations: &[Station],
+        geo: &Geo,
+        default_expiry: u64,
+    ) -> Result<AdhocChannel, AppError> {
+        if stations.iter().any(|s| s.id == request.id) {
+            return Err(AppError::BadRequest(format!(
+                "{} is already part of the lineup",
+                request.id
+            )));
+        }
+
+        let mut channels = self.channels.lock().await;
+        let now = Utc::now().timestamp();
+        channels.retain(|c| !c.expired(now) && c.station.id != request.id);
+
+        let taken: Vec<&str> = stations
+            .iter()
+            .chain(channels.iter().map(|c| &c.station))
+            .filter_map(|s| s.channel_remapped.as_ref().or(s.channel.as_ref()))
+            .map(|c| c.as_str())
+            .collect();
+        let channel = match &request.channel {
+            Some(c) => {
+                if c.parse::<f32>().is_err() {
+                    return Err(AppError::BadRequest(format!("invalid channel {}", c)));
+                }
+                if taken.contains(&c.as_str()) {
+                    return Err(AppError::BadRequest(format!("channel {} is taken", c)));
+                }
+                c.to_owned()
+            }
+            None => (FIRST_CHANNEL..)
+                .map(|c| c.to_string())
+                .find(|c| !taken.contains(&c.as_str()))
+                .unwrap(),
+        };
+
+        let expires = now + request.expires_in.unwrap_or(default_expiry) as i64;
+        let channel = AdhocChannel {
+            station: adhoc_station(request, channel, geo, now, expires),
+            expires,
+        };
+        info!(
+            "Added ad hoc channel {} {} (locast id {}) until {}",
+            channel.station.channel.as_ref().unwrap(),
+            request.name,
+            request.id,
+            expires
+        );
+        channels.push(channel.clone());
+        Ok(channel)
+    }
+
+    /// Remove an ad hoc channel before it expires
+    pub async fn remove(&self, id: i64) -> Result<(), AppError> {
+        let mut channels = self.channels.lock().await;
+        match channels.iter().position(|c| c.station.id == id) {
+            Some(i) => {
+                info!("Removed ad hoc channel {}", channels.remove(i).station.name);
+                Ok(())
+            }
+            None => Err(AppError::NotFound),
+        }
+    }
+
+    /// All ad hoc channels that haven't expired. Expired channels are cleaned up.
+    pub async fn active(&self) -> Vec<AdhocChannel> {
+        let mut channels = self.channels.lock().await;
+        let now = Utc::now().timestamp();
+        channels.retain(|c| {
+            if c.expired(now) {
+                info!("Ad hoc channel {} expired", c.station.name);
+            }
+            !c.expired(now)
+        });
+        channels.clone()
+    }
+}
+
+/// Build the station of an ad hoc channel. The guide consists of hourly blocks, starting at the current
+/// hour, until the channel expires.
+fn adhoc_station(
+    request: &AdhocRequest,
+    channel: String,
+    geo: &Geo,
+    now: i64,
+    expires: i64,
+) -> Station {
+    let title = request
+        .title
+        .clone()
+        .unwrap_or_else(|| request.name.clone());
+    let listings = (now - now % GUIDE_BLOCK..expires)
+        .step_by(GUIDE_BLOCK as usize)
+        .map(|start| Listing {
+            airdate: None,
+            audioProperties: None,
+            description: Some(format!("{} (temporary channel)", title)),
+            directors: None,
+            duration: GUIDE_BLOCK.min(expires - start),
+            entityType: "Show".to_owned(),
+            episodeNumber: None,
+            episodeTitle: None,
+            genres: Some("Special".to_owned()),
+            hasImageArtwork: false,
+            hasSeriesArtwork: false,
+            isNew: Some(true),
+            isLive: None,
+            isPremiere: None,
+            preferredImage: None,
+            preferredImageHeight: None,
+            preferredImageWidth: None,
+            programId: format!("ADHOC{}.{}", request.id, start),
+            rating: None,
+            releaseDate: None,
+            releaseYear: None,
+            seasonNumber: None,
+            seriesId: None,
+            shortDescription: None,
+            showType: "Special".to_owned(),
+            startTime: start * 1000,
+            stationId: request.id,
+            title: title.clone(),
+            topCast: None,
+            videoProperties: None,
+        })
+        .collect();
+
+    Station {
+        active: true,
+        callSign: request.name.clone(),
+        channel: Some(channel),
+        city: Some(geo.name.clone()),
+        dma: geo.DMA.parse().unwrap_or_default(),
+        id: request.id,
+        listings,
+        logo226Url: None,
+        logoUrl: None,
+        name: request.name.clone(),
+        sequence: None,
+        stationId: request.id.to_string(),
+        timezone: geo.timezone.clone(),
+        tivoId: None,
+        transcodeId: 0,
+        channel_remapped: None,
+        callSign_remapped: None,
+        remapped: None,
+        channel_locast: None,
+        channel_fcc: None,
+    }
+}
@@ -0,0 +1,65 @@
+use super::station::Station;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// Stations (including EPG data) for a DMA as stored on disk
+#[derive(Deserialize, Serialize)]
+pub struct CachedStations {
+    pub dma: String,
+    /// Unix timestamp of when the stations were fetched from locast
+    pub timestamp: i64,
+    pub stations: Vec<Station>,
+}
+
+impl CachedStations {
+    /// Time since the stations were fetched from locast
+    pub fn age(&self) -> Duration {
+        Duration::seconds(Utc::now().timestamp() - self.timestamp)
+    }
+}
+
+/// Location of the station cache for a DMA
+fn cache_file(cache_directory: &Path, dma: &str) -> PathBuf {
+    cache_directory
+        .join("stations")
+        .join(format!("{}.json", dma))
+}
+
+/// Load the cached stations for a DMA. Returns None if there's no cache or it can't be read.
+pub fn load(cache_directory: &Path, dma: &str) -> Option<CachedStations> {
+    let path = cache_file(cache_directory, dma);
+    let file = File::open(&path).ok()?;
+    match serde_json::from_reader::<_, CachedStations>(BufReader::new(file)) {
+        Ok(cached) if cached.dma == dma => Some(cached),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Ignoring invalid station cache {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Store the stations for a DMA. Failing to write the cache is not fatal, so errors are only logged.
+pub fn store(cache_directory: &Path, dma: &str, stations: &[Station]) {
+    let path = cache_file(cache_directory, dma);
+    let cached = CachedStations {
+        dma: dma.to_owned(),
+        timestamp: Utc::now().timestamp(),
+        stations: stations.to_vec(),
+    };
+
+    // Write to a temporary file first, so we never leave a partially written cache behind
+    let tmp_path = path.with_extension("json.tmp");
+    let result = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| File::create(&tmp_path))
+        .and_then(|f| serde_json::to_writer(f, &cached).map_err(|e| e.into()))
+        .and_then(|_| fs::rename(&tmp_path, &path));
+    if let Err(e) = result {
+        warn!("Unable to write station cache {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,101 @@
+use super::station::{channel_key, Station};
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// A channel number (after remapping) that is used by more than one station. Media servers like Plex
+/// can't tell these stations apart.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ChannelConflict {
+    pub channel: String,
+    /// The stations using `channel`, the one that keeps it first
+    pub stations: Vec<ConflictingStation>,
+    /// How to resolve the conflict
+    pub suggestion: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ConflictingStation {
+    pub station_id: i64,
+    pub call_sign: String,
+    pub city: Option<String>,
+    pub dma: i64,
+    /// Channel number before remapping
+    pub original_channel: Option<String>,
+    /// A free channel number to remap this station to. `None` for the station that keeps the channel.
+    pub suggested_channel: Option<String>,
+}
+
+/// Find the channel numbers that are used by more than one active station. `remap` is whether
+/// channels are remapped with offsets, which changes the suggested fix for conflicts between
+/// locations.
+pub fn channel_conflicts(stations: &[Station], remap: bool) -> Vec<ChannelConflict> {
+    let channel_of = |station: &Station| {
+        station
+            .channel_remapped
+            .clone()
+            .or_else(|| station.channel.clone())
+            .unwrap_or_default()
+    };
+    let active: Vec<&Station> = stations.iter().filter(|s| s.active).collect();
+    let mut used: HashSet<String> = active.iter().map(|s| channel_of(s)).collect();
+
+    active
+        .into_iter()
+        .into_group_map_by(|s| channel_of(s))
+        .into_iter()
+        .filter(|(_, stations)| stations.len() > 1)
+        .sorted_by_key(|(channel, _)| channel_key(channel))
+        .map(|(channel, stations)| {
+            let keeper = stations[0].callSign_remapped.as_ref().unwrap_or(&stations[0].callSign);
+            let locations = stations.iter().map(|s| s.dma).unique().count();
+            let suggestion = if locations > 1 && !remap {
+                format!(
+                    "Stations of different locations share channel {}. Enable remap, or remap all stations but {} to their suggested channel.",
+                    channel, keeper
+                )
+            } else if locations > 1 {
+                format!(
+                    "The remapped channels of multiple locations overlap. Give the locations remap_offsets that are further apart, or remap all stations but {} to their suggested channel.",
+                    keeper
+                )
+            } else {
+                format!(
+                    "Remap all stations but {} to their suggested channel in the remap file or at /admin.",
+                    keeper
+                )
+            };
+            let stations = stations
+                .iter()
+                .enumerate()
+                .map(|(i, s)| ConflictingStation {
+                    station_id: s.id,
+                    call_sign: s.callSign_remapped.clone().unwrap_or_else(|| s.callSign.clone()),
+                    city: s.city.clone(),
+                    dma: s.dma,
+                    original_channel: s.channel.clone(),
+                    suggested_channel: (i > 0).then(|| free_channel(&channel, &mut used)),
+                })
+                .collect();
+            ChannelConflict {
+                channel,
+                stations,
+                suggestion,
+            }
+        })
+        .collect()
+}
+
+/// The first channel number after `channel` with the same major number that isn't in `used`, which
+/// is marked as used (e.g. "4.1" --> "4.2" if that's free)
+fn free_channel(channel: &str, used: &mut HashSet<String>) -> String {
+    let major = channel.split('.').next().unwrap_or(channel);
+    let mut minor = channel_key(channel).1.saturating_add(1);
+    loop {
+        let candidate = format!("{}.{}", major, minor);
+        if used.insert(candidate.clone()) {
+            return candidate;
+        }
+        minor += 1;
+    }
+}
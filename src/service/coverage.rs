@@ -0,0 +1,108 @@
+use super::station::Station;
+use chrono::Utc;
+use serde::Serialize;
+
+/// Holes between programmes shorter than this (in seconds) aren't counted as gaps
+static GAP_TOLERANCE: i64 = 300;
+/// Stations with less guide coverage than this fraction of the requested window have a poor guide
+static LOW_COVERAGE: f64 = 0.5;
+/// After this many consecutive refreshes with poor coverage, a station is flagged as chronically empty
+static CHRONIC_REFRESHES: u32 = 3;
+
+/// Guide coverage of a single station, computed after every EPG refresh
+#[derive(Debug, Serialize, Clone)]
+pub struct EpgCoverage {
+    pub station_id: i64,
+    pub call_sign: String,
+    pub channel: Option<String>,
+    pub dma: i64,
+    /// Hours of programming available from now until the end of the requested window
+    pub hours: f64,
+    /// Hours of programming requested (`days` * 24)
+    pub requested_hours: f64,
+    /// `hours` as a fraction of `requested_hours`
+    pub coverage: f64,
+    /// Holes in the guide longer than `GAP_TOLERANCE`
+    pub gaps: usize,
+    /// Consecutive refreshes with poor coverage
+    pub low_coverage_refreshes: u32,
+    pub chronically_empty: bool,
+}
+
+/// Compute the guide coverage of `stations` for the next `days` days. `previous` is the coverage
+/// computed after the previous refresh, used to track stations whose guide is chronically empty.
+pub fn epg_coverage(stations: &[Station], days: u8, previous: &[EpgCoverage]) -> Vec<EpgCoverage> {
+    let start = Utc::now().timestamp();
+    let requested = i64::from(days) * 24 * 3600;
+    let end = start + requested;
+
+    stations
+        .iter()
+        .map(|station| {
+            let (covered, gaps) = covered_seconds(station, start, end);
+            let coverage = if requested > 0 {
+                covered as f64 / requested as f64
+            } else {
+                1.0
+            };
+            let previous_low = previous
+                .iter()
+                .find(|c| c.station_id == station.id)
+                .map_or(0, |c| c.low_coverage_refreshes);
+            let low_coverage_refreshes = if coverage < LOW_COVERAGE {
+                previous_low + 1
+            } else {
+                0
+            };
+            let chronically_empty = low_coverage_refreshes >= CHRONIC_REFRESHES;
+            if chronically_empty && low_coverage_refreshes == CHRONIC_REFRESHES {
+                warn!(
+                    "Guide for {} (station {}) has been (nearly) empty for {} refreshes",
+                    station.callSign, station.id, low_coverage_refreshes
+                );
+            }
+
+            EpgCoverage {
+                station_id: station.id,
+                call_sign: station.callSign.clone(),
+                channel: station.channel.clone(),
+                dma: station.dma,
+                hours: covered as f64 / 3600.0,
+                requested_hours: requested as f64 / 3600.0,
+                coverage,
+                gaps,
+                low_coverage_refreshes,
+                chronically_empty,
+            }
+        })
+        .collect()
+}
+
+/// Seconds covered by listings between `start` and `end` (unix timestamps in seconds) and the amount of
+/// gaps. Overlapping listings are only counted once.
+fn covered_seconds(station: &Station, start: i64, end: i64) -> (i64, usize) {
+    let mut intervals: Vec<(i64, i64)> = station
+        .listings
+        .iter()
+        .map(|l| {
+            let s = l.startTime / 1000;
+            (s.max(start), (s + l.duration).min(end))
+        })
+        .filter(|(s, e)| e > s)
+        .collect();
+    intervals.sort_unstable();
+
+    let mut covered = 0;
+    let mut gaps = 0;
+    let mut cursor = start;
+    for (s, e) in intervals {
+        if s - cursor > GAP_TOLERANCE {
+            gaps += 1;
+        }
+        if e > cursor {
+            covered += e - s.max(cursor);
+            cursor = e;
+        }
+    }
+    (covered, gaps)
+}
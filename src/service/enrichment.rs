@@ -0,0 +1,148 @@
+use super::station::{Listing, Station};
+use crate::{config::Config, utils::HttpError};
+use futures::stream::{self, StreamExt};
+use lazy_static::lazy_static;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex};
+
+/// Lookups that run at the same time
+const CONCURRENCY: usize = 8;
+/// Metadata that is kept before the cache is cleared
+const MAX_CACHED: usize = 50_000;
+
+lazy_static! {
+    /// Metadata by TMS id. `None` if the enrichment service doesn't know a program.
+    static ref METADATA: Mutex<HashMap<String, Option<ProgramMetadata>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Metadata of a program, as returned by the enrichment service. Every field is optional.
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug, Clone, Default)]
+struct ProgramMetadata {
+    seasonNumber: Option<i16>,
+    episodeNumber: Option<i16>,
+    episodeTitle: Option<String>,
+    description: Option<String>,
+    seriesId: Option<String>,
+    releaseYear: Option<i16>,
+}
+
+/// The TMS (Gracenote) id of a program id, if it is one: two letters for the type (e.g. EP for an
+/// episode, SH for a show and MV for a movie) followed by 12 digits.
+pub fn tms_id(program_id: &str) -> Option<&str> {
+    let bytes = program_id.as_bytes();
+    (bytes.len() == 14
+        && bytes[..2].iter().all(u8::is_ascii_uppercase)
+        && bytes[2..].iter().all(u8::is_ascii_digit))
+    .then_some(program_id)
+}
+
+/// Program id in the format of Schedules Direct (e.g. EP01234567.0012), which media servers use to
+/// match programs with Gracenote. Program ids that aren't TMS ids are used as is.
+pub fn dd_progid(program_id: &str) -> String {
+    match tms_id(program_id) {
+        Some(id) => format!("{}.{}", &id[..10], &id[10..]),
+        None => program_id.to_owned(),
+    }
+}
+
+/// Fill in the metadata that's missing from episodes (season and episode numbers, episode title,
+/// description, series id and release year) by looking up their TMS id with `tms_enrichment_url`.
+/// Lookups are cached, so every program is only looked up once.
+pub async fn enrich(config: &Config, stations: &mut [Station]) {
+    let url = match &config.tms_enrichment_url {
+        Some(u) => u,
+        None => return,
+    };
+
+    let missing: Vec<String> = {
+        let cache = METADATA.lock().unwrap();
+        let mut ids: Vec<String> = stations
+            .iter()
+            .flat_map(|s| s.listings.iter())
+            .filter(|l| incomplete(l))
+            .filter_map(|l| tms_id(&l.programId))
+            .filter(|id| !cache.contains_key(*id))
+            .map(|id| id.to_owned())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    };
+    if !missing.is_empty() {
+        debug!("Looking up metadata of {} programs", missing.len());
+        let results: Vec<(String, Result<Option<ProgramMetadata>, HttpError>)> =
+            stream::iter(missing)
+                .map(|id| async move {
+                    let result = lookup(url, &id).await;
+                    (id, result)
+                })
+                .buffer_unordered(CONCURRENCY)
+                .collect()
+                .await;
+
+        let mut cache = METADATA.lock().unwrap();
+        if cache.len() > MAX_CACHED {
+            cache.clear();
+        }
+        for (id, result) in results {
+            match result {
+                Ok(metadata) => {
+                    cache.insert(id, metadata);
+                }
+                // Not cached, so it's looked up again at the next refresh
+                Err(e) => debug!("Unable to look up metadata of {}: {}", id, e),
+            }
+        }
+    }
+
+    let cache = METADATA.lock().unwrap();
+    let mut enriched = 0;
+    for listing in stations.iter_mut().flat_map(|s| s.listings.iter_mut()) {
+        let metadata = tms_id(&listing.programId).and_then(|id| cache.get(id)?.as_ref());
+        if let Some(metadata) = metadata {
+            apply(listing, metadata);
+            enriched += 1;
+        }
+    }
+    debug!("Enriched {} listings", enriched);
+}
+
+/// Returns true if an episode is missing metadata that media servers use to match it
+fn incomplete(listing: &Listing) -> bool {
+    listing.programId.starts_with("EP")
+        && (listing.seasonNumber.is_none()
+            || listing.episodeNumber.is_none()
+            || listing.episodeTitle.is_none()
+            || listing.description.is_none())
+}
+
+/// Fill in the fields of `listing` that are missing. Fields that locast has are kept.
+fn apply(listing: &mut Listing, metadata: &ProgramMetadata) {
+    fn fill<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+        if field.is_none() {
+            *field = value.clone();
+        }
+    }
+    fill(&mut listing.seasonNumber, &metadata.seasonNumber);
+    fill(&mut listing.episodeNumber, &metadata.episodeNumber);
+    fill(&mut listing.episodeTitle, &metadata.episodeTitle);
+    fill(&mut listing.description, &metadata.description);
+    fill(&mut listing.seriesId, &metadata.seriesId);
+    fill(&mut listing.releaseYear, &metadata.releaseYear);
+}
+
+/// Look up a program. Returns `None` if the enrichment service doesn't know it.
+async fn lookup(url: &str, tms_id: &str) -> Result<Option<ProgramMetadata>, HttpError> {
+    let response = crate::utils::get(&url.replace("{tms_id}", tms_id), None, 1).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let metadata = response
+        .error_for_status()?
+        .json::<ProgramMetadata>()
+        .await?;
+    Ok(Some(metadata))
+}
@@ -0,0 +1,75 @@
+use super::station::Station;
+use crate::config::Config;
+use regex::{Regex, RegexBuilder};
+use std::str::FromStr;
+
+/// A pattern that selects stations: a call sign or channel number, or a regular expression between
+/// slashes (e.g. "/^K.*-DT$/"). Call signs and regular expressions are case insensitive.
+#[derive(Debug, Clone)]
+pub enum StationPattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl StationPattern {
+    /// Returns true if the call sign, channel number or name of `station` matches this pattern
+    pub fn matches(&self, station: &Station) -> bool {
+        let fields = [
+            Some(station.callSign.as_str()),
+            station.channel.as_deref(),
+            Some(station.name.as_str()),
+        ];
+        let mut fields = fields.iter().flatten();
+        match self {
+            StationPattern::Exact(s) => fields.any(|f| f.eq_ignore_ascii_case(s)),
+            StationPattern::Regex(r) => fields.any(|f| r.is_match(f)),
+        }
+    }
+}
+
+impl FromStr for StationPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Empty station pattern".to_owned());
+        }
+        match s.strip_prefix('/').and_then(|r| r.strip_suffix('/')) {
+            Some(r) => RegexBuilder::new(r)
+                .case_insensitive(true)
+                .build()
+                .map(StationPattern::Regex)
+                .map_err(|e| format!("Invalid station pattern {}: {}", s, e)),
+            None => Ok(StationPattern::Exact(s.to_owned())),
+        }
+    }
+}
+
+/// Hides stations that don't match `include_stations` (if set) or that match `exclude_stations`
+#[derive(Debug)]
+pub struct StationFilter {
+    include: Vec<StationPattern>,
+    exclude: Vec<StationPattern>,
+}
+
+impl StationFilter {
+    pub fn new(config: &Config) -> StationFilter {
+        let parse = |patterns: &[String]| patterns.iter().filter_map(|p| p.parse().ok()).collect();
+        StationFilter {
+            include: parse(&config.include_stations),
+            exclude: parse(&config.exclude_stations),
+        }
+    }
+
+    /// Returns true if no stations are filtered
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns true if `station` should be part of the lineup
+    pub fn allows(&self, station: &Station) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(station)))
+            && !self.exclude.iter().any(|p| p.matches(station))
+    }
+}
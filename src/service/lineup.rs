@@ -0,0 +1,257 @@
+use super::station::Station;
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use serde::Serialize;
+use simple_error::SimpleError;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+/// Changes that are kept per refresh
+static MAX_DIFF_CHANGES: usize = 100;
+/// Changes that are logged per refresh
+static MAX_LOGGED_CHANGES: usize = 10;
+/// Number of refreshes (with changes) for which the diff is kept
+static MAX_DIFFS: usize = 10;
+/// Individual changes are logged at most once per this many seconds
+static DIFF_LOG_INTERVAL: i64 = 600;
+
+/// A daily window (in local time) during which the lineup is frozen. Windows that cross
+/// midnight (e.g. 22:00-02:00) are supported.
+#[derive(Debug, Clone, Copy)]
+pub struct FreezeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl FreezeWindow {
+    /// Returns true if `time` falls within the window
+    pub fn contains<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let t = time.time();
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+impl FromStr for FreezeWindow {
+    type Err = SimpleError;
+
+    /// Parse a window in the format "HH:MM-HH:MM"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SimpleError::new(format!("Invalid freeze window '{}' (HH:MM-HH:MM)", s));
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(FreezeWindow {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Renumbered,
+    CallSign,
+    Logo,
+}
+
+/// A change to a single station between two refreshes. `from` and `to` are set for changed values.
+#[derive(Debug, Serialize, Clone)]
+pub struct StationChange {
+    pub kind: ChangeKind,
+    pub station_id: i64,
+    pub call_sign: String,
+    pub channel: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl StationChange {
+    fn new(kind: ChangeKind, station: &Station) -> StationChange {
+        StationChange {
+            kind,
+            station_id: station.id,
+            call_sign: station.callSign.clone(),
+            channel: station.channel.clone().unwrap_or_default(),
+            from: None,
+            to: None,
+        }
+    }
+
+    fn changed(mut self, from: &str, to: &str) -> StationChange {
+        self.from = Some(from.to_owned());
+        self.to = Some(to.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for StationChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let from = self.from.as_deref().unwrap_or_default();
+        let to = self.to.as_deref().unwrap_or_default();
+        match self.kind {
+            ChangeKind::Added => write!(f, "{} {} added", self.channel, self.call_sign),
+            ChangeKind::Removed => write!(f, "{} {} removed", self.channel, self.call_sign),
+            ChangeKind::Renumbered => {
+                write!(f, "{} renumbered from {} to {}", self.call_sign, from, to)
+            }
+            ChangeKind::CallSign => write!(
+                f,
+                "{} call sign changed from {} to {}",
+                self.channel, from, to
+            ),
+            ChangeKind::Logo => write!(f, "{} {} logo changed", self.channel, self.call_sign),
+        }
+    }
+}
+
+/// Describe the differences between two lineups: stations that were added or removed, and changes
+/// to channel numbers, call signs and logos
+pub fn lineup_diff(old: &[Station], new: &[Station]) -> Vec<StationChange> {
+    let old_by_id: HashMap<i64, &Station> = old.iter().map(|s| (s.id, s)).collect();
+    let new_by_id: HashMap<i64, &Station> = new.iter().map(|s| (s.id, s)).collect();
+    let logo = |s: &Station| s.logoUrl.clone().or_else(|| s.logo226Url.clone());
+
+    let mut changes = Vec::new();
+    for o in old {
+        let n = match new_by_id.get(&o.id) {
+            Some(n) => n,
+            None => {
+                changes.push(StationChange::new(ChangeKind::Removed, o));
+                continue;
+            }
+        };
+        if n.channel != o.channel {
+            changes.push(StationChange::new(ChangeKind::Renumbered, n).changed(
+                o.channel.as_deref().unwrap_or_default(),
+                n.channel.as_deref().unwrap_or_default(),
+            ));
+        }
+        if n.callSign != o.callSign {
+            changes.push(
+                StationChange::new(ChangeKind::CallSign, n).changed(&o.callSign, &n.callSign),
+            );
+        }
+        if logo(n) != logo(o) {
+            changes.push(StationChange::new(ChangeKind::Logo, n).changed(
+                logo(o).as_deref().unwrap_or_default(),
+                logo(n).as_deref().unwrap_or_default(),
+            ));
+        }
+    }
+    changes.extend(
+        new.iter()
+            .filter(|n| !old_by_id.contains_key(&n.id))
+            .map(|n| StationChange::new(ChangeKind::Added, n)),
+    );
+    changes
+}
+
+/// The changes of a single refresh. At most `MAX_DIFF_CHANGES` changes are kept, `total` is the number
+/// of changes before truncating.
+#[derive(Debug, Serialize, Clone)]
+pub struct LineupDiff {
+    pub city: String,
+    pub dma: String,
+    /// Unix timestamp of the refresh
+    pub timestamp: i64,
+    pub total: usize,
+    pub changes: Vec<StationChange>,
+}
+
+/// Lineup diffs of the last `MAX_DIFFS` refreshes that changed the lineup
+#[derive(Debug, Default)]
+pub struct DiffLog {
+    diffs: VecDeque<LineupDiff>,
+    last_logged: i64,
+}
+
+impl DiffLog {
+    pub fn new() -> DiffLog {
+        DiffLog::default()
+    }
+
+    /// Record and log the changes of a refresh. A summary is always logged, individual changes are
+    /// limited to `MAX_LOGGED_CHANGES` and logged at most once every `DIFF_LOG_INTERVAL` seconds, so a
+    /// market that changes completely doesn't flood the logs.
+    pub fn record(&mut self, city: &str, dma: &str, mut changes: Vec<StationChange>) {
+        if changes.is_empty() {
+            return;
+        }
+        let now = Utc::now().timestamp();
+        info!("Lineup for {} changed: {}", city, summary(&changes));
+        if now - self.last_logged >= DIFF_LOG_INTERVAL {
+            self.last_logged = now;
+            for change in changes.iter().take(MAX_LOGGED_CHANGES) {
+                info!(" {}", change);
+            }
+            if changes.len() > MAX_LOGGED_CHANGES {
+                info!(
+                    " .. and {} more (see /lineup/diff.json)",
+                    changes.len() - MAX_LOGGED_CHANGES
+                );
+            }
+        }
+
+        let total = changes.len();
+        changes.truncate(MAX_DIFF_CHANGES);
+        self.diffs.push_front(LineupDiff {
+            city: city.to_owned(),
+            dma: dma.to_owned(),
+            timestamp: now,
+            total,
+            changes,
+        });
+        self.diffs.truncate(MAX_DIFFS);
+    }
+
+    /// Diffs of the last refreshes, most recent first
+    pub fn diffs(&self) -> Vec<LineupDiff> {
+        self.diffs.iter().cloned().collect()
+    }
+}
+
+/// Count changes by kind (e.g. "2 added, 1 removed")
+fn summary(changes: &[StationChange]) -> String {
+    [
+        (ChangeKind::Added, "added"),
+        (ChangeKind::Removed, "removed"),
+        (ChangeKind::Renumbered, "renumbered"),
+        (ChangeKind::CallSign, "call sign changed"),
+        (ChangeKind::Logo, "logo changed"),
+    ]
+    .iter()
+    .filter_map(
+        |(kind, label)| match changes.iter().filter(|c| c.kind == *kind).count() {
+            0 => None,
+            n => Some(format!("{} {}", n, label)),
+        },
+    )
+    .collect::<Vec<String>>()
+    .join(", ")
+}
+
+/// Describe the changes between two lineups that would renumber or remove channels
+pub fn lineup_changes(old: &[Station], new: &[Station]) -> Vec<String> {
+    lineup_diff(old, new)
+        .into_iter()
+        .filter(|c| matches!(c.kind, ChangeKind::Removed | ChangeKind::Renumbered))
+        .map(|c| c.to_string())
+        .collect()
+}
+
+/// Refresh the listings of the stations in `stations` without changing the lineup itself
+pub fn refresh_listings(stations: &mut [Station], new: Vec<Station>) {
+    let mut new_by_id: HashMap<i64, Station> = new.into_iter().map(|s| (s.id, s)).collect();
+    for station in stations.iter_mut() {
+        if let Some(n) = new_by_id.remove(&station.id) {
+            station.listings = n.listings;
+        }
+    }
+}
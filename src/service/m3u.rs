@@ -0,0 +1,527 @@
+use super::{
+    adhoc::{AdhocChannel, AdhocRequest},
+    conflicts::{channel_conflicts, ChannelConflict},
+    coverage::{epg_coverage, EpgCoverage},
+    filter::StationFilter,
+    lineup::LineupDiff,
+    quality::SelectedVariant,
+    station::{ChannelRemapEntry, Listing, Station, Stations},
+    stationprovider::{ServiceArc, StationProvider},
+    xmltv, Geo, ServiceStatus, StationRoute,
+};
+use crate::{
+    config::{Config, M3uSource},
+    credentials::{AccountLease, AccountStatus, LocastCredentials},
+    errors::AppError,
+    fcc_facilities::FCCFacilities,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::lock::Mutex;
+use lazy_static::lazy_static;
+use regex::Regex;
+use ring::digest;
+use simple_error::SimpleError;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+
+/// Station ids of M3U stations start here, so they don't collide with locast station ids
+static STATION_ID_BASE: i64 = 1_000_000_000;
+
+lazy_static! {
+    static ref ATTRIBUTE: Regex = Regex::new(r#"([\w-]+)="([^"]*)""#).unwrap();
+}
+
+/// A service backed by an M3U playlist (and optionally an XMLTV guide) instead of locast. The
+/// playlist and guide are loaded again when they're older than `cache_timeout`. Streams are served
+/// from the URLs in the playlist, which have to be HLS playlists.
+pub struct M3uService {
+    config: Arc<Config>,
+    credentials: Arc<LocastCredentials>,
+    fcc_facilities: Arc<FCCFacilities>,
+    source: M3uSource,
+    geo: Arc<Geo>,
+    uuid: String,
+    stations: Stations,
+    /// Stream URL of every station
+    urls: Mutex<HashMap<i64, String>>,
+    /// Unix timestamp of the last time the playlist was loaded
+    last_refresh: AtomicI64,
+    filter: StationFilter,
+}
+
+pub type M3uServiceArc = Arc<M3uService>;
+
+impl M3uService {
+    /// Construct a service for an M3U source. `credentials` and `fcc_facilities` aren't used by the
+    /// service itself, but are shared with the locast services it's multiplexed with.
+    pub async fn new(
+        config: Arc<Config>,
+        credentials: Arc<LocastCredentials>,
+        fcc_facilities: Arc<FCCFacilities>,
+        source: M3uSource,
+    ) -> M3uServiceArc {
+        let geo = Arc::new(Geo {
+            latitude: 0.0,
+            longitude: 0.0,
+            DMA: format!("m3u-{}", slug(&source.name)),
+            name: source.name.clone(),
+            active: true,
+            timezone: None,
+            timezone_resolution: None,
+        });
+        let uuid = uuid::Uuid::new_v5(
+            &uuid::Uuid::from_str(&config.uuid).unwrap(),
+            geo.DMA.as_bytes(),
+        )
+        .to_string();
+        let filter = StationFilter::new(&config);
+        let service = Arc::new(M3uService {
+            config,
+            credentials,
+            fcc_facilities,
+            source,
+            geo,
+            uuid,
+            stations: Arc::new(Mutex::new(Vec::new())),
+            urls: Mutex::new(HashMap::new()),
+            last_refresh: AtomicI64::new(0),
+            filter,
+        });
+        service.load().await;
+        service
+    }
+
+    /// Load the playlist and guide. If that fails, the stations that were loaded before are kept.
+    async fn load(&self) {
+        self.last_refresh
+            .store(Utc::now().timestamp(), Ordering::SeqCst);
+        match self.fetch_stations().await {
+            Ok((stations, urls)) => {
+                info!(
+                    "Loaded {} stations for {} from {}",
+                    stations.len(),
+                    self.source.name,
+                    self.source.playlist
+                );
+                *self.stations.lock().await = stations;
+                *self.urls.lock().await = urls;
+            }
+            Err(e) => warn!("Unable to load stations for {}: {}", self.source.name, e),
+        }
+    }
+
+    async fn fetch_stations(&self) -> Result<(Vec<Station>, HashMap<i64, String>), SimpleError> {
+        let playlist = xmltv::fetch(&self.source.playlist).await?;
+        let entries = parse_playlist(&playlist);
+
+        let mut stations = Vec::new();
+        let mut urls = HashMap::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let key = entry.attribute("tvg-id").unwrap_or(&entry.url);
+            let id = station_id(&self.source.name, key);
+            let channel = entry
+                .attribute("tvg-chno")
+                .or_else(|| entry.attribute("channel-number"))
+                .map(|c| c.to_owned())
+                .unwrap_or_else(|| (i + 1).to_string());
+            let logo = entry.attribute("tvg-logo").map(|l| l.to_owned());
+            stations.push(Station {
+                active: true,
+                callSign: entry
+                    .attribute("tvg-name")
+                    .unwrap_or(&entry.name)
+                    .to_owned(),
+                channel: Some(channel),
+                city: Some(self.source.name.clone()),
+                dma: 0,
+                id,
+                listings: Vec::new(),
+                logo226Url: logo.clone(),
+                logoUrl: logo,
+                name: entry.name.clone(),
+                sequence: None,
+                stationId: id.to_string(),
+                // Listings are in UTC
+                timezone: Some("UTC".to_owned()),
+                tivoId: None,
+                transcodeId: 0,
+                channel_remapped: None,
+                callSign_remapped: None,
+                remapped: None,
+                channel_locast: None,
+                channel_fcc: None,
+            });
+            urls.insert(id, entry.url.clone());
+        }
+
+        if let Some(guide) = &self.source.guide {
+            match xmltv::fetch(guide).await {
+                Ok(xmltv) => {
+                    let channels: HashMap<String, i64> = entries
+                        .iter()
+                        .filter_map(|e| e.attribute("tvg-id"))
+                        .map(|tvg_id| (tvg_id.to_owned(), station_id(&self.source.name, tvg_id)))
+                        .collect();
+                    let mut listings = parse_listings(&xmltv, &channels)?;
+                    for station in stations.iter_mut() {
+                        station.listings = listings.remove(&station.id).unwrap_or_default();
+                    }
+                }
+                // Without a guide, the stations are still usable
+                Err(e) => warn!("Unable to load the guide of {}: {}", self.source.name, e),
+            }
+        }
+        Ok((stations, urls))
+    }
+
+    /// Load the playlist again if it's older than `cache_timeout`
+    async fn load_if_expired(&self) {
+        let age = Utc::now().timestamp() - self.last_refresh.load(Ordering::SeqCst);
+        if age >= self.config.cache_timeout as i64 {
+            self.load().await;
+        }
+    }
+}
+
+/// A station in an M3U playlist
+struct PlaylistEntry {
+    attributes: HashMap<String, String>,
+    name: String,
+    url: String,
+}
+
+impl PlaylistEntry {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .get(name)
+            .map(|a| a.as_str())
+            .filter(|a| !a.is_empty())
+    }
+}
+
+/// Parse the `#EXTINF` entries of an M3U playlist and their URLs
+fn parse_playlist(playlist: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut extinf: Option<&str> = None;
+    for line in playlist.lines().map(|l| l.trim()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            extinf = Some(info);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let info = extinf.take().unwrap_or_default();
+            // The name follows the last comma that's not part of an attribute
+            let attributes_end = ATTRIBUTE.find_iter(info).last().map_or(0, |m| m.end());
+            let name = info[attributes_end..]
+                .split_once(',')
+                .map_or("", |(_, name)| name.trim());
+            entries.push(PlaylistEntry {
+                attributes: ATTRIBUTE
+                    .captures_iter(info)
+                    .map(|c| (c[1].to_owned(), c[2].to_owned()))
+                    .collect(),
+                name: if name.is_empty() { line } else { name }.to_owned(),
+                url: line.to_owned(),
+            });
+        }
+    }
+    entries
+}
+
+/// Convert the programmes of `channels` (XMLTV channel id --> station id) in an XMLTV document to
+/// listings, by station id
+fn parse_listings(
+    xmltv: &str,
+    channels: &HashMap<String, i64>,
+) -> Result<HashMap<i64, Vec<Listing>>, SimpleError> {
+    let reader = EventReader::new_with_config(
+        xmltv.as_bytes(),
+        ParserConfig::new()
+            .trim_whitespace(true)
+            .ignore_comments(true),
+    );
+    let mut listings: HashMap<i64, Vec<Listing>> = HashMap::new();
+    // The programme that's being read, and the element of it
+    let mut programme: Option<Listing> = None;
+    let mut element = String::new();
+    let mut episode_system = String::new();
+    for event in reader {
+        match event.map_err(SimpleError::from)? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                let attribute = |n: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == n)
+                        .map(|a| a.value.clone())
+                };
+                if name.local_name == "programme" {
+                    programme = new_listing(
+                        attribute("channel").and_then(|c| channels.get(&c)),
+                        attribute("start"),
+                        attribute("stop"),
+                    );
+                } else if let Some(p) = programme.as_mut() {
+                    match name.local_name.as_str() {
+                        "icon" => {
+                            p.preferredImage = attribute("src");
+                            p.hasImageArtwork = p.preferredImage.is_some();
+                        }
+                        "new" => p.isNew = Some(true),
+                        "live" => p.isLive = Some(true),
+                        "premiere" => p.isPremiere = Some(true),
+                        _ => {}
+                    }
+                    episode_system = attribute("system").unwrap_or_default();
+                    element = name.local_name;
+                }
+            }
+            XmlEvent::Characters(text) => {
+                if let Some(p) = programme.as_mut() {
+                    match element.as_str() {
+                        "title" => p.title = text,
+                        "sub-title" => p.episodeTitle = Some(text),
+                        "desc" => p.description = Some(text),
+                        "category" => {
+                            p.genres = Some(match p.genres.take() {
+                                Some(genres) => format!("{}, {}", genres, text),
+                                None => text,
+                            })
+                        }
+                        "episode-num" if episode_system == "xmltv_ns" => {
+                            // "season.episode.part", zero based
+                            let mut parts = text.split('.').map(|p| p.trim().parse::<i16>().ok());
+                            p.seasonNumber = parts.next().flatten().map(|s| s + 1);
+                            p.episodeNumber = parts.next().flatten().map(|e| e + 1);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "programme" {
+                    if let Some(p) = programme.take() {
+                        listings.entry(p.stationId).or_default().push(p);
+                    }
+                }
+                element.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(listings)
+}
+
+/// An empty listing for a programme of `station_id` from `start` until `stop` (XMLTV times), or
+/// `None` if the programme isn't for one of our stations or the times can't be parsed
+fn new_listing(
+    station_id: Option<&i64>,
+    start: Option<String>,
+    stop: Option<String>,
+) -> Option<Listing> {
+    let parse = |t: Option<String>| {
+        DateTime::parse_from_str(t?.as_str(), "%Y%m%d%H%M%S %z")
+            .ok()
+            .map(|t| t.timestamp())
+    };
+    let station_id = *station_id?;
+    let start = parse(start)?;
+    let stop = parse(stop)?;
+    Some(Listing {
+        airdate: None,
+        audioProperties: None,
+        description: None,
+        directors: None,
+        duration: stop - start,
+        entityType: "Episode".to_owned(),
+        episodeNumber: None,
+        episodeTitle: None,
+        genres: None,
+        hasImageArtwork: false,
+        hasSeriesArtwork: false,
+        isNew: None,
+        isLive: None,
+        isPremiere: None,
+        preferredImage: None,
+        preferredImageHeight: None,
+        preferredImageWidth: None,
+        programId: format!("{}.{}", station_id, start),
+        rating: None,
+        releaseDate: None,
+        releaseYear: None,
+        seasonNumber: None,
+        seriesId: None,
+        shortDescription: None,
+        showType: "Series".to_owned(),
+        startTime: start * 1000,
+        stationId: station_id,
+        title: String::new(),
+        topCast: None,
+        videoProperties: None,
+    })
+}
+
+/// Stable station id of the station with `key` (its `tvg-id` or URL) in the M3U source `source`
+fn station_id(source: &str, key: &str) -> i64 {
+    let hash = digest::digest(&digest::SHA256, format!("{}/{}", source, key).as_bytes());
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&hash.as_ref()[..4]);
+    STATION_ID_BASE + i64::from(u32::from_be_bytes(bytes)) % STATION_ID_BASE
+}
+
+/// Lowercase `name`, with everything but letters and digits replaced by dashes
+fn slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+#[async_trait]
+impl StationProvider for M3uServiceArc {
+    /// The stream URL of a station is the URL in the playlist
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        _lease: &AccountLease,
+    ) -> Result<Mutex<String>, AppError> {
+        let id: i64 = id.parse().map_err(|_| AppError::NotFound)?;
+        match self.urls.lock().await.get(&id) {
+            Some(url) => Ok(Mutex::new(url.clone())),
+            None => Err(AppError::NotFound),
+        }
+    }
+
+    /// Playlist URLs don't expire
+    async fn forget_stream_uri(&self, _id: &str) {}
+
+    /// Playlist entries aren't master playlists
+    async fn stream_variant(&self, _id: &str) -> Option<SelectedVariant> {
+        None
+    }
+
+    /// Get the stations that pass `include_stations` and `exclude_stations`
+    async fn stations(&self) -> Stations {
+        self.load_if_expired().await;
+        if self.filter.is_empty() {
+            return self.stations.clone();
+        }
+        let stations = self
+            .stations
+            .lock()
+            .await
+            .iter()
+            .filter(|s| self.filter.allows(s))
+            .cloned()
+            .collect();
+        Arc::new(Mutex::new(stations))
+    }
+
+    fn geo(&self) -> Arc<Geo> {
+        self.geo.clone()
+    }
+
+    fn uuid(&self) -> String {
+        self.uuid.clone()
+    }
+
+    fn zipcode(&self) -> String {
+        "".to_string()
+    }
+
+    fn services(&self) -> Vec<ServiceArc> {
+        vec![Arc::new(self.clone())]
+    }
+
+    async fn lease_account(&self, _id: &str) -> Result<AccountLease, AppError> {
+        Ok(self.credentials.lease())
+    }
+
+    async fn account_status(&self) -> Vec<AccountStatus> {
+        self.credentials.status().await
+    }
+
+    fn fcc_facilities(&self) -> Arc<FCCFacilities> {
+        self.fcc_facilities.clone()
+    }
+
+    async fn status(&self) -> Vec<ServiceStatus> {
+        let last_refresh = self.last_refresh.load(Ordering::SeqCst);
+        vec![ServiceStatus {
+            name: self.geo.name.clone(),
+            backend: "m3u",
+            zipcode: None,
+            coordinates: None,
+            dma: self.geo.DMA.clone(),
+            stations: self.stations.lock().await.len(),
+            last_refresh,
+            modified: last_refresh,
+            timezone: None,
+            loading: false,
+        }]
+    }
+
+    async fn refresh(&self) {
+        self.load().await;
+    }
+
+    async fn epg_coverage(&self) -> Vec<EpgCoverage> {
+        let stations = self.stations.lock().await;
+        epg_coverage(&stations, self.config.days, &[])
+    }
+
+    /// Lineup changes aren't tracked for M3U sources
+    async fn lineup_diffs(&self) -> Vec<LineupDiff> {
+        Vec::new()
+    }
+
+    async fn routing(&self) -> Vec<StationRoute> {
+        let stations_mutex = self.stations().await;
+        let stations = stations_mutex.lock().await;
+        stations
+            .iter()
+            .map(|s| StationRoute::new(s, self as &(dyn StationProvider + Send + Sync)))
+            .collect()
+    }
+
+    async fn channel_conflicts(&self) -> Vec<ChannelConflict> {
+        let stations_mutex = self.stations().await;
+        let stations = stations_mutex.lock().await;
+        channel_conflicts(&stations, false)
+    }
+
+    /// Channel remapping is handled by the `Multiplexer`, so a single service can't be remapped.
+    async fn update_channel_remap(
+        &self,
+        _entries: HashMap<String, ChannelRemapEntry>,
+    ) -> Result<(), AppError> {
+        Err(AppError::RemapUnavailable)
+    }
+
+    async fn remove_channel_remap(&self, _key: &str) -> Result<(), AppError> {
+        Err(AppError::RemapUnavailable)
+    }
+
+    async fn adhoc_channels(&self) -> Vec<AdhocChannel> {
+        Vec::new()
+    }
+
+    /// Ad hoc channels are locast stations or events, so they can't be added to an M3U source
+    async fn add_adhoc_channel(&self, _request: AdhocRequest) -> Result<AdhocChannel, AppError> {
+        Err(AppError::BadRequest(
+            "Ad hoc channels are only available for locast locations".to_owned(),
+        ))
+    }
+
+    async fn remove_adhoc_channel(&self, _id: i64) -> Result<(), AppError> {
+        Err(AppError::NotFound)
+    }
+}
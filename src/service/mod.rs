@@ -1,38 +1,80 @@
+pub mod adhoc;
+pub mod cache;
+pub mod conflicts;
+pub mod coverage;
+pub mod enrichment;
+pub mod filter;
+pub mod lineup;
+pub mod m3u;
 pub mod multiplexer;
+pub mod quality;
+pub mod scheduler;
 pub mod station;
 pub mod stationprovider;
+pub mod stream_uris;
+pub mod timezone;
+pub mod xmltv;
 use self::{
-    station::{Station, Stations},
-    stationprovider::StationProvider,
+    adhoc::{AdhocChannel, AdhocChannels, AdhocRequest},
+    conflicts::{channel_conflicts, ChannelConflict},
+    coverage::{epg_coverage, EpgCoverage},
+    filter::StationFilter,
+    lineup::{lineup_changes, lineup_diff, refresh_listings, DiffLog, FreezeWindow, LineupDiff},
+    quality::{variant_url, Quality, SelectedVariant},
+    scheduler::RefreshScheduler,
+    station::{ChannelRemapEntry, Station, Stations},
+    stationprovider::{ServiceArc, StationProvider},
+    stream_uris::StreamUris,
 };
 use crate::{
-    config::Config, credentials::LocastCredentials, errors::AppError,
-    fcc_facilities::FCCFacilities, utils::get,
+    config::Config,
+    credentials::{AccountLease, AccountStatus, LocastCredentials},
+    errors::AppError,
+    fcc_facilities::FCCFacilities,
+    notify::{self, Event},
+    telemetry::Span,
+    utils::{get, HttpError},
 };
 use async_trait::async_trait;
 use chrono::Utc;
+use chrono_tz::Tz;
 use futures::lock::Mutex;
+use lazy_static::lazy_static;
 use log::info;
 use regex::Regex;
-use reqwest::Url;
-use serde::Deserialize;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use simple_error::SimpleError;
 use std::{
-    borrow::Cow,
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     convert::{From, TryFrom},
     fmt,
+    hash::{Hash, Hasher},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
 };
-use tokio::task;
-use tokio::time::{sleep, Duration};
+use tokio::{sync::Notify, task};
 
 static DMA_URL: &str = "https://api.locastnet.org/api/watch/dma";
 static IP_URL: &str = "https://api.locastnet.org/api/watch/dma/ip";
 static STATIONS_URL: &str = "https://api.locastnet.org/api/watch/epg";
 static WATCH_URL: &str = "https://api.locastnet.org/api/watch/station";
 
+/// Retries of a single background refresh. The refresh scheduler backs off when these are exhausted.
+static REFRESH_RETRIES: usize = 3;
+
+lazy_static! {
+    static ref CHANNEL_FROM_CALL_SIGN: Regex = Regex::new(r"(\d+\.\d+) .+").unwrap();
+    /// Channel mismatches (station id, locast channel, FCC channel) that were logged, so they're only
+    /// logged once instead of on every refresh
+    static ref LOGGED_MISMATCHES: std::sync::Mutex<HashSet<(i64, String, String)>> =
+        std::sync::Mutex::new(HashSet::new());
+}
+
 /// Struct that interacts with locast. Note that valid credentials are required
 #[derive(Debug)]
 pub struct LocastService {
@@ -40,9 +82,106 @@ pub struct LocastService {
     credentials: Arc<LocastCredentials>,
     fcc_facilities: Arc<FCCFacilities>,
     pub zipcode: Option<String>,
+    /// Coordinates the service was created for, instead of a zip code
+    pub coordinates: Option<Coordinates>,
     pub geo: Arc<Geo>,
     pub uuid: String,
     stations: Stations,
+    refresh: Arc<RefreshState>,
+    adhoc: AdhocChannels,
+    filter: StationFilter,
+    stream_uris: Arc<StreamUris>,
+}
+
+/// Results of the last station refresh, maintained by the updater thread
+#[derive(Debug)]
+struct RefreshState {
+    /// Unix timestamp of the last time stations were fetched from locast
+    last_refresh: AtomicI64,
+    /// Unix timestamp of the last time the stations or their listings changed
+    modified: AtomicI64,
+    coverage: Mutex<Vec<EpgCoverage>>,
+    diffs: Mutex<DiffLog>,
+    /// Wakes up the updater thread to refresh right away
+    requested: Notify,
+    /// Number of refreshes that were attempted, successful or not
+    attempts: AtomicU64,
+    /// Notified after every refresh attempt
+    attempted: Notify,
+}
+
+impl RefreshState {
+    fn attempt_done(&self) {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        self.attempted.notify_waiters();
+    }
+}
+
+/// Status of a single `LocastService`, used for diagnostics
+#[derive(Debug, Serialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    /// Backend of the service, e.g. `locast` or `m3u`
+    pub backend: &'static str,
+    pub zipcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coordinates: Option<Coordinates>,
+    pub dma: String,
+    pub stations: usize,
+    /// Unix timestamp of the last time stations were fetched from locast
+    pub last_refresh: i64,
+    /// Unix timestamp of the last time the stations or their listings changed
+    pub modified: i64,
+    /// How the timezone of the service was resolved
+    pub timezone: Option<timezone::TimezoneResolution>,
+    /// Whether the stations are still being loaded for the first time (`lazy_start`)
+    pub loading: bool,
+}
+
+/// Service a station is routed to, as reported at `/routing.json`
+#[derive(Debug, Serialize, Clone)]
+pub struct StationRoute {
+    pub station_id: i64,
+    pub call_sign: String,
+    pub channel: Option<String>,
+    pub channel_remapped: Option<String>,
+    pub city: String,
+    pub zipcode: Option<String>,
+    pub dma: String,
+}
+
+impl StationRoute {
+    pub fn new(station: &Station, service: &(dyn StationProvider + Send + Sync)) -> StationRoute {
+        let geo = service.geo();
+        let zipcode = service.zipcode();
+        StationRoute {
+            station_id: station.id,
+            call_sign: station.callSign.clone(),
+            channel: station.channel.clone(),
+            channel_remapped: station.channel_remapped.clone(),
+            city: geo.name.clone(),
+            zipcode: (!zipcode.is_empty()).then_some(zipcode),
+            dma: geo.DMA.clone(),
+        }
+    }
+}
+
+impl fmt::Display for StationRoute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "station {} ({} {}) -> {} (zipcode: {}, DMA: {})",
+            self.station_id,
+            self.channel_remapped
+                .as_ref()
+                .or(self.channel.as_ref())
+                .map_or("-", |c| c.as_str()),
+            self.call_sign,
+            self.city,
+            self.zipcode.as_deref().unwrap_or("-"),
+            self.dma
+        )
+    }
 }
 
 impl LocastService {
@@ -51,13 +190,14 @@ impl LocastService {
         config: Arc<Config>,
         credentials: Arc<LocastCredentials>,
         fcc_facilities: Arc<FCCFacilities>,
-        zipcode: Option<String>,
+        location: Location,
     ) -> LocastServiceArc {
         // Figure out what location we are serving
-        let geo = Arc::new(geo_from(&zipcode).await);
+        let geo = Arc::new(geo_from(&location).await);
         if !geo.active {
             panic!("{} not active", geo.name)
         }
+        let credentials = credentials.for_location(|l| location.matches(l) || l == geo.DMA);
 
         // Generate a UUID for this specific service
         let uuid = uuid::Uuid::new_v5(
@@ -66,40 +206,115 @@ impl LocastService {
         )
         .to_string();
 
-        // Get a list of stations
-        let stations = Arc::new(Mutex::new(
-            build_stations(
-                locast_stations(&geo.DMA, config.days, &credentials.token().await).await,
-                &geo,
-                &config,
-                &fcc_facilities,
-            )
-            .await,
-        ));
+        // Get a list of stations. If we have cached stations on disk, we use those and let the
+        // updater thread refresh them once they've expired.
+        let cached = if config.disable_station_cache {
+            None
+        } else {
+            cache::load(&config.cache_directory, &geo.DMA)
+        };
+        let (stations, first_update, last_refresh) = match cached {
+            Some(cached) => {
+                info!(
+                    "Loaded {} cached stations for {} (fetched {} minutes ago)",
+                    cached.stations.len(),
+                    geo.name,
+                    cached.age().num_minutes()
+                );
+                let remaining = config.cache_timeout as i64 - cached.age().num_seconds();
+                (cached.stations, remaining.max(0) as u64, cached.timestamp)
+            }
+            // Stations are loaded by the updater thread, so the servers can start right away
+            None if config.lazy_start => {
+                info!("Loading stations for {} in the background", geo.name);
+                (Vec::new(), 0, 0)
+            }
+            None => {
+                let stations = build_stations(
+                    locast_stations(&geo.DMA, config.days, &credentials.token().await, 100)
+                        .await
+                        .unwrap(),
+                    &geo,
+                    &config,
+                    &fcc_facilities,
+                )
+                .await;
+                if !config.disable_station_cache {
+                    cache::store(&config.cache_directory, &geo.DMA, &stations);
+                }
+                (stations, config.cache_timeout, Utc::now().timestamp())
+            }
+        };
+        let refresh = Arc::new(RefreshState {
+            last_refresh: AtomicI64::new(last_refresh),
+            modified: AtomicI64::new(last_refresh),
+            coverage: Mutex::new(epg_coverage(&stations, config.days, &[])),
+            diffs: Mutex::new(DiffLog::new()),
+            requested: Notify::new(),
+            attempts: AtomicU64::new(0),
+            attempted: Notify::new(),
+        });
+        let stations = Arc::new(Mutex::new(stations));
 
         // Start an updater thread that will periodically update all station information
         // including EPG data
-        start_updater_thread(&config, &stations, &geo, &credentials, &fcc_facilities);
+        start_updater_thread(
+            &config,
+            &stations,
+            &refresh,
+            &geo,
+            &credentials,
+            &fcc_facilities,
+            first_update,
+        );
+        if last_refresh == 0 {
+            refresh.requested.notify_one();
+        }
 
+        let filter = StationFilter::new(&config);
         Arc::new(LocastService {
             config,
             credentials,
             fcc_facilities,
-            zipcode,
+            zipcode: location.zipcode(),
+            coordinates: location.coordinates(),
             geo,
             uuid,
             stations,
+            refresh,
+            adhoc: AdhocChannels::new(),
+            filter,
+            stream_uris: Arc::new(StreamUris::default()),
         })
     }
 
+    /// Status of this service. Note that this doesn't fetch stations, even if the station cache is
+    /// disabled.
+    pub async fn status(&self) -> ServiceStatus {
+        ServiceStatus {
+            name: self.geo.name.clone(),
+            backend: "locast",
+            zipcode: self.zipcode.clone(),
+            coordinates: self.coordinates,
+            dma: self.geo.DMA.clone(),
+            stations: self.stations.lock().await.len(),
+            last_refresh: self.refresh.last_refresh.load(Ordering::SeqCst),
+            modified: self.refresh.modified.load(Ordering::SeqCst),
+            timezone: self.geo.timezone_resolution.clone(),
+            loading: self.refresh.last_refresh.load(Ordering::SeqCst) == 0,
+        }
+    }
+
     /// Convenience method for building stations based on &self
     async fn build_stations(&self) -> Vec<Station> {
         let locast_stations = locast_stations(
             &self.geo.DMA,
             self.config.days,
             &self.credentials.token().await,
+            100,
         )
-        .await;
+        .await
+        .unwrap();
         build_stations(
             locast_stations,
             &self.geo,
@@ -108,55 +323,130 @@ impl LocastService {
         )
         .await
     }
+
+    /// The stream URL locast returns for station `id`, which might be a master playlist
+    async fn locast_stream_url(&self, id: &str, lease: &AccountLease) -> Result<String, AppError> {
+        let mut span = Span::new("locast_stream_uri", None);
+        span.set_attribute("station.id", id.to_owned());
+
+        // Construct the URL for the station
+        let url = format!(
+            "{}/{}/{}/{}",
+            WATCH_URL, id, self.geo.latitude, self.geo.longitude
+        );
+
+        let mut response = match get(&url, Some(&lease.token().await), 100).await {
+            Ok(r) => r,
+            Err(_) => return Err(AppError::UpstreamError),
+        };
+
+        // The token might have been invalidated by locast. If so, login again and retry once.
+        if response.status() == StatusCode::UNAUTHORIZED {
+            if let Err(e) = lease.relogin().await {
+                warn!("Unable to log in again with {}: {}", lease.username(), e);
+                return Err(AppError::UpstreamError);
+            }
+            response = match get(&url, Some(&lease.token().await), 100).await {
+                Ok(r) => r,
+                Err(_) => return Err(AppError::UpstreamError),
+            };
+        }
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(AppError::NotFound);
+        }
+
+        let value: HashMap<String, Value> = match response.json().await {
+            Ok(v) => v,
+            Err(_) => return Err(AppError::UpstreamError),
+        };
+
+        match value.get("streamUrl").and_then(|u| u.as_str()) {
+            Some(u) => Ok(u.to_owned()),
+            None => Err(AppError::UpstreamError),
+        }
+    }
 }
 
 pub type LocastServiceArc = Arc<LocastService>;
 
 #[async_trait]
 impl StationProvider for LocastServiceArc {
-    /// Get stations
+    /// Get stations that pass `include_stations` and `exclude_stations`, including ad hoc channels that
+    /// haven't expired
     async fn stations(&self) -> Stations {
-        if self.config.disable_station_cache {
+        let stations = if self.config.disable_station_cache {
             Arc::new(Mutex::new(self.build_stations().await))
         } else {
             self.stations.clone()
+        };
+
+        // Filtered stations and ad hoc channels are never part of the station list that's refreshed
+        // and cached, so changes to the filters apply right away
+        let adhoc = self.adhoc.active().await;
+        if adhoc.is_empty() && self.filter.is_empty() {
+            return stations;
         }
+        let mut stations: Vec<Station> = stations
+            .lock()
+            .await
+            .iter()
+            .filter(|s| self.filter.allows(s))
+            .cloned()
+            .collect();
+        stations.extend(adhoc.into_iter().map(|c| c.station));
+        Arc::new(Mutex::new(stations))
     }
 
-    /// Get the stream URI for a specified station id
-    async fn station_stream_uri(&self, id: &str) -> Result<Mutex<String>, AppError> {
-        // Construct the URL for the station
-        let url = format!(
-            "{}/{}/{}/{}",
-            WATCH_URL, id, self.geo.latitude, self.geo.longitude
-        );
-
-        let response = match get(&url, Some(&self.credentials.token().await.to_owned()), 100).await
+    /// Get the stream URI for a specified station id, using the leased account. URIs that were
+    /// resolved in the last few seconds are reused.
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        lease: &AccountLease,
+    ) -> Result<Mutex<String>, AppError> {
+        if let Some(uri) = self.stream_uris.get(id) {
+            debug!("Reusing stream URL of station {}", id);
+            return Ok(Mutex::new(uri));
+        }
+        // Overridden stations are streamed from their configured URL, without asking locast
+        let stream_url = match self
+            .config
+            .stream_overrides
+            .iter()
+            .find(|o| o.station_id.to_string() == id)
         {
-            Ok(r) => r,
-            Err(_) => return Err(AppError::NotFound),
+            Some(o) => {
+                debug!("Using stream override of station {}: {}", id, o.url);
+                o.url.clone()
+            }
+            None => self.locast_stream_url(id, lease).await?,
         };
+        let stream_url = stream_url.as_str();
+        let m3u_data = match get(stream_url, None, 100).await {
+            Ok(r) => r.text().await.map_err(|_| AppError::UpstreamError)?,
+            Err(_) => return Err(AppError::UpstreamError),
+        };
+        let master_playlist = hls_m3u8::MasterPlaylist::try_from(m3u_data.as_str());
 
-        let value: HashMap<String, Value> = response.json().await.unwrap();
+        // If there's a master playlist, parse it and get the stream matching the configured quality,
+        // else we already have the correct URL.
+        let (uri, variant) = match master_playlist {
+            Ok(mp) => {
+                let quality = self.config.quality.parse().unwrap_or(Quality::Highest);
+                variant_url(mp.variant_streams, stream_url, &quality)
+            }
+            Err(_) => (stream_url.to_owned(), None),
+        };
+        self.stream_uris.insert(id, &uri, variant);
+        Ok(Mutex::new(uri))
+    }
 
-        let stream_url = value.get("streamUrl").unwrap().as_str().unwrap();
-        let m3u_data = get(stream_url, None, 100)
-            .await
-            .unwrap()
-            .text()
-            .await
-            .unwrap();
-        let master_playlist = hls_m3u8::MasterPlaylist::try_from(m3u_data.as_str());
+    async fn forget_stream_uri(&self, id: &str) {
+        self.stream_uris.forget(id);
+    }
 
-        // If there's a master playlist, parse it and get the highest quality stream, else we already have the
-        // correct URL.
-        match master_playlist {
-            Ok(mp) => Ok(Mutex::new(highest_quality_url(
-                mp.variant_streams,
-                stream_url,
-            ))),
-            Err(_) => Ok(Mutex::new(stream_url.to_owned())),
-        }
+    async fn stream_variant(&self, id: &str) -> Option<SelectedVariant> {
+        self.stream_uris.variant(id)
     }
 
     /// Returns the `Geo` that is associated with this service
@@ -178,44 +468,111 @@ impl StationProvider for LocastServiceArc {
         }
     }
 
-    /// Returns the services associated to this service. In the case of locast service implementation,
-    /// this is an empty vector.
-    fn services(&self) -> Vec<LocastServiceArc> {
-        Vec::new()
+    /// Returns the services associated to this service. A single service is its only service.
+    fn services(&self) -> Vec<ServiceArc> {
+        vec![Arc::new(self.clone())]
+    }
+
+    /// Status of the locast accounts used by this service
+    async fn account_status(&self) -> Vec<AccountStatus> {
+        self.credentials.status().await
+    }
+
+    /// Returns the FCC facilities used by this service
+    fn fcc_facilities(&self) -> Arc<FCCFacilities> {
+        self.fcc_facilities.clone()
+    }
+
+    /// Status of this service
+    async fn status(&self) -> Vec<ServiceStatus> {
+        vec![LocastService::status(self).await]
+    }
+
+    /// Refresh stations from locast right away, instead of waiting for the updater thread. If the
+    /// refresh failed, the current stations are kept.
+    async fn refresh(&self) {
+        let attempts = self.refresh.attempts.load(Ordering::SeqCst);
+        self.refresh.requested.notify_one();
+        loop {
+            let attempted = self.refresh.attempted.notified();
+            if self.refresh.attempts.load(Ordering::SeqCst) > attempts {
+                return;
+            }
+            attempted.await;
+        }
+    }
+
+    /// Guide coverage of all stations, as computed after the last refresh
+    async fn epg_coverage(&self) -> Vec<EpgCoverage> {
+        self.refresh.coverage.lock().await.clone()
+    }
+
+    /// Lineup changes of the last refreshes
+    async fn lineup_diffs(&self) -> Vec<LineupDiff> {
+        self.refresh.diffs.lock().await.diffs()
+    }
+
+    /// Channel numbers that are used by more than one station, e.g. through the remap file
+    async fn channel_conflicts(&self) -> Vec<ChannelConflict> {
+        let stations_mutex = self.stations().await;
+        let stations = stations_mutex.lock().await;
+        channel_conflicts(&stations, false)
+    }
+
+    /// All stations are routed to this service
+    async fn routing(&self) -> Vec<StationRoute> {
+        let stations_mutex = self.stations().await;
+        let stations = stations_mutex.lock().await;
+        stations
+            .iter()
+            .map(|s| StationRoute::new(s, self as &(dyn StationProvider + Send + Sync)))
+            .collect()
+    }
+
+    /// Lease the least loaded locast account
+    async fn lease_account(&self, _id: &str) -> Result<AccountLease, AppError> {
+        Ok(self.credentials.lease())
+    }
+
+    /// Channel remapping is handled by the `Multiplexer`, so a single service can't be remapped.
+    async fn update_channel_remap(
+        &self,
+        _entries: HashMap<String, ChannelRemapEntry>,
+    ) -> Result<(), AppError> {
+        Err(AppError::RemapUnavailable)
+    }
+
+    async fn remove_channel_remap(&self, _key: &str) -> Result<(), AppError> {
+        Err(AppError::RemapUnavailable)
+    }
+
+    /// Ad hoc channels that haven't expired
+    async fn adhoc_channels(&self) -> Vec<AdhocChannel> {
+        self.adhoc.active().await
+    }
+
+    /// Add a temporary channel for a locast station or event id
+    async fn add_adhoc_channel(&self, request: AdhocRequest) -> Result<AdhocChannel, AppError> {
+        if request.dma.as_ref().is_some_and(|dma| *dma != self.geo.DMA) {
+            return Err(AppError::NotFound);
+        }
+        let stations = self.stations.lock().await;
+        self.adhoc
+            .add(&request, &stations, &self.geo, self.config.adhoc_expiry)
+            .await
+    }
+
+    async fn remove_adhoc_channel(&self, id: i64) -> Result<(), AppError> {
+        self.adhoc.remove(id).await
     }
 }
 
-/// Sort the variant streams by bandwith (desc), pick the top one and return the full URL
-fn highest_quality_url(
-    mut variant_streams: Vec<hls_m3u8::tags::VariantStream>,
+/// Full URL of the highest quality variant stream
+pub fn highest_quality_url(
+    variant_streams: Vec<hls_m3u8::tags::VariantStream>,
     stream_url: &str,
 ) -> String {
-    variant_streams.sort_by(|a, b| {
-        let ea = match a {
-            hls_m3u8::tags::VariantStream::ExtXStreamInf { stream_data, .. } => {
-                stream_data.bandwidth()
-            }
-            _ => 0,
-        };
-        let eb = match b {
-            hls_m3u8::tags::VariantStream::ExtXStreamInf { stream_data, .. } => {
-                stream_data.bandwidth()
-            }
-            _ => 0,
-        };
-        ea.cmp(&eb)
-    });
-    let variant = variant_streams.pop().unwrap();
-    let variant_url = match variant {
-        hls_m3u8::tags::VariantStream::ExtXStreamInf { uri, .. } => uri,
-        _ => Cow::Borrowed(""),
-    };
-    let full_url = Url::parse(&stream_url)
-        .unwrap()
-        .join(&variant_url.to_string())
-        .unwrap()
-        .to_string();
-    full_url
+    variant_url(variant_streams, stream_url, &Quality::Highest).0
 }
 
 impl fmt::Display for LocastService {
@@ -228,39 +585,135 @@ impl fmt::Display for LocastService {
     }
 }
 
-/// Start a `LocastService` updater thread
+/// Start a `LocastService` updater thread. The first update happens after `first_update` seconds,
+/// after that stations are updated every `cache_timeout` seconds. See `RefreshScheduler` for how
+/// jitter and failures are handled.
 fn start_updater_thread(
     config: &Arc<Config>,
     stations: &Stations,
+    refresh: &Arc<RefreshState>,
     geo: &Arc<Geo>,
     credentials: &Arc<LocastCredentials>,
     fcc_facilities: &Arc<FCCFacilities>,
+    first_update: u64,
 ) {
     // TODO: Can this be done nicer?
     let thread_stations = stations.clone();
+    let thread_refresh = refresh.clone();
     let thread_config = config.clone();
     let thread_geo = geo.clone();
     let thread_credentials = credentials.clone();
     let thread_facilities = fcc_facilities.clone();
-    let thread_timeout = config.cache_timeout.clone();
+    let mut scheduler = RefreshScheduler::new(
+        &geo.name,
+        config.cache_timeout,
+        config.refresh_jitter,
+        first_update,
+    );
+    let freeze_window = config
+        .lineup_freeze
+        .as_ref()
+        .map(|w| w.parse::<FreezeWindow>().unwrap());
+    let timezone: Tz = match &geo.timezone {
+        Some(tz) => tz.parse::<Tz>().unwrap_or_else(|e| {
+            warn!("Unknown timezone {} for {}, using UTC: {}", tz, geo.name, e);
+            Tz::UTC
+        }),
+        None => Tz::UTC,
+    };
 
     task::spawn(async move {
+        let mut deferred = false;
         loop {
-            sleep(Duration::from_secs(thread_timeout)).await;
-            let ls = locast_stations(
+            scheduler.wait(&thread_refresh.requested).await;
+            let ls = match locast_stations(
                 &thread_geo.DMA,
                 thread_config.days,
                 &thread_credentials.token().await,
+                REFRESH_RETRIES,
             )
-            .await;
+            .await
+            {
+                Ok(ls) => ls,
+                Err(e) => {
+                    warn!("Unable to refresh stations for {}: {}", thread_geo.name, e);
+                    // Only the first failure is notified, retries back off anyway
+                    if scheduler.failed() == 1 {
+                        notify::notify(Event::EpgRefreshFailed {
+                            location: thread_geo.name.clone(),
+                            error: e.to_string(),
+                        });
+                    }
+                    thread_refresh.attempt_done();
+                    continue;
+                }
+            };
+            scheduler.succeeded();
+            thread_refresh
+                .last_refresh
+                .store(Utc::now().timestamp(), Ordering::SeqCst);
             let new_stations =
                 build_stations(ls, &thread_geo, &thread_config, &thread_facilities).await;
             let mut stations = thread_stations.lock().await;
-            *stations = new_stations;
+            let previous_hash = content_hash(&stations);
+
+            // Changes that would renumber or remove channels are deferred while the lineup is
+            // frozen. Listings are still refreshed.
+            let changes = lineup_changes(&stations, &new_stations);
+            let frozen =
+                freeze_window.is_some_and(|w| w.contains(&Utc::now().with_timezone(&timezone)));
+            if !changes.is_empty() && frozen {
+                info!(
+                    "Lineup for {} is frozen, deferring {} change(s)",
+                    thread_geo.name,
+                    changes.len()
+                );
+                refresh_listings(&mut stations, new_stations);
+                deferred = true;
+            } else {
+                if deferred {
+                    info!("Applying deferred lineup changes for {}:", thread_geo.name);
+                    for change in &changes {
+                        info!(" {}", change);
+                    }
+                    deferred = false;
+                }
+                let diff = lineup_diff(&stations, &new_stations);
+                thread_refresh
+                    .diffs
+                    .lock()
+                    .await
+                    .record(&thread_geo.name, &thread_geo.DMA, diff);
+                *stations = new_stations;
+            }
+            if content_hash(&stations) != previous_hash {
+                thread_refresh
+                    .modified
+                    .store(Utc::now().timestamp(), Ordering::SeqCst);
+            }
+
+            if !thread_config.disable_station_cache {
+                cache::store(&thread_config.cache_directory, &thread_geo.DMA, &stations);
+            }
+
+            let mut coverage = thread_refresh.coverage.lock().await;
+            *coverage = epg_coverage(&stations, thread_config.days, &coverage);
+            drop(coverage);
+            drop(stations);
+            thread_refresh.attempt_done();
         }
     });
 }
 
+/// Hash of everything that's served about `stations`, used to detect if a refresh changed anything
+fn content_hash(stations: &[Station]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(stations)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
 /// Retrieve and enrich station data
 async fn build_stations(
     locast_stations: Vec<Station>,
@@ -282,24 +735,66 @@ async fn build_stations(
         station.city = Some(geo.name.to_owned());
 
         // See if we can get the channel number from the call sign (i.e. X.Y NAME)
-        let channel_from_call_sign = match Regex::new(r"(\d+\.\d+) .+")
-            .unwrap()
+        let channel_from_call_sign = CHANNEL_FROM_CALL_SIGN
             .captures(&station.callSign)
-        {
-            Some(c) => Some(c.get(1).map_or("", |m| m.as_str())),
-            None => None,
-        };
+            .map(|c| c.get(1).map_or("", |m| m.as_str()).to_string());
+        let dma = geo.DMA.parse::<i64>().unwrap();
 
         // If the station's call sign is in the format "X.Y NAME", use X.Y as the channel number,
         // otherwise, we'll have to lookup the channel number using the name or the call sign.
-        // And if we can't find the channel, we panic.
+        // If the FCC doesn't know the call sign, the station is skipped. And if there is no call sign, we panic.
         let c = if let Some(channel) = channel_from_call_sign {
-            Some(channel.to_string())
+            // Locast sometimes reports the RF channel instead of the virtual channel, so we compare
+            // it to the virtual channel from the FCC facility data.
+            // Call signs are matched without their suffix (e.g. "KCBS-DT"), like in the FCC data
+            let fcc_channel = match detect_callsign(without_suffix(&station.name)).or_else(|| {
+                detect_callsign(without_suffix(crate::utils::name_only(&station.callSign)))
+            }) {
+                Some((call_sign, _)) => fcc_facilities.virtual_channel(dma, call_sign).await,
+                None => None,
+            }
+            .map(|major| match channel.split_once('.') {
+                Some((_, minor)) => format!("{}.{}", major, minor),
+                None => major,
+            });
+
+            station.channel_locast = Some(channel.clone());
+            station.channel_fcc = fcc_channel.clone();
+
+            match fcc_channel {
+                Some(fcc_channel) if fcc_channel != channel => {
+                    let mismatch = (station.id, channel.clone(), fcc_channel.clone());
+                    if LOGGED_MISMATCHES.lock().unwrap().insert(mismatch) {
+                        warn!(
+                            "Channel mismatch for {}: locast reports {}, FCC reports {}",
+                            &station.callSign, channel, fcc_channel
+                        );
+                    }
+                    if config.prefer_locast_channels {
+                        Some(channel)
+                    } else {
+                        Some(fcc_channel)
+                    }
+                }
+                _ => Some(channel),
+            }
         } else if let Some((call_sign, sub_channel)) =
             detect_callsign(&station.name).or(detect_callsign(&station.callSign))
         {
-            let dma = geo.DMA.parse::<i64>().unwrap();
-            Some(fcc_facilities.lookup(dma, call_sign, sub_channel).await)
+            match fcc_facilities.lookup(dma, call_sign, sub_channel).await {
+                Some(channel) => {
+                    station.channel_fcc = Some(channel.clone());
+                    Some(channel)
+                }
+                None => {
+                    // This can happen when the FCC facilities couldn't be loaded (see /readyz)
+                    warn!(
+                        "Channel for {} (call sign: {}) not found in FCC facilities, skipping station",
+                        &station.name, &station.callSign
+                    );
+                    continue;
+                }
+            }
         } else {
             panic!(
                 "Channel {}, call sign: {} not found!",
@@ -311,12 +806,20 @@ async fn build_stations(
         station.callSign = crate::utils::name_only(&station.callSign).to_string();
         stations.push(station);
     }
+    enrichment::enrich(config, &mut stations).await;
     stations
 }
 
 /// Get all stations from locast.org by specifying how many days in the future we would
 /// like station information.
-async fn locast_stations(dma: &str, days: u8, token: &str) -> Vec<Station> {
+async fn locast_stations(
+    dma: &str,
+    days: u8,
+    token: &str,
+    max_retries: usize,
+) -> Result<Vec<Station>, HttpError> {
+    let mut span = Span::new("locast_stations", None);
+    span.set_attribute("locast.dma", dma.to_owned());
     let start_time = Utc::now().format("%Y-%m-%dT00:00:00-00:00").to_string();
     let uri = format!(
         "{}/{}?startTime={}&hours={}",
@@ -325,12 +828,12 @@ async fn locast_stations(dma: &str, days: u8, token: &str) -> Vec<Station> {
         start_time,
         days * 24
     );
-    crate::utils::get(&uri, Some(token), 100)
-        .await
-        .unwrap()
+    crate::utils::get(&uri, Some(token), max_retries)
+        .await?
+        .error_for_status()?
         .json::<Vec<Station>>()
         .await
-        .unwrap()
+        .map_err(HttpError::from)
 }
 
 /// Detect a call sign from a string.
@@ -342,6 +845,11 @@ fn detect_callsign(input: &str) -> Option<(&str, &str)> {
     Some((call_sign, sub_channel))
 }
 
+/// A call sign without its suffix (e.g. "KCBS-DT" becomes "KCBS")
+fn without_suffix(call_sign: &str) -> &str {
+    call_sign.split('-').next().unwrap_or(call_sign)
+}
+
 #[allow(non_snake_case)]
 #[derive(Deserialize, Debug)]
 pub struct Geo {
@@ -351,19 +859,163 @@ pub struct Geo {
     pub name: String,
     pub active: bool,
     pub timezone: Option<String>,
+    /// How `timezone` was resolved from what locast reported
+    #[serde(skip)]
+    pub timezone_resolution: Option<timezone::TimezoneResolution>,
+}
+
+/// Latitude and longitude of a location, specified as `latitude/longitude`. Used instead of a zip
+/// code when that maps to the wrong DMA.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl FromStr for Coordinates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid coordinates '{}', expected latitude/longitude", s);
+        let (latitude, longitude) = s.split_once('/').ok_or_else(invalid)?;
+        let latitude = latitude.trim().parse::<f64>().map_err(|_| invalid())?;
+        let longitude = longitude.trim().parse::<f64>().map_err(|_| invalid())?;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(format!("Coordinates '{}' are out of range", s));
+        }
+        Ok(Coordinates {
+            latitude,
+            longitude,
+        })
+    }
 }
-async fn geo_from(zipcode: &Option<String>) -> Geo {
-    let uri = match zipcode {
-        Some(z) => format!("{}/zip/{}", DMA_URL, z),
-        None => String::from(IP_URL),
+
+impl fmt::Display for Coordinates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.latitude, self.longitude)
+    }
+}
+
+/// Location a service is created for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// Determined by locast from the IP address
+    Ip,
+    Zipcode(String),
+    Coordinates(Coordinates),
+}
+
+impl Location {
+    fn zipcode(&self) -> Option<String> {
+        match self {
+            Location::Zipcode(z) => Some(z.clone()),
+            _ => None,
+        }
+    }
+
+    fn coordinates(&self) -> Option<Coordinates> {
+        match self {
+            Location::Coordinates(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    /// Returns true if `location` (a zip code or coordinates from the config) is this location
+    fn matches(&self, location: &str) -> bool {
+        match self {
+            Location::Ip => false,
+            Location::Zipcode(z) => z == location,
+            Location::Coordinates(c) => location.parse::<Coordinates>().as_ref() == Ok(c),
+        }
+    }
+
+    /// URL of the locast endpoint that returns the location
+    fn uri(&self) -> String {
+        match self {
+            Location::Ip => String::from(IP_URL),
+            Location::Zipcode(z) => format!("{}/zip/{}", DMA_URL, z),
+            Location::Coordinates(c) => format!("{}/{}/{}", DMA_URL, c.latitude, c.longitude),
+        }
+    }
+
+    /// The locations to create services for: `override_zipcodes` and `override_coordinates`, or
+    /// the location of the IP address if neither is set
+    pub fn configured(config: &Config) -> Vec<Location> {
+        let mut locations: Vec<Location> = config
+            .override_zipcodes
+            .iter()
+            .flatten()
+            .map(|z| Location::Zipcode(z.to_owned()))
+            .collect();
+        // Coordinates are validated when the config is loaded
+        locations.extend(
+            config
+                .override_coordinates
+                .iter()
+                .filter_map(|c| c.parse().ok())
+                .map(Location::Coordinates),
+        );
+        if locations.is_empty() {
+            locations.push(Location::Ip);
+        }
+        locations
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Ip => write!(f, "your location"),
+            Location::Zipcode(z) => write!(f, "{}", z),
+            Location::Coordinates(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+/// Check if locast is available for a location, without panicking. Returns the location. Used to
+/// validate the setup wizard and `--check-config`.
+pub async fn check_location(location: &Location) -> Result<Geo, SimpleError> {
+    let uri = location.uri();
+    let geo = match get(&uri, None, 3).await {
+        Ok(r) => r
+            .json::<Geo>()
+            .await
+            .map_err(|_| SimpleError::new(format!("Locast is not available for {}", location)))?,
+        Err(e) => return Err(SimpleError::from(e)),
     };
+    if !geo.active {
+        return Err(SimpleError::new(format!(
+            "Locast is not active in {} ({})",
+            geo.name, location
+        )));
+    }
+    Ok(geo)
+}
 
-    let mut geo = crate::utils::get(&uri, None, 100)
+async fn geo_from(location: &Location) -> Geo {
+    let _span = Span::new("locast_geo", None);
+    let mut geo = crate::utils::get(&location.uri(), None, 100)
         .await
         .unwrap()
         .json::<Geo>()
         .await
         .unwrap();
-    geo.timezone = Some(tz_search::lookup(geo.latitude, geo.longitude).unwrap());
+    // Streams are requested for the coordinates of the location, so use the exact ones
+    if let Location::Coordinates(c) = location {
+        geo.latitude = c.latitude;
+        geo.longitude = c.longitude;
+    }
+    let resolution = timezone::resolve(geo.timezone.as_deref(), geo.latitude, geo.longitude);
+    if resolution.mismatch {
+        warn!(
+            "Timezone {} reported for {} doesn't match its location ({}), using {}",
+            resolution.reported.as_deref().unwrap_or(""),
+            geo.name,
+            resolution.coordinates.as_deref().unwrap_or("unknown"),
+            resolution.resolved
+        );
+    }
+    geo.timezone = Some(resolution.resolved.clone());
+    geo.timezone_resolution = Some(resolution);
     geo
 }
@@ -1,113 +1,298 @@
-use super::station::ChannelRemapEntry;
+use super::{
+    adhoc::{AdhocChannel, AdhocRequest},
+    conflicts::{channel_conflicts, ChannelConflict},
+    coverage::EpgCoverage,
+    lineup::LineupDiff,
+    quality::SelectedVariant,
+    station::ChannelRemapEntry,
+    stationprovider::ServiceArc,
+};
 use crate::{
     config::Config,
+    credentials::{AccountLease, AccountStatus},
     errors::AppError,
-    service::{Geo, LocastServiceArc, Station, StationProvider, Stations},
+    fcc_facilities::FCCFacilities,
+    service::{Geo, ServiceStatus, Station, StationProvider, StationRoute, Stations},
 };
 use async_trait::async_trait;
-use futures::lock::Mutex;
+use futures::{future::join_all, lock::Mutex};
+use itertools::Itertools;
 use log::info;
-use std::{collections::HashMap, fs::File, sync::Arc};
-/// Multiplex `LocastService` objects. `Multiplexer` implements the `StationProvider` trait
-/// and can act as a LocastService.
+use simple_error::SimpleError;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+/// Multiplex services of any backend. `Multiplexer` implements the `StationProvider` trait
+/// and can act as a single service.
 pub struct Multiplexer {
-    services: Vec<LocastServiceArc>,
+    services: Vec<ServiceArc>,
     config: Arc<Config>,
-    station_id_service_map: Mutex<HashMap<String, LocastServiceArc>>,
-    channel_remap: Option<HashMap<String, ChannelRemapEntry>>,
+    /// Service and route of every station, by station id
+    station_id_service_map: Mutex<HashMap<String, (ServiceArc, StationRoute)>>,
+    channel_remap: Mutex<HashMap<String, ChannelRemapEntry>>,
+    /// File remappings are saved to. Without one, changes made through /admin are lost on restart.
+    remap_file: Option<PathBuf>,
+    /// Channel offset of each service when remapping
+    remap_offsets: Vec<u32>,
+    /// Channel number conflicts after remapping, as found the last time the stations were loaded
+    conflicts: Mutex<Vec<ChannelConflict>>,
 }
 
 impl Multiplexer {
-    /// Create a new `Multiplexer` with a vector of `LocastServiceArcs` and a `Config`
-    pub fn new(services: Vec<LocastServiceArc>, config: Arc<Config>) -> MultiplexerArc {
-        let channel_remap = match &config.remap_file {
-            Some(f) => {
-                let file = File::open(f).unwrap();
-                let c: HashMap<String, ChannelRemapEntry> = serde_json::from_reader(file).unwrap();
-                Some(c)
-            }
-            None => None,
+    /// Create a new `Multiplexer` with a vector of services and a `Config`
+    pub fn new(
+        services: Vec<ServiceArc>,
+        config: Arc<Config>,
+    ) -> Result<MultiplexerArc, SimpleError> {
+        // Remappings are read from (and saved to) the remap file, if one is specified
+        let remap_file = config.remap_file.as_ref().map(PathBuf::from);
+        let channel_remap = match &remap_file {
+            Some(f) if f.exists() => read_remap_file(f).map_err(|e| {
+                SimpleError::new(format!("Unable to read remap file {}: {}", f.display(), e))
+            })?,
+            _ => HashMap::new(),
         };
-        Arc::new(Multiplexer {
+        let remap_offsets = remap_offsets(&services, &config);
+        Ok(Arc::new(Multiplexer {
+            remap_offsets,
             services,
             config,
             station_id_service_map: Mutex::new(HashMap::new()),
-            channel_remap,
+            channel_remap: Mutex::new(channel_remap),
+            remap_file,
+            conflicts: Mutex::new(Vec::new()),
+        }))
+    }
+}
+
+impl Multiplexer {
+    /// Save the channel remappings to the remap file
+    fn save_channel_remap(
+        &self,
+        channel_remap: &HashMap<String, ChannelRemapEntry>,
+    ) -> Result<(), AppError> {
+        let remap_file = match &self.remap_file {
+            Some(f) => f,
+            None => {
+                warn!("No remap_file configured, channel remapping is lost on restart");
+                return Ok(());
+            }
+        };
+        match write_remap_file(remap_file, channel_remap) {
+            Ok(()) => {
+                info!("Saved channel remapping to {}", remap_file.display());
+                Ok(())
+            }
+            Err(e) => {
+                error!(
+                    "Unable to save channel remapping to {}: {}",
+                    remap_file.display(),
+                    e
+                );
+                Err(AppError::InternalError)
+            }
+        }
+    }
+
+    /// Check the remapped stations for channel numbers that are used more than once. Conflicts are
+    /// logged when they change, so they don't flood the log on every lineup request.
+    async fn check_conflicts(&self, stations: &[Station]) {
+        let conflicts = channel_conflicts(stations, self.config.remap);
+        let mut previous = self.conflicts.lock().await;
+        if conflicts == *previous {
+            return;
+        }
+        for conflict in &conflicts {
+            let stations = conflict
+                .stations
+                .iter()
+                .map(|s| format!("{} ({})", s.call_sign, s.city.as_deref().unwrap_or("?")))
+                .join(", ");
+            warn!(
+                "CHANNEL CONFLICT: channel {} is used by {}. {} See /lineup_conflicts.json for details.",
+                conflict.channel, stations, conflict.suggestion
+            );
+        }
+        if conflicts.is_empty() {
+            info!("All channel conflicts are resolved");
+        }
+        *previous = conflicts;
+    }
+}
+
+/// Channel offset of every service when remapping. Locations in `remap_offsets` (by zip code or DMA) get
+/// the configured offset, others get `100 * index`.
+fn remap_offsets(services: &[ServiceArc], config: &Config) -> Vec<u32> {
+    let offsets: Vec<u32> = services
+        .iter()
+        .enumerate()
+        .map(|(i, service)| {
+            config
+                .remap_offsets
+                .iter()
+                .find(|o| service.zipcode() == o.location || service.geo().DMA == o.location)
+                .map_or(100 * i as u32, |o| o.offset)
         })
+        .collect();
+
+    if config.remap {
+        for (service, offset) in services.iter().zip(&offsets) {
+            info!(
+                "Channels of {} are remapped with offset {}",
+                service.geo().name,
+                offset
+            );
+        }
+        if offsets.iter().unique().count() < offsets.len() {
+            warn!("Multiple locations share a remap offset, their channel numbers might collide");
+        }
+    }
+    offsets
+}
+
+fn read_remap_file(path: &Path) -> std::io::Result<HashMap<String, ChannelRemapEntry>> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Write the channel remappings to `path`. Entries are sorted to keep the file easy to edit by hand.
+/// They're written to a temporary file first, so an interrupted write never leaves invalid JSON behind.
+fn write_remap_file(
+    path: &Path,
+    channel_remap: &HashMap<String, ChannelRemapEntry>,
+) -> std::io::Result<()> {
+    let sorted: BTreeMap<&String, &ChannelRemapEntry> = channel_remap.iter().collect();
+    let tmp_path = path.with_extension("json.tmp");
+    let file = File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(&file, &sorted)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Remap a station of a service with channel offset `offset` (see `remap_offsets`). If `remap` is set,
+/// channels are renumbered by adding `offset`, otherwise the remapping in `channel_remap` (if any) is
+/// applied.
+pub fn remap_station(
+    station: &mut Station,
+    offset: u32,
+    remap: bool,
+    channel_remap: &HashMap<String, ChannelRemapEntry>,
+) {
+    if remap {
+        let channel = station.channel.as_ref().unwrap();
+        if let Ok(c) = channel.parse::<u32>() {
+            station.channel_remapped = Some((c + offset).to_string());
+        } else if let Ok(c) = channel.parse::<f32>() {
+            station.channel_remapped = Some((c + offset as f32).to_string());
+        } else {
+            panic!("Could not remap {}", channel);
+        };
+
+        station.callSign_remapped = Some(station.callSign.clone());
+        station.remapped = Some(true)
+    } else {
+        // Look if the channel is is remapped in the channel map
+        let key = format!("channel.{}", station.id);
+        match channel_remap.get(&key) {
+            Some(r) if r.remapped => {
+                station.channel_remapped = Some(r.remap_channel.clone());
+                station.callSign_remapped = Some(r.remap_call_sign.clone());
+                station.active = r.active;
+                station.remapped = Some(r.remapped);
+                debug!(
+                    "Remap -  {} {} => {} {}",
+                    station.channel.clone().unwrap(),
+                    station.callSign,
+                    station.channel_remapped.clone().unwrap(),
+                    station.callSign_remapped.clone().unwrap()
+                );
+            }
+            _ => {}
+        }
     }
 }
 
 type MultiplexerArc = Arc<Multiplexer>;
+
+/// The service that streams station `id`
+async fn route(multiplexer: &MultiplexerArc, id: &str) -> Result<ServiceArc, AppError> {
+    // Make sure the station_id_service_map is loaded. Feels wrong to do it like this though.. Needs refactoring.
+    multiplexer.stations().await;
+
+    match multiplexer.station_id_service_map.lock().await.get(id) {
+        Some((service, route)) => {
+            info!("Routing {}", route);
+            Ok(service.clone())
+        }
+        None => {
+            warn!("No route for station {}", id);
+            Err(AppError::NotFound)
+        }
+    }
+}
 #[async_trait]
 impl StationProvider for Arc<Multiplexer> {
     /// Get the stream URL for a locast station id.
-    async fn station_stream_uri(&self, id: &str) -> Result<Mutex<String>, AppError> {
-        // Make sure the station_id_service_map is loaded. Feels wrong to do it like this though.. Needs refactoring.
-        self.stations().await;
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        lease: &AccountLease,
+    ) -> Result<Mutex<String>, AppError> {
+        route(self, id).await?.station_stream_uri(id, lease).await
+    }
 
-        let service = match self
+    async fn forget_stream_uri(&self, id: &str) {
+        let service = self
             .station_id_service_map
             .lock()
             .await
-            .get(&id.to_string())
-        {
-            Some(s) => s.clone(),
-            None => return Err(AppError::NotFound),
-        };
+            .get(id)
+            .map(|(service, _)| service.clone());
+        if let Some(service) = service {
+            service.forget_stream_uri(id).await;
+        }
+    }
 
-        service.station_stream_uri(id).await
+    async fn stream_variant(&self, id: &str) -> Option<SelectedVariant> {
+        let service = self
+            .station_id_service_map
+            .lock()
+            .await
+            .get(id)
+            .map(|(service, _)| service.clone())?;
+        service.stream_variant(id).await
     }
 
-    /// Get all stations for all `LocastService`s.
+    /// Get all stations of all services.
     async fn stations(&self) -> Stations {
         let mut all_stations: Vec<Station> = Vec::new();
         let services = self.services.clone();
         let services_len = services.len();
+        let channel_remap = self.channel_remap.lock().await;
+        let mut routes = HashMap::new();
         for (i, service) in services.into_iter().enumerate() {
             let stations_mutex = service.stations().await;
 
             let stations = stations_mutex.lock().await;
-            for mut station in stations.iter().map(|s| s.clone()) {
-                if self.config.remap {
-                    let channel = station.channel.as_ref().unwrap();
-                    if let Ok(c) = channel.parse::<usize>() {
-                        station.channel_remapped = Some((c + 100 * i).to_string());
-                    } else if let Ok(c) = channel.parse::<f32>() {
-                        station.channel_remapped = Some((c + 100.0 * i as f32).to_string());
-                    } else {
-                        panic!("Could not remap {}", channel);
-                    };
-
-                    station.callSign_remapped = Some(station.callSign.clone());
-                    station.remapped = Some(true)
-                } else if self.channel_remap.is_some() {
-                    // Look if the channel is is remapped in the channel map
-                    let channel_remap = self.channel_remap.as_ref().unwrap();
-                    let key = format!("channel.{}", station.id);
-                    match channel_remap.get(&key) {
-                        Some(r) if r.remapped => {
-                            station.channel_remapped = Some(r.remap_channel.clone());
-                            station.callSign_remapped = Some(r.remap_call_sign.clone());
-                            station.active = r.active;
-                            station.remapped = Some(r.remapped);
-                            debug!(
-                                "Remap -  {} {} => {} {}",
-                                station.channel.clone().unwrap(),
-                                station.callSign,
-                                station.channel_remapped.clone().unwrap(),
-                                station.callSign_remapped.clone().unwrap()
-                            );
-                        }
-                        _ => {}
-                    }
-                }
-                self.station_id_service_map
-                    .lock()
-                    .await
-                    .insert(station.id.to_string(), service.clone());
+            for mut station in stations.iter().cloned() {
+                remap_station(
+                    &mut station,
+                    self.remap_offsets[i],
+                    self.config.remap,
+                    &channel_remap,
+                );
+                let route = StationRoute::new(&station, service.as_ref());
+                routes.insert(station.id.to_string(), (service.clone(), route));
                 all_stations.push(station);
             }
         }
+        // Stations that are gone (e.g. expired ad hoc channels) are no longer routed
+        *self.station_id_service_map.lock().await = routes;
+        drop(channel_remap);
+        self.check_conflicts(&all_stations).await;
         info!(
             "Got {} stations for {} cities",
             all_stations.len(),
@@ -124,6 +309,7 @@ impl StationProvider for Arc<Multiplexer> {
             name: "Multiplexer".to_string(),
             active: true,
             timezone: None,
+            timezone_resolution: None,
         })
     }
 
@@ -135,7 +321,140 @@ impl StationProvider for Arc<Multiplexer> {
         "".to_string()
     }
 
-    fn services(&self) -> Vec<LocastServiceArc> {
+    fn services(&self) -> Vec<ServiceArc> {
         self.services.clone()
     }
+
+    /// Lease the least loaded locast account of the service that streams station `id`. Locations
+    /// can have their own accounts, so the lease has to come from that service.
+    async fn lease_account(&self, id: &str) -> Result<AccountLease, AppError> {
+        route(self, id).await?.lease_account(id).await
+    }
+
+    /// Status of the accounts of all multiplexed services. Accounts that are shared by services are
+    /// only included once, with the locations of all of them.
+    async fn account_status(&self) -> Vec<AccountStatus> {
+        let mut all: Vec<AccountStatus> = Vec::new();
+        for service in &self.services {
+            for account in service.account_status().await {
+                match all.iter_mut().find(|a| a.username == account.username) {
+                    Some(a) => {
+                        for location in account.locations {
+                            if !a.locations.contains(&location) {
+                                a.locations.push(location);
+                            }
+                        }
+                    }
+                    None => all.push(account),
+                }
+            }
+        }
+        all
+    }
+
+    fn fcc_facilities(&self) -> Arc<FCCFacilities> {
+        self.services[0].fcc_facilities()
+    }
+
+    /// Status of all multiplexed services
+    async fn status(&self) -> Vec<ServiceStatus> {
+        let mut status = Vec::new();
+        for service in &self.services {
+            status.extend(service.status().await);
+        }
+        status
+    }
+
+    /// Refresh the stations of all multiplexed services
+    async fn refresh(&self) {
+        join_all(self.services.iter().map(|s| s.refresh())).await;
+    }
+
+    /// Guide coverage of the stations of all multiplexed services
+    async fn epg_coverage(&self) -> Vec<EpgCoverage> {
+        let mut coverage = Vec::new();
+        for service in &self.services {
+            coverage.extend(service.epg_coverage().await);
+        }
+        coverage
+    }
+
+    /// Lineup changes of all multiplexed services, most recent first
+    async fn lineup_diffs(&self) -> Vec<LineupDiff> {
+        let mut diffs = Vec::new();
+        for service in &self.services {
+            diffs.extend(service.lineup_diffs().await);
+        }
+        diffs.sort_by_key(|d| std::cmp::Reverse(d.timestamp));
+        diffs
+    }
+
+    /// Routes of all stations, after remapping
+    async fn routing(&self) -> Vec<StationRoute> {
+        self.stations().await;
+        let mut routes: Vec<StationRoute> = self
+            .station_id_service_map
+            .lock()
+            .await
+            .values()
+            .map(|(_, route)| route.clone())
+            .collect();
+        routes.sort_by_key(|r| r.station_id);
+        routes
+    }
+
+    /// Channel number conflicts between the stations of all multiplexed services, after remapping
+    async fn channel_conflicts(&self) -> Vec<ChannelConflict> {
+        self.stations().await;
+        self.conflicts.lock().await.clone()
+    }
+
+    /// Update the channel remappings and persist them to the remap file.
+    async fn update_channel_remap(
+        &self,
+        entries: HashMap<String, ChannelRemapEntry>,
+    ) -> Result<(), AppError> {
+        let mut channel_remap = self.channel_remap.lock().await;
+        channel_remap.extend(entries);
+        self.save_channel_remap(&channel_remap)
+    }
+
+    /// Remove a channel remapping and persist the remaining remappings to the remap file.
+    async fn remove_channel_remap(&self, key: &str) -> Result<(), AppError> {
+        let mut channel_remap = self.channel_remap.lock().await;
+        if channel_remap.remove(key).is_none() {
+            return Err(AppError::NotFound);
+        }
+        self.save_channel_remap(&channel_remap)
+    }
+
+    /// Ad hoc channels of all multiplexed services
+    async fn adhoc_channels(&self) -> Vec<AdhocChannel> {
+        let mut channels = Vec::new();
+        for service in &self.services {
+            channels.extend(service.adhoc_channels().await);
+        }
+        channels
+    }
+
+    /// Add an ad hoc channel to the service of the requested DMA, or the first service if no DMA is given
+    async fn add_adhoc_channel(&self, request: AdhocRequest) -> Result<AdhocChannel, AppError> {
+        let service = match &request.dma {
+            Some(dma) => self.services.iter().find(|s| s.geo().DMA == *dma),
+            None => self.services.first(),
+        };
+        match service {
+            Some(s) => s.add_adhoc_channel(request).await,
+            None => Err(AppError::NotFound),
+        }
+    }
+
+    async fn remove_adhoc_channel(&self, id: i64) -> Result<(), AppError> {
+        for service in &self.services {
+            if service.remove_adhoc_channel(id).await.is_ok() {
+                return Ok(());
+            }
+        }
+        Err(AppError::NotFound)
+    }
 }
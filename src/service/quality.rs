@@ -0,0 +1,138 @@
+use hls_m3u8::tags::VariantStream;
+use reqwest::Url;
+use serde::Serialize;
+use simple_error::SimpleError;
+use std::str::FromStr;
+
+/// Which variant of a master playlist is streamed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Highest,
+    Lowest,
+    /// The highest quality variant that doesn't exceed this bandwidth (bits per second)
+    MaxBandwidth(u64),
+    /// The highest quality variant that doesn't exceed this resolution (width, height)
+    MaxResolution(usize, usize),
+}
+
+impl FromStr for Quality {
+    type Err = SimpleError;
+
+    /// Parse "highest", "lowest", a maximum bandwidth ("3000000", "3000k" or "3M"), a maximum
+    /// resolution ("1280x720") or a maximum height ("720p")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            SimpleError::new(format!(
+                "Invalid quality '{}' (highest, lowest, a bandwidth like 3000k or a resolution like 1280x720 or 720p)",
+                s
+            ))
+        };
+        let s = s.trim().to_lowercase();
+        let quality = match s.as_str() {
+            "highest" => Quality::Highest,
+            "lowest" => Quality::Lowest,
+            _ => {
+                if let Some(height) = s.strip_suffix('p') {
+                    Quality::MaxResolution(usize::MAX, height.parse().map_err(|_| invalid())?)
+                } else if let Some((width, height)) = s.split_once('x') {
+                    Quality::MaxResolution(
+                        width.parse().map_err(|_| invalid())?,
+                        height.parse().map_err(|_| invalid())?,
+                    )
+                } else {
+                    let (number, multiplier) = match s.strip_suffix('k') {
+                        Some(n) => (n, 1_000),
+                        None => match s.strip_suffix('m') {
+                            Some(n) => (n, 1_000_000),
+                            None => (s.as_str(), 1),
+                        },
+                    };
+                    Quality::MaxBandwidth(
+                        number.parse::<u64>().map_err(|_| invalid())? * multiplier,
+                    )
+                }
+            }
+        };
+        Ok(quality)
+    }
+}
+
+impl Quality {
+    /// Returns true if a variant with `bandwidth` and `resolution` doesn't exceed this quality
+    fn allows(&self, bandwidth: u64, resolution: Option<(usize, usize)>) -> bool {
+        match *self {
+            Quality::Highest | Quality::Lowest => true,
+            Quality::MaxBandwidth(max) => bandwidth <= max,
+            Quality::MaxResolution(max_width, max_height) => {
+                resolution.is_none_or(|(w, h)| w <= max_width && h <= max_height)
+            }
+        }
+    }
+}
+
+/// The variant of a master playlist that was selected for a stream, as reported at `/streams`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SelectedVariant {
+    /// Bandwidth of the variant according to the master playlist (bits per second)
+    pub bandwidth: u64,
+    /// Resolution of the variant (e.g. "1280x720"), if the master playlist has it
+    pub resolution: Option<String>,
+}
+
+/// A variant stream of a master playlist
+struct Variant {
+    bandwidth: u64,
+    resolution: Option<(usize, usize)>,
+    uri: String,
+}
+
+/// Absolute URL of the variant in `variant_streams` that matches `quality`, and the selected
+/// variant. When no variant fits within a maximum, the lowest quality variant is used. `stream_url` is
+/// the URL of the master playlist, which is also returned if it doesn't contain any variants.
+pub fn variant_url(
+    variant_streams: Vec<VariantStream>,
+    stream_url: &str,
+    quality: &Quality,
+) -> (String, Option<SelectedVariant>) {
+    let mut variants: Vec<Variant> = variant_streams
+        .into_iter()
+        .filter_map(|v| match v {
+            VariantStream::ExtXStreamInf {
+                uri, stream_data, ..
+            } => Some(Variant {
+                bandwidth: stream_data.bandwidth(),
+                resolution: stream_data.resolution().map(|r| (r.width(), r.height())),
+                uri: uri.into_owned(),
+            }),
+            _ => None,
+        })
+        .collect();
+    variants.sort_by_key(|v| v.bandwidth);
+
+    let variant = match quality {
+        Quality::Lowest => variants.first(),
+        _ => variants
+            .iter()
+            .rev()
+            .find(|v| quality.allows(v.bandwidth, v.resolution))
+            .or_else(|| variants.first()),
+    };
+    match variant {
+        Some(v) => {
+            debug!(
+                "Selected variant {} ({} bps, {:?}) for quality {:?}",
+                v.uri, v.bandwidth, v.resolution, quality
+            );
+            let url = Url::parse(stream_url)
+                .and_then(|u| u.join(&v.uri))
+                .map(|u| u.to_string())
+                .unwrap_or_else(|_| stream_url.to_owned());
+            let selected = SelectedVariant {
+                bandwidth: v.bandwidth,
+                resolution: v.resolution.map(|(w, h)| format!("{}x{}", w, h)),
+            };
+            (url, Some(selected))
+        }
+        None => (stream_url.to_owned(), None),
+    }
+}
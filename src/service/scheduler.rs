@@ -0,0 +1,78 @@
+use futures::{pin_mut, FutureExt};
+use rand::Rng;
+use tokio::{
+    sync::Notify,
+    time::{sleep, Duration},
+};
+
+/// Delay before retrying after the first failed refresh. Every consecutive failure doubles the delay,
+/// up to the refresh interval.
+static BASE_BACKOFF: u64 = 30;
+
+/// Schedules periodic refreshes of station and EPG data. Every refresh is delayed by a random
+/// jitter, so multiple instances (or services) don't all hit locast at the same time. When a refresh
+/// fails, it is retried with exponential backoff.
+pub struct RefreshScheduler {
+    name: String,
+    interval: u64,
+    jitter: u64,
+    next_delay: u64,
+    failures: u32,
+}
+
+impl RefreshScheduler {
+    /// Create a scheduler that refreshes every `interval` seconds (plus up to `jitter` seconds).
+    /// The first refresh happens after `first_refresh` seconds (plus jitter).
+    pub fn new(name: &str, interval: u64, jitter: u64, first_refresh: u64) -> RefreshScheduler {
+        RefreshScheduler {
+            name: name.to_owned(),
+            interval,
+            jitter,
+            next_delay: first_refresh,
+            failures: 0,
+        }
+    }
+
+    /// Wait until the next refresh is due, or until a refresh is `requested`
+    pub async fn wait(&self, requested: &Notify) {
+        let jitter = if self.jitter > 0 {
+            rand::thread_rng().gen_range(0..=self.jitter)
+        } else {
+            0
+        };
+        let delay = self.next_delay + jitter;
+        debug!("Next refresh for {} in {}s", self.name, delay);
+        let due = sleep(Duration::from_secs(delay)).fuse();
+        let requested = requested.notified().fuse();
+        pin_mut!(due, requested);
+        futures::select! {
+            _ = due => {},
+            _ = requested => info!("Refresh for {} requested", self.name),
+        }
+    }
+
+    /// Record a successful refresh. The next refresh is scheduled after the regular interval.
+    pub fn succeeded(&mut self) {
+        if self.failures > 0 {
+            info!(
+                "Refresh for {} succeeded after {} failure(s)",
+                self.name, self.failures
+            );
+        }
+        self.failures = 0;
+        self.next_delay = self.interval;
+    }
+
+    /// Record a failed refresh. The next refresh is scheduled using exponential backoff. Returns the
+    /// number of consecutive failures.
+    pub fn failed(&mut self) -> u32 {
+        self.failures += 1;
+        let backoff = BASE_BACKOFF.saturating_mul(1 << (self.failures - 1).min(16));
+        self.next_delay = backoff.min(self.interval);
+        warn!(
+            "Refresh for {} failed ({} consecutive failure(s)), retrying in {}s",
+            self.name, self.failures, self.next_delay
+        );
+        self.failures
+    }
+}
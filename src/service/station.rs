@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use chrono_tz::Tz;
 use futures::lock::Mutex;
 use serde::{Deserialize, Serialize};
 #[allow(non_snake_case)]
@@ -24,9 +25,31 @@ pub struct Station {
     pub channel_remapped: Option<String>,
     pub callSign_remapped: Option<String>,
     pub remapped: Option<bool>,
+    /// Channel as reported by locast (if any)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_locast: Option<String>,
+    /// Channel based on FCC facility data (if any)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_fcc: Option<String>,
 }
 pub type Stations = Arc<Mutex<Vec<Station>>>;
 
+impl Station {
+    /// Timezone of the station's location, or UTC if it's unknown
+    pub fn tz(&self) -> Tz {
+        self.timezone
+            .as_ref()
+            .and_then(|t| t.parse::<Tz>().ok())
+            .unwrap_or(Tz::UTC)
+    }
+}
+
+/// Sort key of a channel number (e.g. "4.1" --> (4, 1))
+pub fn channel_key(channel: &str) -> (u32, u32) {
+    let mut parts = channel.split('.').map(|p| p.parse().unwrap_or(u32::MAX));
+    (parts.next().unwrap_or(u32::MAX), parts.next().unwrap_or(0))
+}
+
 #[allow(non_snake_case)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Listing {
@@ -50,6 +73,12 @@ pub struct Listing {
     pub hasSeriesArtwork: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub isNew: Option<bool>,
+    /// Broadcast live, e.g. sports and news
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isLive: Option<bool>,
+    /// First episode of a series or season
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isPremiere: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preferredImage: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -89,3 +118,24 @@ pub struct ChannelRemapEntry {
     pub active: bool,
     pub remapped: bool,
 }
+
+impl From<&Station> for ChannelRemapEntry {
+    /// Build a remap entry that reflects the current (possibly remapped) state of a station
+    fn from(station: &Station) -> Self {
+        ChannelRemapEntry {
+            original_call_sign: station.callSign.clone(),
+            remap_call_sign: station
+                .callSign_remapped
+                .clone()
+                .unwrap_or_else(|| station.callSign.clone()),
+            original_channel: station.channel.clone().unwrap(),
+            remap_channel: station
+                .channel_remapped
+                .clone()
+                .unwrap_or_else(|| station.channel.clone().unwrap()),
+            city: station.city.clone().unwrap(),
+            active: station.active,
+            remapped: station.remapped.unwrap_or(false),
+        }
+    }
+}
@@ -1,16 +1,141 @@
-use crate::errors::AppError;
+use crate::{
+    credentials::{AccountLease, AccountStatus},
+    errors::AppError,
+    fcc_facilities::FCCFacilities,
+};
 
-use super::{station::Stations, Geo, LocastServiceArc};
+use super::{
+    adhoc::{AdhocChannel, AdhocRequest},
+    conflicts::ChannelConflict,
+    coverage::EpgCoverage,
+    lineup::LineupDiff,
+    quality::SelectedVariant,
+    station::{ChannelRemapEntry, Stations},
+    Geo, ServiceStatus, StationRoute,
+};
 use async_trait::async_trait;
 use futures::lock::Mutex;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
+/// A service of any backend, as used by the `Multiplexer` and the tuners to mix backends
+pub type ServiceArc = Arc<dyn StationProvider + Send + Sync>;
+
+/// Source of stations, listings and streams. Implemented by `LocastService` (a locast location) and
+/// `M3uService` (an M3U playlist with an optional XMLTV guide), and by the `Multiplexer`, which combines
+/// services of any backend.
 #[async_trait]
 pub trait StationProvider {
-    async fn station_stream_uri(&self, id: &str) -> Result<Mutex<String>, AppError>;
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        lease: &AccountLease,
+    ) -> Result<Mutex<String>, AppError>;
+    /// Resolve the stream of a station again the next time, e.g. because its URI stopped working
+    async fn forget_stream_uri(&self, id: &str);
+    /// Variant of the master playlist that the last resolved stream of a station uses, if known
+    async fn stream_variant(&self, id: &str) -> Option<SelectedVariant>;
     async fn stations(&self) -> Stations;
     fn geo(&self) -> Arc<Geo>;
     fn uuid(&self) -> String;
     fn zipcode(&self) -> String;
-    fn services(&self) -> Vec<LocastServiceArc>;
+    fn services(&self) -> Vec<ServiceArc>;
+    /// Lease the least loaded locast account of the service that streams station `id`
+    async fn lease_account(&self, id: &str) -> Result<AccountLease, AppError>;
+    /// Status of the locast accounts of all services
+    async fn account_status(&self) -> Vec<AccountStatus>;
+    fn fcc_facilities(&self) -> Arc<FCCFacilities>;
+    async fn status(&self) -> Vec<ServiceStatus>;
+    /// Fetch the stations from the backend right away. Returns once the refresh was attempted.
+    async fn refresh(&self);
+    async fn epg_coverage(&self) -> Vec<EpgCoverage>;
+    async fn lineup_diffs(&self) -> Vec<LineupDiff>;
+    async fn routing(&self) -> Vec<StationRoute>;
+    async fn channel_conflicts(&self) -> Vec<ChannelConflict>;
+    async fn update_channel_remap(
+        &self,
+        entries: HashMap<String, ChannelRemapEntry>,
+    ) -> Result<(), AppError>;
+    async fn remove_channel_remap(&self, key: &str) -> Result<(), AppError>;
+    async fn adhoc_channels(&self) -> Vec<AdhocChannel>;
+    async fn add_adhoc_channel(&self, request: AdhocRequest) -> Result<AdhocChannel, AppError>;
+    async fn remove_adhoc_channel(&self, id: i64) -> Result<(), AppError>;
+}
+
+/// Services of different backends are passed around as `ServiceArc`, so they can be served by the same
+/// tuners
+#[async_trait]
+impl StationProvider for ServiceArc {
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        lease: &AccountLease,
+    ) -> Result<Mutex<String>, AppError> {
+        (**self).station_stream_uri(id, lease).await
+    }
+    async fn forget_stream_uri(&self, id: &str) {
+        (**self).forget_stream_uri(id).await
+    }
+    async fn stream_variant(&self, id: &str) -> Option<SelectedVariant> {
+        (**self).stream_variant(id).await
+    }
+    async fn stations(&self) -> Stations {
+        (**self).stations().await
+    }
+    fn geo(&self) -> Arc<Geo> {
+        (**self).geo()
+    }
+    fn uuid(&self) -> String {
+        (**self).uuid()
+    }
+    fn zipcode(&self) -> String {
+        (**self).zipcode()
+    }
+    fn services(&self) -> Vec<ServiceArc> {
+        (**self).services()
+    }
+    async fn lease_account(&self, id: &str) -> Result<AccountLease, AppError> {
+        (**self).lease_account(id).await
+    }
+    async fn account_status(&self) -> Vec<AccountStatus> {
+        (**self).account_status().await
+    }
+    fn fcc_facilities(&self) -> Arc<FCCFacilities> {
+        (**self).fcc_facilities()
+    }
+    async fn status(&self) -> Vec<ServiceStatus> {
+        (**self).status().await
+    }
+    async fn refresh(&self) {
+        (**self).refresh().await
+    }
+    async fn epg_coverage(&self) -> Vec<EpgCoverage> {
+        (**self).epg_coverage().await
+    }
+    async fn lineup_diffs(&self) -> Vec<LineupDiff> {
+        (**self).lineup_diffs().await
+    }
+    async fn routing(&self) -> Vec<StationRoute> {
+        (**self).routing().await
+    }
+    async fn channel_conflicts(&self) -> Vec<ChannelConflict> {
+        (**self).channel_conflicts().await
+    }
+    async fn update_channel_remap(
+        &self,
+        entries: HashMap<String, ChannelRemapEntry>,
+    ) -> Result<(), AppError> {
+        (**self).update_channel_remap(entries).await
+    }
+    async fn remove_channel_remap(&self, key: &str) -> Result<(), AppError> {
+        (**self).remove_channel_remap(key).await
+    }
+    async fn adhoc_channels(&self) -> Vec<AdhocChannel> {
+        (**self).adhoc_channels().await
+    }
+    async fn add_adhoc_channel(&self, request: AdhocRequest) -> Result<AdhocChannel, AppError> {
+        (**self).add_adhoc_channel(request).await
+    }
+    async fn remove_adhoc_channel(&self, id: i64) -> Result<(), AppError> {
+        (**self).remove_adhoc_channel(id).await
+    }
 }
@@ -0,0 +1,50 @@
+use super::quality::SelectedVariant;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a resolved stream URL is reused
+const STREAM_URI_TTL: Duration = Duration::from_secs(15);
+
+/// Stream URLs of stations that were resolved recently. Media servers often request the same station
+/// a few times in quick succession when tuning, which would otherwise resolve the stream with locast
+/// every time.
+#[derive(Debug, Default)]
+pub struct StreamUris {
+    uris: Mutex<HashMap<String, (String, Instant)>>,
+    /// Variant of the last stream URL of every station
+    variants: Mutex<HashMap<String, SelectedVariant>>,
+}
+
+impl StreamUris {
+    /// The stream URL of `station_id`, if it was resolved less than `STREAM_URI_TTL` ago
+    pub fn get(&self, station_id: &str) -> Option<String> {
+        let mut uris = self.uris.lock().unwrap();
+        uris.retain(|_, (_, resolved)| resolved.elapsed() < STREAM_URI_TTL);
+        uris.get(station_id).map(|(uri, _)| uri.clone())
+    }
+
+    pub fn insert(&self, station_id: &str, uri: &str, variant: Option<SelectedVariant>) {
+        self.uris
+            .lock()
+            .unwrap()
+            .insert(station_id.to_owned(), (uri.to_owned(), Instant::now()));
+        let mut variants = self.variants.lock().unwrap();
+        match variant {
+            Some(v) => variants.insert(station_id.to_owned(), v),
+            None => variants.remove(station_id),
+        };
+    }
+
+    /// The variant of the last stream URL of `station_id` that was resolved
+    pub fn variant(&self, station_id: &str) -> Option<SelectedVariant> {
+        self.variants.lock().unwrap().get(station_id).cloned()
+    }
+
+    /// Forget the stream URL of `station_id`, e.g. because it didn't work
+    pub fn forget(&self, station_id: &str) {
+        self.uris.lock().unwrap().remove(station_id);
+    }
+}
@@ -0,0 +1,123 @@
+use chrono_tz::Tz;
+use serde::Serialize;
+
+/// Zone to use when nothing else works
+static DEFAULT_TIMEZONE: &str = "UTC";
+
+/// Abbreviations and names that show up instead of IANA zone names, mapped to the zone they mean
+static ALIASES: &[(&str, &str)] = &[
+    ("EST", "America/New_York"),
+    ("EDT", "America/New_York"),
+    ("ET", "America/New_York"),
+    ("EASTERN", "America/New_York"),
+    ("CST", "America/Chicago"),
+    ("CDT", "America/Chicago"),
+    ("CT", "America/Chicago"),
+    ("CENTRAL", "America/Chicago"),
+    ("MST", "America/Denver"),
+    ("MDT", "America/Denver"),
+    ("MT", "America/Denver"),
+    ("MOUNTAIN", "America/Denver"),
+    ("ARIZONA", "America/Phoenix"),
+    ("PST", "America/Los_Angeles"),
+    ("PDT", "America/Los_Angeles"),
+    ("PT", "America/Los_Angeles"),
+    ("PACIFIC", "America/Los_Angeles"),
+    ("AKST", "America/Anchorage"),
+    ("AKDT", "America/Anchorage"),
+    ("ALASKA", "America/Anchorage"),
+    ("HST", "Pacific/Honolulu"),
+    ("HAWAII", "Pacific/Honolulu"),
+    ("AST", "America/Puerto_Rico"),
+    ("ATLANTIC", "America/Puerto_Rico"),
+];
+
+/// Where a resolved timezone came from
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimezoneSource {
+    /// The zone locast reported is a valid zone
+    Reported,
+    /// The zone locast reported is a known alias
+    Alias,
+    /// Looked up from the coordinates of the location
+    Coordinates,
+    /// Nothing worked, UTC is used
+    Default,
+}
+
+/// How the timezone of a location was resolved. Reported at `/status`.
+#[derive(Debug, Serialize, Clone)]
+pub struct TimezoneResolution {
+    /// Timezone as reported by locast
+    pub reported: Option<String>,
+    /// Timezone looked up from the coordinates of the location
+    pub coordinates: Option<String>,
+    /// Timezone that is used
+    pub resolved: String,
+    pub source: TimezoneSource,
+    /// True if the reported timezone couldn't be used as is, or doesn't match the coordinates
+    pub mismatch: bool,
+}
+
+/// Resolve the timezone of a location, so it's always a valid zone. The timezone reported by locast
+/// is used if it's valid or a known alias, then the timezone of the coordinates, then UTC.
+pub fn resolve(reported: Option<&str>, latitude: f64, longitude: f64) -> TimezoneResolution {
+    let reported = reported.map(str::trim).filter(|r| !r.is_empty());
+    let coordinates = tz_search::lookup(latitude, longitude).filter(|tz| tz.parse::<Tz>().is_ok());
+
+    let (resolved, source) = match reported {
+        Some(r) if r.parse::<Tz>().is_ok() => (r.to_owned(), TimezoneSource::Reported),
+        Some(r) => match alias(r) {
+            Some(a) => (a.to_owned(), TimezoneSource::Alias),
+            None => fallback(&coordinates),
+        },
+        None => fallback(&coordinates),
+    };
+    let mismatch = reported.is_some()
+        && (source != TimezoneSource::Reported
+            || coordinates
+                .as_ref()
+                .is_some_and(|c| !same_zone(c, &resolved)));
+
+    TimezoneResolution {
+        reported: reported.map(str::to_owned),
+        coordinates,
+        resolved,
+        source,
+        mismatch,
+    }
+}
+
+fn fallback(coordinates: &Option<String>) -> (String, TimezoneSource) {
+    match coordinates {
+        Some(c) => (c.clone(), TimezoneSource::Coordinates),
+        None => (DEFAULT_TIMEZONE.to_owned(), TimezoneSource::Default),
+    }
+}
+
+fn alias(timezone: &str) -> Option<&'static str> {
+    let timezone = timezone.to_ascii_uppercase();
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == timezone)
+        .map(|(_, tz)| *tz)
+}
+
+/// Returns true if both zones are the same, or one is a link to the other (e.g. "US/Eastern" and
+/// "America/New_York"). Zones are compared by their UTC offsets in the middle of winter and summer.
+fn same_zone(a: &str, b: &str) -> bool {
+    use chrono::{Offset, TimeZone};
+
+    if a == b {
+        return true;
+    }
+    let (a, b) = match (a.parse::<Tz>(), b.parse::<Tz>()) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return false,
+    };
+    [(1, 15), (7, 15)].iter().all(|&(month, day)| {
+        let date = chrono::NaiveDate::from_ymd(2021, month, day).and_hms(12, 0, 0);
+        a.offset_from_utc_datetime(&date).fix() == b.offset_from_utc_datetime(&date).fix()
+    })
+}
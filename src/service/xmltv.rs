@@ -0,0 +1,183 @@
+use crate::{
+    config::{Config, EpgOverride},
+    utils::get,
+};
+use futures::future::join_all;
+use simple_error::SimpleError;
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::sleep;
+use xml::{
+    reader::{EventReader, ParserConfig, XmlEvent},
+    writer::{EmitterConfig, XmlEvent as WriterEvent},
+};
+
+/// Programmes (serialized XMLTV `<programme>` elements) by station id
+pub type Programmes = HashMap<i64, Vec<String>>;
+
+/// External XMLTV sources that replace the locast listings of specific stations. Sources are fetched in
+/// the background every `cache_timeout` seconds, so guide requests never wait for them.
+pub struct EpgOverrides {
+    overrides: Vec<EpgOverride>,
+    timeout: Duration,
+    programmes: RwLock<Arc<Programmes>>,
+}
+
+impl EpgOverrides {
+    pub fn new(config: &Config) -> EpgOverrides {
+        EpgOverrides {
+            overrides: config.epg_overrides.clone(),
+            timeout: Duration::from_secs(config.cache_timeout),
+            programmes: RwLock::new(Arc::new(HashMap::new())),
+        }
+    }
+
+    /// The programmes for all stations that have an override, as they were last fetched. Until the
+    /// sources are fetched for the first time, the locast listings are used.
+    pub fn programmes(&self) -> Arc<Programmes> {
+        self.programmes.read().unwrap().clone()
+    }
+
+    /// Fetch all sources every `cache_timeout` seconds
+    pub async fn refresh(self: Arc<Self>) {
+        if self.overrides.is_empty() {
+            return;
+        }
+        loop {
+            self.fetch_all().await;
+            sleep(self.timeout).await;
+        }
+    }
+
+    /// Fetch all sources at the same time, so a slow source doesn't hold up the others
+    async fn fetch_all(&self) {
+        let results = join_all(
+            self.overrides
+                .iter()
+                .map(|o| async move { (o, load_programmes(o).await) }),
+        )
+        .await;
+
+        let previous = self.programmes();
+        let mut programmes = HashMap::new();
+        for (o, result) in results {
+            match result {
+                Ok(p) => {
+                    info!(
+                        "Loaded {} programmes for station {} from {}",
+                        p.len(),
+                        o.station_id,
+                        o.source
+                    );
+                    programmes.insert(o.station_id, p);
+                }
+                Err(e) => {
+                    // Keep using the programmes we loaded before. If there aren't any, the
+                    // locast listings are used.
+                    warn!(
+                        "Unable to load EPG override for station {}: {}",
+                        o.station_id, e
+                    );
+                    if let Some(p) = previous.get(&o.station_id) {
+                        programmes.insert(o.station_id, p.clone());
+                    }
+                }
+            }
+        }
+        *self.programmes.write().unwrap() = Arc::new(programmes);
+    }
+}
+
+/// Fetch an XMLTV source (a URL or a file) and extract the programmes for a station
+async fn load_programmes(o: &EpgOverride) -> Result<Vec<String>, SimpleError> {
+    let xmltv = fetch(&o.source).await?;
+    extract_programmes(&xmltv, o.channel.as_deref(), o.station_id)
+}
+
+/// Fetch a source that's either a URL or a file
+pub async fn fetch(source: &str) -> Result<String, SimpleError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        get(source, None, 3)
+            .await
+            .map_err(SimpleError::from)?
+            .text()
+            .await
+            .map_err(SimpleError::from)
+    } else {
+        fs::read_to_string(source).map_err(SimpleError::from)
+    }
+}
+
+/// Extract all `<programme>` elements for `channel` (or all programmes if no channel is specified)
+/// from an XMLTV document. The channel of each programme is rewritten to the channel id locast2tuner
+/// uses for `station_id`, so the programmes can be merged into our own EPG.
+pub fn extract_programmes(
+    xmltv: &str,
+    channel: Option<&str>,
+    station_id: i64,
+) -> Result<Vec<String>, SimpleError> {
+    let channel_id = format!("channel.{}", station_id);
+    let reader = EventReader::new_with_config(
+        xmltv.as_bytes(),
+        ParserConfig::new()
+            .trim_whitespace(true)
+            .ignore_comments(true),
+    );
+
+    let mut programmes = Vec::new();
+    let mut writer = None;
+    let mut depth = 0;
+    for event in reader {
+        let event = event.map_err(SimpleError::from)?;
+
+        // Start capturing when we find a programme for the channel we're looking for
+        if writer.is_none() {
+            if let XmlEvent::StartElement {
+                name, attributes, ..
+            } = &event
+            {
+                let programme_channel = attributes
+                    .iter()
+                    .find(|a| a.name.local_name == "channel")
+                    .map(|a| a.value.as_str());
+                if name.local_name == "programme"
+                    && channel.is_none_or(|c| programme_channel == Some(c))
+                {
+                    let mut w = EmitterConfig::new()
+                        .write_document_declaration(false)
+                        .create_writer(Vec::new());
+                    let mut start = WriterEvent::start_element(name.borrow());
+                    for a in attributes {
+                        start = match a.name.local_name.as_str() {
+                            "channel" => start.attr(a.name.borrow(), &channel_id),
+                            _ => start.attr(a.name.borrow(), &a.value),
+                        };
+                    }
+                    w.write(start).map_err(SimpleError::from)?;
+                    writer = Some(w);
+                    depth = 1;
+                }
+            }
+            continue;
+        }
+
+        let w = writer.as_mut().unwrap();
+        match event {
+            XmlEvent::StartElement { .. } => depth += 1,
+            XmlEvent::EndElement { .. } => depth -= 1,
+            _ => {}
+        }
+        if let Some(e) = event.as_writer_event() {
+            w.write(e).map_err(SimpleError::from)?;
+        }
+        if depth == 0 {
+            let programme = writer.take().unwrap().into_inner();
+            programmes.push(String::from_utf8(programme).map_err(SimpleError::from)?);
+        }
+    }
+    Ok(programmes)
+}
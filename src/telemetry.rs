@@ -0,0 +1,155 @@
+// Optional OpenTelemetry tracing. When locast2tuner is built with the `otel` feature and an
+// `otlp_endpoint` is configured, spans are exported using OTLP over HTTP. Without the `otel`
+// feature, spans are no-ops.
+use crate::config::Config;
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    trace::{Span as _, SpanContext, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use std::future::Future;
+
+#[cfg(feature = "otel")]
+static TRACER_NAME: &str = "locast2tuner";
+
+/// A span that ends when it's dropped or when `end` is called
+pub struct Span {
+    #[cfg(feature = "otel")]
+    inner: BoxedSpan,
+}
+
+/// Reference to a span, that spans started in its scope (see `in_scope`) are children of
+#[derive(Clone)]
+pub struct Parent {
+    #[cfg(feature = "otel")]
+    context: SpanContext,
+}
+
+tokio::task_local! {
+    static PARENT: Parent;
+}
+
+/// Run `f` with `parent` as the parent of all spans that are started by it, so e.g. the stream and
+/// locast requests of an HTTP request end up in the same trace as the request itself
+pub fn in_scope<F: Future>(parent: Parent, f: F) -> impl Future<Output = F::Output> {
+    PARENT.scope(parent, f)
+}
+
+#[cfg(feature = "otel")]
+impl Span {
+    /// Start a new span, as a child of the span in whose scope it's started (if any). `stream_id` is
+    /// used to correlate spans that belong to the same stream.
+    pub fn new(name: &'static str, stream_id: Option<&str>) -> Span {
+        let tracer = global::tracer(TRACER_NAME);
+        let mut inner = match PARENT.try_with(|p| p.context.clone()) {
+            Ok(parent) => {
+                tracer.start_with_context(name, &Context::new().with_remote_span_context(parent))
+            }
+            Err(_) => tracer.start(name),
+        };
+        if let Some(stream_id) = stream_id {
+            inner.set_attribute(KeyValue::new("stream.id", stream_id.to_owned()));
+        }
+        Span { inner }
+    }
+
+    /// Start a span that's a child of this one, e.g. a segment download of a stream
+    pub fn child(&self, name: &'static str) -> Span {
+        let parent = Context::new().with_remote_span_context(self.inner.span_context().clone());
+        Span {
+            inner: global::tracer(TRACER_NAME).start_with_context(name, &parent),
+        }
+    }
+
+    /// Reference to this span, to start spans as its children with `in_scope`
+    pub fn parent(&self) -> Parent {
+        Parent {
+            context: self.inner.span_context().clone(),
+        }
+    }
+
+    /// Add an attribute to the span
+    pub fn set_attribute(&mut self, key: &'static str, value: String) {
+        self.inner.set_attribute(KeyValue::new(key, value));
+    }
+
+    /// Record something that happened during the span, e.g. a stall of a stream
+    pub fn add_event(&mut self, name: &'static str) {
+        self.inner.add_event(name, Vec::new());
+    }
+
+    /// End the span before it goes out of scope
+    pub fn end(mut self) {
+        self.inner.end();
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl Span {
+    pub fn new(_name: &'static str, _stream_id: Option<&str>) -> Span {
+        Span {}
+    }
+
+    pub fn child(&self, _name: &'static str) -> Span {
+        Span {}
+    }
+
+    pub fn parent(&self) -> Parent {
+        Parent {}
+    }
+
+    pub fn set_attribute(&mut self, _key: &'static str, _value: String) {}
+
+    pub fn add_event(&mut self, _name: &'static str) {}
+
+    pub fn end(self) {}
+}
+
+/// Install the OTLP exporter if an `otlp_endpoint` is configured
+#[cfg(feature = "otel")]
+pub fn init(config: &Config) {
+    let endpoint = match &config.otlp_endpoint {
+        Some(e) => e,
+        None => return,
+    };
+
+    let exporter = match SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+    {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Unable to create OTLP exporter for {}: {}", endpoint, e);
+            return;
+        }
+    };
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            TRACER_NAME,
+        )]))
+        .build();
+    global::set_tracer_provider(provider);
+    info!("Exporting traces to {}", endpoint);
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(config: &Config) {
+    if config.otlp_endpoint.is_some() {
+        warn!("otlp_endpoint is set, but locast2tuner was built without the otel feature");
+    }
+}
+
+/// Flush and stop exporting traces
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    global::shutdown_tracer_provider();
+}
@@ -1,6 +1,8 @@
+use crate::config::Config;
 use again::RetryPolicy;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use chrono_tz::Tz;
+use derive_more::Display;
 use lazy_static::lazy_static;
 use regex::Regex;
 use reqwest::{
@@ -8,59 +10,283 @@ use reqwest::{
     Response,
 };
 use serde_json::Value;
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 pub trait Or {
     /// Return `self` if it's not empty, otherwise `other`
-    fn or<'a>(&'a self, other: &'a str) -> &str;
+    fn or<'a>(&'a self, other: &'a str) -> &'a str;
 }
 
 static BACKOFF_DELAY: u64 = 100;
 static MAX_DELAY: u64 = 5000;
+/// Host of the locast API, which requests are rate limited to
+static LOCAST_API_HOST: &str = "api.locastnet.org";
 
 lazy_static! {
     static ref POLICY: RetryPolicy = RetryPolicy::exponential(Duration::from_millis(BACKOFF_DELAY))
         .with_max_delay(Duration::from_millis(MAX_DELAY))
         .with_jitter(false);
+    static ref HTTP_SETTINGS: RwLock<HttpSettings> = RwLock::new(HttpSettings::default());
+    static ref BREAKERS: Mutex<HashMap<String, Breaker>> = Mutex::new(HashMap::new());
+    /// When the next request to the locast API may be sent
+    static ref NEXT_LOCAST_REQUEST: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+/// Settings for outgoing HTTP requests
+#[derive(Clone)]
+struct HttpSettings {
+    /// Client shared by all requests, so connections (and their TLS sessions) are pooled
+    client: reqwest::Client,
+    max_retries: usize,
+    breaker_threshold: u32,
+    breaker_cooldown: Duration,
+    /// Time between requests to the locast API, zero if they're not rate limited
+    locast_interval: Duration,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(60))
+                .build()
+                .unwrap_or_default(),
+            max_retries: 100,
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(30),
+            locast_interval: Duration::ZERO,
+        }
+    }
+}
+
+/// Configure the client, retries, timeouts and the circuit breaker for all outgoing HTTP requests
+pub fn configure_http(config: &Config) {
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.http_timeout))
+        .connect_timeout(Duration::from_secs(config.http_connect_timeout))
+        .pool_idle_timeout(Duration::from_secs(90));
+    // Proxies are validated when the config is loaded, and only one of them can be set
+    if let Some(proxy) = config
+        .http_proxy
+        .as_ref()
+        .or(config.socks_proxy.as_ref())
+        .and_then(|p| reqwest::Proxy::all(p).ok())
+    {
+        builder = builder.proxy(proxy);
+    }
+    let client = match builder.build() {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Unable to configure the HTTP client, using defaults: {}", e);
+            reqwest::Client::new()
+        }
+    };
+    *HTTP_SETTINGS.write().unwrap() = HttpSettings {
+        client,
+        max_retries: config.http_max_retries,
+        breaker_threshold: config.circuit_breaker_threshold,
+        breaker_cooldown: Duration::from_secs(config.circuit_breaker_cooldown),
+        locast_interval: if config.locast_rate_limit > 0.0 {
+            Duration::from_secs_f64(1.0 / config.locast_rate_limit)
+        } else {
+            Duration::ZERO
+        },
+    };
+}
+
+/// Circuit breaker state of a host. After `breaker_threshold` consecutive failures, the circuit opens
+/// and requests to the host fail immediately for `breaker_cooldown`. After that, a single request is
+/// let through to probe if the host recovered.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Errors of outgoing HTTP requests
+#[derive(Debug, Display)]
+pub enum HttpError {
+    #[display(fmt = "{}", _0)]
+    Request(reqwest::Error),
+    #[display(fmt = "circuit breaker for {} is open", _0)]
+    CircuitOpen(String),
+}
+
+impl std::error::Error for HttpError {}
+
+impl HttpError {
+    /// Status code of the response, if the request failed because of it
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            HttpError::Request(e) => e.status(),
+            HttpError::CircuitOpen(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for HttpError {
+    fn from(e: reqwest::Error) -> Self {
+        HttpError::Request(e)
+    }
+}
+
+/// Result of a single attempt. Server errors are retried, but returned as a response when retries run out.
+#[derive(Debug)]
+enum Attempt {
+    Failed(HttpError),
+    ServerError(Response),
+}
+
+impl Attempt {
+    /// An open circuit breaker or a request that can't be built won't be fixed by retrying
+    fn retryable(&self) -> bool {
+        match self {
+            Attempt::Failed(HttpError::CircuitOpen(_)) => false,
+            Attempt::Failed(HttpError::Request(e)) => !e.is_builder(),
+            Attempt::ServerError(_) => true,
+        }
+    }
 }
+
 /// HTTP Get (async). A token is optional, but should be used for authenticated requests
 pub async fn get(
     uri: &str,
     token: Option<&str>,
     max_retries: usize,
-) -> Result<Response, reqwest::Error> {
-    POLICY
-        .clone()
-        .with_max_retries(max_retries)
-        .retry(|| async {
-            let client = reqwest::Client::new();
-            let request_builder = client.get(uri).headers(construct_headers());
-            let request = match token {
-                Some(t) => request_builder.header("authorization", format!("Bearer {}", t)),
-                None => request_builder,
-            }
-            .build()
-            .unwrap();
-            Ok(client.execute(request).await?)
-        })
-        .await
-}
-
-pub async fn post(uri: &str, data: Value, max_retries: usize) -> Result<Response, reqwest::Error> {
-    POLICY
+) -> Result<Response, HttpError> {
+    send(uri, max_retries, |client| {
+        let request_builder = client.get(uri).headers(construct_headers());
+        match token {
+            Some(t) => request_builder.header("authorization", format!("Bearer {}", t)),
+            None => request_builder,
+        }
+    })
+    .await
+}
+
+pub async fn post(uri: &str, data: Value, max_retries: usize) -> Result<Response, HttpError> {
+    send(uri, max_retries, |client| {
+        client.post(uri).headers(construct_headers()).json(&data)
+    })
+    .await
+}
+
+/// Send a request built by `build`. Connection errors, timeouts and server errors (5xx) are retried up
+/// to `max_retries` times (capped by `http_max_retries`) with exponential backoff. Requests to a host
+/// with an open circuit breaker fail right away.
+async fn send<F>(uri: &str, max_retries: usize, build: F) -> Result<Response, HttpError>
+where
+    F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+{
+    let settings = HTTP_SETTINGS.read().unwrap().clone();
+    let host = reqwest::Url::parse(uri)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_owned()))
+        .unwrap_or_default();
+    let (settings, host, build) = (&settings, &host, &build);
+
+    let result = POLICY
         .clone()
-        .with_max_retries(max_retries)
-        .retry(|| async {
-            let client = reqwest::Client::new();
-            let request = client
-                .post(uri)
-                .headers(construct_headers())
-                .json(&data)
-                .build()
-                .unwrap();
-            Ok(client.execute(request).await?)
-        })
-        .await
+        .with_max_retries(max_retries.min(settings.max_retries))
+        .retry_if(
+            || async move {
+                if !breaker_allows(host, settings) {
+                    return Err(Attempt::Failed(HttpError::CircuitOpen(host.to_owned())));
+                }
+                if host == LOCAST_API_HOST {
+                    rate_limit(settings.locast_interval).await;
+                }
+                let client = &settings.client;
+                let request = build(client)
+                    .build()
+                    .map_err(|e| Attempt::Failed(e.into()))?;
+                match client.execute(request).await {
+                    Ok(r) if r.status().is_server_error() => {
+                        breaker_record(host, false, settings);
+                        Err(Attempt::ServerError(r))
+                    }
+                    Ok(r) => {
+                        breaker_record(host, true, settings);
+                        Ok(r)
+                    }
+                    Err(e) => {
+                        breaker_record(host, false, settings);
+                        Err(Attempt::Failed(e.into()))
+                    }
+                }
+            },
+            Attempt::retryable,
+        )
+        .await;
+
+    match result {
+        Ok(r) | Err(Attempt::ServerError(r)) => Ok(r),
+        Err(Attempt::Failed(e)) => Err(e),
+    }
+}
+
+/// Wait until the next request may be sent, so requests are at least `interval` apart. The requests of
+/// all services share the same limit.
+async fn rate_limit(interval: Duration) {
+    if interval.is_zero() {
+        return;
+    }
+    let wait = {
+        let mut next = NEXT_LOCAST_REQUEST.lock().unwrap();
+        let now = Instant::now();
+        let at = (*next).max(now);
+        *next = at + interval;
+        at - now
+    };
+    if !wait.is_zero() {
+        debug!("Rate limiting locast request for {}ms", wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Check if the circuit breaker of `host` allows a request
+fn breaker_allows(host: &str, settings: &HttpSettings) -> bool {
+    let mut breakers = BREAKERS.lock().unwrap();
+    let breaker = breakers.entry(host.to_owned()).or_default();
+    match breaker.open_until {
+        Some(until) if Instant::now() < until => false,
+        Some(_) => {
+            // Let this request probe the host and keep others out until it has finished
+            breaker.open_until = Some(Instant::now() + settings.breaker_cooldown);
+            true
+        }
+        None => true,
+    }
+}
+
+/// Record the outcome of a request to `host`
+fn breaker_record(host: &str, success: bool, settings: &HttpSettings) {
+    let mut breakers = BREAKERS.lock().unwrap();
+    let breaker = breakers.entry(host.to_owned()).or_default();
+    if success {
+        if breaker.open_until.is_some() {
+            info!("{} recovered, closing circuit breaker", host);
+        }
+        *breaker = Breaker::default();
+        return;
+    }
+
+    breaker.failures += 1;
+    if breaker.failures >= settings.breaker_threshold {
+        if breaker.open_until.is_none() {
+            warn!(
+                "{} failed {} times in a row, opening circuit breaker for {}s",
+                host,
+                breaker.failures,
+                settings.breaker_cooldown.as_secs()
+            );
+        }
+        breaker.open_until = Some(Instant::now() + settings.breaker_cooldown);
+    }
 }
 
 /// Construct additional headers for HTTP requests.
@@ -72,7 +298,6 @@ pub fn construct_headers() -> HeaderMap {
 }
 
 /// Construct a valid HDHomeRun checksum for a `device_id`
-
 pub fn hdhr_checksum(device_id: usize) -> usize {
     let lookup_table: Vec<usize> = vec![
         0xA, 0x5, 0xF, 0x6, 0x7, 0xC, 0x1, 0xB, 0x9, 0x2, 0x8, 0xD, 0x4, 0x3, 0xE, 0x0,
@@ -85,7 +310,7 @@ pub fn hdhr_checksum(device_id: usize) -> usize {
     checksum ^= lookup_table[(device_id >> 12) & 0x0F];
     checksum ^= (device_id >> 8) & 0x0F;
     checksum ^= lookup_table[(device_id >> 4) & 0x0F];
-    checksum ^= (device_id >> 0) & 0x0F;
+    checksum ^= device_id & 0x0F;
     checksum
 }
 
@@ -93,15 +318,19 @@ pub fn hdhr_checksum(device_id: usize) -> usize {
 pub fn name_only(value: &str) -> &str {
     match Regex::new(r"\d+\.\d+ (.+)").unwrap().captures(value) {
         Some(c) => c.get(1).map_or("", |m| m.as_str()),
-        None => &value,
+        None => value,
     }
 }
 
-/// Format time for XMLTV
-pub fn format_time(timestamp: i64) -> String {
+/// Format time for XMLTV in the local time of `timezone`, with its UTC offset at that time (so
+/// DST is taken into account), e.g. "20210314030000 -0700"
+pub fn format_time_local(timestamp: i64, timezone: &Tz) -> String {
     let naive = NaiveDateTime::from_timestamp(timestamp / 1000, 0);
     let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-    datetime.format("%Y%m%d%H%M%S %z").to_string()
+    datetime
+        .with_timezone(timezone)
+        .format("%Y%m%d%H%M%S %z")
+        .to_string()
 }
 
 /// Format date for XMLTV
@@ -124,6 +353,13 @@ pub fn format_time_local_iso(timestamp: i64, timezone: &Tz) -> String {
     in_timezone.format("%Y-%m-%d %H:%M:%S").to_string()
 }
 
+/// Format the local time of day (specified by the timezone) as HH:MM
+pub fn format_clock_local(timestamp: i64, timezone: &Tz) -> String {
+    let naive = NaiveDateTime::from_timestamp(timestamp / 1000, 0);
+    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+    datetime.with_timezone(timezone).format("%H:%M").to_string()
+}
+
 /// Format a timestamp as an ISO 8601 date, based on the current time in UTC
 pub fn format_date_iso(timestamp: i64) -> String {
     let naive = NaiveDateTime::from_timestamp(timestamp / 1000, 0);
@@ -131,7 +367,7 @@ pub fn format_date_iso(timestamp: i64) -> String {
     datetime.format("%F").to_string()
 }
 
-const HD: [&'static str; 3] = ["1080", "720", "HDTV"];
+const HD: [&str; 3] = ["1080", "720", "HDTV"];
 
 /// Returns the aspect ratio based on a string of properties.
 pub fn aspect_ratio(properties: &str) -> String {
@@ -146,8 +382,8 @@ pub fn aspect_ratio(properties: &str) -> String {
 /// Return either `HDTV` or `SD` based on a string of properties
 pub fn quality(properties: &str) -> String {
     if properties.contains("HDTV") {
-        return "HDTV".to_string();
+        "HDTV".to_string()
     } else {
-        return "SD".to_string();
+        "SD".to_string()
     }
 }